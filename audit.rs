@@ -0,0 +1,63 @@
+/// Audit log of outgoing messages
+///
+/// Each message the bot sends is appended to the audit file as a tab-separated line
+/// of timestamp, origin, command, destination, and text.
+
+use outbound::Origin;
+use std::io;
+use std::str;
+use time;
+
+pub struct AuditLog {
+    priv path: Path,
+    priv file: io::File
+}
+
+impl AuditLog {
+    /// Opens the audit log for appending, creating it if necessary
+    pub fn open(path: &Path) -> io::IoResult<AuditLog> {
+        let file = try!(io::File::open_mode(path, io::Append, io::Write));
+        Ok(AuditLog { path: path.clone(), file: file })
+    }
+
+    /// Records a sent message
+    pub fn record(&mut self, origin: &Origin, cmd: &str, dst: &[u8], msg: &[u8]) {
+        let res = writeln!(&mut self.file, "{}\t{}\t{}\t{}\t{}", time::now_utc().rfc3339(),
+                           origin, cmd, str::from_utf8_lossy(dst), str::from_utf8_lossy(msg));
+        match res.and_then(|_| self.file.flush()) {
+            Ok(()) => (),
+            Err(e) => println!("Error writing audit log {}: {}", self.path.display(), e)
+        }
+    }
+
+    /// Prints the last `count` entries that contain `filter`
+    pub fn print(&self, filter: &str, count: uint) {
+        let file = match io::File::open(&self.path) {
+            Ok(f) => f,
+            Err(e) => {
+                println!("Error reading audit log {}: {}", self.path.display(), e);
+                return;
+            }
+        };
+        let mut entries = ~[];
+        let mut reader = io::BufferedReader::new(file);
+        for line in reader.lines() {
+            let line = match line {
+                Ok(line) => line,
+                Err(_) => break
+            };
+            if line.contains(filter) {
+                if entries.len() == count {
+                    entries.shift();
+                }
+                entries.push(line);
+            }
+        }
+        if entries.is_empty() {
+            println!("No matching audit entries");
+        }
+        for line in entries.iter() {
+            print!("{}", line);
+        }
+    }
+}
@@ -0,0 +1,232 @@
+/// Authentication backends
+///
+/// Each server's auth setting lists the backends the bot authenticates with, in the
+/// order they're tried: "sasl_external" authenticates with SASL EXTERNAL during
+/// capability negotiation, and "nickserv" identifies to NickServ once the bot is
+/// registered. When a backend fails, or can't be used on the connection, the next one
+/// is tried, and none are tried once one succeeds. Backends that run during capability
+/// negotiation are always tried before those that run after registration. Without an
+/// auth setting, the list is "sasl_external" if sasl_external is set, followed by
+/// "nickserv" if nickserv_password is set.
+///
+/// A backend that runs after registration has TIMEOUT to succeed before the next one is
+/// tried. With nickserv_delay_autojoin, the autojoin channels are only joined once
+/// those backends are done.
+
+use config;
+use cap::{Caps, SaslExternal};
+use casemap::CaseMapping;
+use nickserv;
+use outbound::Outbound;
+use timer;
+use State;
+use irc::conn::{Conn, Line};
+use std::mem;
+
+static TIMEOUT: u64 = 30000; // ms a backend has after registration to succeed
+
+/// When a backend authenticates
+#[deriving(Eq)]
+pub enum Stage {
+    Negotiating, // during capability negotiation, before CAP END
+    Registered // once the server has welcomed the bot
+}
+
+/// What a backend made of a line
+pub enum Outcome {
+    Pending,
+    Succeeded,
+    Failed(~str) // with the reason
+}
+
+/// A way of authenticating to the server or its services
+pub trait AuthBackend {
+    /// Returns the backend's name, as used in the auth setting
+    fn name(&self) -> &'static str;
+
+    /// Returns when the backend authenticates
+    fn stage(&self) -> Stage;
+
+    /// Returns the capability the backend needs, which is requested if it's offered
+    fn capability(&self) -> Option<&'static str> {
+        None
+    }
+
+    /// Starts authenticating, returning false if the backend can't on this connection
+    fn start(&mut self, conn: &mut Conn, out: &mut Outbound, caps: &Caps) -> bool;
+
+    /// Called with each line while the backend is authenticating
+    fn line_received(&mut self, conn: &mut Conn, out: &mut Outbound, casemapping: &CaseMapping,
+                     line: &Line) -> Outcome;
+}
+
+/// Returns the backend of that name in the auth setting, if the server has what it needs
+pub fn backend(server: &config::Server, name: &str) -> Option<~AuthBackend> {
+    match name {
+        "sasl_external" => Some(~SaslExternal as ~AuthBackend),
+        "nickserv" => server.nickserv_password.as_ref().map(|password| {
+            ~nickserv::Identify::new(server, password.as_slice()) as ~AuthBackend
+        }),
+        _ => None
+    }
+}
+
+/// A server's backends, and how far through them the bot is on this connection
+pub struct Auth {
+    priv backends: ~[~AuthBackend],
+    priv stage: Stage,
+    priv next: uint, // index of the next backend to try at this stage
+    priv current: Option<uint>, // index of the backend that's authenticating
+    priv attempt: uint, // counts started backends, so a timeout only ends its own
+    priv authenticated: bool,
+    priv delay_autojoin: bool,
+    priv delayed: ~[config::Channel] // channels to join once the backends are done
+}
+
+impl Auth {
+    pub fn new(server: &config::Server) -> Auth {
+        Auth {
+            backends: server.auth.iter().filter_map(|name| backend(server, name.as_slice()))
+                                 .collect(),
+            stage: Negotiating,
+            next: 0,
+            current: None,
+            attempt: 0,
+            authenticated: false,
+            delay_autojoin: server.nickserv_delay_autojoin,
+            delayed: ~[]
+        }
+    }
+
+    /// Returns the capabilities the backends need
+    pub fn capabilities(&self) -> ~[~str] {
+        self.backends.iter().filter_map(|b| b.capability()).map(|c| c.to_owned()).collect()
+    }
+
+    /// Forgets the last connection's authentication. Call this when connected.
+    pub fn reset(&mut self) {
+        self.stage = Negotiating;
+        self.next = 0;
+        self.current = None;
+        self.authenticated = false;
+        self.delayed.clear();
+    }
+
+    /// Starts the first backend that can authenticate at this stage, unless one already
+    /// succeeded. Returns whether one started.
+    pub fn begin(&mut self, conn: &mut Conn, out: &mut Outbound, caps: &Caps,
+                 stage: Stage) -> bool {
+        self.stage = stage;
+        self.next = 0;
+        self.current = None;
+        self.advance(conn, out, caps)
+    }
+
+    /// Passes a line to the backend that's authenticating. Returns whether the backends
+    /// are done with this stage, because one succeeded or the last one failed.
+    pub fn line_received(&mut self, conn: &mut Conn, out: &mut Outbound, caps: &Caps,
+                         casemapping: &CaseMapping, line: &Line) -> bool {
+        let i = match self.current {
+            None => return false,
+            Some(i) => i
+        };
+        let outcome = self.backends[i].line_received(conn, out, casemapping, line);
+        match outcome {
+            Pending => false,
+            Succeeded => {
+                println!("Authenticated with {}", self.backends[i].name());
+                self.authenticated = true;
+                self.current = None;
+                true
+            }
+            Failed(reason) => self.failed(conn, out, caps, reason.as_slice())
+        }
+    }
+
+    /// Gives up on the backend that's authenticating and starts the next one. Returns
+    /// whether the backends are done with this stage.
+    fn failed(&mut self, conn: &mut Conn, out: &mut Outbound, caps: &Caps, reason: &str) -> bool {
+        match self.current {
+            None => return false,
+            Some(i) => {
+                println!("Authenticating with {} failed: {}", self.backends[i].name(), reason);
+            }
+        }
+        self.current = None;
+        !self.advance(conn, out, caps)
+    }
+
+    fn advance(&mut self, conn: &mut Conn, out: &mut Outbound, caps: &Caps) -> bool {
+        if self.authenticated {
+            return false;
+        }
+        let stage = self.stage;
+        while self.next < self.backends.len() {
+            let i = self.next;
+            self.next += 1;
+            let started = {
+                let backend = &mut self.backends[i];
+                backend.stage() == stage && backend.start(conn, out, caps)
+            };
+            if started {
+                self.current = Some(i);
+                self.attempt += 1;
+                return true;
+            }
+        }
+        false
+    }
+}
+
+/// Starts the backends that authenticate after registration, and joins the autojoin
+/// channels unless that's delayed until they're done
+pub fn logged_in(conn: &mut Conn, state: &mut State, autojoin: &[config::Channel]) {
+    let started = state.auth.begin(conn, &mut state.out, &state.caps, Registered);
+    if started {
+        watch(state);
+    }
+    if started && state.auth.delay_autojoin && !autojoin.is_empty() {
+        println!("Delaying autojoin until authenticated");
+        state.auth.delayed = autojoin.to_owned();
+    } else {
+        ::join_channels(conn, &mut state.out, &state.isupport, autojoin);
+    }
+}
+
+/// Passes each line to the backend that's authenticating
+pub fn line_received(conn: &mut Conn, state: &mut State, line: &Line) {
+    let attempt = state.auth.attempt;
+    let done = state.auth.line_received(conn, &mut state.out, &state.caps,
+                                        &state.isupport.casemapping(), line);
+    stepped(conn, state, attempt, done);
+}
+
+// Moves on once the backends are done with a stage, or watches the next backend
+fn stepped(conn: &mut Conn, state: &mut State, attempt: uint, done: bool) {
+    if done {
+        match state.auth.stage {
+            Negotiating => state.caps.authenticated(conn, &mut state.out),
+            Registered => {
+                let delayed = mem::replace(&mut state.auth.delayed, ~[]);
+                ::join_channels(conn, &mut state.out, &state.isupport, delayed.as_slice());
+            }
+        }
+    } else if state.auth.attempt != attempt {
+        watch(state);
+    }
+}
+
+// Gives a backend that started after registration TIMEOUT to succeed
+fn watch(state: &mut State) {
+    if state.auth.stage != Registered {
+        return;
+    }
+    let attempt = state.auth.attempt;
+    timer::after("auth timeout", TIMEOUT, state.cmd_tx.clone(),
+                 proc(conn: &mut Conn, state: &mut State) {
+        if state.auth.current.is_some() && state.auth.attempt == attempt {
+            let done = state.auth.failed(conn, &mut state.out, &state.caps, "timed out");
+            stepped(conn, state, attempt, done);
+        }
+    });
+}
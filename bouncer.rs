@@ -0,0 +1,160 @@
+/// Bouncer (ZNC) awareness
+///
+/// A bouncer stays connected to the network for the bot, and plays back what was said
+/// in its channels when the bot connects to it. Plugins shouldn't act on those lines
+/// again, so on a server with bouncer = true a line is marked as replayed if it's in a
+/// znc.in/playback or chathistory batch, between the "Buffer Playback..." and "Playback
+/// Complete." messages ZNC sends from *** around a buffer's playback, or from
+/// *buffextras, which plays back joins, parts and the like as messages. Plugins can tell
+/// with irc.replayed(), and commands aren't run for replayed lines.
+///
+/// The bot also requests znc.in/self-message there, so the messages sent from the
+/// bouncer's other clients come back as irc.SELFMSG, and znc.in/playback, which stops
+/// ZNC from playing its buffers back by itself. Instead, once the MOTD is over, the bot
+/// asks *playback for the lines since the last one it received on an earlier
+/// connection, so a reconnect doesn't play anything twice, and the first connection
+/// doesn't play anything at all.
+
+use casemap::CaseMapping;
+use outbound;
+use outbound::Outbound;
+use tags;
+use irc::conn::{Conn, Line, IRCCmd, IRCCode};
+use std::str;
+use sync::MutexArc;
+use time;
+
+static RPL_ENDOFMOTD: uint = 376;
+static ERR_NOMOTD: uint = 422;
+
+// what ZNC's *** marker user says before and after playing back a buffer
+static PLAYBACK_START: &'static [u8] = bytes!("Buffer Playback...");
+static PLAYBACK_END: &'static [u8] = bytes!("Playback Complete.");
+
+/// When the bot last received a line from the server, in seconds since the epoch,
+/// shared by the server's connections
+#[deriving(Clone)]
+pub struct LastSeen {
+    priv time: MutexArc<Option<f64>>
+}
+
+impl LastSeen {
+    pub fn new() -> LastSeen {
+        LastSeen { time: MutexArc::new(None) }
+    }
+}
+
+pub struct Bouncer {
+    priv enabled: bool, // the server is a bouncer
+    priv last_seen: LastSeen,
+    priv batches: ~[~str], // reference tags of the open playback batches
+    priv buffers: ~[~[u8]], // lowercased targets whose buffers ZNC is playing back
+    priv requested: bool // *playback was asked for this connection's playback
+}
+
+impl Bouncer {
+    pub fn new(enabled: bool, last_seen: LastSeen) -> Bouncer {
+        Bouncer { enabled: enabled, last_seen: last_seen, batches: ~[], buffers: ~[],
+                  requested: false }
+    }
+
+    /// Starts over for a new connection
+    pub fn reset(&mut self) {
+        self.batches.clear();
+        self.buffers.clear();
+        self.requested = false;
+    }
+
+    /// Tracks playback from the line, returning whether it's replayed. With `playback`,
+    /// the server has znc.in/playback, and the missed lines are asked for at the end of
+    /// the MOTD.
+    pub fn line_received(&mut self, conn: &mut Conn, out: &mut Outbound, casemap: &CaseMapping,
+                         line: &Line, tags: &[(~str, ~str)], playback: bool) -> bool {
+        if !self.enabled {
+            return false;
+        }
+        let replayed = self.replayed(casemap, line, tags);
+        match line.command {
+            IRCCode(code) if (code == RPL_ENDOFMOTD || code == ERR_NOMOTD) &&
+                             !self.requested => {
+                self.requested = true;
+                if playback {
+                    self.request(conn, out);
+                }
+            }
+            _ => ()
+        }
+        if !replayed {
+            let time = match tags::server_time(tags) {
+                Some(time) => time,
+                None => {
+                    let now = time::get_time();
+                    now.sec as f64 + now.nsec as f64 / 1e9
+                }
+            };
+            self.last_seen.time.access(|last| *last = Some(time));
+        }
+        replayed
+    }
+
+    fn replayed(&mut self, casemap: &CaseMapping, line: &Line, tags: &[(~str, ~str)]) -> bool {
+        let cmd = match line.command {
+            IRCCmd(ref cmd) => cmd.as_slice(),
+            _ => ""
+        };
+        if cmd == "BATCH" && !line.args.is_empty() {
+            let reference = str::from_utf8_lossy(line.args[0].as_slice()).into_owned();
+            if reference.starts_with("+") && line.args.len() >= 2 {
+                let kind = line.args[1].as_slice();
+                if kind == bytes!("znc.in/playback") || kind == bytes!("chathistory") {
+                    self.batches.push(reference.slice_from(1).to_owned());
+                }
+            } else if reference.starts_with("-") {
+                let reference = reference.slice_from(1);
+                self.batches.retain(|b| b.as_slice() != reference);
+            }
+            return false;
+        }
+        match tags::find(tags, "batch") {
+            Some(reference) if self.batches.iter().any(|b| b.as_slice() == reference) => {
+                return true;
+            }
+            _ => ()
+        }
+        let nick = match line.prefix {
+            Some(ref user) => user.nick(),
+            None => return false
+        };
+        if nick == bytes!("*buffextras") {
+            return true;
+        }
+        let target = match line.args.head() {
+            Some(target) => casemap.lower(target.as_slice()),
+            None => return false
+        };
+        if nick == bytes!("***") && cmd == "PRIVMSG" && line.args.len() >= 2 {
+            let text = line.args[1].as_slice();
+            if text == PLAYBACK_START {
+                if !self.buffers.contains(&target) {
+                    self.buffers.push(target);
+                }
+                return true;
+            } else if text == PLAYBACK_END {
+                self.buffers.retain(|b| *b != target);
+                return true;
+            }
+        }
+        self.buffers.contains(&target)
+    }
+
+    /// Asks *playback for the lines missed since the last connection, if there was one
+    fn request(&mut self, conn: &mut Conn, out: &mut Outbound) {
+        match self.last_seen.time.access(|last| *last) {
+            None => (),
+            Some(time) => {
+                let msg = format!("PLAY * {:.3f}", time);
+                out.privmsg(conn, outbound::Bot, bytes!("*playback"), msg.as_bytes());
+            }
+        }
+    }
+}
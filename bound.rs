@@ -0,0 +1,132 @@
+/// Connections from a chosen local address
+///
+/// std's TcpStream always lets the kernel pick the local address, but on a multi-homed
+/// host the address the bot connects from decides its vhost. BoundStream binds the
+/// socket to the configured address before connecting, and is relayed to irclib like a
+/// proxied connection.
+
+use relay;
+use resolver;
+use std::io;
+use std::io::IoResult;
+use std::io::net::ip::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::{libc, mem, os};
+
+pub struct BoundStream {
+    priv fd: libc::c_int
+}
+
+/// Connects from the local address to the first address of the host that accepts,
+/// skipping addresses of the other family
+pub fn connect(local: IpAddr, host: &str, port: u16) -> Result<BoundStream, ~str> {
+    let v6 = match local { Ipv6Addr(..) => true, Ipv4Addr(..) => false };
+    let addrs = try!(resolver::addresses(host, !v6, v6));
+    let mut last = format!("no {} addresses for {}", if v6 { "IPv6" } else { "IPv4" }, host);
+    for &ip in addrs.iter() {
+        match BoundStream::connect(local, SocketAddr { ip: ip, port: port }) {
+            Ok(s) => return Ok(s),
+            Err(e) => last = format!("{}: {}", ip, e)
+        }
+    }
+    Err(last)
+}
+
+impl BoundStream {
+    pub fn connect(local: IpAddr, remote: SocketAddr) -> Result<BoundStream, ~str> {
+        let family = match local { Ipv4Addr(..) => libc::AF_INET, Ipv6Addr(..) => libc::AF_INET6 };
+        let fd = unsafe { libc::socket(family, libc::SOCK_STREAM, 0) };
+        if fd < 0 {
+            return Err(os::last_os_error());
+        }
+        // dropping the stream closes the socket when either step fails
+        let stream = BoundStream { fd: fd };
+        let (addr, len) = sockaddr(SocketAddr { ip: local, port: 0 });
+        if unsafe { libc::bind(fd, &addr as *_ as *libc::sockaddr, len) } < 0 {
+            return Err(format!("could not bind to {}: {}", local, os::last_os_error()));
+        }
+        let (addr, len) = sockaddr(remote);
+        if unsafe { libc::connect(fd, &addr as *_ as *libc::sockaddr, len) } < 0 {
+            return Err(os::last_os_error());
+        }
+        Ok(stream)
+    }
+}
+
+impl Reader for BoundStream {
+    fn read(&mut self, buf: &mut [u8]) -> IoResult<uint> {
+        let n = unsafe {
+            libc::read(self.fd, buf.as_mut_ptr() as *mut libc::c_void, buf.len() as libc::size_t)
+        };
+        match n {
+            0 => Err(io::standard_error(io::EndOfFile)),
+            n if n < 0 => Err(io::IoError::last_error()),
+            n => Ok(n as uint)
+        }
+    }
+}
+
+impl Writer for BoundStream {
+    fn write(&mut self, buf: &[u8]) -> IoResult<()> {
+        let mut buf = buf;
+        while !buf.is_empty() {
+            let n = unsafe {
+                libc::write(self.fd, buf.as_ptr() as *libc::c_void, buf.len() as libc::size_t)
+            };
+            if n < 0 {
+                return Err(io::IoError::last_error());
+            }
+            buf = buf.slice_from(n as uint);
+        }
+        Ok(())
+    }
+}
+
+impl Clone for BoundStream {
+    fn clone(&self) -> BoundStream {
+        BoundStream { fd: unsafe { libc::dup(self.fd) } }
+    }
+}
+
+impl Drop for BoundStream {
+    fn drop(&mut self) {
+        unsafe { libc::close(self.fd); }
+    }
+}
+
+impl relay::Upstream for BoundStream {
+    fn shutdown(&mut self) {
+        unsafe { libc::shutdown(self.fd, libc::SHUT_RDWR); }
+    }
+}
+
+/// Returns the address as a sockaddr_in or sockaddr_in6, and its length
+fn sockaddr(addr: SocketAddr) -> (libc::sockaddr_storage, libc::socklen_t) {
+    unsafe {
+        let mut storage: libc::sockaddr_storage = mem::init();
+        let len = match addr.ip {
+            Ipv4Addr(a, b, c, d) => {
+                let sin = &mut storage as *mut _ as *mut libc::sockaddr_in;
+                let ip = (a as u32 << 24) | (b as u32 << 16) | (c as u32 << 8) | d as u32;
+                (*sin).sin_family = libc::AF_INET as libc::sa_family_t;
+                (*sin).sin_port = htons(addr.port);
+                (*sin).sin_addr = libc::in_addr { s_addr: mem::to_be32(ip as i32) as u32 };
+                mem::size_of::<libc::sockaddr_in>()
+            }
+            Ipv6Addr(a, b, c, d, e, f, g, h) => {
+                let sin6 = &mut storage as *mut _ as *mut libc::sockaddr_in6;
+                (*sin6).sin6_family = libc::AF_INET6 as libc::sa_family_t;
+                (*sin6).sin6_port = htons(addr.port);
+                (*sin6).sin6_addr = libc::in6_addr {
+                    s6_addr: [htons(a), htons(b), htons(c), htons(d),
+                              htons(e), htons(f), htons(g), htons(h)]
+                };
+                mem::size_of::<libc::sockaddr_in6>()
+            }
+        };
+        (storage, len as libc::socklen_t)
+    }
+}
+
+fn htons(n: u16) -> u16 {
+    mem::to_be16(n as i16) as u16
+}
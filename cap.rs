@@ -0,0 +1,241 @@
+/// IRCv3 capability negotiation
+///
+/// On connection we send CAP LS, request the offered capabilities that we want,
+/// and then end negotiation so registration can complete. Servers that don't
+/// support CAP ignore it (or reply with ERR_UNKNOWNCOMMAND) and register us normally.
+///
+/// The capabilities the server's auth backends need are requested too, and negotiation
+/// only ends once the backends that authenticate during it are done. SaslExternal is
+/// the backend for SASL EXTERNAL, with which services identify the bot by the client
+/// certificate. The bot can't present a certificate itself, so it has to connect through
+/// a TLS proxy (e.g. stunnel) that does. If authentication fails, registration carries
+/// on without it.
+///
+/// With twitch set, Twitch's own capabilities are requested too, whether or not they're
+/// offered, and with bouncer set, ZNC's self-message and playback capabilities.
+
+use auth;
+use auth::{Auth, AuthBackend};
+use casemap::CaseMapping;
+use config;
+use outbound::Outbound;
+use irc::conn::{Conn, Line, IRCCmd, IRCCode};
+use std::str;
+use std::ascii::StrAsciiExt;
+
+pub struct Caps {
+    priv wanted: ~[~str], // capabilities the bot can make use of
+    priv deny: ~[~str], // capabilities never to request
+    priv force: ~[~str], // capabilities to request even if they're not offered
+    priv offered: ~[(~str, Option<~str>)], // name and value of each offered capability
+    priv enabled: ~[~str],
+    priv negotiating: bool,
+    priv authenticating: bool // waiting for authentication to finish before ending negotiation
+}
+
+impl Caps {
+    pub fn new(server: &config::Server, auth: &Auth) -> Caps {
+        let mut wanted = ~[~"message-tags", ~"account-tag", ~"server-time", ~"away-notify",
+                           ~"echo-message", ~"chghost", ~"batch", ~"draft/chathistory",
+                           ~"multi-prefix", ~"userhost-in-names", ~"labeled-response",
+                           ~"account-notify", ~"extended-join"];
+        wanted.push_all_move(auth.capabilities());
+        if server.bouncer {
+            wanted.push_all_move(~[~"znc.in/self-message", ~"znc.in/playback"]);
+        }
+        let mut force = server.caps_request.clone();
+        if server.twitch {
+            force.push_all_move(~[~"twitch.tv/tags", ~"twitch.tv/commands",
+                                  ~"twitch.tv/membership"]);
+        }
+        Caps {
+            wanted: wanted,
+            deny: server.caps_deny.clone(),
+            force: force,
+            offered: ~[],
+            enabled: ~[],
+            negotiating: false,
+            authenticating: false
+        }
+    }
+
+    /// Returns whether the capability has been acknowledged by the server
+    pub fn is_enabled(&self, cap: &str) -> bool {
+        self.enabled.iter().any(|c| c.as_slice() == cap)
+    }
+
+    /// Returns the value the server offered the capability with, if any
+    pub fn offered_value<'a>(&'a self, cap: &str) -> Option<&'a str> {
+        self.offered.iter().find(|&&(ref c, _)| c.as_slice() == cap)
+                    .and_then(|&(_, ref v)| v.as_ref().map(|v| v.as_slice()))
+    }
+
+    /// Starts capability negotiation. Call this when the connection is established.
+    pub fn start(&mut self, conn: &mut Conn, out: &mut Outbound) {
+        self.offered.clear();
+        self.enabled.clear();
+        self.negotiating = true;
+        self.authenticating = false;
+        out.send_protocol(conn, bytes!("CAP LS 302"));
+    }
+
+    /// Ends negotiation once the auth backends that run during it are done
+    pub fn authenticated(&mut self, conn: &mut Conn, out: &mut Outbound) {
+        self.authenticating = false;
+        self.end(conn, out);
+    }
+
+    /// Handles CAP replies. Other lines are ignored.
+    pub fn handle_line(&mut self, conn: &mut Conn, out: &mut Outbound, auth: &mut Auth,
+                       line: &Line) {
+        match line.command {
+            IRCCmd(ref cmd) if cmd.as_slice() == "CAP" => (),
+            IRCCode(421) => {
+                // ERR_UNKNOWNCOMMAND, the server doesn't do CAP
+                if self.negotiating && line.args.len() > 1 &&
+                   line.args[1].as_slice() == bytes!("CAP") {
+                    self.negotiating = false;
+                }
+                return;
+            }
+            _ => return
+        }
+        if line.args.len() < 3 {
+            return;
+        }
+        let subcmd = str::from_utf8_lossy(line.args[1].as_slice()).into_owned();
+        let caps = str::from_utf8_lossy(line.args[line.args.len() - 1].as_slice()).into_owned();
+        // a * before the final argument means more lines follow
+        let more = line.args.len() > 3 && line.args[2].as_slice() == bytes!("*");
+        match subcmd.as_slice() {
+            "LS" => {
+                for cap in caps.words() {
+                    let (name, value) = match cap.find('=') {
+                        None => (cap.to_owned(), None),
+                        Some(i) => {
+                            (cap.slice_to(i).to_owned(), Some(cap.slice_from(i+1).to_owned()))
+                        }
+                    };
+                    self.offered.push((name, value));
+                }
+                if !more && self.negotiating {
+                    self.request(conn, out, auth);
+                }
+            }
+            "ACK" => {
+                for cap in caps.words() {
+                    if cap.starts_with("-") {
+                        let cap = cap.slice_from(1);
+                        self.enabled.retain(|c| c.as_slice() != cap);
+                    } else if !self.is_enabled(cap) {
+                        self.enabled.push(cap.to_owned());
+                    }
+                }
+                println!("Enabled capabilities: {}", self.enabled.connect(" "));
+                if !more {
+                    self.negotiated(conn, out, auth);
+                }
+            }
+            "NAK" => {
+                println!("Server refused capabilities: {}", caps);
+                if !more {
+                    self.negotiated(conn, out, auth);
+                }
+            }
+            "DEL" => {
+                for cap in caps.words() {
+                    self.enabled.retain(|c| c.as_slice() != cap);
+                }
+            }
+            _ => ()
+        }
+    }
+
+    /// Starts the auth backends once capabilities are acknowledged, ending negotiation
+    /// unless one is authenticating
+    fn negotiated(&mut self, conn: &mut Conn, out: &mut Outbound, auth: &mut Auth) {
+        if self.negotiating && !self.authenticating {
+            let started = auth.begin(conn, out, &*self, auth::Negotiating);
+            self.authenticating = started;
+        }
+        self.end(conn, out);
+    }
+
+    fn request(&mut self, conn: &mut Conn, out: &mut Outbound, auth: &mut Auth) {
+        let mut req = ~[];
+        for cap in self.wanted.iter().chain(self.force.iter()) {
+            let offered = self.offered.iter().any(|&(ref c, _)| c == cap);
+            let forced = self.force.contains(cap);
+            if (offered || forced) && !self.deny.contains(cap) && !req.contains(cap) {
+                req.push(cap.clone());
+            }
+        }
+        if req.is_empty() {
+            self.negotiated(conn, out, auth);
+            return;
+        }
+        let line = format!("CAP REQ :{}", req.connect(" "));
+        out.send_protocol(conn, line.as_bytes());
+    }
+
+    fn end(&mut self, conn: &mut Conn, out: &mut Outbound) {
+        if self.negotiating && !self.authenticating {
+            self.negotiating = false;
+            out.send_protocol(conn, bytes!("CAP END"));
+        }
+    }
+}
+
+/// Authenticates with SASL EXTERNAL during capability negotiation
+pub struct SaslExternal;
+
+impl AuthBackend for SaslExternal {
+    fn name(&self) -> &'static str {
+        "sasl_external"
+    }
+
+    fn stage(&self) -> auth::Stage {
+        auth::Negotiating
+    }
+
+    fn capability(&self) -> Option<&'static str> {
+        Some("sasl")
+    }
+
+    fn start(&mut self, conn: &mut Conn, out: &mut Outbound, caps: &Caps) -> bool {
+        if !caps.is_enabled("sasl") {
+            return false;
+        }
+        // CAP LS 302 servers may list their mechanisms
+        let offered = match caps.offered_value("sasl") {
+            None => true,
+            Some(mechs) => mechs.split(',').any(|m| m.eq_ignore_ascii_case("EXTERNAL"))
+        };
+        if offered {
+            out.send_protocol(conn, bytes!("AUTHENTICATE EXTERNAL"));
+        }
+        offered
+    }
+
+    fn line_received(&mut self, conn: &mut Conn, out: &mut Outbound, _casemapping: &CaseMapping,
+                     line: &Line) -> auth::Outcome {
+        match line.command {
+            IRCCmd(ref cmd) if cmd.as_slice() == "AUTHENTICATE" => {
+                // the server is ready for our (empty) response
+                if line.args.len() > 0 && line.args[0].as_slice() == bytes!("+") {
+                    out.send_protocol(conn, bytes!("AUTHENTICATE +"));
+                }
+                auth::Pending
+            }
+            IRCCode(903) => auth::Succeeded,
+            IRCCode(code) if code >= 902 && code <= 907 => {
+                let msg = match line.args.last() {
+                    None => ~"",
+                    Some(arg) => str::from_utf8_lossy(arg.as_slice()).into_owned()
+                };
+                auth::Failed(msg)
+            }
+            _ => auth::Pending
+        }
+    }
+}
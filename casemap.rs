@@ -0,0 +1,176 @@
+/// Server casemapping
+///
+/// Servers advertise how they fold the case of nicks and channels with the CASEMAPPING
+/// token of RPL_ISUPPORT. Under rfc1459, the default, []\~ are the uppercase forms of
+/// {}|^ so e.g. "foo[a]" and "FOO{A}" are the same nick.
+
+use collections::HashMap;
+use collections::hashmap::{Values, MutEntries};
+use std::mem;
+
+#[deriving(Eq, Clone)]
+pub enum CaseMapping {
+    Ascii,
+    Rfc1459,
+    StrictRfc1459 // rfc1459 without ~ and ^
+}
+
+impl CaseMapping {
+    /// Returns the casemapping with the given ISUPPORT name
+    pub fn from_name(name: &str) -> Option<CaseMapping> {
+        match name {
+            "ascii" => Some(Ascii),
+            "rfc1459" => Some(Rfc1459),
+            "strict-rfc1459" => Some(StrictRfc1459),
+            _ => None
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match *self {
+            Ascii => "ascii",
+            Rfc1459 => "rfc1459",
+            StrictRfc1459 => "strict-rfc1459"
+        }
+    }
+
+    /// Lowercases a single byte
+    pub fn lower_byte(&self, b: u8) -> u8 {
+        match (*self, b as char) {
+            (_, 'A'..'Z') => b - 'A' as u8 + 'a' as u8,
+            (Rfc1459, '[') | (StrictRfc1459, '[') => '{' as u8,
+            (Rfc1459, ']') | (StrictRfc1459, ']') => '}' as u8,
+            (Rfc1459, '\\') | (StrictRfc1459, '\\') => '|' as u8,
+            (Rfc1459, '~') => '^' as u8,
+            _ => b
+        }
+    }
+
+    pub fn lower(&self, s: &[u8]) -> ~[u8] {
+        s.iter().map(|&b| self.lower_byte(b)).collect()
+    }
+
+    /// Returns whether the two nicks or channels are the same under this casemapping
+    pub fn eq(&self, a: &[u8], b: &[u8]) -> bool {
+        a.len() == b.len() && a.iter().zip(b.iter()).all(|(&x, &y)| {
+            self.lower_byte(x) == self.lower_byte(y)
+        })
+    }
+}
+
+/// A map keyed by nicks or channels, in which keys that are the same under the
+/// server's casemapping find the same entry. Each entry keeps its key as it was
+/// inserted, so the entries can be keyed again when the server's ISUPPORT says its
+/// casemapping isn't the rfc1459 assumed until then.
+pub struct CaseMap<V> {
+    priv casemap: CaseMapping,
+    priv entries: HashMap<~[u8], Entry<V>> // lowercased key -> entry
+}
+
+/// A CaseMap's key, as it was inserted, and its value
+pub struct Entry<V> {
+    key: ~[u8],
+    value: V
+}
+
+impl<V> CaseMap<V> {
+    pub fn new() -> CaseMap<V> {
+        CaseMap { casemap: Rfc1459, entries: HashMap::new() }
+    }
+
+    /// Keys the entries by the casemapping. Entries whose keys become the same keep
+    /// just one of their values.
+    pub fn set_casemapping(&mut self, casemap: CaseMapping) {
+        if casemap == self.casemap {
+            return;
+        }
+        self.casemap = casemap;
+        let entries = mem::replace(&mut self.entries, HashMap::new());
+        for (_, entry) in entries.move_iter() {
+            self.entries.insert(casemap.lower(entry.key.as_slice()), entry);
+        }
+    }
+
+    pub fn casemapping(&self) -> CaseMapping {
+        self.casemap
+    }
+
+    pub fn len(&self) -> uint {
+        self.entries.len()
+    }
+
+    pub fn clear(&mut self) {
+        self.entries.clear();
+    }
+
+    pub fn contains_key(&self, key: &[u8]) -> bool {
+        self.entries.contains_key(&self.casemap.lower(key))
+    }
+
+    pub fn find<'a>(&'a self, key: &[u8]) -> Option<&'a V> {
+        self.entries.find(&self.casemap.lower(key)).map(|e| &e.value)
+    }
+
+    pub fn find_mut<'a>(&'a mut self, key: &[u8]) -> Option<&'a mut V> {
+        self.entries.find_mut(&self.casemap.lower(key)).map(|e| &mut e.value)
+    }
+
+    /// Inserts the value, replacing the entry with the same key under the casemapping
+    /// along with the way its key was written
+    pub fn insert(&mut self, key: &[u8], value: V) {
+        let entry = Entry { key: key.to_owned(), value: value };
+        self.entries.insert(self.casemap.lower(key), entry);
+    }
+
+    pub fn pop(&mut self, key: &[u8]) -> Option<V> {
+        self.entries.pop(&self.casemap.lower(key)).map(|e| e.value)
+    }
+
+    /// Returns the value for the key, inserting the one `f` makes if there isn't one
+    pub fn find_or_insert_with<'a>(&'a mut self, key: &[u8], f: |&[u8]| -> V) -> &'a mut V {
+        let lower = self.casemap.lower(key);
+        &mut self.entries.find_or_insert_with(lower, |_| {
+            Entry { key: key.to_owned(), value: f(key) }
+        }).value
+    }
+
+    /// Iterates over the entries, in no particular order
+    pub fn iter<'a>(&'a self) -> Values<'a, ~[u8], Entry<V>> {
+        self.entries.values()
+    }
+
+    pub fn mut_iter<'a>(&'a mut self) -> MutEntries<'a, ~[u8], Entry<V>> {
+        self.entries.mut_iter()
+    }
+
+    /// Removes the entries for which `f` is false
+    pub fn retain(&mut self, f: |&[u8], &V| -> bool) {
+        let entries = mem::replace(&mut self.entries, HashMap::new());
+        self.entries = entries.move_iter().filter(|&(_, ref e)| f(e.key.as_slice(), &e.value))
+                              .collect();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{CaseMap, Ascii, Rfc1459, StrictRfc1459};
+
+    #[test]
+    fn test_eq() {
+        assert!(Rfc1459.eq(bytes!("Foo[a]\\b~"), bytes!("fOO{A}|B^")));
+        assert!(StrictRfc1459.eq(bytes!("Foo[a]\\b"), bytes!("fOO{A}|B")));
+        assert!(!StrictRfc1459.eq(bytes!("foo~"), bytes!("foo^")));
+        assert!(Ascii.eq(bytes!("FOO"), bytes!("foo")));
+        assert!(!Ascii.eq(bytes!("foo[]"), bytes!("foo{}")));
+    }
+
+    #[test]
+    fn test_set_casemapping() {
+        let mut map = CaseMap::new();
+        map.insert(bytes!("nick[a]"), 1);
+        assert_eq!(map.find(bytes!("NICK{A}")), Some(&1));
+        map.set_casemapping(Ascii);
+        assert_eq!(map.find(bytes!("NICK{A}")), None);
+        assert_eq!(map.find(bytes!("NICK[A]")), Some(&1));
+    }
+}
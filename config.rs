@@ -1,7 +1,13 @@
 use std::{io, os};
 use std::io::{IoError, FileNotFound, PathAlreadyExists};
+use std::ascii::StrAsciiExt;
+use std::io::net::ip::{IpAddr, Ipv4Addr, Ipv6Addr};
 use getopts::{getopts, optflag, optopt, usage, OptGroup};
 use toml;
+use http;
+use flood;
+use encoding;
+use encoding::{Encoding, Codec};
 
 static CONFIG_EXAMPLE: &'static str = include_str!("config.example.toml");
 
@@ -9,31 +15,127 @@ static CONFIG_EXAMPLE: &'static str = include_str!("config.example.toml");
 pub struct Config {
     config_dir: Path, // path for the dir where the config file resides
     plugin_dir: Path, // path for the dir where plugins exist
+    plugin_paths: ~[Path], // dirs, files and glob patterns plugins are loaded from, in order
+    plugin_data_dir: Path, // holds a dir for each sandboxed plugin
+    plugin_table: toml::Value, // the [plugin] table, exposed to plugins
+    plugin_watch: bool, // reload plugins when their files change
+    plugin_configs: Option<toml::Value>, // the [plugins] table of per-plugin sections
+    dry_run: bool, // log outgoing messages instead of sending them
+    scenario: Option<Path>, // scenario file to run against a mock server instead of connecting
+    test_plugin: Option<Path>, // the only plugin to load for the scenario, when testing it
     reconnect_time: Option<uint>,
     reconnect_backoff: bool,
+    nick_regain: Option<uint>,
+    read_only: bool, // never send PRIVMSG or NOTICE
+    audit_log: Option<Path>, // file to record sent messages in
+    plugin_quota: Option<uint>, // messages each plugin may send per minute
+    handler_instruction_limit: Option<uint>, // Lua instructions a handler may run at a time
+    handler_timeout: Option<uint>, // seconds a handler may run at a time
+    plugin_quota_disable: bool, // disable plugins that exceed the quota instead of throttling
+    greet_cooldown: Option<uint>, // seconds before the same user is greeted again
+    command_prefix: ~str, // marks a channel message as a command for irc.addcommand
+    proc_allow: ~[~str], // programs plugins may run
+    proc_timeout: uint, // seconds before a plugin's program is killed
+    proc_output_limit: uint, // bytes of a program's output given to plugins
+    paste_url: Option<~str>, // http endpoint that plugins upload long text to
+    paste_field: Option<~str>, // form field to upload the text in, instead of a plain body
+    dcc: Dcc,
+    feeds: ~[Feed],
     servers: ~[Server]
 }
 
 #[deriving(Clone)]
 pub struct Server {
     name: ~str,
-    host: ~str,
+    host: ~str, // the address being connected to, at first the first of addresses
     port: u16,
+    addresses: ~[(~str, u16)], // every host and port of the server, tried in turn
     use_ssl: bool,
+    prefer_ipv6: bool, // try the server's IPv6 addresses before its IPv4 ones
+    ipv4_only: bool, // never connect over IPv6
+    connect_timeout: Option<uint>, // seconds to wait for the TCP connection
+    registration_timeout: Option<uint>, // seconds to wait for 001 once connected
+    ping_interval: Option<uint>, // seconds between keepalive PINGs
+    ping_timeout: uint, // seconds to wait for the PONG before reconnecting
+    proxy: Option<Proxy>, // SOCKS5 proxy to connect through
+    bind_address: Option<IpAddr>, // local address to connect from
+    twitch: bool, // request Twitch's capabilities
+    bouncer: bool, // the server is a bouncer such as ZNC, which plays back what was missed
+    password: Option<~str>, // sent with PASS before registering
+    webirc: Option<WebIrc>, // sent with WEBIRC before registering
+    codec: Codec, // the server's text encoding
     nick: ~str,
+    altnicks: ~[~str], // nicks to try in order when nick is taken while registering
     user: ~str,
     real: ~str,
-    autojoin: ~[Channel]
+    autojoin: ~[Channel],
+    rejoin_on_kick: ~[~str], // channels to rejoin when kicked from, "*" for any
+    rejoin_delay: uint, // seconds to wait before rejoining
+    rejoin_attempts: uint, // JOINs to send when rejoining before giving up
+    read_only_channels: ~[~str], // channels to never send PRIVMSG or NOTICE to
+    flood: Option<(uint, u64)>, // burst and ms interval of flood protection, if it's on
+    caps_deny: ~[~str], // capabilities never to request
+    caps_request: ~[~str], // capabilities to request even if not offered
+    greetings: ~[Greeting],
+    invite_notify: Option<~str>, // channel or nick to announce invites and knocks to
+    selftest_target: Option<~str>, // where /selftest sends, instead of the bot's own nick
+    nickserv_service: ~str, // nick to identify to
+    nickserv_password: Option<~str>, // identifies to nickserv_service with the nickserv backend
+    nickserv_confirm: ~str, // glob for the service's notice confirming identification
+    nickserv_delay_autojoin: bool, // join autojoin channels only once identified
+    nickserv_regain: Option<~str>, // GHOST or REGAIN, to regain the nick through services
+    auth: ~[~str], // names of the auth backends to try in order
+    admins: ~[~str], // hostmasks allowed to accept or deny invites and knocks
+    watch: ~[~str] // nicks to watch for coming online and going offline
+}
+
+#[deriving(Clone)]
+pub struct Greeting {
+    channel: ~str,
+    template: ~str // may contain {nick}, {user}, {host} and {channel}
+}
+
+#[deriving(Clone)]
+pub struct Feed {
+    name: ~str,
+    url: ~str,
+    interval: uint // seconds between fetches
 }
 
 #[deriving(Clone)]
 pub struct Channel {
     name: ~str,
+    password: Option<~str> // the channel key
+}
+
+#[deriving(Clone)]
+pub struct Dcc {
+    dir: Option<Path>, // the only dir files are sent from and received into, None for no DCC
+    max_size: u64, // largest file in bytes that's accepted
+    ports: Option<(u16, u16)>, // first and last port to listen on, None for any
+    address: Option<IpAddr>, // our IPv4 address as other clients reach it
+    passive: bool // offer files with passive DCC, for when the bot can't accept connections
+}
+
+#[deriving(Clone)]
+pub struct WebIrc {
+    password: ~str,
+    gateway: ~str, // the gateway's name, as the server knows it
+    host: ~str, // the user's hostname, or the ip if it has none
+    ip: IpAddr // the user's address
+}
+
+#[deriving(Clone)]
+pub struct Proxy {
+    host: ~str,
+    port: u16,
+    username: Option<~str>,
     password: Option<~str>
 }
 
 pub fn print_usage(opts: &[OptGroup]) {
-    let s = usage(format!("Usage: {} [OPTIONS]", os::args()[0]), opts);
+    let s = usage(format!("Usage: {} [OPTIONS] [scenario FILE | test PLUGIN FILE]",
+                          os::args()[0]), opts);
     let _ = writeln!(&mut io::stderr(), "{}", s);
 }
 
@@ -50,7 +152,8 @@ pub fn parse_args() -> Result<Config,Error> {
 
     let opts = [
         optflag("h", "help", "Displays this help"),
-        optopt("c", "config", "Path for the config file, defaults to ~/.rustirc/config", "file")
+        optopt("c", "config", "Path for the config file, defaults to ~/.rustirc/config", "file"),
+        optflag("n", "dry-run", "Log messages and raw lines instead of sending them")
     ];
 
     let matches = match getopts(args.tail(), opts) {
@@ -67,6 +170,22 @@ pub fn parse_args() -> Result<Config,Error> {
         return Err(ErrHelpFlag);
     }
 
+    let (scenario, test_plugin) = match matches.free.as_slice() {
+        [] => (None, None),
+        [ref cmd, ref file] if cmd.as_slice() == "scenario" => {
+            (Some(os::make_absolute(&Path::new(file.as_slice()))), None)
+        }
+        [ref cmd, ref plugin, ref file] if cmd.as_slice() == "test" => {
+            (Some(os::make_absolute(&Path::new(file.as_slice()))),
+             Some(os::make_absolute(&Path::new(plugin.as_slice()))))
+        }
+        _ => {
+            let _ = writeln!(&mut io::stderr(), "error: unexpected arguments\n");
+            print_usage(opts);
+            return Err(ErrBadFlag);
+        }
+    };
+
     let path = match matches.opt_str("c") {
         None => {
             let p = os::homedir().expect("can't find user's home dir").join(".rustirc/config");
@@ -115,6 +234,12 @@ pub fn parse_args() -> Result<Config,Error> {
         }
         Some(s) => s.clone()
     };
+    let plugin_table = root.lookup("plugin").unwrap().clone();
+    let plugin_paths = string_list(&root, "plugin.paths");
+    let plugin_data_dir = root.lookup("plugin.data_dir").and_then(|v| v.get_str())
+                              .map_or(~"data", |s| s.clone());
+    let plugin_configs = root.lookup("plugins").map(|v| v.clone());
+    let plugin_watch = root.lookup("plugin.watch").and_then(|v| v.get_bool()).unwrap_or(false);
     let reconnect = match root.lookup("general.reconnect").and_then(|v| v.get_int()) {
         None => Some(5),
         Some(x) if x < 0 => None,
@@ -122,6 +247,103 @@ pub fn parse_args() -> Result<Config,Error> {
     };
     let backoff = root.lookup("general.reconnect_backoff").and_then(|v| v.get_bool())
                       .unwrap_or(true);
+    let nick_regain = match root.lookup("general.nick_regain").and_then(|v| v.get_int()) {
+        None => Some(60),
+        Some(x) if x <= 0 => None,
+        Some(x) => Some(x.to_uint().unwrap())
+    };
+    let read_only = root.lookup("general.read_only").and_then(|v| v.get_bool())
+                        .unwrap_or(false);
+    let audit_log = root.lookup("general.audit_log").and_then(|v| v.get_str())
+                        .map(|s| path.dir_path().join(s.as_slice()));
+    let plugin_quota = match root.lookup("general.plugin_quota").and_then(|v| v.get_int()) {
+        None => None,
+        Some(x) if x <= 0 => None,
+        Some(x) => Some(x.to_uint().unwrap())
+    };
+    let plugin_quota_disable = root.lookup("general.plugin_quota_disable")
+                                   .and_then(|v| v.get_bool()).unwrap_or(false);
+    let handler_instruction_limit = match root.lookup("general.handler_instruction_limit")
+                                              .and_then(|v| v.get_int()) {
+        None => Some(10000000),
+        Some(x) if x <= 0 => None,
+        Some(x) => Some(x.to_uint().unwrap())
+    };
+    let handler_timeout = match root.lookup("general.handler_timeout").and_then(|v| v.get_int()) {
+        None => Some(10),
+        Some(x) if x <= 0 => None,
+        Some(x) => Some(x.to_uint().unwrap())
+    };
+    let ping_interval = match root.lookup("general.ping_interval").and_then(|v| v.get_int()) {
+        None => Some(120),
+        Some(x) if x <= 0 => None,
+        Some(x) => Some(x.to_uint().unwrap())
+    };
+    let ping_timeout = match root.lookup("general.ping_timeout").and_then(|v| v.get_int()) {
+        None => 60,
+        Some(x) if x <= 0 => {
+            let _ = writeln!(&mut io::stderr(), "error: ping_timeout must be positive");
+            return Err(ErrBadConfig);
+        }
+        Some(x) => x.to_uint().unwrap()
+    };
+    let command_prefix = root.lookup("general.command_prefix").and_then(|v| v.get_str())
+                             .map_or(~"!", |s| s.clone());
+    let greet_cooldown = match root.lookup("general.greet_cooldown").and_then(|v| v.get_int()) {
+        None => Some(3600),
+        Some(x) if x <= 0 => None,
+        Some(x) => Some(x.to_uint().unwrap())
+    };
+    let proc_allow = string_list(&root, "general.proc_allow");
+    let proc_timeout = match root.lookup("general.proc_timeout").and_then(|v| v.get_int()) {
+        None => 10,
+        Some(x) if x <= 0 => {
+            let _ = writeln!(&mut io::stderr(), "error: general.proc_timeout must be positive");
+            return Err(ErrBadConfig);
+        }
+        Some(x) => x.to_uint().unwrap()
+    };
+    let proc_output_limit = match root.lookup("general.proc_output_limit")
+                                      .and_then(|v| v.get_int()) {
+        None => 4096,
+        Some(x) if x < 0 => 0,
+        Some(x) => x.to_uint().unwrap()
+    };
+    let dcc_dir = root.lookup("general.dcc_dir").and_then(|v| v.get_str())
+                      .map(|s| path.dir_path().join(s.as_slice()));
+    let dcc_max_size = match root.lookup("general.dcc_max_size").and_then(|v| v.get_int()) {
+        None => 10 * 1024 * 1024,
+        Some(x) if x < 0 => 0,
+        Some(x) => x as u64
+    };
+    let dcc_ports = match root.lookup("general.dcc_ports").and_then(|v| v.get_vec()) {
+        None => None,
+        Some(v) => {
+            let range: ~[Option<u16>] = v.iter().map(|p| p.get_int().and_then(|p| p.to_u16()))
+                                         .collect();
+            match range.as_slice() {
+                [Some(first), Some(last)] if first > 0 && first <= last => Some((first, last)),
+                _ => {
+                    let _ = writeln!(&mut io::stderr(),
+                                     "error: general.dcc_ports must be [first, last]");
+                    return Err(ErrBadConfig);
+                }
+            }
+        }
+    };
+    let dcc_address = match root.lookup("general.dcc_address").and_then(|v| v.get_str()) {
+        None => None,
+        Some(s) => match from_str::<IpAddr>(s.as_slice()) {
+            Some(ip @ Ipv4Addr(..)) => Some(ip),
+            _ => {
+                let _ = writeln!(&mut io::stderr(),
+                                 "error: general.dcc_address `{}' is not an IPv4 address", s);
+                return Err(ErrBadConfig);
+            }
+        }
+    };
+    let dcc_passive = root.lookup("general.dcc_passive").and_then(|v| v.get_bool())
+                          .unwrap_or(dcc_address.is_none());
     let default_nick = root.lookup("general.defaults.nick").and_then(|v| v.get_str())
                            .map(|s| s.clone()).unwrap_or_else(|| ~"rustbot");
     let default_user = root.lookup("general.defaults.user").and_then(|v| v.get_str())
@@ -129,6 +351,49 @@ pub fn parse_args() -> Result<Config,Error> {
     let default_real = root.lookup("general.defaults.real").and_then(|v| v.get_str())
                            .map(|s| s.clone()).unwrap_or_else(|| ~"Rust IRC Bot");
 
+    let paste_url = root.lookup("general.paste_url").and_then(|v| v.get_str())
+                        .map(|s| s.clone());
+    match paste_url.as_ref().map(|url| http::Url::parse(*url)) {
+        Some(Err(e)) => {
+            let _ = writeln!(&mut io::stderr(), "error: general.paste_url: {}", e);
+            return Err(ErrBadConfig);
+        }
+        _ => ()
+    }
+    let paste_field = root.lookup("general.paste_field").and_then(|v| v.get_str())
+                          .map(|s| s.clone());
+    let mut feeds = ~[];
+    let feed_list = root.lookup("feeds").and_then(|v| v.get_table_array())
+                        .map_or(&[], |ary| ary.as_slice());
+    for elem in feed_list.iter() {
+        let url = match elem.lookup("url").and_then(|v| v.get_str()) {
+            None => {
+                let _ = writeln!(&mut io::stderr(), "error: feed entry missing required 'url' key");
+                return Err(ErrBadConfig);
+            }
+            Some(s) => s.clone()
+        };
+        match http::Url::parse(url) {
+            Ok(_) => (),
+            Err(e) => {
+                let _ = writeln!(&mut io::stderr(), "error: {}", e);
+                return Err(ErrBadConfig);
+            }
+        }
+        let name = elem.lookup("name").and_then(|v| v.get_str()).map(|s| s.clone())
+                       .unwrap_or_else(|| url.clone());
+        let interval = match elem.lookup("interval").and_then(|v| v.get_int()) {
+            None => 900,
+            Some(x) if x < 60 => {
+                let _ = writeln!(&mut io::stderr(),
+                                 "error: interval of feed {} must be at least 60 seconds", name);
+                return Err(ErrBadConfig);
+            }
+            Some(x) => x.to_uint().unwrap()
+        };
+        feeds.push(Feed{ name: name, url: url, interval: interval });
+    }
+
     let mut servers = ~[];
     let server_list = match root.lookup("servers").and_then(|v| v.get_table_array()) {
         None => {
@@ -146,19 +411,145 @@ pub fn parse_args() -> Result<Config,Error> {
             }
             Some(s) => s.clone()
         };
-        let server = match elem.lookup("server").and_then(|v| v.get_str()) {
-            None => {
-                let _ = writeln!(&mut io::stderr(),
-                                 "error: server entry missing required 'server' key");
-                return Err(ErrBadConfig);
-            }
-            Some(s) => s.clone()
+        if servers.iter().any(|s: &Server| s.name == name) {
+            let _ = writeln!(&mut io::stderr(),
+                             "error: there's more than one server named {}", name);
+            return Err(ErrBadConfig);
+        }
+        // one address, or a list of them to rotate through
+        let entries = match elem.lookup("server") {
+            Some(v) if v.get_str().is_some() => ~[v.get_str().unwrap().clone()],
+            Some(v) if v.get_vec().is_some() => string_list(elem, "server"),
+            _ => ~[]
         };
+        if entries.is_empty() {
+            let _ = writeln!(&mut io::stderr(),
+                             "error: server entry missing required 'server' key");
+            return Err(ErrBadConfig);
+        }
         let use_ssl = elem.lookup("use_ssl").and_then(|v| v.get_bool()).unwrap_or(false);
         if use_ssl {
             let _ = writeln!(&mut io::stderr(), "error: use_ssl is not currently implemented");
             return Err(ErrBadConfig);
         }
+        let prefer_ipv6 = elem.lookup("prefer_ipv6").and_then(|v| v.get_bool()).unwrap_or(false);
+        let ipv4_only = elem.lookup("ipv4_only").and_then(|v| v.get_bool()).unwrap_or(false);
+        let connect_timeout = match elem.lookup("connect_timeout").and_then(|v| v.get_int()) {
+            None => Some(30),
+            Some(x) if x <= 0 => None,
+            Some(x) => Some(x.to_uint().unwrap())
+        };
+        let registration_timeout = match elem.lookup("registration_timeout")
+                                             .and_then(|v| v.get_int()) {
+            None => Some(120),
+            Some(x) if x <= 0 => None,
+            Some(x) => Some(x.to_uint().unwrap())
+        };
+        let ping_interval = match elem.lookup("ping_interval").and_then(|v| v.get_int()) {
+            None => ping_interval,
+            Some(x) if x <= 0 => None,
+            Some(x) => Some(x.to_uint().unwrap())
+        };
+        let ping_timeout = match elem.lookup("ping_timeout").and_then(|v| v.get_int()) {
+            None => ping_timeout,
+            Some(x) if x <= 0 => {
+                let _ = writeln!(&mut io::stderr(), "error: ping_timeout must be positive");
+                return Err(ErrBadConfig);
+            }
+            Some(x) => x.to_uint().unwrap()
+        };
+        let proxy = match elem.lookup("proxy_host").and_then(|v| v.get_str()) {
+            None => None,
+            Some(host) => {
+                let port = match elem.lookup("proxy_port").and_then(|v| v.get_int())
+                                     .unwrap_or(1080).to_u16() {
+                    None | Some(0) => {
+                        let _ = writeln!(&mut io::stderr(), "error: invalid proxy_port");
+                        return Err(ErrBadConfig);
+                    }
+                    Some(p) => p
+                };
+                Some(Proxy {
+                    host: host.clone(),
+                    port: port,
+                    username: elem.lookup("proxy_username").and_then(|v| v.get_str())
+                                  .map(|s| s.clone()),
+                    password: elem.lookup("proxy_password").and_then(|v| v.get_str())
+                                  .map(|s| s.clone())
+                })
+            }
+        };
+        let bind_address = match elem.lookup("bind_address").and_then(|v| v.get_str()) {
+            None => None,
+            Some(s) => match from_str::<IpAddr>(s.as_slice()) {
+                None => {
+                    let _ = writeln!(&mut io::stderr(),
+                                     "error: bind_address `{}' is not an IP address", s);
+                    return Err(ErrBadConfig);
+                }
+                Some(ip @ Ipv6Addr(..)) if ipv4_only => {
+                    let _ = writeln!(&mut io::stderr(),
+                                     "error: bind_address {} is IPv6, but ipv4_only is set", ip);
+                    return Err(ErrBadConfig);
+                }
+                Some(ip) => Some(ip)
+            }
+        };
+        let twitch = elem.lookup("twitch").and_then(|v| v.get_bool()).unwrap_or(false);
+        let bouncer = elem.lookup("bouncer").and_then(|v| v.get_bool()).unwrap_or(false);
+        let sasl_external = elem.lookup("sasl_external").and_then(|v| v.get_bool())
+                                .unwrap_or(false);
+        let password = elem.lookup("password").and_then(|v| v.get_str()).map(|s| s.clone());
+        let webirc = match elem.lookup("webirc_password").and_then(|v| v.get_str()) {
+            None => None,
+            Some(password) => {
+                let ip = match elem.lookup("webirc_ip").and_then(|v| v.get_str())
+                                   .and_then(|s| from_str::<IpAddr>(s.as_slice())) {
+                    None => {
+                        let _ = writeln!(&mut io::stderr(),
+                                         "error: webirc_ip must be the user's IP address");
+                        return Err(ErrBadConfig);
+                    }
+                    Some(ip) => ip
+                };
+                Some(WebIrc {
+                    password: password.clone(),
+                    gateway: elem.lookup("webirc_gateway").and_then(|v| v.get_str())
+                                 .map_or(~"rust-ircbot", |s| s.clone()),
+                    host: elem.lookup("webirc_host").and_then(|v| v.get_str())
+                              .map_or_else(|| ip.to_str(), |s| s.clone()),
+                    ip: ip
+                })
+            }
+        };
+        let encoding = match elem.lookup("encoding").and_then(|v| v.get_str()) {
+            None => encoding::Utf8,
+            Some(name) => match Encoding::from_name(name.as_slice()) {
+                Some(e) => e,
+                None => {
+                    let _ = writeln!(&mut io::stderr(), "error: unknown encoding `{}'", name);
+                    return Err(ErrBadConfig);
+                }
+            }
+        };
+        let fallback = match elem.lookup("encoding_fallback") {
+            None => Some(encoding::Latin1),
+            Some(&toml::Boolean(false)) => None,
+            Some(&toml::String(ref name)) => match Encoding::from_name(name.as_slice()) {
+                Some(encoding::Utf8) | None => {
+                    let _ = writeln!(&mut io::stderr(),
+                                     "error: encoding_fallback `{}' must be \"latin-1\" or \
+                                      \"cp1252\"", name);
+                    return Err(ErrBadConfig);
+                }
+                Some(e) => Some(e)
+            },
+            Some(_) => {
+                let _ = writeln!(&mut io::stderr(),
+                                 "error: encoding_fallback must be an encoding or false");
+                return Err(ErrBadConfig);
+            }
+        };
         let default_port = if use_ssl { 6697 } else { 6667 };
         let port = match elem.lookup("port").and_then(|v| v.get_int()).unwrap_or(default_port)
                              .to_u16() {
@@ -168,8 +559,21 @@ pub fn parse_args() -> Result<Config,Error> {
             }
             Some(p) => p
         };
+        let mut addresses = ~[];
+        for entry in entries.iter() {
+            match parse_address(entry.as_slice(), port) {
+                None => {
+                    let _ = writeln!(&mut io::stderr(),
+                                     "error: invalid server address `{}'", entry);
+                    return Err(ErrBadConfig);
+                }
+                Some(addr) => addresses.push(addr)
+            }
+        }
+        let (server, port) = addresses[0].clone();
         let nick = elem.lookup("nick").and_then(|v| v.get_str()).map(|s| s.clone())
                        .unwrap_or_else(|| default_nick.clone());
+        let altnicks = string_list(elem, "altnicks");
         let user = elem.lookup("user").and_then(|v| v.get_str()).map(|s| s.clone())
                        .unwrap_or_else(|| default_user.clone());
         let real = elem.lookup("real").and_then(|v| v.get_str()).map(|s| s.clone())
@@ -179,31 +583,272 @@ pub fn parse_args() -> Result<Config,Error> {
             None => (),
             Some(v) => {
                 for val in v.iter() {
-                    let s = match val.get_str() {
-                        None => continue,
-                        Some(s) => s
-                    };
-                    let (name, pass) = match s.find(',') {
-                        None => (s.to_owned(), None),
-                        Some(idx) => {
-                            (s.slice_to(idx).to_owned(), Some(s.slice_from(idx+1).to_owned()))
+                    // "#channel" or "#channel,key", or a table with name and key
+                    let (name, pass) = match val.get_str() {
+                        Some(s) => match s.find(',') {
+                            None => (s.to_owned(), None),
+                            Some(idx) => {
+                                (s.slice_to(idx).to_owned(), Some(s.slice_from(idx+1).to_owned()))
+                            }
+                        },
+                        None => match val.lookup("name").and_then(|v| v.get_str()) {
+                            None => {
+                                let _ = writeln!(&mut io::stderr(),
+                                                 "error: autojoin entry missing 'name' key");
+                                return Err(ErrBadConfig);
+                            }
+                            Some(name) => {
+                                (name.clone(), val.lookup("key").and_then(|v| v.get_str())
+                                                  .map(|s| s.clone()))
+                            }
                         }
                     };
+                    let pass = pass.and_then(|p| if p.is_empty() { None } else { Some(p) });
                     channels.push(Channel{ name: name, password: pass });
                 }
             }
         }
-        servers.push(Server{ name: name, host: server, port: port, use_ssl: use_ssl,
-                             nick: nick, user: user, real: real, autojoin: channels });
+        let rejoin_on_kick = string_list(elem, "rejoin_on_kick");
+        let rejoin_delay = match elem.lookup("rejoin_delay").and_then(|v| v.get_int()) {
+            None => 5,
+            Some(x) if x < 0 => 0,
+            Some(x) => x.to_uint().unwrap()
+        };
+        let rejoin_attempts = match elem.lookup("rejoin_attempts").and_then(|v| v.get_int()) {
+            None => 3,
+            Some(x) if x <= 0 => {
+                let _ = writeln!(&mut io::stderr(), "error: rejoin_attempts must be positive");
+                return Err(ErrBadConfig);
+            }
+            Some(x) => x.to_uint().unwrap()
+        };
+        let read_only_channels = string_list(elem, "read_only_channels");
+        let preset = elem.lookup("flood_preset").and_then(|v| v.get_str())
+                         .map_or("default", |s| s.as_slice());
+        let preset = match flood::preset(preset) {
+            None => {
+                let names: ~[&str] = flood::PRESETS.iter().map(|&(n, _)| n).collect();
+                let _ = writeln!(&mut io::stderr(), "error: flood_preset `{}' must be one of {}",
+                                 preset, names.connect(", "));
+                return Err(ErrBadConfig);
+            }
+            Some(p) => p
+        };
+        let burst = elem.lookup("flood_burst").and_then(|v| v.get_int());
+        let interval = elem.lookup("flood_interval").and_then(|v| v.get_int());
+        let flood = match (burst, interval) {
+            (None, None) => preset,
+            (Some(b), _) if b <= 0 => None,
+            (b, i) => {
+                let (default_burst, default_interval) = preset.unwrap_or((5, 2000));
+                Some((b.map_or(default_burst, |b| b as uint),
+                      i.map_or(default_interval, |i| if i < 0 { 0 } else { i as u64 })))
+            }
+        };
+        let caps_deny = string_list(elem, "caps_deny");
+        let caps_request = string_list(elem, "caps_request");
+        let mut greetings = ~[];
+        for s in string_list(elem, "greetings").move_iter() {
+            match s.find(',') {
+                None => {
+                    let _ = writeln!(&mut io::stderr(),
+                                     "error: greeting `{}' must be of the form \"#channel,text\"",
+                                     s);
+                    return Err(ErrBadConfig);
+                }
+                Some(idx) => {
+                    greetings.push(Greeting{ channel: s.slice_to(idx).to_owned(),
+                                             template: s.slice_from(idx+1).to_owned() });
+                }
+            }
+        }
+        let invite_notify = elem.lookup("invite_notify").and_then(|v| v.get_str())
+                                .map(|s| s.clone());
+        let selftest_target = elem.lookup("selftest_target").and_then(|v| v.get_str())
+                                  .map(|s| s.clone());
+        let admins = string_list(elem, "admins");
+        let watch = string_list(elem, "watch");
+        let nickserv_service = elem.lookup("nickserv_service").and_then(|v| v.get_str())
+                                   .map_or(~"NickServ", |s| s.clone());
+        let nickserv_password = elem.lookup("nickserv_password").and_then(|v| v.get_str())
+                                    .map(|s| s.clone());
+        let nickserv_confirm = elem.lookup("nickserv_confirm").and_then(|v| v.get_str())
+                                   .map_or(~"*You are now identified*", |s| s.clone());
+        let nickserv_delay_autojoin = elem.lookup("nickserv_delay_autojoin")
+                                          .and_then(|v| v.get_bool()).unwrap_or(false);
+        let nickserv_regain = match elem.lookup("nickserv_regain").and_then(|v| v.get_str()) {
+            None => None,
+            Some(s) if s.eq_ignore_ascii_case("ghost") || s.eq_ignore_ascii_case("regain") => {
+                Some(s.to_ascii_upper())
+            }
+            Some(s) => {
+                let _ = writeln!(&mut io::stderr(),
+                                 "error: nickserv_regain `{}' must be \"ghost\" or \"regain\"", s);
+                return Err(ErrBadConfig);
+            }
+        };
+        let auth = if elem.lookup("auth").is_some() {
+            string_list(elem, "auth")
+        } else {
+            let mut auth = ~[];
+            if sasl_external {
+                auth.push(~"sasl_external");
+            }
+            if nickserv_password.is_some() {
+                auth.push(~"nickserv");
+            }
+            auth
+        };
+        for backend in auth.iter() {
+            match backend.as_slice() {
+                "sasl_external" => (),
+                "nickserv" if nickserv_password.is_some() => (),
+                "nickserv" => {
+                    let _ = writeln!(&mut io::stderr(),
+                                     "error: auth has nickserv, but nickserv_password isn't set");
+                    return Err(ErrBadConfig);
+                }
+                s => {
+                    let _ = writeln!(&mut io::stderr(), "error: unknown auth backend `{}'", s);
+                    return Err(ErrBadConfig);
+                }
+            }
+        }
+        servers.push(Server{ name: name, host: server, port: port, addresses: addresses,
+                             use_ssl: use_ssl,
+                             prefer_ipv6: prefer_ipv6, ipv4_only: ipv4_only,
+                             connect_timeout: connect_timeout,
+                             registration_timeout: registration_timeout,
+                             ping_interval: ping_interval, ping_timeout: ping_timeout,
+                             proxy: proxy,
+                             bind_address: bind_address,
+                             twitch: twitch, bouncer: bouncer,
+                             password: password, webirc: webirc,
+                             codec: Codec::new(encoding, fallback),
+                             nick: nick, altnicks: altnicks, user: user, real: real,
+                             autojoin: channels, rejoin_on_kick: rejoin_on_kick,
+                             rejoin_delay: rejoin_delay, rejoin_attempts: rejoin_attempts,
+                             read_only_channels: read_only_channels, flood: flood,
+                             caps_deny: caps_deny, caps_request: caps_request,
+                             greetings: greetings, invite_notify: invite_notify,
+                             selftest_target: selftest_target,
+                             nickserv_service: nickserv_service,
+                             nickserv_password: nickserv_password,
+                             nickserv_confirm: nickserv_confirm,
+                             nickserv_delay_autojoin: nickserv_delay_autojoin,
+                             nickserv_regain: nickserv_regain, auth: auth,
+                             admins: admins, watch: watch });
     }
 
     let config_dir = path.dir_path();
     let plugin_dir = config_dir.join(plugin_dir);
+    let mut plugin_paths = if plugin_paths.is_empty() {
+        ~[plugin_dir.clone()]
+    } else {
+        plugin_paths.iter().map(|p| config_dir.join(p.as_slice())).collect()
+    };
+    // bundled plugins come last, so a plugin of the same name in the plugin paths wins
+    let share_dir = match root.lookup("plugin.share_dir").and_then(|v| v.get_str()) {
+        Some(s) => Some(config_dir.join(s.as_slice())),
+        None => os::self_exe_path().map(|p| {
+            // share/plugins next to the bot where it was built, or where make install put it
+            let built = p.join("share/plugins");
+            if built.is_dir() { built } else { p.join("../share/rustirc/plugins") }
+        })
+    };
+    for name in string_list(&root, "plugin.enable").iter() {
+        let path = share_dir.as_ref().map(|dir| dir.join(format!("{}.lua", *name)));
+        match path {
+            Some(ref p) if p.is_file() => plugin_paths.push(p.clone()),
+            _ => {
+                let _ = writeln!(&mut io::stderr(), "error: no bundled plugin named {}", *name);
+                return Err(ErrBadConfig);
+            }
+        }
+    }
     Ok(Config{
         config_dir: config_dir,
         plugin_dir: plugin_dir,
+        plugin_paths: plugin_paths,
+        plugin_data_dir: config_dir.join(plugin_data_dir),
+        plugin_table: plugin_table,
+        plugin_watch: plugin_watch,
+        plugin_configs: plugin_configs,
+        dry_run: matches.opt_present("n"),
+        scenario: scenario,
+        test_plugin: test_plugin,
         reconnect_time: reconnect,
         reconnect_backoff: backoff,
+        nick_regain: nick_regain,
+        read_only: read_only,
+        audit_log: audit_log,
+        plugin_quota: plugin_quota,
+        handler_instruction_limit: handler_instruction_limit,
+        handler_timeout: handler_timeout,
+        plugin_quota_disable: plugin_quota_disable,
+        greet_cooldown: greet_cooldown,
+        command_prefix: command_prefix,
+        proc_allow: proc_allow,
+        proc_timeout: proc_timeout,
+        proc_output_limit: proc_output_limit,
+        paste_url: paste_url,
+        paste_field: paste_field,
+        dcc: Dcc {
+            dir: dcc_dir,
+            max_size: dcc_max_size,
+            ports: dcc_ports,
+            address: dcc_address,
+            passive: dcc_passive
+        },
+        feeds: feeds,
         servers: servers
     })
 }
+
+/// Parses a server address, "host", "host:port" or "[IPv6 address]:port", with the port
+/// defaulting to `port`
+fn parse_address(s: &str, port: u16) -> Option<(~str, u16)> {
+    let (host, rest) = if s.starts_with("[") {
+        match s.find(']') {
+            None => return None,
+            Some(i) => (s.slice(1, i), s.slice_from(i + 1))
+        }
+    } else {
+        match s.find(':') {
+            // more than one colon is a bare IPv6 address
+            Some(i) if s.rfind(':') == Some(i) => (s.slice_to(i), s.slice_from(i)),
+            _ => (s, "")
+        }
+    };
+    let port = if rest.is_empty() {
+        port
+    } else if rest.starts_with(":") {
+        match from_str::<u16>(rest.slice_from(1)) {
+            Some(p) if p > 0 => p,
+            _ => return None
+        }
+    } else {
+        return None;
+    };
+    if host.is_empty() {
+        return None;
+    }
+    Some((host.to_owned(), port))
+}
+
+/// Returns the strings in the array at the given key, ignoring any non-string values
+fn string_list(val: &toml::Value, key: &str) -> ~[~str] {
+    let mut list = ~[];
+    match val.lookup(key).and_then(|v| v.get_vec()) {
+        None => (),
+        Some(v) => {
+            for elem in v.iter() {
+                match elem.get_str() {
+                    None => continue,
+                    Some(s) => list.push(s.clone())
+                }
+            }
+        }
+    }
+    list
+}
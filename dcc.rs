@@ -0,0 +1,284 @@
+/// DCC SEND file transfers
+///
+/// A DCC SEND offer is a CTCP DCC message naming a file and its size, and the address
+/// and port the receiver connects to for it. A passive offer has port 0 and a token, and
+/// the receiver answers it with an offer of its own that gives the address and port
+/// where it waits for the sender instead. The receiver acknowledges each block with the
+/// total number of bytes it has received, as a 32-bit big-endian number. Offers sent to
+/// the bot are passed to plugins as irc.DCC, and the dcc package sends and accepts files.
+
+use config;
+use State;
+use irc::conn::{Conn, Line, IRCCTCP};
+use std::ascii::StrAsciiExt;
+use std::io;
+use std::io::File;
+use std::io::net::ip::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::io::net::tcp::{TcpListener, TcpAcceptor, TcpStream};
+use std::iter::range_inclusive;
+use std::{str, task};
+use time;
+
+pub static TIMEOUT: u64 = 60 * 1000; // ms an offer waits to be taken, or a transfer may stall
+static PROGRESS_INTERVAL: u64 = 1000000000; // ns between progress reports
+static BLOCK_SIZE: uint = 4096;
+
+/// A DCC SEND offer, or the reply to a passive one
+#[deriving(Clone)]
+pub struct Offer {
+    filename: ~str,
+    ip: u32, // the IPv4 address as a number, the way offers give it
+    port: u16, // 0 for a passive offer
+    size: u64,
+    token: Option<~str> // pairs a passive offer with its reply
+}
+
+impl Offer {
+    /// Parses the text of a CTCP DCC message, if it's a SEND
+    pub fn parse(text: &[u8]) -> Option<Offer> {
+        let text = str::from_utf8_lossy(text).into_owned();
+        let text = text.as_slice();
+        if text.len() < 5 || !text.slice_to(5).eq_ignore_ascii_case("SEND ") {
+            return None;
+        }
+        let rest = text.slice_from(5).trim_left();
+        // a file name with spaces is quoted
+        let (filename, rest) = if rest.starts_with("\"") {
+            match rest.slice_from(1).find('"') {
+                None => return None,
+                Some(i) => (rest.slice(1, i + 1), rest.slice_from(i + 2))
+            }
+        } else {
+            match rest.find(' ') {
+                None => return None,
+                Some(i) => (rest.slice_to(i), rest.slice_from(i))
+            }
+        };
+        let words: ~[&str] = rest.words().collect();
+        if filename.is_empty() || words.len() < 3 {
+            return None;
+        }
+        match (from_str::<u32>(words[0]), from_str::<u16>(words[1]), from_str::<u64>(words[2])) {
+            (Some(ip), Some(port), Some(size)) => Some(Offer {
+                filename: filename.to_owned(),
+                ip: ip,
+                port: port,
+                size: size,
+                token: words.get(3).map(|&t| t.to_owned())
+            }),
+            _ => None
+        }
+    }
+
+    /// Returns the CTCP message that makes the offer
+    pub fn text(&self) -> ~[u8] {
+        let name = if self.filename.contains_char(' ') {
+            format!("\"{}\"", self.filename)
+        } else {
+            self.filename.clone()
+        };
+        let token = self.token.as_ref().map_or(~"", |t| format!(" {}", t));
+        format!("\x01DCC SEND {} {} {} {}{}\x01", name, self.ip, self.port, self.size, token)
+            .into_bytes()
+    }
+
+    /// Returns the address the receiver connects to
+    pub fn address(&self) -> SocketAddr {
+        let ip = self.ip;
+        SocketAddr {
+            ip: Ipv4Addr((ip >> 24) as u8, (ip >> 16) as u8, (ip >> 8) as u8, ip as u8),
+            port: self.port
+        }
+    }
+}
+
+/// Returns the IPv4 address as a number, the way offers give it
+pub fn ip_number(ip: IpAddr) -> u32 {
+    match ip {
+        Ipv4Addr(a, b, c, d) => (a as u32 << 24) | (b as u32 << 16) | (c as u32 << 8) | d as u32,
+        Ipv6Addr(..) => 0
+    }
+}
+
+/// Strips the directories and quotes from an offered file name, and any leading dots
+/// so it can't make a hidden file. Returns "" if nothing is left.
+pub fn safe_name(name: &str) -> ~str {
+    let base = name.rsplit(|c: char| c == '/' || c == '\\').next().unwrap_or("");
+    let base: ~str = base.chars().map(|c| {
+        if c.is_control() || c == '"' { '_' } else { c }
+    }).collect();
+    base.trim_left_chars(&'.').to_owned()
+}
+
+/// Returns the path in the dir to save the offered file as, appending a number to the
+/// name if a file by that name exists already
+pub fn download_path(dir: &Path, name: &str) -> Option<Path> {
+    let base = safe_name(name);
+    if base.is_empty() {
+        return None;
+    }
+    let mut path = dir.join(base.as_slice());
+    let mut n = 1;
+    while path.exists() {
+        path = dir.join(format!("{}.{}", base, n));
+        n += 1;
+    }
+    Some(path)
+}
+
+/// Listens for the other end of a transfer on the first free port of the configured
+/// range, returning the acceptor and its port
+pub fn listen(conf: &config::Dcc) -> Result<(TcpAcceptor, u16), ~str> {
+    let (first, last) = conf.ports.unwrap_or((0, 0));
+    let mut err = ~"no free port";
+    for port in range_inclusive(first, last) {
+        let mut listener = match TcpListener::bind(SocketAddr { ip: Ipv4Addr(0, 0, 0, 0),
+                                                                port: port }) {
+            Ok(l) => l,
+            Err(e) => {
+                err = e.to_str();
+                continue;
+            }
+        };
+        let port = match listener.socket_name() {
+            Ok(addr) => addr.port,
+            Err(e) => return Err(format!("could not listen for the transfer: {}", e))
+        };
+        match listener.listen() {
+            Ok(mut acceptor) => {
+                acceptor.set_timeout(Some(TIMEOUT));
+                return Ok((acceptor, port));
+            }
+            Err(e) => err = e.to_str()
+        }
+    }
+    Err(format!("could not listen for the transfer: {}", err))
+}
+
+/// Connects to the address of an offer, or of the reply to a passive one
+pub fn connect(addr: SocketAddr) -> Result<TcpStream, ~str> {
+    TcpStream::connect(addr).map_err(|e| format!("could not connect to {}: {}", addr, e))
+}
+
+/// Sends the file, calling `progress` with the bytes sent so far at most once a second,
+/// and waits for the receiver to acknowledge all of it
+pub fn send(stream: TcpStream, file: &mut File, size: u64, progress: |u64|)
+            -> Result<(), ~str> {
+    if size == 0 {
+        return Ok(());
+    }
+    let mut stream = stream;
+    // the acks are read as they come, so they can't fill the receiver's send buffer
+    let mut acks = stream.clone();
+    let (tx, rx) = channel();
+    task::task().named("dcc acks").spawn(proc() {
+        // acks wrap around past 4 GiB
+        let expected = size as u32;
+        let res;
+        loop {
+            acks.set_read_timeout(Some(TIMEOUT));
+            match acks.read_be_u32() {
+                Ok(n) if n == expected => {
+                    res = Ok(());
+                    break;
+                }
+                Ok(_) => (),
+                Err(ref e) if e.kind == io::EndOfFile => {
+                    res = Err(~"the receiver closed the connection");
+                    break;
+                }
+                Err(e) => {
+                    res = Err(e.to_str());
+                    break;
+                }
+            }
+        }
+        if res.is_err() {
+            // wake up the sender if it's blocked on a stalled receiver
+            let _ = acks.close_read();
+            let _ = acks.close_write();
+        }
+        tx.try_send(res);
+    });
+
+    let mut buf = [0u8, ..BLOCK_SIZE];
+    let mut sent = 0u64;
+    let mut reported = time::precise_time_ns();
+    while sent < size {
+        let n = match file.read(buf) {
+            Ok(n) => n,
+            Err(e) => return Err(format!("could not read the file: {}", e))
+        };
+        if stream.write(buf.slice_to(n)).is_err() {
+            // the ack reader has the reason
+            break;
+        }
+        sent += n as u64;
+        let now = time::precise_time_ns();
+        if now - reported >= PROGRESS_INTERVAL {
+            reported = now;
+            progress(sent);
+        }
+    }
+    rx.recv()
+}
+
+/// Receives `size` bytes into the file, acknowledging each block and calling `progress`
+/// with the bytes received so far at most once a second
+pub fn receive(stream: TcpStream, file: &mut File, size: u64, progress: |u64|)
+               -> Result<(), ~str> {
+    let mut stream = stream;
+    let mut buf = [0u8, ..BLOCK_SIZE];
+    let mut got = 0u64;
+    let mut reported = time::precise_time_ns();
+    while got < size {
+        stream.set_read_timeout(Some(TIMEOUT));
+        let n = match stream.read(buf) {
+            Ok(n) => n,
+            Err(ref e) if e.kind == io::EndOfFile => {
+                return Err(format!("the sender closed the connection after {} of {} bytes",
+                                   got, size));
+            }
+            Err(e) => return Err(e.to_str())
+        };
+        if got + n as u64 > size {
+            return Err(~"the sender sent more than the offered size");
+        }
+        match file.write(buf.slice_to(n)) {
+            Ok(()) => (),
+            Err(e) => return Err(format!("could not write the file: {}", e))
+        }
+        got += n as u64;
+        match stream.write_be_u32(got as u32) {
+            Ok(()) => (),
+            Err(e) => return Err(e.to_str())
+        }
+        let now = time::precise_time_ns();
+        if now - reported >= PROGRESS_INTERVAL {
+            reported = now;
+            progress(got);
+        }
+    }
+    Ok(())
+}
+
+/// Gives plugins the DCC SEND offers sent to the bot
+pub fn line_dispatched(conn: &mut Conn, state: &mut State, line: &Line) {
+    let (cmd, dst) = match line.command {
+        IRCCTCP(ref cmd, ref dst) => (cmd, dst),
+        _ => return
+    };
+    let user = match line.prefix {
+        Some(ref user) => user,
+        None => return
+    };
+    if !str::from_utf8_lossy(cmd.as_slice()).as_slice().eq_ignore_ascii_case("DCC") ||
+       !state.isupport.casemapping().eq(dst.as_slice(), conn.me().nick()) {
+        return;
+    }
+    let offer = match line.args.head().and_then(|text| Offer::parse(text.as_slice())) {
+        Some(offer) => offer,
+        None => return
+    };
+    state.plugins.dispatch_dcc(conn, &mut state.out, user, &offer);
+}
@@ -0,0 +1,227 @@
+/// SHA-1 and SHA-256 digests, and HMAC over them
+///
+/// These are small one-shot implementations for verifying webhook signatures and
+/// authentication exchanges, not for hashing large amounts of data.
+
+use std::vec;
+
+#[deriving(Eq, Clone)]
+pub enum Algorithm {
+    Sha1,
+    Sha256
+}
+
+impl Algorithm {
+    pub fn from_name(name: &str) -> Option<Algorithm> {
+        match name {
+            "sha1" => Some(Sha1),
+            "sha256" => Some(Sha256),
+            _ => None
+        }
+    }
+
+    /// Returns the digest of the data
+    pub fn digest(&self, data: &[u8]) -> ~[u8] {
+        match *self {
+            Sha1 => sha1(data),
+            Sha256 => sha256(data)
+        }
+    }
+}
+
+static BLOCK_SIZE: uint = 64; // both algorithms use 64-byte blocks
+
+/// Returns the HMAC of the message with the given key
+pub fn hmac(algo: Algorithm, key: &[u8], msg: &[u8]) -> ~[u8] {
+    let mut key = if key.len() > BLOCK_SIZE { algo.digest(key) } else { key.to_owned() };
+    key.grow(BLOCK_SIZE - key.len(), &0u8);
+    let inner: ~[u8] = key.iter().map(|&b| b ^ 0x36).chain(msg.iter().map(|&b| b)).collect();
+    let inner = algo.digest(inner);
+    let outer: ~[u8] = key.iter().map(|&b| b ^ 0x5c).chain(inner.move_iter()).collect();
+    algo.digest(outer)
+}
+
+/// Returns the message padded to a whole number of blocks, with its length in bits
+fn pad(data: &[u8]) -> ~[u8] {
+    let mut msg = vec::with_capacity(data.len() + BLOCK_SIZE + 8);
+    msg.push_all(data);
+    msg.push(0x80u8);
+    while msg.len() % BLOCK_SIZE != BLOCK_SIZE - 8 {
+        msg.push(0u8);
+    }
+    let bits = data.len() as u64 * 8;
+    for i in range(0u, 8).rev() {
+        msg.push((bits >> (i * 8)) as u8);
+    }
+    msg
+}
+
+fn read_u32(b: &[u8]) -> u32 {
+    (b[0] as u32 << 24) | (b[1] as u32 << 16) | (b[2] as u32 << 8) | b[3] as u32
+}
+
+fn write_u32s(words: &[u32]) -> ~[u8] {
+    let mut out = vec::with_capacity(words.len() * 4);
+    for &w in words.iter() {
+        out.push((w >> 24) as u8);
+        out.push((w >> 16) as u8);
+        out.push((w >> 8) as u8);
+        out.push(w as u8);
+    }
+    out
+}
+
+fn rotl(x: u32, n: uint) -> u32 {
+    (x << n) | (x >> (32 - n))
+}
+
+fn rotr(x: u32, n: uint) -> u32 {
+    (x >> n) | (x << (32 - n))
+}
+
+pub fn sha1(data: &[u8]) -> ~[u8] {
+    let mut h = [0x67452301u32, 0xEFCDAB89, 0x98BADCFE, 0x10325476, 0xC3D2E1F0];
+    let msg = pad(data);
+    let mut w = [0u32, ..80];
+    for block in msg.chunks(BLOCK_SIZE) {
+        for i in range(0u, 16) {
+            w[i] = read_u32(block.slice_from(i * 4));
+        }
+        for i in range(16u, 80) {
+            w[i] = rotl(w[i-3] ^ w[i-8] ^ w[i-14] ^ w[i-16], 1);
+        }
+        let (mut a, mut b, mut c, mut d, mut e) = (h[0], h[1], h[2], h[3], h[4]);
+        for i in range(0u, 80) {
+            let (f, k) = match i {
+                0..19 => ((b & c) | (!b & d), 0x5A827999u32),
+                20..39 => (b ^ c ^ d, 0x6ED9EBA1),
+                40..59 => ((b & c) | (b & d) | (c & d), 0x8F1BBCDC),
+                _ => (b ^ c ^ d, 0xCA62C1D6)
+            };
+            let t = rotl(a, 5) + f + e + k + w[i];
+            e = d;
+            d = c;
+            c = rotl(b, 30);
+            b = a;
+            a = t;
+        }
+        h[0] += a;
+        h[1] += b;
+        h[2] += c;
+        h[3] += d;
+        h[4] += e;
+    }
+    write_u32s(h)
+}
+
+static K256: [u32, ..64] = [
+    0x428a2f98, 0x71374491, 0xb5c0fbcf, 0xe9b5dba5, 0x3956c25b, 0x59f111f1, 0x923f82a4, 0xab1c5ed5,
+    0xd807aa98, 0x12835b01, 0x243185be, 0x550c7dc3, 0x72be5d74, 0x80deb1fe, 0x9bdc06a7, 0xc19bf174,
+    0xe49b69c1, 0xefbe4786, 0x0fc19dc6, 0x240ca1cc, 0x2de92c6f, 0x4a7484aa, 0x5cb0a9dc, 0x76f988da,
+    0x983e5152, 0xa831c66d, 0xb00327c8, 0xbf597fc7, 0xc6e00bf3, 0xd5a79147, 0x06ca6351, 0x14292967,
+    0x27b70a85, 0x2e1b2138, 0x4d2c6dfc, 0x53380d13, 0x650a7354, 0x766a0abb, 0x81c2c92e, 0x92722c85,
+    0xa2bfe8a1, 0xa81a664b, 0xc24b8b70, 0xc76c51a3, 0xd192e819, 0xd6990624, 0xf40e3585, 0x106aa070,
+    0x19a4c116, 0x1e376c08, 0x2748774c, 0x34b0bcb5, 0x391c0cb3, 0x4ed8aa4a, 0x5b9cca4f, 0x682e6ff3,
+    0x748f82ee, 0x78a5636f, 0x84c87814, 0x8cc70208, 0x90befffa, 0xa4506ceb, 0xbef9a3f7, 0xc67178f2
+];
+
+pub fn sha256(data: &[u8]) -> ~[u8] {
+    let mut h = [0x6a09e667u32, 0xbb67ae85, 0x3c6ef372, 0xa54ff53a,
+                 0x510e527f, 0x9b05688c, 0x1f83d9ab, 0x5be0cd19];
+    let msg = pad(data);
+    let mut w = [0u32, ..64];
+    for block in msg.chunks(BLOCK_SIZE) {
+        for i in range(0u, 16) {
+            w[i] = read_u32(block.slice_from(i * 4));
+        }
+        for i in range(16u, 64) {
+            let s0 = rotr(w[i-15], 7) ^ rotr(w[i-15], 18) ^ (w[i-15] >> 3);
+            let s1 = rotr(w[i-2], 17) ^ rotr(w[i-2], 19) ^ (w[i-2] >> 10);
+            w[i] = w[i-16] + s0 + w[i-7] + s1;
+        }
+        let mut v = h;
+        for i in range(0u, 64) {
+            let s1 = rotr(v[4], 6) ^ rotr(v[4], 11) ^ rotr(v[4], 25);
+            let ch = (v[4] & v[5]) ^ (!v[4] & v[6]);
+            let t1 = v[7] + s1 + ch + K256[i] + w[i];
+            let s0 = rotr(v[0], 2) ^ rotr(v[0], 13) ^ rotr(v[0], 22);
+            let maj = (v[0] & v[1]) ^ (v[0] & v[2]) ^ (v[1] & v[2]);
+            let t2 = s0 + maj;
+            v[7] = v[6];
+            v[6] = v[5];
+            v[5] = v[4];
+            v[4] = v[3] + t1;
+            v[3] = v[2];
+            v[2] = v[1];
+            v[1] = v[0];
+            v[0] = t1 + t2;
+        }
+        for i in range(0u, 8) {
+            h[i] += v[i];
+        }
+    }
+    write_u32s(h)
+}
+
+#[cfg(test)]
+mod test {
+    use super::{hmac, sha1, sha256, Sha1, Sha256};
+    use std::vec;
+
+    // 448 bits, so the padding's length doesn't fit in the first block
+    static LONG: &'static [u8] = bytes!("abcdbcdecdefdefgefghfghighijhijkijkljklmklmnlmnomnopnopq");
+    static BIG_KEY_MSG: &'static [u8] =
+        bytes!("Test Using Larger Than Block-Size Key - Hash Key First");
+    static JEFE_MSG: &'static [u8] = bytes!("what do ya want for nothing?");
+
+    fn hex(bytes: ~[u8]) -> ~str {
+        let mut s = ~"";
+        for b in bytes.iter() {
+            s.push_str(format!("{:02x}", *b));
+        }
+        s
+    }
+
+    #[test]
+    fn test_sha1() {
+        assert_eq!(hex(sha1(bytes!("abc"))), ~"a9993e364706816aba3e25717850c26c9cd0d89d");
+        assert_eq!(hex(sha1(bytes!(""))), ~"da39a3ee5e6b4b0d3255bfef95601890afd80709");
+        assert_eq!(hex(sha1(LONG)), ~"84983e441c3bd26ebaae4aa1f95129e5e54670f1");
+    }
+
+    #[test]
+    fn test_sha256() {
+        assert_eq!(hex(sha256(bytes!("abc"))),
+                   ~"ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad");
+        assert_eq!(hex(sha256(bytes!(""))),
+                   ~"e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855");
+        assert_eq!(hex(sha256(LONG)),
+                   ~"248d6a61d20638b8e5c026930c3e6039a33ce45964ff2167f6ecedd419db06c1");
+    }
+
+    #[test]
+    fn test_hmac_sha1() {
+        // RFC 2202 test cases 1, 2 and 6
+        let key = vec::from_elem(20, 0x0bu8);
+        assert_eq!(hex(hmac(Sha1, key.as_slice(), bytes!("Hi There"))),
+                   ~"b617318655057264e28bc0b6fb378c8ef146be00");
+        assert_eq!(hex(hmac(Sha1, bytes!("Jefe"), JEFE_MSG)),
+                   ~"effcdf6ae5eb2fa2d27416d5f184df9c259a7c79");
+        let key = vec::from_elem(80, 0xaau8);
+        assert_eq!(hex(hmac(Sha1, key.as_slice(), BIG_KEY_MSG)),
+                   ~"aa4ae5e15272d00e95705637ce8a3b55ed402112");
+    }
+
+    #[test]
+    fn test_hmac_sha256() {
+        // RFC 4231 test cases 1, 2 and 6
+        let key = vec::from_elem(20, 0x0bu8);
+        assert_eq!(hex(hmac(Sha256, key.as_slice(), bytes!("Hi There"))),
+                   ~"b0344c61d8db38535ca8afceaf0bf12b881dc200c9833da726e9376c2e32cff7");
+        assert_eq!(hex(hmac(Sha256, bytes!("Jefe"), JEFE_MSG)),
+                   ~"5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843");
+        let key = vec::from_elem(131, 0xaau8);
+        assert_eq!(hex(hmac(Sha256, key.as_slice(), BIG_KEY_MSG)),
+                   ~"60e431591ee0b67f0d8a26aacbf5b77f8e0bc6213728c5140546040f0ee37f54");
+    }
+}
@@ -0,0 +1,117 @@
+/// Text encodings of legacy networks
+///
+/// Plugins expect UTF-8, but some networks still carry Latin-1 or CP1252 text. Each
+/// server has an encoding and, for UTF-8, a fallback for lines that aren't valid UTF-8.
+/// The arguments of received lines are decoded to UTF-8 before they're dispatched, and
+/// the messages sent through Outbound are encoded back, with characters the encoding
+/// lacks sent as '?'.
+
+use irc::conn;
+use irc::conn::{Event, Line, IRCCTCP, IRCCTCPReply, IRCAction};
+use std::ascii::StrAsciiExt;
+use std::str;
+
+#[deriving(Clone, Eq)]
+pub enum Encoding {
+    Utf8,
+    Latin1,
+    Cp1252
+}
+
+// the characters of CP1252's 0x80-0x9F, where it differs from Latin-1. Its five unused
+// bytes are read as the Latin-1 control characters, as Windows does.
+static CP1252_HIGH: [char, ..32] = [
+    '\u20ac', '\x81', '\u201a', '\u0192', '\u201e', '\u2026', '\u2020', '\u2021',
+    '\u02c6', '\u2030', '\u0160', '\u2039', '\u0152', '\x8d', '\u017d', '\x8f',
+    '\x90', '\u2018', '\u2019', '\u201c', '\u201d', '\u2022', '\u2013', '\u2014',
+    '\u02dc', '\u2122', '\u0161', '\u203a', '\u0153', '\x9d', '\u017e', '\u0178'
+];
+
+impl Encoding {
+    /// Returns the encoding with the name, e.g. "utf-8", "latin-1" or "cp1252"
+    pub fn from_name(name: &str) -> Option<Encoding> {
+        let name: ~str = name.to_ascii_lower().chars().filter(|&c| c != '-' && c != '_')
+                             .collect();
+        match name.as_slice() {
+            "utf8" => Some(Utf8),
+            "latin1" | "iso88591" => Some(Latin1),
+            "cp1252" | "windows1252" => Some(Cp1252),
+            _ => None
+        }
+    }
+
+    /// Decodes the text into UTF-8. UTF-8 text that isn't valid has its invalid
+    /// sequences replaced by U+FFFD.
+    pub fn decode(&self, text: &[u8]) -> ~[u8] {
+        match *self {
+            Utf8 => str::from_utf8_lossy(text).into_owned().into_bytes(),
+            Latin1 => text.iter().map(|&b| b as char).collect::<~str>().into_bytes(),
+            Cp1252 => text.iter().map(|&b| {
+                if b >= 0x80 && b < 0xa0 { CP1252_HIGH[b as uint - 0x80] } else { b as char }
+            }).collect::<~str>().into_bytes()
+        }
+    }
+
+    /// Encodes the UTF-8 text. Text that isn't valid UTF-8 is sent as it is, since a
+    /// plugin may have encoded it already.
+    pub fn encode(&self, text: &[u8]) -> ~[u8] {
+        let s = match str::from_utf8(text) {
+            Some(s) if *self != Utf8 => s,
+            _ => return text.to_owned()
+        };
+        s.chars().map(|c| {
+            let n = c as u32;
+            if n < 0x80 || (n < 0x100 && (*self == Latin1 || n >= 0xa0)) {
+                n as u8
+            } else if *self == Cp1252 {
+                CP1252_HIGH.iter().position(|&h| h == c).map_or('?' as u8, |i| 0x80 + i as u8)
+            } else {
+                '?' as u8
+            }
+        }).collect()
+    }
+}
+
+/// A server's encoding, and the fallback for lines that aren't valid in it
+#[deriving(Clone)]
+pub struct Codec {
+    priv encoding: Encoding,
+    priv fallback: Option<Encoding> // only used with Utf8, since any bytes are valid Latin-1
+}
+
+impl Codec {
+    pub fn new(encoding: Encoding, fallback: Option<Encoding>) -> Codec {
+        Codec { encoding: encoding, fallback: fallback }
+    }
+
+    /// Decodes the text into UTF-8
+    pub fn decode(&self, text: &[u8]) -> ~[u8] {
+        match (self.encoding, self.fallback) {
+            (Utf8, _) if str::is_utf8(text) => text.to_owned(),
+            (Utf8, Some(fallback)) => fallback.decode(text),
+            (encoding, _) => encoding.decode(text)
+        }
+    }
+
+    /// Encodes UTF-8 text for the server
+    pub fn encode(&self, text: &[u8]) -> ~[u8] {
+        self.encoding.encode(text)
+    }
+
+    /// Decodes the arguments and destination of a received line
+    pub fn decode_event(&self, event: Event) -> Event {
+        match event {
+            conn::LineReceived(Line{command, args, prefix}) => {
+                let command = match command {
+                    IRCCTCP(cmd, dst) => IRCCTCP(cmd, self.decode(dst.as_slice())),
+                    IRCCTCPReply(cmd, dst) => IRCCTCPReply(cmd, self.decode(dst.as_slice())),
+                    IRCAction(dst) => IRCAction(self.decode(dst.as_slice())),
+                    command => command
+                };
+                let args = args.iter().map(|arg| self.decode(arg.as_slice())).collect();
+                conn::LineReceived(Line { command: command, args: args, prefix: prefix })
+            }
+            event => event
+        }
+    }
+}
@@ -0,0 +1,286 @@
+/// RSS and Atom feed polling
+///
+/// Each configured feed is polled on its own task. The task outlives connections, so
+/// it remembers what it has seen across reconnects, and items that show up while
+/// there's no connection are held until there is one. New items are dispatched to
+/// plugins as the feed.item event, on every server that's connected. The first fetch
+/// only records the items already in the feed, so starting the bot doesn't announce
+/// all of them.
+
+use {Conns, State};
+use config;
+use http;
+use irc::conn::Conn;
+use collections::HashSet;
+use std::{char, io, num, str, task};
+
+static MAX_PENDING: uint = 50; // items held per feed while there's no connection
+
+/// An entry in a feed. Missing values are empty.
+#[deriving(Clone)]
+pub struct Item {
+    id: ~str, // the guid or id, falling back to the link or title
+    title: ~str,
+    link: ~str,
+    date: ~str // as given by the feed
+}
+
+/// Spawns a new (unwatched) task to poll each configured feed
+pub fn spawn_pollers(conf: &config::Config, conns: Conns) {
+    for feed in conf.feeds.iter() {
+        let feed = feed.clone();
+        let conns = conns.clone();
+        task::task().named("feed poller").spawn(proc() {
+            poll(feed, conns);
+        });
+    }
+}
+
+fn poll(feed: config::Feed, conns: Conns) {
+    let mut timer = match io::timer::Timer::new() {
+        Ok(t) => t,
+        Err(e) => {
+            println!("Error creating timer for feed {}: {}", feed.name, e);
+            return;
+        }
+    };
+    let periodic = timer.periodic(feed.interval as u64 * 1000);
+    let mut seen: HashSet<~str> = HashSet::new();
+    let mut first = true;
+    let mut pending: ~[Item] = ~[];
+    loop {
+        match http::get(feed.url).and_then(|body| parse(body)) {
+            Err(e) => println!("Error fetching feed {}: {}", feed.name, e),
+            Ok(items) => {
+                // feeds list their newest items first
+                for item in items.rev_iter() {
+                    if !first && !seen.contains(&item.id) {
+                        pending.push(item.clone());
+                    }
+                }
+                seen = items.move_iter().map(|item| item.id).collect();
+                first = false;
+                if pending.len() > MAX_PENDING {
+                    let extra = pending.len() - MAX_PENDING;
+                    pending = pending.move_iter().skip(extra).collect();
+                }
+            }
+        }
+
+        if !pending.is_empty() {
+            let mut sent = false;
+            for &(_, ref arc) in conns.iter() {
+                let items = pending.clone();
+                let name = feed.name.clone();
+                let mut cmd = Some(proc(conn: &mut Conn, state: &mut State) {
+                    for item in items.iter() {
+                        state.plugins.dispatch_feed_item(conn, &mut state.out, name.as_slice(),
+                                                         item);
+                    }
+                });
+                sent |= arc.access(|chan| {
+                    match *chan {
+                        None => false,
+                        Some(ref c) => c.try_send(cmd.take_unwrap())
+                    }
+                });
+            }
+            if sent {
+                pending.clear();
+            }
+        }
+
+        periodic.recv();
+    }
+}
+
+/// Returns the items of an RSS or Atom feed, in the feed's order
+pub fn parse(body: &[u8]) -> Result<~[Item], ~str> {
+    let text = str::from_utf8_lossy(body).into_owned();
+    let text = text.as_slice();
+    let rss = open_tag(text, 0, "rss").is_some() || open_tag(text, 0, "rdf:RDF").is_some();
+    let (tag, atom) = if rss {
+        ("item", false)
+    } else if open_tag(text, 0, "feed").is_some() {
+        ("entry", true)
+    } else {
+        return Err(~"not an RSS or Atom feed");
+    };
+
+    let mut items = ~[];
+    let mut pos = 0;
+    loop {
+        let (start, end, next) = match element(text, pos, tag) {
+            None => break,
+            Some(e) => e
+        };
+        pos = next;
+        let content = text.slice(start, end);
+        let title = text_of(content, "title").unwrap_or(~"");
+        let link = if atom { atom_link(content) } else { text_of(content, "link") };
+        let link = link.unwrap_or(~"");
+        let date = ["pubDate", "dc:date", "published", "updated"].iter()
+                       .filter_map(|t| text_of(content, *t)).next().unwrap_or(~"");
+        let id = text_of(content, if atom { "id" } else { "guid" }).unwrap_or_else(|| {
+            if link.is_empty() { title.clone() } else { link.clone() }
+        });
+        items.push(Item { id: id, title: title, link: link, date: date });
+    }
+    Ok(items)
+}
+
+/// Finds the next start tag with the given name at or after `from`, returning the
+/// position of its < and of its >
+fn open_tag(text: &str, from: uint, name: &str) -> Option<(uint, uint)> {
+    let pat = format!("<{}", name);
+    let mut from = from;
+    loop {
+        let i = match text.slice_from(from).find_str(pat) {
+            None => return None,
+            Some(i) => from + i
+        };
+        from = i + pat.len();
+        // make sure this isn't a longer name that starts with this one
+        match text.slice_from(from).chars().next() {
+            Some(c) if c == '>' || c == '/' || c.is_whitespace() => (),
+            _ => continue
+        }
+        return text.slice_from(from).find('>').map(|j| (i, from + j));
+    }
+}
+
+/// Finds the next element with the given name at or after `from`, returning the
+/// positions where its content starts and ends, and where the element ends
+fn element(text: &str, from: uint, name: &str) -> Option<(uint, uint, uint)> {
+    let (_, gt) = match open_tag(text, from, name) {
+        None => return None,
+        Some(t) => t
+    };
+    let start = gt + 1;
+    if text.slice_to(gt).ends_with("/") {
+        return Some((start, start, start));
+    }
+    let close = format!("</{}", name);
+    let end = match text.slice_from(start).find_str(close) {
+        None => return None,
+        Some(i) => start + i
+    };
+    let next = text.slice_from(end).find('>').map_or(text.len(), |i| end + i + 1);
+    Some((start, end, next))
+}
+
+/// Returns the decoded text of the first element with the given name, with runs of
+/// whitespace collapsed
+fn text_of(content: &str, name: &str) -> Option<~str> {
+    element(content, 0, name).map(|(start, end, _)| {
+        let text = decode(content.slice(start, end));
+        text.words().collect::<~[&str]>().connect(" ")
+    })
+}
+
+/// Returns the href of an Atom entry's alternate link, or of its first link
+fn atom_link(content: &str) -> Option<~str> {
+    let mut first = None;
+    let mut pos = 0;
+    loop {
+        let (lt, gt) = match open_tag(content, pos, "link") {
+            None => break,
+            Some(t) => t
+        };
+        pos = gt;
+        let tag = content.slice(lt, gt);
+        let href = match attr(tag, "href") {
+            None => continue,
+            Some(href) => href
+        };
+        match attr(tag, "rel") {
+            None => return Some(href),
+            Some(ref rel) if rel.as_slice() == "alternate" => return Some(href),
+            Some(_) => {
+                if first.is_none() {
+                    first = Some(href);
+                }
+            }
+        }
+    }
+    first
+}
+
+/// Returns the decoded value of the attribute in the start tag
+fn attr(tag: &str, name: &str) -> Option<~str> {
+    let mut from = 0;
+    loop {
+        let i = match tag.slice_from(from).find_str(name) {
+            None => return None,
+            Some(i) => from + i
+        };
+        from = i + name.len();
+        if i == 0 || !(tag.as_bytes()[i-1] as char).is_whitespace() {
+            continue;
+        }
+        let rest = tag.slice_from(from).trim_left();
+        if !rest.starts_with("=") {
+            continue;
+        }
+        let rest = rest.slice_from(1).trim_left();
+        let quote = match rest.chars().next() {
+            Some(q) if q == '"' || q == '\'' => q,
+            _ => continue
+        };
+        let rest = rest.slice_from(1);
+        return rest.find(quote).map(|j| decode(rest.slice_to(j)));
+    }
+}
+
+/// Replaces entities and unwraps CDATA sections
+fn decode(s: &str) -> ~str {
+    let mut out = str::with_capacity(s.len());
+    let mut rest = s;
+    while !rest.is_empty() {
+        if rest.starts_with("<![CDATA[") {
+            let body = rest.slice_from("<![CDATA[".len());
+            match body.find_str("]]>") {
+                None => {
+                    out.push_str(body);
+                    break;
+                }
+                Some(i) => {
+                    out.push_str(body.slice_to(i));
+                    rest = body.slice_from(i + 3);
+                }
+            }
+            continue;
+        }
+        if rest.starts_with("&") {
+            match rest.find(';').and_then(|i| entity(rest.slice(1, i)).map(|c| (i, c))) {
+                Some((i, c)) => {
+                    out.push_char(c);
+                    rest = rest.slice_from(i + 1);
+                    continue;
+                }
+                None => ()
+            }
+        }
+        let c = rest.char_at(0);
+        out.push_char(c);
+        rest = rest.slice_from(c.len_utf8_bytes());
+    }
+    out
+}
+
+fn entity(name: &str) -> Option<char> {
+    match name {
+        "amp" => Some('&'),
+        "lt" => Some('<'),
+        "gt" => Some('>'),
+        "quot" => Some('"'),
+        "apos" => Some('\''),
+        _ if name.starts_with("#x") || name.starts_with("#X") => {
+            num::from_str_radix::<u32>(name.slice_from(2), 16).and_then(char::from_u32)
+        }
+        _ if name.starts_with("#") => {
+            from_str::<u32>(name.slice_from(1)).and_then(char::from_u32)
+        }
+        _ => None
+    }
+}
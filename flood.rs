@@ -0,0 +1,105 @@
+/// Outgoing flood protection
+///
+/// Servers kill clients that send too much too fast with "Excess Flood". Messages go out
+/// immediately while there's room in a burst, and after that they're queued and sent one
+/// per interval, as the burst refills. Each server picks a preset, or its own burst and
+/// interval.
+
+use collections::RingBuf;
+use collections::Deque;
+use time;
+
+/// Milliseconds between attempts to send queued lines
+pub static FLUSH_INTERVAL: u64 = 250;
+/// Most lines that are queued before more are dropped
+static MAX_QUEUE: uint = 200;
+
+/// Burst and interval (in ms) of each preset, or None for no flood protection
+pub static PRESETS: &'static [(&'static str, Option<(uint, u64)>)] = &[
+    ("default", Some((5, 2000))),
+    ("strict", Some((3, 3000))), // e.g. IRCnet, which has little tolerance
+    ("relaxed", Some((10, 1000))), // servers and bouncers that exempt the bot
+    ("off", None)
+];
+
+/// Returns the burst and interval of the named preset
+pub fn preset(name: &str) -> Option<Option<(uint, u64)>> {
+    PRESETS.iter().find(|&&(n, _)| n == name).map(|&(_, p)| p)
+}
+
+/// Flood protection for queued lines of type T
+pub struct Flood<T> {
+    priv burst: uint, // lines that may be sent at once
+    priv interval: u64, // ns for a line of the burst to refill
+    priv tokens: uint, // lines that may be sent now
+    priv refilled: u64, // precise_time_ns() when tokens were last refilled
+    priv queue: RingBuf<T>,
+    priv dropping: bool // the queue is full, and that's been reported
+}
+
+impl<T> Flood<T> {
+    pub fn new(burst: uint, interval_ms: u64) -> Flood<T> {
+        Flood {
+            burst: burst,
+            interval: interval_ms * 1000000,
+            tokens: burst,
+            refilled: time::precise_time_ns(),
+            queue: RingBuf::new(),
+            dropping: false
+        }
+    }
+
+    /// Returns whether a line can be sent now rather than queued, taking a token for it
+    pub fn ready(&mut self) -> bool {
+        if !self.queue.is_empty() {
+            return false;
+        }
+        self.take()
+    }
+
+    /// Takes a token for a line that's sent now regardless, e.g. a PONG, if there's one
+    /// left, so the lines after it still keep to the burst
+    pub fn spend(&mut self) {
+        self.take();
+    }
+
+    /// Queues a line to be sent once the burst refills
+    pub fn queue(&mut self, line: T) {
+        if self.queue.len() >= MAX_QUEUE {
+            if !self.dropping {
+                println!("Warning: the send queue is full; dropping messages");
+                self.dropping = true;
+            }
+            return;
+        }
+        self.queue.push_back(line);
+    }
+
+    /// Passes as many queued lines to `send` as the burst allows
+    pub fn flush(&mut self, send: |T|) {
+        while !self.queue.is_empty() && self.take() {
+            send(self.queue.pop_front().unwrap());
+        }
+        if self.queue.is_empty() {
+            self.dropping = false;
+        }
+    }
+
+    fn take(&mut self) -> bool {
+        let now = time::precise_time_ns();
+        if self.interval > 0 {
+            let refills = (now - self.refilled) / self.interval;
+            self.refilled += refills * self.interval;
+            self.tokens += refills as uint;
+        }
+        if self.tokens >= self.burst {
+            self.tokens = self.burst;
+            self.refilled = now;
+        }
+        if self.tokens == 0 {
+            return false;
+        }
+        self.tokens -= 1;
+        true
+    }
+}
@@ -0,0 +1,111 @@
+/// Channel join greetings
+///
+/// Greetings are configured per channel and rendered from a template when someone
+/// joins. Each user is greeted at most once per cooldown in a given channel, and
+/// plugins may change or suppress the greeting with the irc.GREET event.
+
+use casemap::CaseMapping;
+use config;
+use outbound;
+use State;
+use irc;
+use irc::conn::{Conn, Line, IRCCmd};
+use collections::HashMap;
+use std::{mem, str};
+use time;
+
+static PRUNE_THRESHOLD: uint = 1000; // forget expired cooldowns once this many are tracked
+
+pub struct Greeter {
+    priv greetings: ~[(~str, ~str)], // channel, template
+    priv cooldown: Option<u64>, // ns before a user is greeted again in the same channel
+    // (lowercased channel, lowercased nick) -> precise_time_ns() of greeting
+    priv greeted: HashMap<(~[u8], ~[u8]), u64>
+}
+
+impl Greeter {
+    pub fn new(conf: &config::Config, server: &config::Server) -> Greeter {
+        Greeter {
+            greetings: server.greetings.iter().map(|g| {
+                (g.channel.clone(), g.template.clone())
+            }).collect(),
+            cooldown: conf.greet_cooldown.map(|secs| secs as u64 * 1000000000),
+            greeted: HashMap::new()
+        }
+    }
+
+    /// Returns the rendered greeting for the user joining chan, if one is configured and
+    /// the user hasn't been greeted there within the cooldown
+    fn greeting(&self, casemap: &CaseMapping, user: &irc::User, chan: &str, now: u64)
+                -> Option<~str> {
+        let template = match self.greetings.iter().find(|&&(ref c, _)| {
+            casemap.eq(c.as_bytes(), chan.as_bytes())
+        }) {
+            None => return None,
+            Some(&(_, ref template)) => template
+        };
+        match (self.cooldown, self.greeted.find(&key(casemap, user, chan))) {
+            (Some(cooldown), Some(&last)) if now - last < cooldown => return None,
+            _ => ()
+        }
+        let (nick, username, host) = (lossy(Some(user.nick())), lossy(user.user()),
+                                      lossy(user.host()));
+        let text = template.replace("{nick}", nick.as_slice())
+                           .replace("{user}", username.as_slice())
+                           .replace("{host}", host.as_slice())
+                           .replace("{channel}", chan);
+        Some(text)
+    }
+
+    /// Records that the user was greeted in chan
+    fn mark(&mut self, casemap: &CaseMapping, user: &irc::User, chan: &str, now: u64) {
+        let cooldown = match self.cooldown {
+            None => return,
+            Some(c) => c
+        };
+        if self.greeted.len() >= PRUNE_THRESHOLD {
+            let greeted = mem::replace(&mut self.greeted, HashMap::new());
+            self.greeted = greeted.move_iter().filter(|&(_, last)| now - last < cooldown)
+                                  .collect();
+        }
+        self.greeted.insert(key(casemap, user, chan), now);
+    }
+}
+
+/// Greets the sender of a JOIN line, if the channel has a greeting
+pub fn line_dispatched(conn: &mut Conn, state: &mut State, line: &Line) {
+    let (user, chan) = match *line {
+        Line{command: IRCCmd(ref cmd), ref args, prefix: Some(ref user)}
+            if cmd.as_slice() == "JOIN" && !args.is_empty() => (user, args[0].as_slice()),
+        _ => return
+    };
+    if user.nick() == conn.me().nick() {
+        return;
+    }
+    let chan = str::from_utf8_lossy(chan).into_owned();
+    let now = time::precise_time_ns();
+    let casemap = state.isupport.casemapping();
+    let text = match state.greeter.greeting(&casemap, user, chan.as_slice(), now) {
+        None => return,
+        Some(text) => text
+    };
+    // the cooldown applies even if a plugin suppresses the greeting
+    state.greeter.mark(&casemap, user, chan.as_slice(), now);
+    let text = match state.plugins.filter_greeting(conn, &mut state.out, user, chan.as_bytes(),
+                                                   text.as_bytes()) {
+        None => return,
+        Some(text) => text
+    };
+    if !text.is_empty() {
+        state.out.privmsg(conn, outbound::Greeting, chan.as_bytes(), text.as_slice());
+    }
+}
+
+/// Returns the key of the user's cooldown in chan
+fn key(casemap: &CaseMapping, user: &irc::User, chan: &str) -> (~[u8], ~[u8]) {
+    (casemap.lower(chan.as_bytes()), casemap.lower(user.nick()))
+}
+
+fn lossy(v: Option<&[u8]>) -> ~str {
+    v.map_or(~"", |v| str::from_utf8_lossy(v).into_owned())
+}
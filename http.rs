@@ -0,0 +1,189 @@
+/// A minimal HTTP client
+///
+/// Only plain http URLs are supported. Requests are made with HTTP/1.0, so the
+/// response is never chunked and ends when the server closes the connection.
+/// Redirects are followed a few times.
+
+use resolver;
+use std::ascii::StrAsciiExt;
+use std::io;
+use std::str;
+
+static TIMEOUT: u64 = 30000; // milliseconds to wait for the server to send anything
+static MAX_REDIRECTS: uint = 3;
+static MAX_RESPONSE: uint = 1024 * 1024; // bytes
+
+/// The parts of an http URL
+pub struct Url {
+    host: ~str,
+    port: u16,
+    path: ~str // includes the query, and always starts with /
+}
+
+impl Url {
+    pub fn parse(url: &str) -> Result<Url, ~str> {
+        if !url.starts_with("http://") {
+            return Err(format!("unsupported URL `{}': only http:// URLs are supported", url));
+        }
+        let rest = url.slice_from("http://".len());
+        let (authority, path) = match rest.find(|c: char| c == '/' || c == '?') {
+            None => (rest, ~"/"),
+            Some(i) if rest.char_at(i) == '?' => {
+                (rest.slice_to(i), format!("/{}", rest.slice_from(i)))
+            }
+            Some(i) => (rest.slice_to(i), rest.slice_from(i).to_owned())
+        };
+        let (host, port) = match authority.rfind(':') {
+            Some(i) if !authority.ends_with("]") => {
+                match from_str::<u16>(authority.slice_from(i+1)) {
+                    None => return Err(format!("invalid port in URL `{}'", url)),
+                    Some(p) => (authority.slice_to(i), p)
+                }
+            }
+            _ => (authority, 80)
+        };
+        let host = host.trim_left_chars(&'[').trim_right_chars(&']');
+        if host.is_empty() {
+            return Err(format!("missing host in URL `{}'", url));
+        }
+        Ok(Url { host: host.to_owned(), port: port, path: path })
+    }
+}
+
+/// Fetches the URL, returning the body of a successful response
+pub fn get(url: &str) -> Result<~[u8], ~str> {
+    request("GET", url, None)
+}
+
+/// Posts the body with the given content type, returning the body of a successful response
+pub fn post(url: &str, content_type: &str, body: &[u8]) -> Result<~[u8], ~str> {
+    request("POST", url, Some((content_type, body)))
+}
+
+/// Encodes the value for an application/x-www-form-urlencoded body
+pub fn form_encode(value: &[u8]) -> ~str {
+    let mut out = str::with_capacity(value.len());
+    for &b in value.iter() {
+        let c = b as char;
+        if (b < 0x80 && c.is_alphanumeric()) || c == '-' || c == '_' || c == '.' || c == '~' {
+            out.push_char(c);
+        } else if c == ' ' {
+            out.push_char('+');
+        } else {
+            out.push_str(format!("%{:02X}", b));
+        }
+    }
+    out
+}
+
+/// Makes a request with an optional body and its content type, following redirects
+fn request(method: &str, url: &str, body: Option<(&str, &[u8])>) -> Result<~[u8], ~str> {
+    let mut url = url.to_owned();
+    let (mut method, mut body) = (method, body);
+    for _ in range(0, MAX_REDIRECTS + 1) {
+        let parsed = match Url::parse(url) {
+            Ok(u) => u,
+            Err(e) => return Err(e)
+        };
+        let (status, headers, content) = match request_once(method, &parsed, body) {
+            Ok(r) => r,
+            Err(e) => return Err(e)
+        };
+        match status {
+            200 .. 299 => return Ok(content),
+            301 | 302 | 303 | 307 | 308 => {
+                if status == 303 {
+                    // see other: fetch the result instead of repeating the request
+                    method = "GET";
+                    body = None;
+                }
+                match header(headers, "location") {
+                    None => return Err(format!("redirect from {} without a location", url)),
+                    Some(loc) if loc.starts_with("/") => {
+                        let port = if parsed.port == 80 {
+                            ~""
+                        } else {
+                            format!(":{}", parsed.port)
+                        };
+                        url = format!("http://{}{}{}", parsed.host, port, loc);
+                    }
+                    Some(loc) => url = loc.to_owned()
+                }
+            }
+            _ => return Err(format!("{} returned status {}", url, status))
+        }
+    }
+    Err(format!("too many redirects fetching {}", url))
+}
+
+/// Makes a single request, returning the status, headers and body
+fn request_once(method: &str, url: &Url, body: Option<(&str, &[u8])>)
+                -> Result<(uint, ~[(~str, ~str)], ~[u8]), ~str> {
+    let mut stream = match resolver::connect(url.host, url.port) {
+        Ok(s) => s,
+        Err(e) => return Err(e)
+    };
+    stream.set_read_timeout(Some(TIMEOUT));
+
+    let mut req = format!("{} {} HTTP/1.0\r\nHost: {}\r\nUser-Agent: rustirc\r\n\
+                           Connection: close\r\n", method, url.path, url.host);
+    match body {
+        None => (),
+        Some((content_type, data)) => {
+            req.push_str(format!("Content-Type: {}\r\nContent-Length: {}\r\n",
+                                 content_type, data.len()));
+        }
+    }
+    req.push_str("\r\n");
+    let mut res = stream.write(req.as_bytes());
+    match body {
+        Some((_, data)) if res.is_ok() => res = stream.write(data),
+        _ => ()
+    }
+    match res {
+        Ok(()) => (),
+        Err(e) => return Err(e.to_str())
+    }
+
+    let mut resp = ~[];
+    let mut buf = [0u8, ..4096];
+    loop {
+        match stream.read(buf) {
+            Ok(n) => {
+                resp.push_all(buf.slice_to(n));
+                if resp.len() > MAX_RESPONSE {
+                    return Err(format!("response from {} is too large", url.host));
+                }
+            }
+            Err(io::IoError { kind: io::EndOfFile, .. }) => break,
+            Err(e) => return Err(e.to_str())
+        }
+    }
+    parse_response(resp)
+}
+
+fn parse_response(resp: &[u8]) -> Result<(uint, ~[(~str, ~str)], ~[u8]), ~str> {
+    let end = range(0, resp.len()).find(|&i| resp.slice_from(i).starts_with(bytes!("\r\n\r\n")));
+    let end = match end {
+        None => return Err(~"malformed response"),
+        Some(i) => i
+    };
+    let head = str::from_utf8_lossy(resp.slice_to(end)).into_owned();
+    let mut lines = head.lines_any();
+    let status = lines.next().and_then(|line| line.words().nth(1)).and_then(from_str::<uint>);
+    let status = match status {
+        None => return Err(~"malformed status line"),
+        Some(s) => s
+    };
+    let headers = lines.filter_map(|line| {
+        line.find(':').map(|i| {
+            (line.slice_to(i).trim().to_ascii_lower(), line.slice_from(i+1).trim().to_owned())
+        })
+    }).collect();
+    Ok((status, headers, resp.slice_from(end + 4).to_owned()))
+}
+
+/// Returns the value of the header with the given lowercase name
+fn header<'a>(headers: &'a [(~str, ~str)], name: &str) -> Option<&'a str> {
+    headers.iter().find(|&&(ref n, _)| n.as_slice() == name).map(|&(_, ref v)| v.as_slice())
+}
@@ -0,0 +1,160 @@
+/// Routing of invites and knocks to admins
+///
+/// Instead of disappearing, each INVITE we receive and each KNOCK on a channel we
+/// operate is recorded as a numbered pending request, printed on the console and
+/// announced to the server's invite_notify target. A request is accepted or denied on
+/// the console with /accept and /deny, or by messaging the bot "accept N" or "deny N"
+/// from a hostmask in the server's admins list. Accepting an invite joins the channel,
+/// and accepting a knock invites the user who knocked.
+
+use casemap::CaseMapping;
+use config;
+use outbound;
+use plugins::mask;
+use State;
+use irc::conn::{Conn, Line, IRCCmd, IRCCode};
+use std::str;
+
+static MAX_PENDING: uint = 20; // the oldest request is dropped beyond this
+static RPL_KNOCK: uint = 710;
+
+pub struct Invites {
+    priv notify: Option<~str>, // channel or nick to announce requests to
+    priv admins: ~[~str], // hostmasks allowed to accept or deny requests
+    priv pending: ~[Request],
+    priv next_id: uint
+}
+
+struct Request {
+    id: uint,
+    kind: Kind,
+    channel: ~str,
+    from: ~str // the raw prefix of the inviting or knocking user
+}
+
+#[deriving(Eq)]
+enum Kind {
+    Invite,
+    Knock
+}
+
+impl Invites {
+    pub fn new(server: &config::Server) -> Invites {
+        Invites {
+            notify: server.invite_notify.clone(),
+            admins: server.admins.clone(),
+            pending: ~[],
+            next_id: 1
+        }
+    }
+
+    fn add(&mut self, casemap: &CaseMapping, kind: Kind, channel: ~str, from: ~str) -> uint {
+        // a repeated request replaces the earlier one
+        self.pending.retain(|r| {
+            !(r.kind == kind && casemap.eq(r.channel.as_bytes(), channel.as_bytes()) &&
+              r.from == from)
+        });
+        if self.pending.len() == MAX_PENDING {
+            self.pending.shift();
+        }
+        let id = self.next_id;
+        self.next_id += 1;
+        self.pending.push(Request { id: id, kind: kind, channel: channel, from: from });
+        id
+    }
+
+    fn take(&mut self, id: uint) -> Option<Request> {
+        self.pending.iter().position(|r| r.id == id).map(|idx| self.pending.remove(idx).unwrap())
+    }
+
+    /// Returns whether the raw prefix matches one of the server's admins hostmasks
+    pub fn is_admin(&self, prefix: &[u8]) -> bool {
+        self.admins.iter().any(|m| mask::matches(m.as_bytes(), prefix))
+    }
+}
+
+/// Records invites and knocks, and handles accept/deny messages from admins
+pub fn line_dispatched(conn: &mut Conn, state: &mut State, line: &Line) {
+    let Line{ref command, ref args, ref prefix} = *line;
+    let from = match *prefix {
+        None => return,
+        Some(ref user) => user
+    };
+    match *command {
+        IRCCmd(ref cmd) if cmd.as_slice() == "INVITE" && args.len() >= 2 => {
+            let chan = str::from_utf8_lossy(args[1]).into_owned();
+            let raw = str::from_utf8_lossy(from.raw()).into_owned();
+            let msg = format!("{} invited me to {}", raw, chan);
+            let id = state.invites.add(&state.isupport.casemapping(), Invite, chan, raw);
+            announce(conn, state, id, msg);
+        }
+        IRCCode(code) if code == RPL_KNOCK && args.len() >= 3 => {
+            let chan = str::from_utf8_lossy(args[1]).into_owned();
+            let raw = str::from_utf8_lossy(args[2]).into_owned();
+            let msg = format!("{} knocked on {}", raw, chan);
+            let id = state.invites.add(&state.isupport.casemapping(), Knock, chan, raw);
+            announce(conn, state, id, msg);
+        }
+        IRCCmd(ref cmd) if cmd.as_slice() == "PRIVMSG" && args.len() >= 2 => {
+            let to_me = state.isupport.casemapping().eq(args[0].as_slice(), conn.me().nick());
+            if !to_me || !state.invites.is_admin(from.raw()) {
+                return;
+            }
+            let text = str::from_utf8_lossy(args[1]);
+            let mut words = text.as_slice().words();
+            let (accept, id) = match (words.next(), words.next().and_then(from_str::<uint>)) {
+                (Some("accept"), Some(id)) => (true, id),
+                (Some("deny"), Some(id)) => (false, id),
+                _ => return
+            };
+            let nick = from.nick().to_owned();
+            let reply = resolve(conn, state, id, accept);
+            state.out.notice(conn, outbound::Admin, nick.as_slice(), reply.as_bytes());
+        }
+        _ => ()
+    }
+}
+
+/// Prints the pending requests
+pub fn list(state: &State) {
+    if state.invites.pending.is_empty() {
+        println!("No pending invites or knocks");
+    }
+    for r in state.invites.pending.iter() {
+        let what = match r.kind { Invite => "invite to", Knock => "knock on" };
+        println!("[{}] {} {} from {}", r.id, what, r.channel, r.from);
+    }
+}
+
+/// Accepts or denies the pending request with the given id, returning a description
+/// of the outcome
+pub fn resolve(conn: &mut Conn, state: &mut State, id: uint, accept: bool) -> ~str {
+    let req = match state.invites.take(id) {
+        None => return format!("No pending request {}", id),
+        Some(req) => req
+    };
+    if !accept {
+        return format!("Denied request {} from {}", id, req.from);
+    }
+    match req.kind {
+        Invite => {
+            state.out.join(conn, outbound::Admin, req.channel.as_bytes(), []);
+            format!("Joining {}", req.channel)
+        }
+        Knock => {
+            let nick = req.from.as_slice().split('!').next().unwrap();
+            let line = format!("INVITE {} {}", nick, req.channel);
+            state.out.send_raw(conn, outbound::Admin, line.as_bytes());
+            format!("Invited {} to {}", nick, req.channel)
+        }
+    }
+}
+
+fn announce(conn: &mut Conn, state: &mut State, id: uint, msg: ~str) {
+    let msg = format!("[{}] {}; reply \"accept {}\" or \"deny {}\"", id, msg, id, id);
+    println!("{}", msg);
+    match state.invites.notify.clone() {
+        None => (),
+        Some(dst) => state.out.notice(conn, outbound::Admin, dst.as_bytes(), msg.as_bytes())
+    }
+}
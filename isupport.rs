@@ -0,0 +1,176 @@
+/// Server features from RPL_ISUPPORT
+///
+/// Once registered, the server sends RPL_ISUPPORT (005) lines of NAME=value tokens that
+/// describe what it supports, e.g. CASEMAPPING=rfc1459, PREFIX=(ov)@+, CHANTYPES=#&,
+/// MODES=4, NICKLEN=16 or TARGMAX=PRIVMSG:4,JOIN:. A -NAME token withdraws one sent
+/// earlier. Until the server says otherwise, the RFC 1459 defaults apply.
+
+use casemap;
+use casemap::CaseMapping;
+use irc::conn::{Line, IRCCode};
+use std::{num, str};
+use std::ascii::StrAsciiExt;
+
+static RPL_ISUPPORT: uint = 5;
+
+#[deriving(Clone)]
+pub struct ISupport {
+    priv tokens: ~[(~str, ~str)] // name and value of each token, "" if it has none
+}
+
+impl ISupport {
+    pub fn new() -> ISupport {
+        ISupport { tokens: ~[] }
+    }
+
+    /// Forgets the server's tokens, e.g. on a new connection
+    pub fn clear(&mut self) {
+        self.tokens.clear();
+    }
+
+    /// Takes the tokens from an RPL_ISUPPORT line, returning whether it was one
+    pub fn line_received(&mut self, line: &Line) -> bool {
+        match line.command {
+            IRCCode(code) if code == RPL_ISUPPORT && line.args.len() > 2 => (),
+            _ => return false
+        }
+        // the first argument is our nick, and the last is "are supported by this server"
+        for token in line.args.slice(1, line.args.len() - 1).iter() {
+            let token = str::from_utf8_lossy(*token).into_owned();
+            if token.starts_with("-") {
+                let name = token.slice_from(1);
+                self.tokens.retain(|&(ref n, _)| n.as_slice() != name);
+                continue;
+            }
+            let (name, value) = match token.find('=') {
+                None => (token.as_slice(), ~""),
+                Some(i) => (token.slice_to(i), unescape(token.slice_from(i + 1)))
+            };
+            if name.is_empty() {
+                continue;
+            }
+            match self.tokens.iter().position(|&(ref n, _)| n.as_slice() == name) {
+                Some(i) => self.tokens[i] = (name.to_owned(), value),
+                None => self.tokens.push((name.to_owned(), value))
+            }
+        }
+        true
+    }
+
+    /// Returns the name and value of every token the server sent, in order
+    pub fn tokens<'a>(&'a self) -> &'a [(~str, ~str)] {
+        self.tokens.as_slice()
+    }
+
+    /// Returns the token's value, "" if it has none, or None if the server didn't send it
+    pub fn get<'a>(&'a self, name: &str) -> Option<&'a str> {
+        self.tokens.iter().find(|&&(ref n, _)| n.as_slice() == name).map(|&(_, ref v)| {
+            v.as_slice()
+        })
+    }
+
+    pub fn casemapping(&self) -> CaseMapping {
+        self.get("CASEMAPPING").and_then(CaseMapping::from_name).unwrap_or(casemap::Rfc1459)
+    }
+
+    /// Returns the mode and prefix of each channel member status, highest first
+    pub fn prefixes(&self) -> ~[(u8, u8)] {
+        // e.g. PREFIX=(ov)@+
+        let value = match self.get("PREFIX") {
+            None => return ~[('o' as u8, '@' as u8), ('v' as u8, '+' as u8)],
+            Some(v) => v.as_bytes()
+        };
+        match value.iter().position(|&b| b == ')' as u8) {
+            Some(close) if value.head() == Some(&('(' as u8)) && value.len() == 2 * close => {
+                let (modes, symbols) = (value.slice(1, close), value.slice_from(close + 1));
+                modes.iter().zip(symbols.iter()).map(|(&m, &s)| (m, s)).collect()
+            }
+            _ => ~[]
+        }
+    }
+
+    /// Returns the channel modes that always take a parameter, and those that only take
+    /// one when they're set
+    pub fn param_modes(&self) -> (~[u8], ~[u8]) {
+        // e.g. CHANMODES=beI,k,l,imnpst: lists, always, only when set, never
+        let types: ~[&[u8]] = match self.get("CHANMODES") {
+            None => ~[],
+            Some(v) => v.as_bytes().split(|&b| b == ',' as u8).collect()
+        };
+        if types.len() >= 3 {
+            ([types[0], types[1]].concat_vec(), types[2].to_owned())
+        } else {
+            (bytes!("beIk").to_owned(), bytes!("l").to_owned())
+        }
+    }
+
+    /// Returns the characters channel names start with
+    pub fn chantypes<'a>(&'a self) -> &'a [u8] {
+        self.get("CHANTYPES").unwrap_or("#&").as_bytes()
+    }
+
+    /// Returns whether the name is a channel rather than a nick
+    pub fn is_channel(&self, name: &[u8]) -> bool {
+        match name.head() {
+            None => false,
+            Some(c) => self.chantypes().contains(c)
+        }
+    }
+
+    /// Returns how many modes with a parameter one MODE line may change, or None if
+    /// there's no limit
+    pub fn modes(&self) -> Option<uint> {
+        match self.get("MODES") {
+            None => Some(3),
+            Some("") => None,
+            Some(v) => Some(from_str::<uint>(v).unwrap_or(3))
+        }
+    }
+
+    /// Returns the longest nick the server accepts, if it said
+    pub fn nicklen(&self) -> Option<uint> {
+        self.get("NICKLEN").and_then(from_str::<uint>)
+    }
+
+    /// Returns how many targets the command accepts at once, or None if there's no limit.
+    /// Commands that TARGMAX doesn't list take 1, except that JOIN and PART take any.
+    pub fn targmax(&self, cmd: &str) -> Option<uint> {
+        // e.g. TARGMAX=PRIVMSG:4,NOTICE:4,JOIN:
+        let listed = self.get("TARGMAX").and_then(|v| {
+            v.split(',').find(|entry| {
+                entry.splitn(':', 1).next().map_or(false, |c| c.eq_ignore_ascii_case(cmd))
+            })
+        });
+        match listed {
+            None if cmd.eq_ignore_ascii_case("JOIN") || cmd.eq_ignore_ascii_case("PART") => None,
+            None => Some(1),
+            Some(entry) => match entry.find(':') {
+                Some(i) if i + 1 < entry.len() => from_str::<uint>(entry.slice_from(i + 1)),
+                _ => None
+            }
+        }
+    }
+}
+
+/// Decodes the \xHH escapes in a token's value
+fn unescape(value: &str) -> ~str {
+    let bytes = value.as_bytes();
+    let mut out = ~[];
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == '\\' as u8 && i + 3 < bytes.len() && bytes[i + 1] == 'x' as u8 {
+            let hex = str::from_utf8(bytes.slice(i + 2, i + 4));
+            match hex.and_then(|h| num::from_str_radix::<u8>(h, 16)) {
+                Some(b) => {
+                    out.push(b);
+                    i += 4;
+                    continue;
+                }
+                None => ()
+            }
+        }
+        out.push(bytes[i]);
+        i += 1;
+    }
+    str::from_utf8_lossy(out).into_owned()
+}
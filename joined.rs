@@ -0,0 +1,68 @@
+/// Channels to rejoin after reconnecting
+///
+/// Besides the autojoin channels, the bot remembers the channels it joins while it's
+/// connected, e.g. from an accepted invite, a plugin or the console, and joins them
+/// again on the next connection to the server, so its channels survive a netsplit or
+/// a dropped connection. A channel it leaves by PART or KICK is forgotten.
+
+use casemap;
+use config;
+use State;
+use irc::conn::{Conn, Line, IRCCmd};
+use std::str;
+use sync::MutexArc;
+
+/// The remembered channels, shared by the server's connections
+#[deriving(Clone)]
+pub struct Joined {
+    priv channels: MutexArc<~[~str]>
+}
+
+impl Joined {
+    pub fn new() -> Joined {
+        Joined { channels: MutexArc::new(~[]) }
+    }
+
+    /// Returns the server's autojoin channels, followed by the remembered channels that
+    /// aren't among them
+    pub fn autojoin(&self, server: &config::Server) -> ~[config::Channel] {
+        let mut channels = server.autojoin.clone();
+        // the server's CASEMAPPING isn't known before connecting
+        let casemap = casemap::Rfc1459;
+        self.channels.access(|joined| {
+            for name in joined.iter() {
+                if !channels.iter().any(|c| casemap.eq(c.name.as_bytes(), name.as_bytes())) {
+                    channels.push(config::Channel { name: name.clone(), password: None });
+                }
+            }
+        });
+        channels
+    }
+}
+
+/// Remembers the channels the bot joins, and forgets those it leaves
+pub fn line_dispatched(conn: &mut Conn, state: &mut State, line: &Line) {
+    let casemap = state.isupport.casemapping();
+    let me = conn.me().nick().to_owned();
+    let from_me = line.prefix.as_ref().map_or(false, |u| casemap.eq(u.nick(), me.as_slice()));
+    let (chan, joined) = match line.command {
+        IRCCmd(ref cmd) if cmd.as_slice() == "JOIN" && from_me && !line.args.is_empty() => {
+            (line.args[0].as_slice(), true)
+        }
+        IRCCmd(ref cmd) if cmd.as_slice() == "PART" && from_me && !line.args.is_empty() => {
+            (line.args[0].as_slice(), false)
+        }
+        IRCCmd(ref cmd) if cmd.as_slice() == "KICK" && line.args.len() >= 2 &&
+                           casemap.eq(line.args[1].as_slice(), me.as_slice()) => {
+            (line.args[0].as_slice(), false)
+        }
+        _ => return
+    };
+    let chan = str::from_utf8_lossy(chan).into_owned();
+    state.joined.channels.access(|channels| {
+        channels.retain(|c| !casemap.eq(c.as_bytes(), chan.as_bytes()));
+        if joined {
+            channels.push(chan.clone());
+        }
+    });
+}
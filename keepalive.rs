@@ -0,0 +1,89 @@
+/// Dead connection detection and lag measurement
+///
+/// A TCP connection can die without either side noticing, e.g. when a NAT drops it, and
+/// the kernel may take hours to give up. When nothing has arrived from the server for
+/// ping_interval seconds, the bot sends a PING, and if no PONG comes back within
+/// ping_timeout seconds it reconnects. Both are set in [general], and can be overridden
+/// for each server, e.g. one behind a NAT that drops idle connections sooner than
+/// usual. The PONG's round trip time is the connection's lag, shown by /status and
+/// given to plugins by irc.lag(), so the lag is measured when the link is quiet.
+
+use State;
+use irc::conn::{Conn, Line, IRCCmd};
+use std::rand;
+use time;
+
+pub static CHECK_INTERVAL: u64 = 5000; // milliseconds between checks
+
+pub struct Keepalive {
+    priv interval: Option<u64>, // ns of silence before we PING, None if we never do
+    priv timeout: u64, // ns to wait for the PONG
+    priv last_line: u64, // precise_time_ns() when we last heard from the server
+    priv ping: Option<(~str, u64)>, // token and send time of the PING we're waiting on
+    priv lag: Option<u64> // ns the last PING took to be answered
+}
+
+impl Keepalive {
+    pub fn new(interval: Option<uint>, timeout: uint) -> Keepalive {
+        Keepalive {
+            interval: interval.map(|secs| secs as u64 * 1000000000),
+            timeout: timeout as u64 * 1000000000,
+            last_line: time::precise_time_ns(),
+            ping: None,
+            lag: None
+        }
+    }
+
+    /// Starts over for a new connection
+    pub fn reset(&mut self) {
+        self.last_line = time::precise_time_ns();
+        self.ping = None;
+        self.lag = None;
+    }
+
+    /// Returns the round trip time of the last PING in ms, if one was answered
+    pub fn lag(&self) -> Option<u64> {
+        self.lag.map(|ns| ns / 1000000)
+    }
+}
+
+/// PINGs the server if it's been quiet, and reconnects if a PING went unanswered
+pub fn check(conn: &mut Conn, state: &mut State) {
+    let interval = match state.keepalive.interval {
+        Some(interval) if state.logged_in => interval,
+        _ => return
+    };
+    let now = time::precise_time_ns();
+    match state.keepalive.ping {
+        Some((_, sent)) => {
+            if now - sent >= state.keepalive.timeout {
+                state.keepalive.ping = None;
+                let secs = state.keepalive.timeout / 1000000000;
+                let reason = format!("no PONG within {} seconds", secs);
+                ::reconnect(conn, state, reason.as_slice());
+            }
+        }
+        None if now - state.keepalive.last_line >= interval => {
+            let token = format!("keepalive-{:08x}", rand::random::<u32>());
+            let line = format!("PING :{}", token);
+            state.out.send_protocol(conn, line.as_bytes());
+            state.keepalive.ping = Some((token, now));
+        }
+        None => ()
+    }
+}
+
+/// Notes that the server is alive, and clears the PING when its PONG arrives, measuring
+/// the lag
+pub fn line_received(state: &mut State, line: &Line) {
+    state.keepalive.last_line = time::precise_time_ns();
+    let sent = match (&state.keepalive.ping, &line.command) {
+        (&Some((ref token, sent)), &IRCCmd(ref cmd)) if cmd.as_slice() == "PONG" &&
+            line.args.iter().any(|arg| arg.as_slice() == token.as_bytes()) => sent,
+        _ => return
+    };
+    let lag = time::precise_time_ns() - sent;
+    state.keepalive.ping = None;
+    state.keepalive.lag = Some(lag);
+    state.plugins.set_lag(Some(lag as f64 / 1e9));
+}
@@ -0,0 +1,77 @@
+/// Plugin management from IRC
+///
+/// Admins (the server's admins hostmasks) can manage plugins by sending the bot
+/// "plugins list", "plugins load NAME", "plugins unload NAME" or "plugins reload [NAME]",
+/// in a private message or in a channel after the command prefix, e.g. "!plugins list".
+/// Replies are sent as notices to the admin. Reloading without a name reloads every
+/// plugin, as /reload does.
+
+use outbound;
+use State;
+use irc::conn::{Conn, Line, IRCCmd};
+use std::str;
+
+/// Handles plugin management messages from admins
+pub fn line_dispatched(conn: &mut Conn, state: &mut State, line: &Line) {
+    let Line{ref command, ref args, ref prefix} = *line;
+    let from = match *prefix {
+        None => return,
+        Some(ref user) => user
+    };
+    match *command {
+        IRCCmd(ref cmd) if cmd.as_slice() == "PRIVMSG" && args.len() >= 2 => (),
+        _ => return
+    }
+    let private = state.isupport.casemapping().eq(args[0].as_slice(), conn.me().nick());
+    let text = str::from_utf8_lossy(args[1]).into_owned();
+    let text = if text.starts_with(state.command_prefix) {
+        text.slice_from(state.command_prefix.len())
+    } else if private {
+        text.as_slice()
+    } else {
+        return;
+    };
+    let mut words = text.words();
+    if words.next() != Some("plugins") || !state.invites.is_admin(from.raw()) {
+        return;
+    }
+
+    let replies = match (words.next(), words.next()) {
+        (Some("list"), None) => ~[list(state)],
+        (Some("load"), Some(name)) => {
+            match state.plugins.load_plugin(conn, &mut state.out, name) {
+                Ok(()) => ~[format!("Loaded plugin {}", name)],
+                Err(e) => ~[format!("Error: {}", e)]
+            }
+        }
+        (Some("unload"), Some(name)) => {
+            let count = state.plugins.unload_plugin(name);
+            ~[format!("Unloaded plugin {} ({} handlers removed)", name, count)]
+        }
+        (Some("reload"), None) => {
+            println!("Reloading plugins for {}...", str::from_utf8_lossy(from.raw()));
+            state.plugins.reload_plugins(conn, &mut state.out);
+            ~[~"Reloaded plugins", list(state)]
+        }
+        (Some("reload"), Some(name)) => {
+            match state.plugins.load_plugin(conn, &mut state.out, name) {
+                Ok(()) => ~[format!("Reloaded plugin {}", name)],
+                Err(e) => ~[format!("Error: {}", e)]
+            }
+        }
+        _ => ~[~"Usage: plugins list | load NAME | unload NAME | reload [NAME]"]
+    };
+    let nick = from.nick().to_owned();
+    for reply in replies.iter() {
+        state.out.notice(conn, outbound::Admin, nick.as_slice(), reply.as_bytes());
+    }
+}
+
+fn list(state: &mut State) -> ~str {
+    let names = state.plugins.plugin_names();
+    if names.is_empty() {
+        ~"No plugins are loaded"
+    } else {
+        format!("Plugins: {}", names.connect(", "))
+    }
+}
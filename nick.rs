@@ -0,0 +1,119 @@
+/// Nickname management
+///
+/// If the configured nick is taken or refused while registering (ERR_NICKNAMEINUSE,
+/// ERR_NICKCOLLISION or ERR_ERRONEUSNICKNAME), the server's altnicks are tried in order,
+/// and then the configured nick with a number in place of its end, cut to the server's
+/// NICKLEN. After MAX_GENERATED_NICKS of those the connection is given up on and
+/// retried. Once registered on an alternate,
+/// plugins get irc.ALTNICK, and the nick is regained every general.nick_regain seconds,
+/// through NickServ if nickserv_regain is set, and as soon as whoever holds it quits or
+/// changes nick.
+
+use nickserv;
+use State;
+use irc::conn::{Conn, Line, IRCCmd, IRCCode};
+use std::{cmp, str};
+
+static ERR_ERRONEUSNICKNAME: uint = 432;
+static ERR_NICKNAMEINUSE: uint = 433;
+static ERR_NICKCOLLISION: uint = 436;
+static MAX_GENERATED_NICKS: uint = 10; // numbered nicks to try once the altnicks are used up
+static DEFAULT_NICKLEN: uint = 9; // RFC 1459's, since ISUPPORT only comes after registering
+
+/// Attempts to switch back to the configured nick if we're running on an alternate.
+/// Does nothing if we already have the configured nick, or haven't logged in yet.
+pub fn regain(conn: &mut Conn, state: &mut State) {
+    if !state.logged_in || conn.me().nick() == state.nick.as_bytes() {
+        return;
+    }
+    debug!("Attempting to regain nick {}", state.nick);
+    if nickserv::regain(conn, state) {
+        // REGAIN gives us the nick, and after a GHOST we take it once NickServ answers
+        return;
+    }
+    state.out.set_nick(conn, state.nick.as_bytes());
+}
+
+/// Tries the next alternate nick if ours was taken while registering, and takes the
+/// configured nick once it's free
+pub fn line_received(conn: &mut Conn, state: &mut State, line: &Line) {
+    if state.logged_in {
+        if nick_freed(conn, state, line) {
+            debug!("Nick {} is free", state.nick);
+            state.out.set_nick(conn, state.nick.as_bytes());
+        }
+        return;
+    }
+    let problem = match line.command {
+        IRCCode(code) if code == ERR_NICKNAMEINUSE || code == ERR_NICKCOLLISION => "in use",
+        IRCCode(code) if code == ERR_ERRONEUSNICKNAME => "refused",
+        _ => return
+    };
+    let taken = match line.args.get(1) {
+        Some(nick) => str::from_utf8_lossy(*nick).into_owned(),
+        None => state.nick.clone()
+    };
+    let attempt = state.nick_attempts;
+    state.nick_attempts += 1;
+    let next = match state.altnicks.get(attempt) {
+        Some(nick) => nick.clone(),
+        None if attempt - state.altnicks.len() < MAX_GENERATED_NICKS => {
+            let nicklen = state.isupport.nicklen().unwrap_or(DEFAULT_NICKLEN);
+            numbered(state.nick.as_slice(), attempt - state.altnicks.len() + 1, nicklen)
+        }
+        None => {
+            println!("Nick {} is {}, and there are no more nicks to try", taken, problem);
+            return ::drop_connection(state, "no nick was accepted");
+        }
+    };
+    println!("Nick {} is {}, trying {}", taken, problem, next);
+    state.out.set_nick(conn, next.as_bytes());
+}
+
+/// Returns the nick with the number in place of as much of its end as it takes to fit
+/// in nicklen
+fn numbered(nick: &str, n: uint, nicklen: uint) -> ~str {
+    let digits = n.to_str();
+    let room = if nicklen > digits.len() { nicklen - digits.len() } else { 0 };
+    let mut keep = cmp::min(nick.len(), room);
+    while !nick.is_char_boundary(keep) {
+        keep -= 1;
+    }
+    format!("{}{}", nick.slice_to(keep), digits)
+}
+
+/// Returns whether the line shows that whoever held the configured nick while we're on
+/// an alternate quit or changed nick
+fn nick_freed(conn: &mut Conn, state: &mut State, line: &Line) -> bool {
+    let casemap = state.isupport.casemapping();
+    let nick = state.nick.as_bytes();
+    if casemap.eq(conn.me().nick(), nick) {
+        return false;
+    }
+    let holder = match line.prefix {
+        None => return false,
+        Some(ref user) => casemap.eq(user.nick(), nick)
+    };
+    if !holder {
+        return false;
+    }
+    match line.command {
+        IRCCmd(ref cmd) if cmd.as_slice() == "QUIT" => true,
+        IRCCmd(ref cmd) if cmd.as_slice() == "NICK" => {
+            // a change to the same nick in another case keeps it
+            line.args.head().map_or(false, |new| !casemap.eq(new.as_slice(), nick))
+        }
+        _ => false
+    }
+}
+
+/// Tells plugins if we registered with an alternate nick
+pub fn logged_in(conn: &mut Conn, state: &mut State) {
+    let nick = conn.me().nick().to_owned();
+    if nick.as_slice() == state.nick.as_bytes() {
+        return;
+    }
+    println!("Registered as {} since {} is in use", str::from_utf8_lossy(nick), state.nick);
+    state.plugins.dispatch_alt_nick(conn, &mut state.out, nick.as_slice(),
+                                    state.nick.as_bytes());
+}
@@ -0,0 +1,129 @@
+/// NickServ identification
+///
+/// Identify is the auth backend that identifies the bot by messaging "IDENTIFY password"
+/// to the nickserv_service with the nickserv_password once it's registered. A notice
+/// from the service matching nickserv_confirm confirms it. With nickserv_delay_autojoin,
+/// the autojoin channels are only joined once identification is confirmed, or once it
+/// times out, so the bot doesn't join channels that need a registered nick before it's
+/// identified or before its cloak is applied.
+///
+/// With nickserv_regain, the bot regains its nick on an alternate by messaging
+/// "GHOST nick password" or "REGAIN nick password" to the service. REGAIN changes the
+/// bot's nick itself, but GHOST only disconnects whoever holds it, so after a GHOST the
+/// bot takes the nick as soon as the service answers.
+
+use auth;
+use auth::AuthBackend;
+use cap::Caps;
+use casemap::CaseMapping;
+use config;
+use outbound;
+use outbound::Outbound;
+use plugins::mask;
+use State;
+use irc::conn::{Conn, Line, IRCCmd};
+use std::str;
+
+pub struct NickServ {
+    priv service: ~str,
+    priv password: Option<~str>,
+    priv regain: Option<~str>, // GHOST or REGAIN
+    priv ghosted: bool // sent GHOST, waiting for the service's answer
+}
+
+impl NickServ {
+    pub fn new(server: &config::Server) -> NickServ {
+        NickServ {
+            service: server.nickserv_service.clone(),
+            password: server.nickserv_password.clone(),
+            regain: server.nickserv_regain.clone(),
+            ghosted: false
+        }
+    }
+}
+
+/// Identifies to the service once the bot is registered
+pub struct Identify {
+    priv service: ~str,
+    priv password: ~str,
+    priv confirm: ~str // glob for the notice text that confirms identification
+}
+
+impl Identify {
+    pub fn new(server: &config::Server, password: &str) -> Identify {
+        Identify {
+            service: server.nickserv_service.clone(),
+            password: password.to_owned(),
+            confirm: server.nickserv_confirm.clone()
+        }
+    }
+}
+
+impl AuthBackend for Identify {
+    fn name(&self) -> &'static str {
+        "nickserv"
+    }
+
+    fn stage(&self) -> auth::Stage {
+        auth::Registered
+    }
+
+    fn start(&mut self, conn: &mut Conn, out: &mut Outbound, _caps: &Caps) -> bool {
+        println!("Identifying to {}", self.service);
+        let msg = format!("IDENTIFY {}", self.password);
+        out.privmsg_secret(conn, outbound::Bot, self.service.as_bytes(), msg.as_bytes());
+        true
+    }
+
+    fn line_received(&mut self, _conn: &mut Conn, _out: &mut Outbound, casemapping: &CaseMapping,
+                     line: &Line) -> auth::Outcome {
+        if !from_service(casemapping, self.service.as_slice(), line) ||
+           !mask::glob(self.confirm.as_bytes(), line.args[1].as_slice()) {
+            return auth::Pending;
+        }
+        println!("Identified to {}: {}", self.service,
+                 str::from_utf8_lossy(line.args[1].as_slice()));
+        auth::Succeeded
+    }
+}
+
+/// Watches for the service's answer to a GHOST
+pub fn line_received(conn: &mut Conn, state: &mut State, line: &Line) {
+    if !state.nickserv.ghosted {
+        return;
+    }
+    let casemapping = state.isupport.casemapping();
+    if from_service(&casemapping, state.nickserv.service.as_slice(), line) {
+        // we won't necessarily see the holder quit, so take the nick now
+        state.nickserv.ghosted = false;
+        state.out.set_nick(conn, state.nick.as_bytes());
+    }
+}
+
+/// Asks the service to free or give us the configured nick, returning whether it's
+/// configured to
+pub fn regain(conn: &mut Conn, state: &mut State) -> bool {
+    let msg = match (&state.nickserv.regain, &state.nickserv.password) {
+        (&Some(ref command), &Some(ref password)) => {
+            format!("{} {} {}", *command, state.nick, *password)
+        }
+        _ => return false
+    };
+    state.nickserv.ghosted = msg.starts_with("GHOST ");
+    debug!("Asking {} to regain nick {}", state.nickserv.service, state.nick);
+    let service = state.nickserv.service.as_bytes();
+    state.out.privmsg_secret(conn, outbound::Bot, service, msg.as_bytes());
+    true
+}
+
+// Returns whether the line is a notice from the service
+fn from_service(casemapping: &CaseMapping, service: &str, line: &Line) -> bool {
+    match line.command {
+        IRCCmd(ref cmd) if cmd.as_slice() == "NOTICE" && line.args.len() >= 2 => (),
+        _ => return false
+    }
+    match line.prefix {
+        None => false,
+        Some(ref user) => casemapping.eq(user.nick(), service.as_bytes())
+    }
+}
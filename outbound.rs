@@ -0,0 +1,440 @@
+/// Outgoing messages
+///
+/// Every line the bot sends goes through here instead of directly through the Conn, so
+/// policy can be applied in one place. This is also where they're encoded for the
+/// server, and where the audit log records them as they're actually sent, which for a
+/// line flood protection queued is when it leaves the queue. The registration and
+/// keepalive lines the connection needs are sent with send_protocol, which skips the
+/// policy, but not the audit log.
+
+use casemap;
+use casemap::CaseMapping;
+use config;
+use audit::AuditLog;
+use encoding::Codec;
+use flood::Flood;
+use tags;
+use irc::conn::Conn;
+use collections::HashMap;
+use std::ascii::StrAsciiExt;
+use std::{fmt, str};
+use time;
+
+static QUOTA_WINDOW: u64 = 60 * 1000000000; // plugin quotas are per minute, in ns
+
+/// Where an outgoing message came from
+#[deriving(Clone)]
+pub enum Origin {
+    Console,
+    Greeting,
+    Admin, // replies and announcements for admins
+    Help, // replies to the built-in help command
+    Ctcp, // built-in CTCP replies
+    Bot, // the bot's own upkeep, e.g. registration, services and bouncer playback
+    Plugin(~str)
+}
+
+impl fmt::Show for Origin {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Console => write!(f.buf, "console"),
+            Greeting => write!(f.buf, "greeting"),
+            Admin => write!(f.buf, "admin"),
+            Help => write!(f.buf, "help"),
+            Ctcp => write!(f.buf, "ctcp"),
+            Bot => write!(f.buf, "bot"),
+            Plugin(ref name) => write!(f.buf, "plugin:{}", name)
+        }
+    }
+}
+
+pub struct Outbound {
+    priv dry_run: bool, // log messages instead of sending them
+    priv read_only: bool, // refuse all messages
+    priv read_only_channels: ~[~str], // channels to refuse messages to
+    priv audit: Option<AuditLog>,
+    priv quota: Option<uint>, // messages each plugin may send per minute
+    priv quota_disable: bool, // stop plugins from sending entirely once they exceed the quota
+    priv quotas: HashMap<~str, Quota>, // keyed by plugin name
+    priv tags: bool, // the server accepts client tags on our messages
+    priv codec: Codec, // encodes messages for the server
+    priv casemap: CaseMapping, // the server's, for matching channels and nicks
+    priv flood: Option<Flood<Queued>> // None if flood protection is off
+}
+
+/// A line waiting for flood protection, with what the audit log records once it's sent
+struct Queued {
+    line: ~[u8],
+    origin: Origin,
+    cmd: ~str,
+    dst: ~[u8],
+    msg: ~[u8]
+}
+
+// commands whose parameters the audit log leaves out, since they hold secrets
+static SECRET_COMMANDS: &'static [&'static str] = &["PASS", "AUTHENTICATE", "WEBIRC", "OPER"];
+
+/// Send accounting for one plugin
+struct Quota {
+    window_start: u64, // precise_time_ns() when the current window began
+    sent: uint, // messages sent in the current window
+    disabled: bool
+}
+
+impl Outbound {
+    pub fn new(conf: &config::Config, server: &config::Server) -> Outbound {
+        let audit = conf.audit_log.as_ref().and_then(|path| {
+            match AuditLog::open(path) {
+                Ok(log) => Some(log),
+                Err(e) => {
+                    println!("Warning: Could not open audit log `{}': {}", path.display(), e);
+                    None
+                }
+            }
+        });
+        Outbound {
+            dry_run: conf.dry_run,
+            read_only: conf.read_only,
+            read_only_channels: server.read_only_channels.clone(),
+            audit: audit,
+            quota: conf.plugin_quota,
+            quota_disable: conf.plugin_quota_disable,
+            quotas: HashMap::new(),
+            tags: false,
+            codec: server.codec.clone(),
+            casemap: casemap::Rfc1459,
+            flood: server.flood.map(|(burst, interval)| Flood::new(burst, interval))
+        }
+    }
+
+    /// Sends a PRIVMSG
+    pub fn privmsg(&mut self, conn: &mut Conn, origin: Origin, dst: &[u8], msg: &[u8]) {
+        if self.refuse(&origin, "PRIVMSG", dst) || self.over_quota(&origin) {
+            return;
+        }
+        let (dst, msg) = (self.codec.encode(dst), self.codec.encode(msg));
+        let (dst, msg) = (dst.as_slice(), msg.as_slice());
+        if self.dry_run {
+            log_dry_run("PRIVMSG", dst, msg);
+            return;
+        }
+        let line = message_line("PRIVMSG", dst, msg);
+        self.send(conn, origin, "PRIVMSG", dst, msg, line);
+    }
+
+    /// Sends a PRIVMSG that holds a secret, e.g. identifying to NickServ with a password.
+    /// The audit log and dry-run only show the message's first word.
+    pub fn privmsg_secret(&mut self, conn: &mut Conn, origin: Origin, dst: &[u8], msg: &[u8]) {
+        if self.refuse(&origin, "PRIVMSG", dst) || self.over_quota(&origin) {
+            return;
+        }
+        let (dst, msg) = (self.codec.encode(dst), self.codec.encode(msg));
+        let (dst, msg) = (dst.as_slice(), msg.as_slice());
+        let shown = msg.split(|&b| b == ' ' as u8).next().unwrap_or(msg);
+        if self.dry_run {
+            log_dry_run("PRIVMSG", dst, shown);
+            return;
+        }
+        let line = message_line("PRIVMSG", dst, msg);
+        self.send(conn, origin, "PRIVMSG", dst, shown, line);
+    }
+
+    /// Sends a PRIVMSG with message tags
+    /// The tags are left off if the server hasn't enabled message-tags.
+    pub fn privmsg_tagged(&mut self, conn: &mut Conn, origin: Origin, tags: &[(~str, ~str)],
+                          dst: &[u8], msg: &[u8]) {
+        if !self.tags || tags.is_empty() {
+            return self.privmsg(conn, origin, dst, msg);
+        }
+        if self.refuse(&origin, "PRIVMSG", dst) || self.over_quota(&origin) {
+            return;
+        }
+        let tags = tags::format(tags);
+        let (dst, msg) = (self.codec.encode(dst), self.codec.encode(msg));
+        let (dst, msg) = (dst.as_slice(), msg.as_slice());
+        if self.dry_run {
+            println!("[dry-run] {} PRIVMSG {} :{}", tags, str::from_utf8_lossy(dst),
+                     str::from_utf8_lossy(msg));
+            return;
+        }
+        let mut line = tags.into_bytes();
+        line.push(' ' as u8);
+        line.push_all(message_line("PRIVMSG", dst, msg));
+        self.send(conn, origin, "PRIVMSG", dst, msg, line);
+    }
+
+    /// Sends a NOTICE
+    pub fn notice(&mut self, conn: &mut Conn, origin: Origin, dst: &[u8], msg: &[u8]) {
+        if self.refuse(&origin, "NOTICE", dst) || self.over_quota(&origin) {
+            return;
+        }
+        let (dst, msg) = (self.codec.encode(dst), self.codec.encode(msg));
+        let (dst, msg) = (dst.as_slice(), msg.as_slice());
+        if self.dry_run {
+            log_dry_run("NOTICE", dst, msg);
+            return;
+        }
+        let line = message_line("NOTICE", dst, msg);
+        self.send(conn, origin, "NOTICE", dst, msg, line);
+    }
+
+    /// Sends a raw line
+    /// In dry-run mode this is logged as well, since it could be anything.
+    pub fn send_raw(&mut self, conn: &mut Conn, origin: Origin, line: &[u8]) {
+        match message_target(line) {
+            Some((cmd, dst)) => {
+                let cmd = str::from_utf8_lossy(cmd).into_owned().to_ascii_upper();
+                if self.refuse(&origin, cmd.as_slice(), dst) {
+                    return;
+                }
+            }
+            None => ()
+        }
+        if self.over_quota(&origin) {
+            return;
+        }
+        let line = self.codec.encode(line);
+        let line = line.as_slice();
+        if self.dry_run {
+            println!("[dry-run] {}", str::from_utf8_lossy(line));
+            return;
+        }
+        self.send(conn, origin, "RAW", bytes!("*"), line, line.to_owned());
+    }
+
+    /// Joins the channels, a comma-separated list, with their comma-separated keys.
+    /// The audit log and dry-run leave the keys out.
+    pub fn join(&mut self, conn: &mut Conn, origin: Origin, chans: &[u8], keys: &[u8]) {
+        let (chans, keys) = (self.codec.encode(chans), self.codec.encode(keys));
+        let chans = chans.as_slice();
+        if self.dry_run {
+            println!("[dry-run] JOIN {}", str::from_utf8_lossy(chans));
+            return;
+        }
+        let mut line = [bytes!("JOIN "), chans].concat_vec();
+        if !keys.is_empty() {
+            line.push(' ' as u8);
+            line.push_all(keys.as_slice());
+        }
+        self.send(conn, origin, "JOIN", chans, [], line);
+    }
+
+    /// Parts the channels, a comma-separated list, with an optional message
+    pub fn part(&mut self, conn: &mut Conn, origin: Origin, chans: &[u8], msg: &[u8]) {
+        let (chans, msg) = (self.codec.encode(chans), self.codec.encode(msg));
+        let (chans, msg) = (chans.as_slice(), msg.as_slice());
+        if self.dry_run {
+            log_dry_run("PART", chans, msg);
+            return;
+        }
+        let line = if msg.is_empty() {
+            [bytes!("PART "), chans].concat_vec()
+        } else {
+            message_line("PART", chans, msg)
+        };
+        self.send(conn, origin, "PART", chans, msg, line);
+    }
+
+    /// Quits with an optional message. This skips the flood queue, since nothing sent
+    /// after it would go out anyway. In dry-run mode the QUIT is only logged.
+    pub fn quit(&mut self, conn: &mut Conn, origin: Origin, msg: &[u8]) {
+        let msg = self.codec.encode(msg);
+        let msg = msg.as_slice();
+        if self.dry_run {
+            println!("[dry-run] QUIT :{}", str::from_utf8_lossy(msg));
+            return;
+        }
+        let line = if msg.is_empty() {
+            bytes!("QUIT").to_owned()
+        } else {
+            [bytes!("QUIT :"), msg].concat_vec()
+        };
+        let queued = Queued { line: line, origin: origin, cmd: ~"QUIT",
+                              dst: bytes!("*").to_owned(), msg: msg.to_owned() };
+        transmit(conn, &mut self.audit, queued);
+    }
+
+    /// Sends a line the connection itself needs, e.g. CAP, NICK while registering or
+    /// PING, right away. It isn't subject to dry-run, read-only or the flood queue, but
+    /// it uses up a line of the burst if there's one left.
+    pub fn send_protocol(&mut self, conn: &mut Conn, line: &[u8]) {
+        let line = self.codec.encode(line);
+        match self.flood {
+            None => (),
+            Some(ref mut flood) => flood.spend()
+        }
+        let (cmd, params) = match line.iter().position(|&b| b == ' ' as u8) {
+            None => (line.as_slice(), &[]),
+            Some(i) => (line.slice_to(i), line.slice_from(i + 1))
+        };
+        let cmd = str::from_utf8_lossy(cmd).into_owned().to_ascii_upper();
+        let params = if SECRET_COMMANDS.contains(&cmd.as_slice()) { &[] } else { params };
+        let queued = Queued { line: line.clone(), origin: Bot, cmd: cmd,
+                              dst: bytes!("*").to_owned(), msg: params.to_owned() };
+        transmit(conn, &mut self.audit, queued);
+    }
+
+    /// Changes the bot's nick. Like send_protocol, this isn't subject to the policy,
+    /// since the nick has to change while registering.
+    pub fn set_nick(&mut self, conn: &mut Conn, nick: &[u8]) {
+        let nick = self.codec.encode(nick);
+        match self.flood {
+            None => (),
+            Some(ref mut flood) => flood.spend()
+        }
+        conn.set_nick(nick.as_slice());
+        match self.audit {
+            None => (),
+            Some(ref mut log) => log.record(&Bot, "NICK", bytes!("*"), nick.as_slice())
+        }
+    }
+
+    /// Prints the last `count` audit log entries that contain `filter`
+    pub fn print_audit(&self, filter: &str, count: uint) {
+        match self.audit {
+            None => println!("The audit log is not enabled"),
+            Some(ref log) => log.print(filter, count)
+        }
+    }
+
+    /// Sets whether the server accepts message tags from us
+    pub fn set_tags_enabled(&mut self, enabled: bool) {
+        self.tags = enabled;
+    }
+
+    /// Sets the casemapping from the server's ISUPPORT
+    pub fn set_casemapping(&mut self, casemap: CaseMapping) {
+        self.casemap = casemap;
+    }
+
+    /// Returns the server's casemapping, for senders that track nicks or channels
+    pub fn casemapping(&self) -> CaseMapping {
+        self.casemap
+    }
+
+    /// Sends the queued messages that flood protection now allows
+    pub fn flush(&mut self, conn: &mut Conn) {
+        let Outbound { ref mut flood, ref mut audit, .. } = *self;
+        match *flood {
+            None => (),
+            Some(ref mut flood) => flood.flush(|queued| transmit(conn, audit, queued))
+        }
+    }
+
+    /// Forgets all plugin send accounting, re-enabling any disabled plugins
+    pub fn reset_quotas(&mut self) {
+        self.quotas.clear();
+    }
+
+    /// Counts a message against the origin's quota, returning whether it must be dropped
+    fn over_quota(&mut self, origin: &Origin) -> bool {
+        let (limit, name) = match (self.quota, origin) {
+            (Some(limit), &Plugin(ref name)) => (limit, name),
+            _ => return false
+        };
+        let now = time::precise_time_ns();
+        let quota = self.quotas.find_or_insert_with(name.clone(), |_| {
+            Quota { window_start: now, sent: 0, disabled: false }
+        });
+        if quota.disabled {
+            return true;
+        }
+        if now - quota.window_start >= QUOTA_WINDOW {
+            quota.window_start = now;
+            quota.sent = 0;
+        }
+        quota.sent += 1;
+        if quota.sent <= limit {
+            return false;
+        }
+        if quota.sent == limit + 1 {
+            if self.quota_disable {
+                quota.disabled = true;
+                println!("Plugin {} exceeded its quota of {} messages per minute; \
+                          disabling its messages until plugins are reloaded", name, limit);
+            } else {
+                println!("Plugin {} exceeded its quota of {} messages per minute; \
+                          dropping messages", name, limit);
+            }
+        }
+        true
+    }
+
+    /// Sends the encoded line now, or queues it if flood protection doesn't allow that
+    /// yet. The audit log records it as the cmd to dst with msg once it's sent.
+    fn send(&mut self, conn: &mut Conn, origin: Origin, cmd: &str, dst: &[u8], msg: &[u8],
+            line: ~[u8]) {
+        let queued = Queued { line: line, origin: origin, cmd: cmd.to_owned(),
+                              dst: dst.to_owned(), msg: msg.to_owned() };
+        match self.flood {
+            None => (),
+            Some(ref mut flood) => {
+                if !flood.ready() {
+                    return flood.queue(queued);
+                }
+            }
+        }
+        transmit(conn, &mut self.audit, queued);
+    }
+
+    /// Returns whether read-only mode forbids sending to dst, logging the refusal if so.
+    /// dst may be a comma-separated list, which is refused if any of its targets is.
+    /// The bot's own messages, e.g. to NickServ, are always allowed.
+    fn refuse(&self, origin: &Origin, cmd: &str, dst: &[u8]) -> bool {
+        match *origin {
+            Bot => return false,
+            _ => ()
+        }
+        let refused = self.read_only || dst.split(|&b| b == ',' as u8).any(|target| {
+            self.read_only_channels.iter().any(|c| self.casemap.eq(c.as_bytes(), target))
+        });
+        let dst = str::from_utf8_lossy(dst);
+        if refused {
+            println!("Refusing to send {} to {}: read-only", cmd, dst);
+        }
+        refused
+    }
+}
+
+/// Sends the line, and records it in the audit log
+fn transmit(conn: &mut Conn, audit: &mut Option<AuditLog>, queued: Queued) {
+    conn.send_raw(queued.line.as_slice());
+    match *audit {
+        None => (),
+        Some(ref mut log) => {
+            log.record(&queued.origin, queued.cmd.as_slice(), queued.dst.as_slice(),
+                       queued.msg.as_slice())
+        }
+    }
+}
+
+/// Returns the command and target of a raw PRIVMSG, NOTICE or TAGMSG line, skipping
+/// its tags and prefix, or None for any other line
+fn message_target<'a>(line: &'a [u8]) -> Option<(&'a [u8], &'a [u8])> {
+    let mut words = line.split(|&b| b == ' ' as u8).filter(|w| !w.is_empty());
+    let mut cmd = match words.next() {
+        Some(w) => w,
+        None => return None
+    };
+    if cmd[0] == '@' as u8 {
+        cmd = match words.next() { Some(w) => w, None => return None };
+    }
+    if cmd[0] == ':' as u8 {
+        cmd = match words.next() { Some(w) => w, None => return None };
+    }
+    let is_message = ["PRIVMSG", "NOTICE", "TAGMSG"].iter().any(|c| {
+        str::from_utf8(cmd).map_or(false, |cmd| cmd.eq_ignore_ascii_case(*c))
+    });
+    if !is_message {
+        return None;
+    }
+    words.next().map(|dst| (cmd, dst))
+}
+
+fn message_line(cmd: &str, dst: &[u8], msg: &[u8]) -> ~[u8] {
+    [cmd.as_bytes(), bytes!(" "), dst, bytes!(" :"), msg].concat_vec()
+}
+
+fn log_dry_run(cmd: &str, dst: &[u8], msg: &[u8]) {
+    println!("[dry-run] {} {} :{}", cmd, str::from_utf8_lossy(dst), str::from_utf8_lossy(msg));
+}
@@ -12,19 +12,60 @@ extern crate toml = "github.com/mneumann/rust-toml#toml:0.1";
 extern crate log;
 extern crate getopts;
 extern crate sync;
+extern crate serialize;
+extern crate collections;
+extern crate regex;
+extern crate time;
 
-use std::os;
+use std::{cmp, os};
 use std::io;
 use std::io::signal::{Listener, Interrupt};
 use std::task;
+use std::cell::Cell;
+use std::rc::Rc;
+use std::rand;
 use irc::conn;
 use irc::conn::{Conn, Line, Event, IRCCode};
 
 pub mod config;
 pub mod stdin;
+pub mod timer;
+pub mod nick;
+pub mod selftest;
+pub mod outbound;
+pub mod flood;
+pub mod audit;
+pub mod cap;
+pub mod casemap;
+pub mod isupport;
+pub mod suspend;
+pub mod keepalive;
+pub mod timeline;
+pub mod digest;
+pub mod resolver;
+pub mod socks;
+pub mod relay;
+pub mod bound;
+pub mod greet;
+pub mod invite;
+pub mod rejoin;
+pub mod joined;
+pub mod bouncer;
+pub mod dcc;
+pub mod encoding;
+pub mod tags;
+pub mod http;
+pub mod feed;
+pub mod scenario;
+pub mod manage;
+pub mod nickserv;
+pub mod auth;
 
 pub mod plugins;
 
+static MAX_JOIN_LEN: uint = 400; // longest JOIN line we send, well within the 512 limit
+static QUIT_GRACE: u64 = 2000; // milliseconds the server has to close the connection after QUIT
+
 fn main() {
     let conf = match config::parse_args() {
         Ok(c) => c,
@@ -34,18 +75,66 @@ fn main() {
         }
     };
 
+    if conf.dry_run {
+        println!("Dry run: messages will be logged instead of sent");
+    }
+
     if conf.servers.is_empty() {
         println!("No servers are specified");
         println!("Exiting...");
         return;
     }
 
-    // use a MutexArc to hold the channel for stdin
+    match conf.scenario {
+        None => (),
+        Some(ref path) => {
+            let passed = scenario::run(&conf, path);
+            // the bot's tasks are still running, so exit directly
+            unsafe { ::std::libc::exit(if passed { 0 } else { 1 }); }
+        }
+    }
+
+    // each server's channel is held in a MutexArc
     // This way we can swap it out on reconnections and stdin will work
-    let arc = sync::MutexArc::new(None);
+    let conns: Conns = conf.servers.iter().map(|server| {
+        (server.name.clone(), sync::MutexArc::new(None))
+    }).collect();
 
     // spawn the stdin listener now to control the bot
-    stdin::spawn_stdin_listener(arc.clone());
+    stdin::spawn_stdin_listener(conns.clone());
+
+    // feeds are polled across connections, so they go through the same channels
+    feed::spawn_pollers(&conf, conns.clone());
+
+    // connect to every server at once, each in its own task
+    let mut results = ~[];
+    for (i, &(ref name, ref arc)) in conns.iter().enumerate() {
+        let mut builder = task::task().named(format!("server {}", name));
+        results.push(builder.future_result());
+        let (conf, arc) = (conf.clone(), arc.clone());
+        builder.spawn(proc() {
+            run(&conf, i, &arc);
+        });
+    }
+    for result in results.iter() {
+        let _ = result.recv();
+    }
+    println!("Exiting...");
+
+    // some task is keeping us alive, so kill it
+    unsafe { ::std::libc::exit(0); }
+}
+
+/// Connects to the server in a loop, based on the reconnection config
+/// When a connection fails, the server's next address is tried right away, and only
+/// once all of them have failed does the bot wait to reconnect.
+fn run(conf: &config::Config, server: uint, arc: &sync::MutexArc<Option<Sender<Cmd>>>) {
+    // the server's host and port are switched to the address being tried
+    let mut conf = conf.clone();
+    let name = conf.servers[server].name.clone();
+    let mut address = 0; // index into the server's addresses
+    let joined = joined::Joined::new(); // channels to join again on the next connection
+    let last_seen = bouncer::LastSeen::new(); // for asking a bouncer for what we missed
 
     // create the reconnect timer, later used to sleep between connections
     let mut recon_timer = io::timer::Timer::new().ok()
@@ -53,30 +142,44 @@ fn main() {
     // reconnect time, used for exponential backoff
     let mut recon_delay = conf.reconnect_time;
 
-    // connect in a loop, based on the reconnection config
-    println!("Connecting...");
+    println!("Connecting to {}...", name);
     loop {
-        match connect(&conf, &arc) {
+        let mut try_next = false;
+        match connect(&conf, server, arc, &joined, &last_seen) {
             Ok(()) => {
                 // bot quit gracefully
-                println!("Exiting...");
+                println!("Disconnected from {}", name);
                 break;
             }
             Err(err) => {
                 // some error occurred
-                println!("Connection error: {}", err);
+                println!("Connection error on {}: {}", name, err);
                 match err {
                     conn::ErrIO(_) => {
                         // reset the reconnect delay, we successfully connected
                         recon_delay = conf.reconnect_time;
                     }
-                    _ => ()
+                    _ => {
+                        // move on to the next address, if there's one we haven't tried
+                        let addresses = conf.servers[server].addresses.len();
+                        address = (address + 1) % addresses;
+                        try_next = address != 0;
+                        let (host, port) = conf.servers[server].addresses[address].clone();
+                        conf.servers[server].host = host;
+                        conf.servers[server].port = port;
+                    }
                 }
             }
         }
 
         arc.access(|c| *c = None);
 
+        if try_next {
+            let s = &conf.servers[server];
+            println!("Trying {} at {}:{}...", name, s.host, s.port);
+            continue;
+        }
+
         match recon_delay {
             None => break,
             Some(mut secs) => {
@@ -97,26 +200,67 @@ fn main() {
                 }
             }
         }
-        println!("Reconnecting...");
+        println!("Reconnecting to {}...", name);
     }
-
-    // some task is keeping us alive, so kill it
-    unsafe { ::std::libc::exit(0); }
 }
 
 /// Payload for the Conn
 pub struct State {
-    plugins: plugins::PluginManager
+    plugins: plugins::PluginManager,
+    out: outbound::Outbound,
+    caps: cap::Caps,
+    auth: auth::Auth, // the server's auth backends
+    isupport: isupport::ISupport, // the server's RPL_ISUPPORT tokens
+    greeter: greet::Greeter,
+    invites: invite::Invites,
+    rejoin: rejoin::Rejoin,
+    joined: joined::Joined, // channels to join again after reconnecting
+    bouncer: bouncer::Bouncer, // playback from a bouncer
+    nickserv: nickserv::NickServ,
+    password: Option<~str>, // the server password
+    webirc: Option<config::WebIrc>,
+    codec: encoding::Codec, // decodes received lines into UTF-8
+    nick: ~str, // the configured nick, which may differ from the current nick
+    altnicks: ~[~str], // nicks to fall back to if nick is taken while registering
+    nick_attempts: uint, // alternate nicks tried while registering
+    command_prefix: ~str, // starts commands in channel messages
+    logged_in: bool,
+    registration_timeout: Option<uint>, // seconds to wait for 001 before reconnecting
+    selftest: Option<selftest::SelfTest>,
+    selftest_target: Option<~str>, // where the self-test sends, by default our own nick
+    session: ~str, // random id of this connection
+    clock: suspend::Clock,
+    keepalive: keepalive::Keepalive,
+    timeline: timeline::Timeline,
+    reconnect: Rc<Cell<bool>>, // set when we quit in order to reconnect
+    relay: relay::Closer, // closes the connection under irclib
+    cmd_tx: Sender<Cmd> // for scheduling work on the connection
 }
 
 pub type Cmd = conn::Cmd<State>;
 
-fn connect(conf: &config::Config, arc: &sync::MutexArc<Option<Sender<Cmd>>>) -> conn::Result {
-    // TODO: eventually we should support multiple servers
-    let server = &conf.servers[0];
-    let mut opts = irc::conn::Options::new(server.host, server.port);
+/// The channel to each server's connection by server name, None while it's not connected
+pub type Conns = ~[(~str, sync::MutexArc<Option<Sender<Cmd>>>)];
+
+/// Connects once to the server at the index into conf.servers
+fn connect(conf: &config::Config, index: uint, arc: &sync::MutexArc<Option<Sender<Cmd>>>,
+           joined: &joined::Joined, last_seen: &bouncer::LastSeen) -> conn::Result {
+    let server = &conf.servers[index];
+    // irclib connects to the relay, which holds the connection the bot made to the server
+    println!("Connecting to {}...", server.host);
+    let relay::Relay { port, user: relay_user, closer } = match relay::connect(server) {
+        Ok(relay) => relay,
+        Err(e) => {
+            return Err(conn::ErrConnectionFailed(io::IoError {
+                kind: io::ConnectionFailed,
+                desc: "could not connect",
+                detail: Some(e)
+            }));
+        }
+    };
+    let mut opts = irc::conn::Options::new("127.0.0.1", port);
     opts.nick = server.nick.as_slice();
-    opts.user = server.user.as_slice();
+    opts.user = relay_user.as_slice(); // the relay registers as server.user
     opts.real = server.real.as_slice();
 
     let (cmd_tx, cmd_rx) = channel();
@@ -135,8 +279,8 @@ fn connect(conf: &config::Config, arc: &sync::MutexArc<Option<Sender<Cmd>>>) ->
             loop {
                 match listener.rx.recv() {
                     Interrupt => {
-                        cmd_tx.try_send(proc(conn: &mut Conn, _state: &mut State) {
-                            conn.quit([]);
+                        cmd_tx.try_send(proc(conn: &mut Conn, state: &mut State) {
+                            quit(conn, state, outbound::Console, "");
                         });
                         listener.unregister(Interrupt);
                         break;
@@ -149,31 +293,276 @@ fn connect(conf: &config::Config, arc: &sync::MutexArc<Option<Sender<Cmd>>>) ->
         warn!("Couldn't register ^C signal handler");
     }
 
-    let state = State { plugins: plugins::PluginManager::new(conf) };
+    // periodically try to get our nick back if we end up on an alternate
+    match conf.nick_regain {
+        None => (),
+        Some(secs) => {
+            timer::every("nick regain", secs as u64 * 1000, cmd_tx.clone(), nick::regain);
+        }
+    }
+
+    // send messages held back by flood protection
+    timer::every("send queue", flood::FLUSH_INTERVAL, cmd_tx.clone(), flush_queue);
+
+    // drive plugin timeouts
+    timer::every("plugin tick", 1000, cmd_tx.clone(), plugin_tick);
+
+    if conf.plugin_watch {
+        timer::every("plugin watch", plugins::WATCH_INTERVAL, cmd_tx.clone(), plugin_watch);
+    }
+
+    // watch for clock jumps that mean the connection may have died during a suspend
+    timer::every("suspend check", suspend::CHECK_INTERVAL, cmd_tx.clone(), suspend::check);
+
+    // reconnect when the server stops answering
+    timer::every("keepalive", keepalive::CHECK_INTERVAL, cmd_tx.clone(), keepalive::check);
+
+    let session = format!("{:016x}", rand::random::<u64>());
+    let reconnect = Rc::new(Cell::new(false));
+    let auth = auth::Auth::new(server);
+    let state = State {
+        plugins: plugins::PluginManager::new(conf, index, session.as_slice(),
+                                              cmd_tx.clone()),
+        out: outbound::Outbound::new(conf, server),
+        caps: cap::Caps::new(server, &auth),
+        auth: auth,
+        isupport: isupport::ISupport::new(),
+        greeter: greet::Greeter::new(conf, server),
+        invites: invite::Invites::new(server),
+        rejoin: rejoin::Rejoin::new(server),
+        joined: joined.clone(),
+        bouncer: bouncer::Bouncer::new(server.bouncer, last_seen.clone()),
+        nickserv: nickserv::NickServ::new(server),
+        password: server.password.clone(),
+        webirc: server.webirc.clone(),
+        codec: server.codec.clone(),
+        nick: server.nick.clone(),
+        altnicks: server.altnicks.clone(),
+        nick_attempts: 0,
+        command_prefix: conf.command_prefix.clone(),
+        logged_in: false,
+        registration_timeout: server.registration_timeout,
+        selftest: None,
+        selftest_target: server.selftest_target.clone(),
+        session: session.clone(),
+        clock: suspend::Clock::new(),
+        keepalive: keepalive::Keepalive::new(server.ping_interval, server.ping_timeout),
+        timeline: timeline::Timeline::new(server.host.as_slice(), server.port),
+        reconnect: reconnect.clone(),
+        relay: closer,
+        cmd_tx: cmd_tx.clone()
+    };
+
+    let autojoin = joined.autojoin(server);
+    let autojoin = autojoin.as_slice();
+
+    println!("Registering with {} (session {})...", server.host, session);
+    let res = irc::conn::connect(opts, state, |conn, event, state| {
+        handler(conn, event, state, autojoin)
+    });
+    match res {
+        Ok(()) if reconnect.get() => {
+            Err(conn::ErrIO(io::IoError {
+                kind: io::OtherIoError,
+                desc: "disconnected in order to reconnect",
+                detail: None
+            }))
+        }
+        res => res
+    }
+}
+
+fn flush_queue(conn: &mut Conn, state: &mut State) {
+    state.out.flush(conn);
+}
+
+fn plugin_tick(conn: &mut Conn, state: &mut State) {
+    state.plugins.dispatch_tick(conn, &mut state.out);
+}
+
+fn plugin_watch(conn: &mut Conn, state: &mut State) {
+    if state.plugins.plugins_changed() {
+        println!("Plugin files changed, reloading plugins...");
+        state.plugins.reload_plugins(conn, &mut state.out);
+    }
+}
+
+/// Sends WEBIRC, which must come before anything else the server gets from us
+fn send_webirc(conn: &mut Conn, out: &mut outbound::Outbound, webirc: &config::WebIrc) {
+    // a leading colon would make the argument trailing, as in IPv6 addresses like ::1
+    let arg = |s: &str| if s.starts_with(":") { format!("0{}", s) } else { s.to_owned() };
+    let line = format!("WEBIRC {} {} {} {}", webirc.password, webirc.gateway,
+                       arg(webirc.host.as_slice()), arg(webirc.ip.to_str().as_slice()));
+    out.send_protocol(conn, line.as_bytes());
+}
+
+/// Sends the server password, which must come before registration
+fn send_pass(conn: &mut Conn, out: &mut outbound::Outbound, password: &str) {
+    // a password with spaces has to be the trailing argument
+    let line = if password.contains_char(' ') || password.starts_with(":") {
+        format!("PASS :{}", password)
+    } else {
+        format!("PASS {}", password)
+    };
+    out.send_protocol(conn, line.as_bytes());
+}
+
+/// Joins the channels, e.g. the server's autojoin channels once we're logged in
+/// Several channels are joined with each JOIN, as many as the server's TARGMAX allows.
+pub fn join_channels(conn: &mut Conn, out: &mut outbound::Outbound,
+                     isupport: &isupport::ISupport, channels: &[config::Channel]) {
+    let max = cmp::max(isupport.targmax("JOIN").unwrap_or(channels.len()), 1);
+    // a JOIN's keys go with its first channels, so the channels with keys come first
+    let mut ordered = ~[];
+    for chan in channels.iter().filter(|c| c.password.is_some()) {
+        ordered.push(chan);
+    }
+    for chan in channels.iter().filter(|c| c.password.is_none()) {
+        ordered.push(chan);
+    }
+    let (mut names, mut keys) = (~[], ~[]);
+    let mut count = 0;
+    for chan in ordered.iter() {
+        println!("Joining {}", chan.name);
+        let key = chan.password.as_ref().map_or("", |k| k.as_slice());
+        let len = names.len() + keys.len() + chan.name.len() + key.len() + 2;
+        if count > 0 && (count == max || bytes!("JOIN ").len() + len >= MAX_JOIN_LEN) {
+            out.join(conn, outbound::Bot, names.as_slice(), keys.as_slice());
+            names.clear();
+            keys.clear();
+            count = 0;
+        }
+        if count > 0 {
+            names.push(',' as u8);
+        }
+        names.push_all(chan.name.as_bytes());
+        if !key.is_empty() {
+            if !keys.is_empty() {
+                keys.push(',' as u8);
+            }
+            keys.push_all(key.as_bytes());
+        }
+        count += 1;
+    }
+    if count > 0 {
+        out.join(conn, outbound::Bot, names.as_slice(), keys.as_slice());
+    }
+}
+
+/// Quits the current connection so that the main loop reconnects
+pub fn reconnect(conn: &mut Conn, state: &mut State, reason: &str) {
+    println!("Reconnecting: {}", reason);
+    state.reconnect.set(true);
+    quit(conn, state, outbound::Bot, reason);
+}
 
-    let autojoin = server.autojoin.as_slice();
+/// Closes the current connection without quitting so that the main loop reconnects, for
+/// a connection that's no use, e.g. one that never registered
+pub fn drop_connection(state: &mut State, reason: &str) {
+    println!("Reconnecting: {}", reason);
+    state.reconnect.set(true);
+    state.relay.close();
+}
 
-    println!("Connecting to {}...", opts.host);
-    irc::conn::connect(opts, state, |conn, event, state| handler(conn, event, state, autojoin))
+/// Quits, and closes the connection QUIT_GRACE later. A server that stopped answering
+/// never closes it, and in dry-run mode the QUIT isn't even sent.
+pub fn quit(conn: &mut Conn, state: &mut State, origin: outbound::Origin, msg: &str) {
+    state.out.quit(conn, origin, msg.as_bytes());
+    let closer = state.relay.clone();
+    task::task().named("quit").spawn(proc() {
+        io::timer::sleep(QUIT_GRACE);
+        closer.close();
+    });
 }
 
 fn handler(conn: &mut Conn, event: Event, state: &mut State, autojoin: &[config::Channel]) {
+    let (event, tags) = tags::split_event(event);
+    let event = state.codec.decode_event(event);
+    let mut replayed = false;
     match event {
-        irc::conn::Connected => println!("Connected"),
-        irc::conn::Disconnected => println!("Disconnected"),
+        irc::conn::Connected => {
+            println!("Connected");
+            state.timeline.mark(~"connected");
+            match state.webirc {
+                None => (),
+                Some(ref webirc) => send_webirc(conn, &mut state.out, webirc)
+            }
+            match state.password {
+                None => (),
+                Some(ref password) => send_pass(conn, &mut state.out, password.as_slice())
+            }
+            state.nick_attempts = 0;
+            state.keepalive.reset();
+            state.bouncer.reset();
+            state.plugins.set_lag(None);
+            state.isupport.clear();
+            state.plugins.set_isupport(&state.isupport);
+            state.out.set_casemapping(state.isupport.casemapping());
+            state.auth.reset();
+            state.caps.start(conn, &mut state.out);
+            match state.registration_timeout {
+                None => (),
+                Some(secs) => {
+                    timer::after("registration timeout", secs as u64 * 1000,
+                                 state.cmd_tx.clone(), proc(_conn: &mut Conn, state: &mut State) {
+                        if !state.logged_in {
+                            let reason = format!("not registered within {} seconds", secs);
+                            drop_connection(state, reason.as_slice());
+                        }
+                    });
+                }
+            }
+        }
+        irc::conn::Disconnected => {
+            println!("Disconnected");
+            state.timeline.mark(~"disconnected");
+            state.logged_in = false;
+            selftest::abort(state);
+        }
         irc::conn::LineReceived(ref line) => {
+            state.caps.handle_line(conn, &mut state.out, &mut state.auth, line);
+            state.out.set_tags_enabled(state.caps.is_enabled("message-tags"));
+            state.plugins.set_labeled_response(state.caps.is_enabled("labeled-response"));
+            suspend::line_received(state, line);
+            keepalive::line_received(state, line);
+            let playback = state.caps.is_enabled("znc.in/playback");
+            replayed = state.bouncer.line_received(conn, &mut state.out,
+                                                   &state.isupport.casemapping(), line, tags,
+                                                   playback);
+            timeline::line_received(conn, state, line);
+            if state.isupport.line_received(line) {
+                state.plugins.set_isupport(&state.isupport);
+                state.out.set_casemapping(state.isupport.casemapping());
+            }
             let Line{ref command, args: _, prefix: _} = *line;
             match *command {
                 IRCCode(1) => {
                     println!("Logged in");
-                    for chan in autojoin.iter() {
-                        println!("Joining {}", chan.name);
-                        conn.join(chan.name.as_bytes(), []);
-                    }
+                    state.logged_in = true;
+                    nick::logged_in(conn, state);
+                    auth::logged_in(conn, state, autojoin);
                 }
                 _ => ()
             }
+            nick::line_received(conn, state, line);
+            auth::line_received(conn, state, line);
+            nickserv::line_received(conn, state, line);
+        }
+    }
+    state.plugins.dispatch_irc_event(conn, &mut state.out, &event, tags, replayed);
+    match event {
+        irc::conn::LineReceived(ref line) => {
+            selftest::line_dispatched(conn, state, line);
+            rejoin::line_dispatched(conn, state, line);
+            joined::line_dispatched(conn, state, line);
+            // what was said before we connected isn't acted on again
+            if !replayed {
+                greet::line_dispatched(conn, state, line);
+                invite::line_dispatched(conn, state, line);
+                dcc::line_dispatched(conn, state, line);
+                manage::line_dispatched(conn, state, line);
+            }
         }
+        _ => ()
     }
-    state.plugins.dispatch_irc_event(conn, &event);
 }
@@ -0,0 +1,167 @@
+//! Lua bot library
+//!
+//! Vends a package named 'bot' with information about the bot itself.
+//!
+//! bot.config: A table describing the bot's configuration. Every access
+//! returns a fresh copy, so modifying it has no effect on the bot or on other
+//! plugins. It contains the following values:
+//!
+//! plugin: The [plugin] section of the config file. Besides the plugin dir,
+//!         this may hold any free-form values that plugins want to read.
+//! server: The server of this connection (see below)
+//! servers: An array of all configured servers
+//!
+//! A server is a table with the following values:
+//!
+//! name: The configured name of the server
+//! host: The server host
+//! port: The server port
+//! nick: The configured nickname
+//! user: The configured username
+//! real: The configured real name
+//! autojoin: An array of channels, each a table with the values name and
+//!           password (the channel key; optional, may be nil, and always nil
+//!           for sandboxed plugins)
+//!
+//! The server password, SASL and NickServ settings are never included.
+
+#[allow(uppercase_variables)];
+
+use lua;
+use config;
+use toml;
+use std::libc;
+
+lua_extern_pub! {
+    unsafe fn lua_require(L: &mut lua::ExternState) -> i32 {
+        // 1 argument is passed: modname
+
+        L.newtable();
+
+        // bot.config is computed on access
+        L.createtable(0, 1);
+        L.pushcfunction(lua_index);
+        L.setfield(-2, "__index");
+        L.setmetatable(-2);
+
+        1
+    }
+}
+
+lua_extern! {
+    unsafe fn lua_index(L: &mut lua::ExternState) -> i32 {
+        // 2 args: table, key
+
+        match L.tostring(2) {
+            Some("config") => {
+                L.pushlightuserdata(lua_require as *mut libc::c_void);
+                L.gettable(lua::REGISTRYINDEX);
+                if !L.istable(-1) {
+                    L.errorstr("could not retrieve config information");
+                }
+                copy_table(L, L.gettop());
+                1
+            }
+            _ => 0
+        }
+    }
+}
+
+/// Converts the config into a Lua table and stores it in the registry for bot.config, with
+/// conf.servers[server] as the connection's server.
+/// This needs to be done eagerly as the config is not guaranteed to outlive this call.
+pub unsafe fn store_config(L: &mut lua::ExternState, conf: &config::Config, server: uint) {
+    L.pushlightuserdata(lua_require as *mut libc::c_void);
+    L.createtable(0, 3);
+
+    push_toml(L, &conf.plugin_table);
+    L.setfield(-2, "plugin");
+
+    L.createtable(conf.servers.len() as i32, 0);
+    for (i, server) in conf.servers.iter().enumerate() {
+        push_server(L, server);
+        L.rawseti(-2, i as i32 + 1);
+    }
+    L.rawgeti(-1, server as i32 + 1);
+    L.setfield(-3, "server");
+    L.setfield(-2, "servers");
+
+    L.settable(lua::REGISTRYINDEX);
+}
+
+unsafe fn push_server(L: &mut lua::ExternState, server: &config::Server) {
+    L.createtable(0, 7);
+    L.pushstring(server.name.as_slice());
+    L.setfield(-2, "name");
+    L.pushstring(server.host.as_slice());
+    L.setfield(-2, "host");
+    L.pushinteger(server.port as int);
+    L.setfield(-2, "port");
+    L.pushstring(server.nick.as_slice());
+    L.setfield(-2, "nick");
+    L.pushstring(server.user.as_slice());
+    L.setfield(-2, "user");
+    L.pushstring(server.real.as_slice());
+    L.setfield(-2, "real");
+    L.createtable(server.autojoin.len() as i32, 0);
+    for (i, chan) in server.autojoin.iter().enumerate() {
+        L.createtable(0, 2);
+        L.pushstring(chan.name.as_slice());
+        L.setfield(-2, "name");
+        match chan.password {
+            None => L.pushnil(),
+            Some(ref s) => L.pushstring(s.as_slice())
+        }
+        L.setfield(-2, "password");
+        L.rawseti(-2, i as i32 + 1);
+    }
+    L.setfield(-2, "autojoin");
+}
+
+/// Pushes the Lua representation of a TOML value
+pub unsafe fn push_toml(L: &mut lua::ExternState, val: &toml::Value) {
+    match *val {
+        toml::NoValue => L.pushnil(),
+        toml::Boolean(b) => L.pushboolean(b),
+        toml::Unsigned(n) => L.pushnumber(n as f64),
+        toml::Signed(n) => L.pushnumber(n as f64),
+        toml::Float(n) => L.pushnumber(n),
+        toml::String(ref s) => L.pushstring(s.as_slice()),
+        toml::Datetime(y, mo, d, h, mi, s) => {
+            let s = format!("{:04u}-{:02u}-{:02u}T{:02u}:{:02u}:{:02u}Z", y, mo, d, h, mi, s);
+            L.pushstring(s.as_slice());
+        }
+        toml::Array(ref ary) | toml::TableArray(ref ary) => {
+            L.createtable(ary.len() as i32, 0);
+            for (i, v) in ary.iter().enumerate() {
+                push_toml(L, v);
+                L.rawseti(-2, i as i32 + 1);
+            }
+        }
+        toml::Table(_, ref map) => {
+            L.createtable(0, map.len() as i32);
+            for (k, v) in map.iter() {
+                push_toml(L, v);
+                L.setfield(-2, k.as_slice());
+            }
+        }
+    }
+}
+
+/// Pushes a deep copy of the table at the given (absolute) index
+/// The table must not contain cycles.
+unsafe fn copy_table(L: &mut lua::ExternState, idx: i32) {
+    L.checkstack(4);
+    L.newtable();
+    L.pushnil();
+    while L.next(idx) {
+        L.pushvalue(-2); // copy the key
+        L.insert(-2); // move it behind the value
+        if L.istable(-1) {
+            copy_table(L, L.gettop());
+            L.remove(-2); // remove the original value
+        }
+        L.settable(-4); // set key=value in the new table
+        // leave behind the key for next
+    }
+}
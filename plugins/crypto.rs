@@ -0,0 +1,114 @@
+//! Lua encoding and hashing library
+//!
+//! Vends a package named 'crypto' with base64 and hashing functions, e.g. for
+//! verifying webhook signatures.
+//!
+//! crypto.base64encode(s): Returns s encoded as standard base64
+//! crypto.base64decode(s): Returns the bytes encoded by s, or nil followed by an
+//! error message if s isn't valid base64
+//! crypto.sha1(s), crypto.sha256(s): Return the raw digest of s
+//! crypto.hmac(algo, key, msg): Returns the raw HMAC of msg, where algo is "sha1"
+//! or "sha256"
+//! crypto.hex(s): Returns s as lowercase hexadecimal
+//! crypto.equal(a, b): Compares two strings in constant time, for checking
+//! signatures without leaking how much of them matched
+
+#[allow(uppercase_variables)];
+
+use lua;
+use digest;
+use serialize::base64::{FromBase64, ToBase64, STANDARD};
+use std::str;
+
+lua_extern_pub! {
+    unsafe fn lua_require(L: &mut lua::ExternState) -> i32 {
+        // 1 argument is passed: modname
+
+        L.newtable();
+        L.registerlib(None, [
+            ("base64encode", lua_base64encode),
+            ("base64decode", lua_base64decode),
+            ("sha1", lua_sha1),
+            ("sha256", lua_sha256),
+            ("hmac", lua_hmac),
+            ("hex", lua_hex),
+            ("equal", lua_equal)
+        ]);
+        1
+    }
+}
+
+lua_extern! {
+    unsafe fn lua_base64encode(L: &mut lua::ExternState) -> i32 {
+        // 1 arg: s
+
+        let s = L.checkbytes(1).to_base64(STANDARD);
+        L.pushstring(s.as_slice());
+        1
+    }
+
+    unsafe fn lua_base64decode(L: &mut lua::ExternState) -> i32 {
+        // 1 arg: s
+
+        let s = str::from_utf8_lossy(L.checkbytes(1)).into_owned();
+        match s.as_slice().from_base64() {
+            Ok(bytes) => {
+                L.pushbytes(bytes.as_slice());
+                1
+            }
+            Err(e) => {
+                L.pushnil();
+                L.pushstring(e.to_str().as_slice());
+                2
+            }
+        }
+    }
+
+    unsafe fn lua_sha1(L: &mut lua::ExternState) -> i32 {
+        // 1 arg: s
+
+        let s = L.checkbytes(1);
+        L.pushbytes(digest::sha1(s));
+        1
+    }
+
+    unsafe fn lua_sha256(L: &mut lua::ExternState) -> i32 {
+        // 1 arg: s
+
+        let s = L.checkbytes(1);
+        L.pushbytes(digest::sha256(s));
+        1
+    }
+
+    unsafe fn lua_hmac(L: &mut lua::ExternState) -> i32 {
+        // 3 args: algo, key, msg
+
+        let algo = match str::from_utf8(L.checkbytes(1)).and_then(digest::Algorithm::from_name) {
+            Some(algo) => algo,
+            None => L.argerror(1, "expected \"sha1\" or \"sha256\"")
+        };
+        let key = L.checkbytes(2);
+        let msg = L.checkbytes(3);
+        L.pushbytes(digest::hmac(algo, key, msg));
+        1
+    }
+
+    unsafe fn lua_hex(L: &mut lua::ExternState) -> i32 {
+        // 1 arg: s
+
+        let s = L.checkbytes(1);
+        let hex: ~[~str] = s.iter().map(|b| format!("{:02x}", *b)).collect();
+        L.pushstring(hex.concat().as_slice());
+        1
+    }
+
+    unsafe fn lua_equal(L: &mut lua::ExternState) -> i32 {
+        // 2 args: a, b
+
+        let a = L.checkbytes(1);
+        let b = L.checkbytes(2);
+        let diff = a.iter().zip(b.iter()).fold(0u8, |acc, (&x, &y)| acc | (x ^ y));
+        L.pushboolean(a.len() == b.len() && diff == 0);
+        1
+    }
+}
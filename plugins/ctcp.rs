@@ -0,0 +1,168 @@
+//! Built-in CTCP replies
+//!
+//! Answers CTCP VERSION, PING, TIME and SOURCE, so every bot doesn't need a Lua plugin
+//! for them. The [plugins.ctcp] section sets the VERSION and SOURCE replies, the TIME
+//! strftime format, and whether PING is answered; setting any of them to false stops
+//! that reply. Each sender gets at most one reply per rate_limit seconds, and all of
+//! them together at most max_per_minute replies a minute, which limits how much of the
+//! send queue a crowd of nicks can take up with CTCPs. CTCPs a bouncer replayed aren't
+//! answered. The CTCP events still reach Lua plugins.
+
+use casemap::CaseMap;
+use outbound;
+use outbound::Outbound;
+use super::native::Plugin;
+use irc::conn::{Conn, Event, LineReceived, Line, IRCCTCP};
+use std::ascii::StrAsciiExt;
+use std::str;
+use time;
+use toml;
+
+static DEFAULT_VERSION: &'static str = "rust-ircbot";
+static DEFAULT_SOURCE: &'static str = "https://github.com/kballard/rust-ircbot";
+static DEFAULT_TIME: &'static str = "%a %b %d %H:%M:%S %Y %Z";
+static DEFAULT_RATE_LIMIT: u64 = 5; // seconds
+static DEFAULT_MAX_PER_MINUTE: uint = 10;
+static MINUTE: u64 = 60 * 1000000000; // ns
+static PRUNE_THRESHOLD: uint = 1000; // forget old senders once this many are tracked
+
+pub struct Ctcp {
+    priv version: Option<~str>,
+    priv source: Option<~str>,
+    priv time: Option<~str>, // strftime format
+    priv ping: bool,
+    priv rate_limit: u64, // ns between replies to the same sender
+    priv max_per_minute: uint, // replies to anyone, 0 for no limit
+    priv minute_start: u64, // precise_time_ns() when the current minute began
+    priv minute_replies: uint, // replies sent in the current minute
+    priv replied: CaseMap<u64> // nick -> precise_time_ns() of the last reply
+}
+
+impl Ctcp {
+    pub fn new() -> Ctcp {
+        Ctcp {
+            version: Some(DEFAULT_VERSION.to_owned()),
+            source: Some(DEFAULT_SOURCE.to_owned()),
+            time: Some(DEFAULT_TIME.to_owned()),
+            ping: true,
+            rate_limit: DEFAULT_RATE_LIMIT * 1000000000,
+            max_per_minute: DEFAULT_MAX_PER_MINUTE,
+            minute_start: 0,
+            minute_replies: 0,
+            replied: CaseMap::new()
+        }
+    }
+
+    /// Returns the reply text for the CTCP command, if it's answered
+    fn reply(&self, cmd: &str, text: &[u8]) -> Option<~[u8]> {
+        match cmd {
+            "VERSION" => self.version.as_ref().map(|v| v.as_bytes().to_owned()),
+            "SOURCE" => self.source.as_ref().map(|s| s.as_bytes().to_owned()),
+            "TIME" => self.time.as_ref().map(|f| time::now().strftime(f.as_slice()).into_bytes()),
+            "PING" if self.ping => Some(text.to_owned()),
+            _ => None
+        }
+    }
+
+    /// Returns whether the sender may get a reply now, and records it if so
+    fn allow(&mut self, nick: &[u8]) -> bool {
+        let now = time::precise_time_ns();
+        if now - self.minute_start >= MINUTE {
+            self.minute_start = now;
+            self.minute_replies = 0;
+        }
+        if self.max_per_minute > 0 && self.minute_replies >= self.max_per_minute {
+            return false;
+        }
+        match self.replied.find(nick) {
+            Some(&last) if now - last < self.rate_limit => return false,
+            _ => ()
+        }
+        if self.replied.len() >= PRUNE_THRESHOLD {
+            let limit = self.rate_limit;
+            self.replied.retain(|_, &last| now - last < limit);
+        }
+        self.replied.insert(nick, now);
+        self.minute_replies += 1;
+        true
+    }
+}
+
+impl Plugin for Ctcp {
+    fn name(&self) -> &'static str {
+        "ctcp"
+    }
+
+    fn on_load(&mut self, conf: Option<&toml::Value>) {
+        let conf = match conf {
+            None => return,
+            Some(c) => c
+        };
+        self.version = reply_setting(conf, "version", self.version.take());
+        self.source = reply_setting(conf, "source", self.source.take());
+        self.time = reply_setting(conf, "time", self.time.take());
+        match conf.lookup("ping") {
+            None => (),
+            Some(&toml::Boolean(b)) => self.ping = b,
+            Some(_) => println!("Warning: [plugins.ctcp] ping must be true or false")
+        }
+        match conf.lookup("rate_limit").and_then(|v| v.get_int()) {
+            None => (),
+            Some(secs) if secs < 0 => self.rate_limit = 0,
+            Some(secs) => self.rate_limit = secs as u64 * 1000000000
+        }
+        match conf.lookup("max_per_minute").and_then(|v| v.get_int()) {
+            None => (),
+            Some(n) if n < 0 => self.max_per_minute = 0,
+            Some(n) => self.max_per_minute = n as uint
+        }
+    }
+
+    fn on_event(&mut self, conn: &mut Conn, out: &mut Outbound, event: &Event,
+                _tags: &[(~str, ~str)], replayed: bool) -> bool {
+        if replayed {
+            // the sender asked long ago, and may not even be around any more
+            return false;
+        }
+        let (cmd, nick, text) = match *event {
+            LineReceived(Line{command: IRCCTCP(ref cmd, _), ref args, prefix: Some(ref user)}) => {
+                (cmd, user.nick(), args.head().map_or(&[], |t| t.as_slice()))
+            }
+            _ => return false
+        };
+        if out.casemapping().eq(nick, conn.me().nick()) {
+            return false;
+        }
+        let cmd = str::from_utf8_lossy(cmd.as_slice()).into_owned().to_ascii_upper();
+        let reply = match self.reply(cmd.as_slice(), text) {
+            None => return false,
+            Some(reply) => reply
+        };
+        self.replied.set_casemapping(out.casemapping());
+        if !self.allow(nick) {
+            return false;
+        }
+        let mut msg = ~[1u8];
+        msg.push_all(cmd.as_bytes());
+        if !reply.is_empty() {
+            msg.push(' ' as u8);
+            msg.push_all(reply.as_slice());
+        }
+        msg.push(1u8);
+        out.notice(conn, outbound::Ctcp, nick, msg.as_slice());
+        false
+    }
+}
+
+/// Returns the configured reply text, None if it's set to false, or the default
+fn reply_setting(conf: &toml::Value, key: &str, default: Option<~str>) -> Option<~str> {
+    match conf.lookup(key) {
+        None => default,
+        Some(&toml::String(ref s)) => Some(s.clone()),
+        Some(&toml::Boolean(false)) => None,
+        Some(_) => {
+            println!("Warning: [plugins.ctcp] {} must be a string or false", key);
+            default
+        }
+    }
+}
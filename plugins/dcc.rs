@@ -0,0 +1,425 @@
+//! Lua DCC library
+//!
+//! Vends a package named 'dcc' for sending and receiving files with DCC SEND. Files
+//! are only read from and saved to general.dcc_dir, and there's no DCC without it.
+//!
+//! dcc.send(nick, filename, handler): Offers the file of that name in dcc_dir to nick.
+//! The receiver connects to the bot at general.dcc_address, or with general.dcc_passive
+//! the receiver is asked to listen instead.
+//! dcc.accept(offer, handler): Accepts an offer from irc.DCC, saving the file in
+//! dcc_dir under the offered name, with a number appended if that's taken. Offers
+//! larger than general.dcc_max_size are refused.
+//!
+//! handler is called as handler(event, ...) where event is one of:
+//!
+//! "progress": The bytes transferred so far and the file's size, at most once a second
+//! "done": The path of the file that was sent or saved. No more events follow.
+//! "failed": An error message. A partly received file is removed. No more events
+//! follow.
+//!
+//! A transfer fails if its offer isn't taken within a minute, or if it stalls for a
+//! minute. dcc.send and dcc.accept may only be called while handling an event or
+//! callback.
+
+#[allow(uppercase_variables)];
+
+use lua;
+use config;
+use outbound;
+use dcc::Offer;
+use super::irc;
+use super::task;
+use std::io;
+use std::io::{fs, File};
+use std::io::net::ip::{IpAddr, SocketAddr};
+use std::io::timer::Timer;
+use std::{libc, rand, str, task};
+
+// registry key for the table of passive offers waiting for a reply, from token to the
+// transfer's callback id
+static PASSIVE: &'static str = "dcc_passive";
+
+/// Stores the DCC settings from the config
+pub unsafe fn store_config(L: &mut lua::ExternState, conf: &config::Dcc) {
+    L.pushlightuserdata(lua_require as *mut libc::c_void);
+    L.createtable(0, 6);
+
+    match conf.dir {
+        None => L.pushnil(),
+        Some(ref dir) => L.pushbytes(dir.as_vec())
+    }
+    L.setfield(-2, "dir");
+    L.pushinteger(conf.max_size as int);
+    L.setfield(-2, "max_size");
+    let (first, last) = conf.ports.unwrap_or((0, 0));
+    L.pushinteger(first as int);
+    L.setfield(-2, "first_port");
+    L.pushinteger(last as int);
+    L.setfield(-2, "last_port");
+    match conf.address {
+        None => L.pushnil(),
+        Some(ip) => L.pushstring(ip.to_str().as_slice())
+    }
+    L.setfield(-2, "address");
+    L.pushboolean(conf.passive);
+    L.setfield(-2, "passive");
+
+    L.settable(lua::REGISTRYINDEX);
+
+    L.newtable();
+    L.setfield(lua::REGISTRYINDEX, PASSIVE);
+}
+
+lua_extern_pub! {
+    unsafe fn lua_require(L: &mut lua::ExternState) -> i32 {
+        // 1 argument is passed: modname
+
+        L.newtable();
+        L.registerlib(None, [
+            ("send", lua_send),
+            ("accept", lua_accept)
+        ]);
+        1
+    }
+}
+
+lua_extern! {
+    unsafe fn lua_send(L: &mut lua::ExternState) -> i32 {
+        // 3 args: nick, filename, handler
+
+        let nick = L.checkbytes(1).to_owned();
+        let name = str::from_utf8_lossy(L.checkbytes(2)).into_owned();
+        L.checktype(3, lua::Type::Function);
+        L.settop(3);
+
+        let conf = getconfig(L);
+        let dir = checkdir(L, &conf);
+        L.argcheck(!name.is_empty() && ::dcc::safe_name(name.as_slice()) == name, 2,
+                   "expected the name of a file in general.dcc_dir");
+        let path = dir.join(name.as_slice());
+        let size = match path.stat() {
+            Ok(stat) => stat.size,
+            Err(e) => L.errorstr(format!("could not open {}: {}", path.display(), e).as_slice())
+        };
+        let mut file = match File::open(&path) {
+            Ok(f) => f,
+            Err(e) => L.errorstr(format!("could not open {}: {}", path.display(), e).as_slice())
+        };
+
+        let mut offer = Offer { filename: name, ip: 0, port: 0, size: size, token: None };
+        let acceptor = if conf.passive {
+            None
+        } else {
+            let ip = match conf.address {
+                Some(ip) => ip,
+                None => L.errorstr("general.dcc_address must be set unless general.dcc_passive is")
+            };
+            let (acceptor, port) = match ::dcc::listen(&conf) {
+                Ok(a) => a,
+                Err(e) => L.errorstr(e.as_slice())
+            };
+            offer.ip = ::dcc::ip_number(ip);
+            offer.port = port;
+            Some(acceptor)
+        };
+        let callback = task::register(L, 3);
+        let done = path.display().to_str();
+        match acceptor {
+            None => {
+                // the receiver replies with where to connect to
+                let token = rand::random::<u32>().to_str();
+                L.getfield(lua::REGISTRYINDEX, PASSIVE);
+                L.pushinteger(callback.id() as int);
+                L.setfield(-2, token.as_slice());
+                L.pop(1);
+                let (tx, rx) = channel();
+                irc::gettasks(L).add_stream(callback.id(), tx);
+                offer.token = Some(token);
+                send_offer(L, nick, &offer);
+
+                task::task().named("dcc send").spawn(proc() {
+                    let res = wait_reply(rx).and_then(::dcc::connect).and_then(|stream| {
+                        ::dcc::send(stream, &mut file, size, |bytes| {
+                            callback.call(push_progress(bytes, size), false);
+                        })
+                    });
+                    callback.call(push_result(res.map(|()| done)), true);
+                });
+            }
+            Some(mut acceptor) => {
+                send_offer(L, nick, &offer);
+
+                task::task().named("dcc send").spawn(proc() {
+                    let res = acceptor.accept().map_err(|e| {
+                        format!("the offer wasn't accepted: {}", e)
+                    }).and_then(|stream| {
+                        ::dcc::send(stream, &mut file, size, |bytes| {
+                            callback.call(push_progress(bytes, size), false);
+                        })
+                    });
+                    callback.call(push_result(res.map(|()| done)), true);
+                });
+            }
+        }
+        0
+    }
+
+    unsafe fn lua_accept(L: &mut lua::ExternState) -> i32 {
+        // 2 args: offer, handler
+
+        L.checktype(1, lua::Type::Table);
+        L.checktype(2, lua::Type::Function);
+        L.settop(2);
+        let (nick, offer) = checkoffer(L);
+
+        let conf = getconfig(L);
+        let dir = checkdir(L, &conf);
+        if offer.size > conf.max_size {
+            let msg = format!("the file is larger than general.dcc_max_size ({} bytes)",
+                              conf.max_size);
+            L.errorstr(msg.as_slice());
+        }
+        if !dir.exists() {
+            match fs::mkdir_recursive(&dir, io::UserDir) {
+                Ok(()) => (),
+                Err(e) => {
+                    let msg = format!("could not create {}: {}", dir.display(), e);
+                    L.errorstr(msg.as_slice());
+                }
+            }
+        }
+        // for a passive offer we listen, and our reply says where
+        let acceptor = if offer.port == 0 {
+            let ip = match conf.address {
+                Some(ip) => ip,
+                None => L.errorstr("general.dcc_address must be set to accept passive offers")
+            };
+            let (acceptor, port) = match ::dcc::listen(&conf) {
+                Ok(a) => a,
+                Err(e) => L.errorstr(e.as_slice())
+            };
+            Some((acceptor, ip, port))
+        } else {
+            None
+        };
+        let path = match ::dcc::download_path(&dir, offer.filename.as_slice()) {
+            Some(p) => p,
+            None => L.argerror(1, "the offered file name can't be used")
+        };
+        // creating the file now reserves its name
+        let mut file = match File::create(&path) {
+            Ok(f) => f,
+            Err(e) => L.errorstr(format!("could not create {}: {}", path.display(), e).as_slice())
+        };
+
+        let callback = task::register(L, 2);
+        let size = offer.size;
+        match acceptor {
+            Some((mut acceptor, ip, port)) => {
+                let reply = Offer { ip: ::dcc::ip_number(ip), port: port, .. offer };
+                send_offer(L, nick, &reply);
+
+                task::task().named("dcc receive").spawn(proc() {
+                    let res = acceptor.accept().map_err(|e| {
+                        format!("the sender didn't connect: {}", e)
+                    }).and_then(|stream| {
+                        ::dcc::receive(stream, &mut file, size, |bytes| {
+                            callback.call(push_progress(bytes, size), false);
+                        })
+                    });
+                    finish_receive(&callback, res, path);
+                });
+            }
+            None => {
+                let addr = offer.address();
+                task::task().named("dcc receive").spawn(proc() {
+                    let res = ::dcc::connect(addr).and_then(|stream| {
+                        ::dcc::receive(stream, &mut file, size, |bytes| {
+                            callback.call(push_progress(bytes, size), false);
+                        })
+                    });
+                    finish_receive(&callback, res, path);
+                });
+            }
+        }
+        0
+    }
+}
+
+/// Hands the reply to one of our passive offers to the transfer waiting for it.
+/// Returns false if the offer isn't such a reply.
+pub unsafe fn passive_reply(L: &mut lua::ExternState, offer: &Offer) -> bool {
+    let token = match offer.token {
+        Some(ref t) if offer.port != 0 => t,
+        _ => return false
+    };
+    L.getfield(lua::REGISTRYINDEX, PASSIVE);
+    L.getfield(-1, token.as_slice());
+    let id = L.tointeger(-1);
+    L.pop(1);
+    if id <= 0 {
+        L.pop(1);
+        return false;
+    }
+    L.pushnil();
+    L.setfield(-2, token.as_slice());
+    L.pop(1);
+    let tasks = irc::gettasks(L);
+    tasks.write_stream(id as uint, format!("{} {}", offer.ip, offer.port).into_bytes());
+    tasks.close_stream(id as uint);
+    true
+}
+
+/// Pushes the offer as the table irc.DCC handlers get
+pub unsafe fn push_offer(L: &mut lua::ExternState, nick: &[u8], offer: &Offer) {
+    L.createtable(0, 6);
+    L.pushbytes(nick);
+    L.setfield(-2, "nick");
+    L.pushstring(offer.filename.as_slice());
+    L.setfield(-2, "filename");
+    L.pushinteger(offer.size as int);
+    L.setfield(-2, "size");
+    L.pushstring(offer.address().ip.to_str().as_slice());
+    L.setfield(-2, "ip");
+    L.pushinteger(offer.port as int);
+    L.setfield(-2, "port");
+    match offer.token {
+        None => L.pushnil(),
+        Some(ref t) => L.pushstring(t.as_slice())
+    }
+    L.setfield(-2, "token");
+}
+
+/// Returns the sender's nick and the offer from the offer table at 1
+unsafe fn checkoffer(L: &mut lua::ExternState) -> (~[u8], Offer) {
+    L.getfield(1, "nick");
+    L.getfield(1, "filename");
+    L.getfield(1, "ip");
+    L.getfield(1, "port");
+    L.getfield(1, "size");
+    L.getfield(1, "token");
+    let nick = L.tobytes(-6).map(|n| n.to_owned());
+    let filename = L.tobytes(-5).map(|f| str::from_utf8_lossy(f).into_owned());
+    let ip = L.tobytes(-4).and_then(str::from_utf8).and_then(from_str::<IpAddr>);
+    let port = L.tointeger(-3);
+    let size = L.tointeger(-2);
+    let token = L.tobytes(-1).map(|t| str::from_utf8_lossy(t).into_owned());
+    L.pop(6);
+    match (nick, filename, ip) {
+        (Some(nick), Some(filename), Some(ip)) if port >= 0 && port < 65536 && size >= 0 => {
+            (nick, Offer {
+                filename: filename,
+                ip: ::dcc::ip_number(ip),
+                port: port as u16,
+                size: size as u64,
+                token: token
+            })
+        }
+        _ => L.argerror(1, "expected an offer from irc.DCC")
+    }
+}
+
+/// Returns the DCC settings stored by store_config
+unsafe fn getconfig(L: &mut lua::ExternState) -> config::Dcc {
+    L.pushlightuserdata(lua_require as *mut libc::c_void);
+    L.gettable(lua::REGISTRYINDEX);
+    L.getfield(-1, "dir");
+    let dir = L.tobytes(-1).map(|d| Path::new(d));
+    L.getfield(-2, "max_size");
+    let max_size = L.tointeger(-1) as u64;
+    L.getfield(-3, "first_port");
+    let first = L.tointeger(-1) as u16;
+    L.getfield(-4, "last_port");
+    let last = L.tointeger(-1) as u16;
+    L.getfield(-5, "address");
+    let address = L.tobytes(-1).and_then(str::from_utf8).and_then(from_str::<IpAddr>);
+    L.getfield(-6, "passive");
+    let passive = L.toboolean(-1);
+    L.pop(7);
+    config::Dcc {
+        dir: dir,
+        max_size: max_size,
+        ports: if first == 0 { None } else { Some((first, last)) },
+        address: address,
+        passive: passive
+    }
+}
+
+/// Returns the DCC directory, raising an error if DCC isn't configured
+unsafe fn checkdir(L: &mut lua::ExternState, conf: &config::Dcc) -> Path {
+    match conf.dir {
+        Some(ref dir) => dir.clone(),
+        None => L.errorstr("DCC is disabled, since general.dcc_dir isn't set")
+    }
+}
+
+/// Sends the offer to the nick as a CTCP DCC
+unsafe fn send_offer(L: &mut lua::ExternState, nick: &[u8], offer: &Offer) {
+    let conn = irc::getconn(L);
+    let out = irc::getoutbound(L);
+    out.privmsg(conn, outbound::Plugin(super::current_plugin(L)), nick, offer.text());
+}
+
+/// Waits for the reply to a passive offer, returning the address it gives
+fn wait_reply(rx: Receiver<task::StreamMsg>) -> Result<SocketAddr, ~str> {
+    let mut timer = match Timer::new() {
+        Ok(t) => t,
+        Err(e) => return Err(e.to_str())
+    };
+    let expired = timer.oneshot(::dcc::TIMEOUT);
+    let reply = select! (
+        msg = rx.recv_opt() => msg,
+        _ = expired.recv() => None
+    );
+    // the reply is written as "ip port" by passive_reply
+    let reply = match reply {
+        Some(Some(data)) => str::from_utf8_lossy(data).into_owned(),
+        _ => return Err(~"the offer wasn't accepted")
+    };
+    let words: ~[&str] = reply.words().collect();
+    let offer = Offer {
+        filename: ~"",
+        ip: words.get(0).and_then(|&w| from_str(w)).unwrap_or(0),
+        port: words.get(1).and_then(|&w| from_str(w)).unwrap_or(0),
+        size: 0,
+        token: None
+    };
+    Ok(offer.address())
+}
+
+/// Reports the end of a transfer into the file at `path`, removing the file if it failed
+fn finish_receive(callback: &task::Callback, res: Result<(), ~str>, path: Path) {
+    let res = match res {
+        Ok(()) => Ok(path.display().to_str()),
+        Err(e) => {
+            let _ = fs::unlink(&path);
+            Err(e)
+        }
+    };
+    callback.call(push_result(res), true);
+}
+
+fn push_progress(bytes: u64, size: u64) -> task::Pusher {
+    proc(L: &mut lua::State) -> i32 {
+        L.pushstring("progress");
+        L.pushinteger(bytes as int);
+        L.pushinteger(size as int);
+        3
+    }
+}
+
+fn push_result(res: Result<~str, ~str>) -> task::Pusher {
+    proc(L: &mut lua::State) -> i32 {
+        match res {
+            Ok(path) => {
+                L.pushstring("done");
+                L.pushstring(path.as_slice());
+            }
+            Err(e) => {
+                L.pushstring("failed");
+                L.pushstring(e.as_slice());
+            }
+        }
+        2
+    }
+}
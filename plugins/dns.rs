@@ -0,0 +1,91 @@
+//! Lua DNS library
+//!
+//! Vends a package named 'dns' for looking up names without blocking the
+//! connection.
+//!
+//! dns.resolve(name, [type], callback): Looks up the name in the background and
+//! later calls callback with an array of results, or with nil followed by an
+//! error message if the lookup failed. type is "A" for IPv4 addresses, "AAAA" for
+//! IPv6 addresses, or "TXT" for TXT records; if it's omitted both kinds of address
+//! are returned. A name with no records of the type gives an empty array.
+//!
+//! dns.resolve may only be called while handling an event or callback.
+
+#[allow(uppercase_variables)];
+
+use lua;
+use resolver;
+use super::task;
+use std::str;
+
+enum Query {
+    Addresses(bool, bool), // IPv4, IPv6
+    Txt
+}
+
+lua_extern_pub! {
+    unsafe fn lua_require(L: &mut lua::ExternState) -> i32 {
+        // 1 argument is passed: modname
+
+        L.newtable();
+        L.registerlib(None, [
+            ("resolve", lua_resolve)
+        ]);
+        1
+    }
+}
+
+lua_extern! {
+    unsafe fn lua_resolve(L: &mut lua::ExternState) -> i32 {
+        // 2 or 3 args: name, [type], callback
+
+        let name = str::from_utf8_lossy(L.checkbytes(1)).into_owned();
+        let (query, callback) = if L.gettop() >= 3 {
+            let kind = L.checkbytes(2);
+            let query = if kind == bytes!("A") {
+                Addresses(true, false)
+            } else if kind == bytes!("AAAA") {
+                Addresses(false, true)
+            } else if kind == bytes!("TXT") {
+                Txt
+            } else {
+                L.argerror(2, "expected \"A\", \"AAAA\" or \"TXT\"")
+            };
+            (query, 3)
+        } else {
+            (Addresses(true, true), 2)
+        };
+        task::spawn(L, "dns lookup", callback, proc() {
+            match query {
+                Addresses(v4, v6) => {
+                    let res = resolver::addresses(name.as_slice(), v4, v6).map(|addrs| {
+                        addrs.move_iter().map(|a| a.to_str().into_bytes()).collect()
+                    });
+                    push_results(res)
+                }
+                Txt => push_results(resolver::txt(name.as_slice()))
+            }
+        });
+        0
+    }
+}
+
+fn push_results(res: Result<~[~[u8]], ~str>) -> task::Pusher {
+    proc(L: &mut lua::State) -> i32 {
+        match res {
+            Ok(list) => {
+                L.createtable(list.len() as i32, 0);
+                for (i, item) in list.iter().enumerate() {
+                    L.pushbytes(item.as_slice());
+                    L.rawseti(-2, i as i32 + 1);
+                }
+                1
+            }
+            Err(e) => {
+                L.pushnil();
+                L.pushstring(e.as_slice());
+                2
+            }
+        }
+    }
+}
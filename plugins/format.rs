@@ -0,0 +1,178 @@
+//! mIRC formatting helpers, vended to Lua as irc.format
+//!
+//! irc.format.bold(text): Returns text in bold
+//! irc.format.italic(text): Returns text in italics
+//! irc.format.underline(text): Returns text underlined
+//! irc.format.color(text, fg, [bg]): Returns text in the given colors
+//! irc.format.reset(): Returns the code that turns off all formatting
+//!
+//! Colors are either numbers 0-15 or one of the names in irc.format.colors.
+//! The color codes are always written with two digits, so text starting with a
+//! digit is displayed correctly.
+//!
+//! irc.stripformat(text) lives in the irc table itself and removes all formatting
+//! codes from text, e.g. before matching keywords in incoming messages.
+
+#[allow(uppercase_variables)];
+
+use lua;
+use std::vec;
+
+pub static BOLD: u8 = 0x02;
+pub static COLOR: u8 = 0x03;
+pub static HEX_COLOR: u8 = 0x04;
+pub static MONOSPACE: u8 = 0x11;
+pub static RESET: u8 = 0x0F;
+pub static REVERSE: u8 = 0x16;
+pub static ITALIC: u8 = 0x1D;
+pub static STRIKETHROUGH: u8 = 0x1E;
+pub static UNDERLINE: u8 = 0x1F;
+
+static COLORS: &'static [&'static str] = &[
+    "white", "black", "blue", "green", "red", "brown", "purple", "orange",
+    "yellow", "lightgreen", "cyan", "lightcyan", "lightblue", "pink", "grey", "lightgrey"
+];
+
+/// Pushes the irc.format table
+pub unsafe fn push_table(L: &mut lua::ExternState) {
+    L.newtable();
+    L.registerlib(None, [
+        ("bold", lua_bold),
+        ("italic", lua_italic),
+        ("underline", lua_underline),
+        ("color", lua_color),
+        ("reset", lua_reset)
+    ]);
+
+    L.createtable(0, COLORS.len() as i32);
+    for (i, name) in COLORS.iter().enumerate() {
+        L.pushinteger(i as int);
+        L.setfield(-2, *name);
+    }
+    L.setfield(-2, "colors");
+}
+
+lua_extern! {
+    unsafe fn lua_bold(L: &mut lua::ExternState) -> i32 {
+        // 1 arg: text
+
+        wrap(L, BOLD)
+    }
+
+    unsafe fn lua_italic(L: &mut lua::ExternState) -> i32 {
+        // 1 arg: text
+
+        wrap(L, ITALIC)
+    }
+
+    unsafe fn lua_underline(L: &mut lua::ExternState) -> i32 {
+        // 1 arg: text
+
+        wrap(L, UNDERLINE)
+    }
+
+    unsafe fn lua_color(L: &mut lua::ExternState) -> i32 {
+        // 2 or 3 args: text, fg, [bg]
+
+        let text = L.checkbytes(1);
+        let fg = checkcolor(L, 2);
+        let bg = if L.isnoneornil(3) { None } else { Some(checkcolor(L, 3)) };
+
+        let mut buf = [0u8, ..6];
+        buf[0] = COLOR;
+        buf[1] = '0' as u8 + fg / 10;
+        buf[2] = '0' as u8 + fg % 10;
+        let n = match bg {
+            None => 3,
+            Some(bg) => {
+                buf[3] = ',' as u8;
+                buf[4] = '0' as u8 + bg / 10;
+                buf[5] = '0' as u8 + bg % 10;
+                6
+            }
+        };
+        L.pushbytes(buf.slice_to(n));
+        L.pushbytes(text);
+        L.pushbytes([COLOR]);
+        L.concat(3);
+        1
+    }
+
+    unsafe fn lua_reset(L: &mut lua::ExternState) -> i32 {
+        // 0 args
+
+        L.pushbytes([RESET]);
+        1
+    }
+}
+
+/// Returns the text with all formatting codes removed, including color arguments
+pub fn strip(text: &[u8]) -> ~[u8] {
+    let mut out = vec::with_capacity(text.len());
+    let mut i = 0;
+    while i < text.len() {
+        let c = text[i];
+        i += 1;
+        if c == COLOR || c == HEX_COLOR {
+            // skip the foreground and optional background
+            let (max, valid): (uint, fn(u8) -> bool) = if c == COLOR {
+                (2, is_digit)
+            } else {
+                (6, is_hex_digit)
+            };
+            let n = skip_while(text, i, max, valid);
+            if n > 0 {
+                i += n;
+                if i + 1 < text.len() && text[i] == ',' as u8 && valid(text[i+1]) {
+                    i += 1 + skip_while(text, i + 1, max, valid);
+                }
+            }
+        } else if !is_toggle(c) {
+            out.push(c);
+        }
+    }
+    out
+}
+
+/// Returns how many bytes starting at `start`, up to `max`, satisfy `f`
+fn skip_while(text: &[u8], start: uint, max: uint, f: fn(u8) -> bool) -> uint {
+    text.slice_from(start).iter().take(max).take_while(|&&b| f(b)).count()
+}
+
+/// Returns whether the byte is a formatting code that takes no arguments
+fn is_toggle(b: u8) -> bool {
+    b == BOLD || b == RESET || b == REVERSE || b == ITALIC || b == UNDERLINE
+        || b == STRIKETHROUGH || b == MONOSPACE
+}
+
+fn is_digit(b: u8) -> bool {
+    b >= '0' as u8 && b <= '9' as u8
+}
+
+fn is_hex_digit(b: u8) -> bool {
+    is_digit(b) || (b >= 'a' as u8 && b <= 'f' as u8) || (b >= 'A' as u8 && b <= 'F' as u8)
+}
+
+/// Returns the text at 1 surrounded by the given control code
+unsafe fn wrap(L: &mut lua::ExternState, code: u8) -> i32 {
+    let text = L.checkbytes(1);
+    L.pushbytes([code]);
+    L.pushbytes(text);
+    L.pushbytes([code]);
+    L.concat(3);
+    1
+}
+
+/// Returns the color number for the color number or name at the given index
+unsafe fn checkcolor(L: &mut lua::ExternState, narg: i32) -> u8 {
+    if L.isnumber(narg) {
+        let n = L.tointeger(narg);
+        L.argcheck(n >= 0 && n < 100, narg, "color out of range");
+        return n as u8;
+    }
+    let name = L.checkbytes(narg);
+    match COLORS.iter().position(|c| c.as_bytes() == name) {
+        Some(i) => i as u8,
+        None => L.argerror(narg, "unknown color name")
+    }
+}
@@ -3,6 +3,44 @@
 //! Vends a package named 'irc' with a set of functions that manipulate the
 //! current connection. Also provides event handling.
 //!
+//! irc.addhandler(event, f) returns an opaque handle that can later be passed to
+//! irc.removehandler(handle) to unregister the function. irc.removehandler
+//! returns whether the handler was still registered. irc.once(event, f) is
+//! like irc.addhandler, except the handler is removed after it's first called.
+//!
+//! Both take an optional third argument, the handler's priority, which defaults to
+//! 0. Handlers with a higher priority are called first, including category and
+//! wildcard handlers, and handlers with the same priority are called in the order
+//! they were added. A handler that returns true consumes the event, so handlers
+//! after it aren't called, e.g. to let an anti-spam plugin swallow a message before
+//! trigger plugins see it.
+//!
+//! irc.on(name, f) and irc.emit(name, ...) let plugins talk to each other through
+//! events of their own, e.g. a storage plugin could emit "db.ready" for plugins
+//! that need it. irc.emit calls each f registered for the name with the emitted
+//! values, and returns once they've all run. These events are separate from IRC
+//! and special events, even if the names match. irc.on takes an optional priority
+//! and returns a handle like irc.addhandler, and its handlers can consume the event
+//! the same way.
+//!
+//! irc.addcommand(name, f, [options]) registers a command, run when a channel
+//! message starts with general.command_prefix and the name, e.g. "!weather Paris",
+//! or when a private message starts with the name, with or without the prefix.
+//! Names are case-insensitive, and a plugin can't replace another plugin's command.
+//! f is called as f(ctx, args...) with the message's words after the name. ctx has
+//! name, sender (a User), channel (nil in a private message), target (the channel,
+//! or the sender's nick in private), text (everything after the name) and
+//! reply(text), which sends text to the target. options may have help, a short
+//! description of the command, and minargs; a command given fewer arguments isn't
+//! called, and the sender gets a notice with the help instead. Commands run after
+//! PRIVMSG handlers, and not at all if one consumes the message.
+//! irc.removecommand(name) removes the calling plugin's command, returning whether
+//! it was registered.
+//!
+//! Unless a plugin registers its own, a built-in help command lists the registered
+//! commands with their help, a few at a time, in notices to the user who asked.
+//! "help 2" shows the second page, and "help weather" shows one command's help.
+//!
 //! Lua functions registered with irc.addhandler(event, f) are called with a
 //! string argument representing the event, followed by the sender, then the
 //! event's arguments.  Regular commands provide their arguments in the
@@ -18,14 +56,240 @@
 //! Note: if the prefix was not provided for a given command, it will be given
 //! to Lua as nil. Otherwise, it will be a table representation of the User.
 //!
-//! There are 6 special events that can be registered:
+//! The sender of an IRC line, and ctx.sender of a command, also has the line's IRCv3
+//! tags as tags, by name, e.g. sender.tags.account or sender.tags["+example.com/vendor"].
+//! Tags without a value are "", and the table is empty if the line had no tags. The
+//! account tag is given on servers with account-tag. The tags are part of the sender
+//! rather than another handler argument, so that the event's own arguments stay where
+//! existing handlers expect them.
+//!
+//! The sender also has time, when the line was sent, in seconds since the epoch with a
+//! fractional part, from its server-time tag on servers with server-time, or otherwise
+//! when it arrived. Loggers should use it instead of os.time(), since e.g. a bouncer's
+//! playback is sent long after the messages were.
+//!
+//! On Twitch, the sender of a line with badges and emotes tags also has badges, mapping
+//! each badge's name to its version, e.g. sender.badges.subscriber == "12", and emotes,
+//! an array of the emotes in the text, each a table with the emote's id and the first
+//! and last characters of the text it replaces, counted from 1. Either is empty if the
+//! message has none.
+//!
+//! irc.plugin{name=, version=, description=, author=} records metadata about the
+//! calling plugin, which should call it when it's loaded. All the fields are
+//! optional. The metadata is shown by /plugins, and the version is included when
+//! errors in the plugin's handlers are reported.
+//!
+//! irc.format and irc.utf8 hold helpers for mIRC formatting and UTF-8 text.
+//!
+//! irc.stripformat(text) returns text with all mIRC formatting codes removed.
+//!
+//! irc.maskmatch(mask, user) returns whether a hostmask such as *!*@*.example.com
+//! matches the user, which is either a User table or a raw nick!user@host prefix.
+//! `*` and `?` are wildcards, and a mask without ! or @ only matches the nick.
+//!
+//! irc.lower(s) lowercases a nick or channel according to the server's CASEMAPPING,
+//! and irc.eq(a, b) returns whether two nicks or channels are the same under it.
+//! Nicks should be compared with these rather than ==, since e.g. under the default
+//! rfc1459 casemapping "foo[a]" and "FOO{A}" are the same nick.
+//!
+//! Each connection has a random session id, given to the CONNECTED and
+//! DISCONNECTED handlers and returned by irc.session() during any event. Plugins
+//! can use it to tell apart state left over from an earlier connection.
+//!
+//! The bot connects to all of its configured servers at once, and each connection
+//! has its own plugin state, so plugins are loaded once per server. irc.server()
+//! returns the configured name of the connection's server, and bot.config.server
+//! holds the rest of its configuration.
+//!
+//! On servers with message-tags, irc.msgid() returns the id of the message being
+//! handled, or nil if the server didn't give it one. irc.reply_to(msgid, dst, text)
+//! sends a PRIVMSG marked as a reply to that message, which clients may show as a
+//! thread. Without message-tags it's sent as a plain PRIVMSG.
+//!
+//! irc.isupport() returns a table of the tokens the server sent in RPL_ISUPPORT, by
+//! name, e.g. isupport.NETWORK or isupport.CHANTYPES. Tokens without a value are "",
+//! and tokens the server didn't send are nil, so plugins should fall back to the RFC
+//! 1459 defaults, e.g. "#&" for CHANTYPES. irc.lower and irc.members already follow
+//! the server's CASEMAPPING and PREFIX.
+//!
+//! irc.away(nick) returns the nick's away message, "" if it isn't known, or nil if the
+//! user isn't known to be away. Users are tracked for the connection from the lines
+//! that dispatch irc.PRESENCE, so with away-notify it's kept up to date for everyone
+//! in the bot's channels without polling WHO.
+//!
+//! irc.hostmask(nick) returns the nick's current nick!user@host, or nil if the bot
+//! hasn't seen it. Hosts are learned from the lines users send and from WHO replies,
+//! and kept up to date through CHGHOST, so irc.maskmatch(mask, irc.hostmask(nick))
+//! checks a user who isn't the sender of the current event.
+//!
+//! irc.user(nick) returns a table of everything the bot knows about the nick, or nil if
+//! it knows nothing: nick, user and host, account (the services account they're logged
+//! in to, with account-tag, account-notify or extended-join, or from WHOIS), away (as
+//! irc.away returns it) and channels, which maps each channel they share with the bot,
+//! named as the bot joined it, to their prefixes there. Any of the values but nick and
+//! channels may be nil. A user is forgotten once they quit or leave the last channel
+//! they shared with the bot, so plugins can look them up instead of sending a WHOIS for
+//! every message.
+//!
+//! irc.watch(nick, callback) watches whether the nick is online, calling
+//! callback(nick, online) each time the server says it came online or went offline,
+//! including the first time. It returns a handle for irc.removehandler, followed by
+//! whether the nick is online, or nil if that isn't known yet. The nick stays watched
+//! until its last callback is removed with irc.removehandler, even across reloads, and
+//! the nicks in the server's watch list always are. It's followed with MONITOR where the
+//! server has it, and otherwise by polling with ISON every minute.
+//!
+//! On Twitch, irc.roomstate(channel) returns a table of the channel's settings from its
+//! ROOMSTATE tags, e.g. roomstate.slow or roomstate["followers-only"], and
+//! irc.userstate([channel]) the bot's own USERSTATE tags in the channel, e.g.
+//! userstate.mod or userstate.badges, or its GLOBALUSERSTATE tags without a channel.
+//! Both return nil until the server sends them.
+//!
+//! Lines in a batch are still dispatched one at a time as they arrive. irc.batch()
+//! returns the batch the line being handled belongs to, as a table with its id, type
+//! and params, or nil if it isn't in one. A plugin that handles irc.BATCH can use it
+//! to skip the individual lines, e.g. to treat a netsplit as one event rather than
+//! hundreds of QUITs.
+//!
+//! irc.history(target, [limit], callback) asks a server with draft/chathistory for the
+//! latest limit (default 50) messages in a channel or private conversation, and calls
+//! callback with an array of them, oldest first. Each message has sender (a User),
+//! command ("PRIVMSG", "NOTICE" or "ACTION"), target, text, time, msgid (or nil) and
+//! tags. If the playback doesn't arrive within 30 seconds, e.g. because the server
+//! doesn't support it, callback is called with nil and an error message.
+//!
+//! irc.query(command, params, callback) sends a command that queries the server, one
+//! of WHOIS, WHOWAS, WHO, NAMES, LIST, USERHOST, ISON, or MODE or TOPIC with one
+//! parameter, with params as an array of its parameters, and calls callback with the
+//! replies as an array of lines like those of irc.BATCH. On servers with
+//! labeled-response the command is sent with a label, so the replies are exactly the
+//! ones the server sent for it, even while other queries are running. Otherwise the
+//! numeric replies are collected until the one that ends them, e.g. RPL_ENDOFWHOIS, or
+//! an error numeric, so replies to other commands sent at the same time may be mixed
+//! in. If the replies don't arrive within 30 seconds, callback is called with nil and
+//! an error message.
+//!
+//! irc.members(channel) returns a table mapping the nick of each member of a channel
+//! the bot is in to their prefixes, highest first, e.g. members.alice == "@+" for an
+//! operator with voice and "" for someone with neither, or nil if the bot isn't in the
+//! channel. With multi-prefix a member's prefixes are all known from NAMES, and with
+//! userhost-in-names NAMES also gives irc.hostmask the hosts of everyone in the
+//! channel, without a WHO.
+//!
+//! irc.mode(channel, modes, [params]) changes a channel's modes, with modes a mode
+//! string such as "+ov-v" and params an array of its parameters, e.g.
+//! irc.mode("#chan", "+oo", {"alice", "bob"}). It's split into as many MODE lines as the
+//! server's ISUPPORT MODES needs, so plugins can change any number of modes at once.
+//! A mode whose parameter is missing is left out, along with the modes after it.
+//!
+//! irc.replayed() returns whether the message being handled was played back by a
+//! bouncer, on a server with bouncer = true, rather than sent since the bot connected,
+//! so plugins can skip reacting to history again. Commands aren't run for replayed
+//! messages.
+//!
+//! irc.lag() returns how long the server took to answer the bot's last keepalive PING,
+//! in seconds with a fractional part, or nil before the first answer. It's measured when
+//! nothing has arrived from the server for ping_interval seconds.
+//!
+//! irc.paste(text, callback) uploads text to the paste service configured in
+//! general.paste_url, and later calls callback with the paste's URL, or with nil
+//! followed by an error message. Plugins should use it for long or multi-line
+//! output instead of flooding a channel.
+//!
+//! Handlers run as coroutines, so they can call irc.await(event, [pred], [timeout])
+//! to wait for a later event, e.g. to send a WHOIS and wait for its RPL_ENDOFWHOIS.
+//! irc.await suspends the handler until the event is dispatched with arguments for
+//! which pred (called like a handler) returns true, and then returns the event's
+//! arguments as a handler would receive them. If timeout seconds pass first, it
+//! returns nothing. Handlers that are suspended don't return a value to the event.
+//!
+//! A handler that runs more than general.handler_instruction_limit Lua instructions,
+//! or for longer than general.handler_timeout seconds, without returning or suspending
+//! is aborted with an error, which is reported like any other error in a handler. A
+//! handler blocked in a library call, e.g. reading a file, can't be aborted until the
+//! call returns, but the watchdog reports the plugin and event while it's blocked. The
+//! same limits apply to callbacks, e.g. of tcp.connect or dns.resolve, and to running a
+//! plugin's file when it's loaded.
+//!
+//! Handlers registered for the event "*" (also available as irc.ALL) are called
+//! for every event, after the handlers for that specific event.
 //!
-//! irc.CONNECTED: No args
-//! irc.DISCONNECTED: No args
+//! Numeric replies are dispatched with their 3-digit code as the event name,
+//! e.g. "001". irc.numerics maps reply names to these event names, so
+//! irc.addhandler(irc.numerics.RPL_WELCOME, f) can be used instead.
+//!
+//! Handlers can also be registered for a whole category of numerics, and are
+//! called with the numeric's own event name:
+//!
+//! irc.SERVER_INFO: 001-005 and 250-266
+//! irc.ERROR_NUMERIC: 400-599
+//! irc.WHOIS_NUMERIC: the replies to WHOIS
+//! irc.MOTD_NUMERIC: the MOTD and its start, end, or absence
+//! irc.MONITOR_NUMERIC: 730-734
+//! irc.SASL_NUMERIC: 900-908
+//!
+//! There are 18 special events that can be registered:
+//!
+//! irc.CONNECTED: Session id
+//! irc.DISCONNECTED: Session id
 //! irc.RELOADED: No args, sent when plugins are reloaded instead of CONNECTED
 //! irc.ACTION: Sender, destination, text
 //! irc.CTCP: Sender, CTCP command, destination, optionally text
 //! irc.CTCPREPLY: Sender, CTCP command, destination, optionally text
+//! irc.SELFMSG: Sender, command ("PRIVMSG", "NOTICE" or "ACTION"), destination, text.
+//!              Sent instead of the usual event for the server's echo of a message the
+//!              bot sent, on servers with echo-message, so it's what was actually
+//!              delivered, and for messages sent from a bouncer's other clients, with
+//!              znc.in/self-message. Commands aren't run for it.
+//! irc.GREET: Joining user, channel, greeting. Sent before a configured greeting is
+//!            sent. A handler may return a string to replace the greeting, or false
+//!            to suppress it. Wildcard handlers don't receive this event.
+//! irc.FEED_ITEM: Feed name, item. Sent for each new item in a configured feed. The
+//!                item is a table with title, link, date and id values, which are
+//!                empty strings if the feed doesn't give them. Wildcard handlers don't
+//!                receive this event.
+//! irc.PRESENCE: Nick, away message or nil. Sent after the line that shows a user went
+//!               away, came back or changed their away message: an AWAY (with
+//!               away-notify), RPL_AWAY or a WHO reply. The message is "" if the server
+//!               didn't say it. Wildcard handlers don't receive this event.
+//! irc.HOSTCHANGE: Old User, new User. Sent after a CHGHOST line (with chghost), e.g.
+//!                 when services apply a cloak, so host-based checks can be redone.
+//!                 Wildcard handlers don't receive this event.
+//! irc.BATCH: Batch type, params, lines, tags. Sent when a batch (with the batch
+//!            capability) ends, e.g. a "netsplit" batch of QUITs or a "chathistory"
+//!            playback. params is an array of the batch's parameters, tags are the
+//!            message tags of the BATCH line that opened it, and lines is an array
+//!            with the arguments a handler got for each line in the batch, in order,
+//!            with n set to their count, so handler(unpack(line, 1, line.n)) replays
+//!            one. Each line also has tags and time, as its sender has them.
+//!            Wildcard handlers don't receive this event.
+//! irc.ALTNICK: Nick, configured nick. Sent once the bot is registered if the
+//!              configured nick was in use, so it fell back to an alternate. Wildcard
+//!              handlers don't receive this event.
+//! irc.KICKED: Channel, kicking User (with the server's name as the nick if a server
+//!             kicked), reason, and whether the bot will rejoin, as configured by the
+//!             server's rejoin_on_kick. Sent after the KICK line's own event. Wildcard
+//!             handlers don't receive this event.
+//! irc.DCC: Sending User, offer. Sent for a DCC SEND offer to the bot, after the
+//!          CTCP event. The offer is a table with nick, filename, size, ip, port and
+//!          token values, where token is nil unless it's a passive offer, and can be
+//!          given to dcc.accept. Wildcard handlers don't receive this event.
+//! irc.WATCH: Nick, and whether it's online now. Sent for every watched nick, from
+//!            the server's watch list or irc.watch, when it comes online or goes
+//!            offline. Wildcard handlers don't receive this event.
+//! irc.CLEARCHAT: Channel, nick or nil, seconds or nil. Sent after a Twitch CLEARCHAT
+//!                line, when a user was timed out for the given seconds or banned
+//!                (with no seconds), so their messages were removed, or the whole
+//!                channel's messages were (with no nick). Wildcard handlers don't
+//!                receive this event.
+//! irc.MODECHANGE: Channel, setting User (nil if the server set them), changes. Sent
+//!                 after a MODE line for one of the bot's channels, with the modes it
+//!                 set and unset already paired with their parameters according to
+//!                 the server's CHANMODES and PREFIX. changes is an array of tables
+//!                 with sign ("+" or "-"), mode (e.g. "o"), param (nil for a mode
+//!                 without one) and nick, which for a member status such as +o or +v
+//!                 is the member's nick as they use it, and nil otherwise. Wildcard
+//!                 handlers don't receive this event.
 //!
 //! A User (the sender value) is a table with the following values:
 //!
@@ -40,7 +304,16 @@ use lua;
 use irc;
 use irc::conn;
 use irc::conn::{Conn, Event};
-use std::{libc, mem, ptr};
+use http;
+use outbound;
+use outbound::Outbound;
+use dcc::Offer;
+use super::{dcc, format, mask, numerics, twitch, users, utf8};
+use super::task;
+use super::task::Tasks;
+use collections::TreeMap;
+use std::{libc, mem, ptr, str};
+use std::ascii::StrAsciiExt;
 use std::io::BufWriter;
 use std::iter::range_inclusive;
 
@@ -50,22 +323,265 @@ static EVT_RELOADED: &'static str = "-RELOADED";
 static EVT_ACTION: &'static str = "-ACTION";
 static EVT_CTCP: &'static str = "-CTCP";
 static EVT_CTCPREPLY: &'static str = "-CTCPREPLY";
+static EVT_GREET: &'static str = "-GREET";
+static EVT_FEED_ITEM: &'static str = "feed.item";
+static EVT_PRESENCE: &'static str = "-PRESENCE";
+static EVT_SELFMSG: &'static str = "-SELFMSG";
+static EVT_HOSTCHANGE: &'static str = "-HOSTCHANGE";
+static EVT_BATCH: &'static str = "-BATCH";
+static EVT_ALTNICK: &'static str = "-ALTNICK";
+static EVT_KICKED: &'static str = "-KICKED";
+static EVT_DCC: &'static str = "-DCC";
+static EVT_WATCH: &'static str = "-WATCH";
+static EVT_CLEARCHAT: &'static str = "-CLEARCHAT";
+static EVT_MODECHANGE: &'static str = "-MODECHANGE";
+// prefix of the handler table keys for irc.on events, so they can't be IRC events
+static BUS_PREFIX: &'static str = "bus:";
+// prefix of the handler table keys for irc.watch callbacks, followed by the lowercased nick
+static WATCH_PREFIX: &'static str = "watch:";
+
+// registry key for the table of open batches, by reference tag
+static BATCHES: &'static str = "batches";
+
+// registry key for the function that waits for a CHATHISTORY playback for irc.history
+static HISTORY_WAITER: &'static str = "history_waiter";
+// messages irc.history asks for by default
+static HISTORY_LIMIT: int = 50;
+// registry key for the function that waits for the replies to a command for irc.query
+static QUERY_WAITER: &'static str = "query_waiter";
+// the commands irc.query can send, and whether each only queries with 1 parameter,
+// since with more it would change something
+static QUERY_COMMANDS: &'static [(&'static str, bool)] = &[
+    ("WHOIS", false), ("WHOWAS", false), ("WHO", false), ("NAMES", false), ("LIST", false),
+    ("MODE", true), ("TOPIC", true), ("USERHOST", false), ("ISON", false)
+];
+
+// registry key for the table of commands registered with irc.addcommand, by name
+static COMMANDS: &'static str = "commands";
+// registry key for the prefix that starts a command in a channel message
+static COMMAND_PREFIX: &'static str = "command_prefix";
+// commands listed in each notice reply to the built-in help command
+static HELP_PAGE_LINES: uint = 5;
+static EVT_WILDCARD: &'static str = "*";
+static EVT_TICK: &'static str = "-TICK"; // internal, dispatched every second for irc.await
+
+// registry key for the function that runs handlers, callbacks and plugin chunks as
+// coroutines
+static HANDLER_RUNNER: &'static str = "handler_runner";
+// registry key for irc.await, which is created along with the runner
+static AWAIT: &'static str = "await";
+
+// Lua support for running handlers as coroutines, and irc.await. The chunk is called
+// with irc.addhandler, irc.removehandler, the tick event, the instruction limit and a
+// function that returns whether the watchdog says the running handler is out of time,
+// and returns the handler runner and irc.await. An awaiting coroutine is resumed by
+// ordinary handlers for the awaited event and for the tick event, so dispatch and plugin
+// tracking work as usual.
+static AWAIT_SRC: &'static str = r#"
+local addhandler, removehandler, tick, limit, expired = ...
+local sethook = debug.sethook
+local handlers = setmetatable({}, {__mode = "k"}) -- coroutines running handlers
+local step = limit and math.min(limit, 1000) or 1000 -- instructions between checks
+
+-- aborts a handler from its count hook. The hook then fires on every instruction, so a
+-- handler that catches the error can't keep running.
+local function abort(msg)
+    sethook(function() error(msg, 2) end, "", 1)
+    error(msg, 3)
+end
+
+-- sets a hook that aborts the coroutine once it runs out of instructions or time
+local function limit_hook(co)
+    local count = 0
+    sethook(co, function()
+        count = count + step
+        if limit and count >= limit then
+            abort("handler exceeded its limit of " .. limit .. " instructions")
+        elseif expired() then
+            abort("handler ran for longer than general.handler_timeout")
+        end
+    end, "", step)
+end
+
+local function finish(co, ok, ...)
+    if not ok then
+        error(debug.traceback(co, tostring((...))), 0)
+    end
+    return ...
+end
+
+local function resume(co, ...)
+    -- the limits start again each time it's resumed
+    limit_hook(co)
+    return finish(co, coroutine.resume(co, ...))
+end
+
+local function run(f, ...)
+    local co = coroutine.create(f)
+    handlers[co] = true
+    return resume(co, ...)
+end
+
+local function await(event, pred, timeout)
+    local co = coroutine.running()
+    if not co or not handlers[co] then
+        error("irc.await can only be called from an event handler", 2)
+    end
+    if pred ~= nil and type(pred) ~= "function" then
+        error("bad argument #2 to 'await' (function expected)", 2)
+    end
+    if timeout ~= nil and type(timeout) ~= "number" then
+        error("bad argument #3 to 'await' (number expected)", 2)
+    end
+    local handle, timer
+    handle = addhandler(event, function(...)
+        if pred and not pred(...) then return end
+        removehandler(handle)
+        if timer then removehandler(timer) end
+        resume(co, ...)
+    end)
+    if timeout then
+        local deadline = os.time() + timeout
+        timer = addhandler(tick, function()
+            if os.time() < deadline then return end
+            removehandler(timer)
+            removehandler(handle)
+            resume(co)
+        end)
+    end
+    return coroutine.yield()
+end
+
+return run, await
+"#;
+
+// Lua support for irc.history. The chunk is called with irc.addhandler,
+// irc.removehandler, the BATCH, tick, ACTION and SELFMSG events and irc.lower, and
+// returns a function that's called with the target and the callback. It waits for the
+// target's chathistory batch and calls the callback with its messages, from a handler
+// of the calling plugin, so errors in the callback are reported like any other.
+static HISTORY_SRC: &'static str = r#"
+local addhandler, removehandler, batch, tick, action, selfmsg, lower = ...
+local timeout = 30 -- seconds to wait for the playback
+
+-- turns a line of the batch into a message, or nil if it isn't one
+local function message(line)
+    local event, command, target, text = line[1]
+    if event == "PRIVMSG" or event == "NOTICE" then
+        command, target, text = event, line[3], line[4]
+    elseif event == action then
+        command, target, text = "ACTION", line[3], line[4]
+    elseif event == selfmsg then
+        command, target, text = line[3], line[4], line[5]
+    else
+        return nil
+    end
+    local tags = line.tags or {}
+    return {sender = line[2], command = command, target = target, text = text,
+            time = line.time, msgid = tags.msgid, tags = tags}
+end
+
+return function(target, callback)
+    local key = lower(target)
+    local deadline = os.time() + timeout
+    local handle, timer
+    handle = addhandler(batch, function(_, kind, params, lines)
+        if kind ~= "chathistory" or not params[1] or lower(params[1]) ~= key then return end
+        removehandler(handle)
+        removehandler(timer)
+        local msgs = {}
+        for _, line in ipairs(lines) do
+            local msg = message(line)
+            if msg then table.insert(msgs, msg) end
+        end
+        callback(msgs)
+    end)
+    timer = addhandler(tick, function()
+        if os.time() < deadline then return end
+        removehandler(timer)
+        removehandler(handle)
+        callback(nil, "timed out waiting for the history of " .. target)
+    end)
+end
+"#;
+
+// Lua support for irc.query. The chunk is called with irc.addhandler,
+// irc.removehandler, the wildcard, BATCH and tick events, functions that return the tags
+// and the time of the line being dispatched, and a function that sends a raw line, and
+// returns a function that's called with the command, its line, whether the server has
+// labeled-response, and the callback. With labeled-response the line is sent with a
+// label, and the reply with the same label, the labeled-response batch or the ACK ends
+// the query. Otherwise numeric replies are collected until one that ends the command's
+// replies.
+static QUERY_SRC: &'static str = r#"
+local addhandler, removehandler, wildcard, batch, tick, tags, time, send = ...
+local timeout = 30 -- seconds to wait for the replies
+local next_label = 0
+
+-- the numerics that end the replies to each command, without labeled-response
+local ends = {
+    WHOIS = {["318"] = true}, WHOWAS = {["369"] = true}, WHO = {["315"] = true},
+    NAMES = {["366"] = true}, LIST = {["323"] = true}, MODE = {["324"] = true, ["221"] = true},
+    TOPIC = {["331"] = true, ["333"] = true}, USERHOST = {["302"] = true},
+    ISON = {["303"] = true}
+}
+
+-- the arguments of a handler as a line, as given by irc.BATCH
+local function pack(...)
+    return {n = select("#", ...), tags = tags(), time = time(), ...}
+end
+
+return function(command, line, labeled, callback)
+    local deadline = os.time() + timeout
+    local handles = {}
+    local function finish(...)
+        for _, handle in ipairs(handles) do removehandler(handle) end
+        callback(...)
+    end
+    if labeled then
+        next_label = next_label + 1
+        local label = tostring(next_label)
+        table.insert(handles, addhandler(wildcard, function(event, ...)
+            -- a labeled BATCH line opens the batch with the replies
+            if tags().label ~= label or event == "BATCH" then return end
+            if event == "ACK" then finish({}) else finish({pack(event, ...)}) end
+        end))
+        table.insert(handles, addhandler(batch, function(_, kind, params, lines, btags)
+            if btags.label == label then finish(lines) end
+        end))
+        line = "@label=" .. label .. " " .. line
+    else
+        local lines = {}
+        table.insert(handles, addhandler(wildcard, function(event, ...)
+            local code = tonumber(event:match("^%d%d%d$"))
+            if not code then return end
+            table.insert(lines, pack(event, ...))
+            if ends[command][event] or (code >= 400 and code < 600) then finish(lines) end
+        end))
+    end
+    table.insert(handles, addhandler(tick, function()
+        if os.time() >= deadline then
+            finish(nil, "timed out waiting for the replies to " .. command)
+        end
+    end))
+    send(line)
+end
+"#;
 
 lua_extern_pub! {
     unsafe fn lua_require(L: &mut lua::ExternState) -> i32 {
         // 1 argument is passed: modname
 
-        // we're going to store the Conn in an Option in the registry
-        // the key for the registry is lua_require as a lightuserdata
-        L.pushlightuserdata(lua_require as *mut libc::c_void);
-        let connptr = L.newuserdata(mem::size_of::<*mut Conn>()) as *mut *mut Conn;
-        *connptr = ptr::mut_null();
-        L.settable(lua::REGISTRYINDEX);
-
         // register our library functions
         L.newtable();
         L.registerlib(None, [
             ("addhandler", lua_addhandler),
+            ("removehandler", lua_removehandler),
+            ("once", lua_once),
+            ("on", lua_on),
+            ("emit", lua_emit),
+            ("addcommand", lua_addcommand),
+            ("removecommand", lua_removecommand),
             ("host", lua_host),
             ("me", lua_me),
             //("send_raw", lua_send_raw),
@@ -73,6 +589,30 @@ lua_extern_pub! {
             //("quit", lua_quit),
             ("privmsg", lua_privmsg),
             ("notice",  lua_notice),
+            ("stripformat", lua_stripformat),
+            ("maskmatch", lua_maskmatch),
+            ("lower", lua_lower),
+            ("eq", lua_eq),
+            ("session", lua_session),
+            ("server", lua_server),
+            ("msgid", lua_msgid),
+            ("replayed", lua_replayed),
+            ("isupport", lua_isupport),
+            ("lag", lua_lag),
+            ("away", lua_away),
+            ("hostmask", lua_hostmask),
+            ("user", lua_user),
+            ("watch", lua_watch),
+            ("roomstate", lua_roomstate),
+            ("userstate", lua_userstate),
+            ("members", lua_members),
+            ("mode", lua_mode),
+            ("batch", lua_batch),
+            ("history", lua_history),
+            ("query", lua_query),
+            ("reply_to", lua_reply_to),
+            ("paste", lua_paste),
+            ("plugin", lua_plugin),
             //("join", lua_join),
             //("quit", lua_quit)
         ]);
@@ -90,6 +630,92 @@ lua_extern_pub! {
         L.setfield(-2, "CTCP");
         L.pushstring(EVT_CTCPREPLY);
         L.setfield(-2, "CTCPREPLY");
+        L.pushstring(EVT_GREET);
+        L.setfield(-2, "GREET");
+        L.pushstring(EVT_FEED_ITEM);
+        L.setfield(-2, "FEED_ITEM");
+        L.pushstring(EVT_PRESENCE);
+        L.setfield(-2, "PRESENCE");
+        L.pushstring(EVT_SELFMSG);
+        L.setfield(-2, "SELFMSG");
+        L.pushstring(EVT_HOSTCHANGE);
+        L.setfield(-2, "HOSTCHANGE");
+        L.pushstring(EVT_BATCH);
+        L.setfield(-2, "BATCH");
+        L.pushstring(EVT_ALTNICK);
+        L.setfield(-2, "ALTNICK");
+        L.pushstring(EVT_KICKED);
+        L.setfield(-2, "KICKED");
+        L.pushstring(EVT_DCC);
+        L.setfield(-2, "DCC");
+        L.pushstring(EVT_WATCH);
+        L.setfield(-2, "WATCH");
+        L.pushstring(EVT_CLEARCHAT);
+        L.setfield(-2, "CLEARCHAT");
+        L.pushstring(EVT_MODECHANGE);
+        L.setfield(-2, "MODECHANGE");
+        L.pushstring(EVT_WILDCARD);
+        L.setfield(-2, "ALL");
+
+        L.getfield(lua::REGISTRYINDEX, AWAIT);
+        L.setfield(-2, "await");
+
+        // load the support for irc.history
+        match L.loadstring(HISTORY_SRC) {
+            Ok(()) => (),
+            Err(_) => {
+                let msg = L.describe(-1);
+                L.errorstr(format!("could not load irc.history support: {}", msg).as_slice());
+            }
+        }
+        L.pushcfunction(lua_addhandler);
+        L.pushcfunction(lua_removehandler);
+        L.pushstring(EVT_BATCH);
+        L.pushstring(EVT_TICK);
+        L.pushstring(EVT_ACTION);
+        L.pushstring(EVT_SELFMSG);
+        L.pushcfunction(lua_lower);
+        L.call(7, 1);
+        L.setfield(lua::REGISTRYINDEX, HISTORY_WAITER);
+
+        // load the support for irc.query
+        match L.loadstring(QUERY_SRC) {
+            Ok(()) => (),
+            Err(_) => {
+                let msg = L.describe(-1);
+                L.errorstr(format!("could not load irc.query support: {}", msg).as_slice());
+            }
+        }
+        L.pushcfunction(lua_addhandler);
+        L.pushcfunction(lua_removehandler);
+        L.pushstring(EVT_WILDCARD);
+        L.pushstring(EVT_BATCH);
+        L.pushstring(EVT_TICK);
+        L.pushcfunction(lua_tags);
+        L.pushcfunction(lua_time);
+        L.pushcfunction(lua_send_query);
+        L.call(8, 1);
+        L.setfield(lua::REGISTRYINDEX, QUERY_WAITER);
+
+        // irc.numerics maps the names of numeric replies to their event names
+        L.createtable(0, numerics::NUMERICS.len() as i32);
+        for &(name, code) in numerics::NUMERICS.iter() {
+            push_numeric(L, code);
+            L.setfield(-2, name);
+        }
+        L.setfield(-2, "numerics");
+        for &(name, _) in numerics::CATEGORIES.iter() {
+            L.pushstring(format!("-{}", name).as_slice());
+            L.setfield(-2, name);
+        }
+
+        // irc.format holds the formatting helpers
+        format::push_table(L);
+        L.setfield(-2, "format");
+
+        // irc.utf8 holds the UTF-8 helpers
+        utf8::push_table(L);
+        L.setfield(-2, "utf8");
 
         1
     }
@@ -103,35 +729,64 @@ lua_extern_pub! {
 
         L.settop(0); // clear the stack
 
+        // numerics are also dispatched to their categories
+        let mut categories = ~[];
+        // the sender, destination and text of a PRIVMSG that may be a command
+        let mut privmsg: Option<(&irc::User, &[u8], &[u8])> = None;
+
         // get the event name
         match *event {
             conn::Connected => {
                 L.pushstring(EVT_CONNECTED);
+                super::push_session(L);
             }
             conn::Disconnected => {
                 L.pushstring(EVT_DISCONNECTED);
+                super::push_session(L);
             }
             conn::LineReceived(ref line) => {
                 let conn::Line{ref command, ref args, ref prefix} = *line;
 
+                match *command {
+                    conn::IRCCmd(ref cmd) if cmd.as_slice() == "BATCH" && !args.is_empty() => {
+                        update_batches(L, args.as_slice());
+                    }
+                    _ => ()
+                }
+
+                // with echo-message, the server sends our own messages back to us
+                let from_me = match *prefix {
+                    None => false,
+                    Some(ref user) => {
+                        let me = getconn(L).me().nick();
+                        super::casemapping(L).eq(user.nick(), me)
+                    }
+                };
+
                 match *command {
                     conn::IRCCode(code) => {
-                        // construct our string on the stack
-                        let mut buf = [0u8, ..64];
-                        let n = {
-                            let mut w = BufWriter::new(buf);
-                            match write!(&mut w, "{:03u}", code).and_then(|_| w.tell()) {
-                                Ok(n) => n,
-                                Err(e) => {
-                                    drop(e);
-                                    L.errorstr("could not format IRCCode");
-                                }
-                            }
-                        };
-                        L.pushbytes(buf.slice_to(n as uint));
+                        push_numeric(L, code);
+                        categories = numerics::categories(code);
+                    }
+                    conn::IRCCmd(ref cmd) if from_me && (cmd.as_slice() == "PRIVMSG" ||
+                                                         cmd.as_slice() == "NOTICE") => {
+                        L.pushstring(EVT_SELFMSG);
+                        L.pushstring(cmd.as_slice());
+                    }
+                    conn::IRCAction(ref dst) if from_me => {
+                        L.pushstring(EVT_SELFMSG);
+                        L.pushstring("ACTION");
+                        L.pushbytes(dst.as_slice());
                     }
                     conn::IRCCmd(ref cmd) => {
                         L.pushstring(cmd.as_slice());
+                        // commands aren't run again for messages a bouncer plays back
+                        if cmd.as_slice() == "PRIVMSG" && args.len() == 2 &&
+                           !super::is_replayed(L) {
+                            privmsg = prefix.as_ref().map(|user| {
+                                (user, args[0].as_slice(), args[1].as_slice())
+                            });
+                        }
                     }
                     conn::IRCAction(ref dst) => {
                         L.pushstring(EVT_ACTION);
@@ -149,19 +804,22 @@ lua_extern_pub! {
                     }
                 }
 
+                // lines in a batch are also collected for the BATCH event
+                push_current_batch(L);
+                let batched = !L.isnil(-1);
+                L.pop(1);
+
                 // ensure we actually have a handler for this event before proceeding
-                L.pushlightuserdata(lua_addhandler as *mut libc::c_void);
-                L.gettable(lua::REGISTRYINDEX);
-                if !L.istable(-1) {
-                    return 0;
-                }
-                L.pushvalue(1); // event name
-                L.gettable(-2);
-                if !L.istable(-1) || L.objlen(-1) == 0 {
+                if !batched && !has_handlers(L, categories.as_slice()) {
+                    match privmsg {
+                        None => (),
+                        Some((user, dst, text)) => {
+                            L.settop(0);
+                            dispatch_command(L, user, dst, text);
+                        }
+                    }
                     return 0;
                 }
-                // we have at least one handler; continue on
-                L.pop(2);
 
                 // construct the sender
                 match *prefix {
@@ -169,7 +827,7 @@ lua_extern_pub! {
                         L.pushnil();
                     }
                     Some(ref user) => {
-                        push_user(L, user);
+                        push_sender(L, user);
                     }
                 }
                 // move sender just after the event name
@@ -178,216 +836,1862 @@ lua_extern_pub! {
                 for arg in args.iter() {
                     L.pushbytes(*arg);
                 }
+                if batched {
+                    record_batch_line(L);
+                }
             }
         }
 
-        dispatch_event_inner(L);
+        // commands are run unless a handler consumed the message
+        if !dispatch_event_inner(L, categories.as_slice(), true) {
+            match privmsg {
+                None => (),
+                Some((user, dst, text)) => {
+                    L.settop(0);
+                    dispatch_command(L, user, dst, text);
+                }
+            }
+        }
         0
     }
 
-    unsafe fn lua_dispatch_reloaded(L: &mut lua::ExternState) -> i32 {
-        // 0 args
+    unsafe fn lua_dispatch_greet(L: &mut lua::ExternState) -> i32 {
+        // 3 args: user, channel, greeting
 
-        L.settop(0); // clear the stack
+        let userptr = L.touserdata(1) as *irc::User;
+        L.argcheck(userptr.is_not_null(), 1, "expected User");
+        L.checkbytes(2);
+        L.checkbytes(3);
+        L.settop(3);
 
-        L.pushstring(EVT_RELOADED);
+        push_user(L, &*userptr);
+        L.replace(1);
+        L.pushstring(EVT_GREET);
+        L.insert(1);
 
-        dispatch_event_inner(L);
-        0
+        dispatch_filter_inner(L);
+        1
     }
-}
 
-unsafe fn dispatch_event_inner(L: &mut lua::ExternState) {
-    // our event arguments are all on the stack
-    let nargs = L.gettop();
-    // get the handler list and call each one with a copy of the arguments
-    L.pushlightuserdata(lua_addhandler as *mut libc::c_void);
-    L.gettable(lua::REGISTRYINDEX);
-    if !L.istable(-1) {
-        return; // no handlers
-    }
-    L.pushvalue(1); // event name
-    L.gettable(-2);
-    if !L.istable(-1) {
-        return; // no handlers
-    }
-    L.pushnil(); // first key
-    while L.next(-2) {
-        // key is -2, value is -1
-        // copy all the arguments; deep-copy the sender table
-        for i in range_inclusive(1, nargs) {
-            if L.istable(i) {
-                // copy it
-                L.newtable();
-                L.pushnil();
-                while L.next(i) {
-                    L.pushvalue(-2); // copy the key
-                    L.insert(-2); // move it behind the value
-                    L.settable(-4); // set key=value in the new table
-                    // leave behind the key for next
-                }
-            } else {
-                L.pushvalue(i);
-            }
-        }
-        match L.pcall(nargs, 0, 0) {
-            Ok(()) => (),
-            Err(e) => {
-                println!("Error dispatching IRC event: {}: {}", e, L.describe(-1));
-                L.pop(1);
-            }
-        }
-    }
-}
+    unsafe fn lua_dispatch_feed_item(L: &mut lua::ExternState) -> i32 {
+        // 2 args: feed name, item
 
-unsafe fn push_user(L: &mut lua::ExternState, user: &irc::User) {
-    L.createtable(0, 4);
-    L.pushbytes(user.raw());
-    L.setfield(-2, "raw");
-    L.pushbytes(user.nick());
-    L.setfield(-2, "nick");
-    match user.user() {
-        None => L.pushnil(),
-        Some(v) => L.pushbytes(v)
-    }
-    L.setfield(-2, "user");
-    match user.user() {
-        None => L.pushnil(),
-        Some(v) => L.pushbytes(v)
-    }
-    L.setfield(-2, "host");
-}
+        L.checkbytes(1);
+        L.checktype(2, lua::Type::Table);
+        L.settop(2);
 
-// unsafe because the Conn isn't really 'static
-unsafe fn getconn(L: &mut lua::ExternState) -> &'static mut Conn<'static> {
-    L.pushlightuserdata(lua_require as *mut libc::c_void);
-    L.gettable(lua::REGISTRYINDEX);
-    let ptr = L.touserdata(-1) as *mut *mut Conn<'static>;
-    if ptr.is_null() {
-        L.errorstr("could not retrieve connection information");
-    }
-    let ptr = *ptr;
-    if ptr.is_null() {
-        L.errorstr("no active connection");
+        L.pushstring(EVT_FEED_ITEM);
+        L.insert(1);
+        dispatch_event_inner(L, [], false);
+        0
     }
-    &mut *ptr
-}
 
-pub fn activate_conn(L: &mut lua::State, conn: &mut Conn) {
-    L.pushlightuserdata(lua_require as *mut libc::c_void);
-    L.gettable(lua::REGISTRYINDEX);
-    let ptr = L.touserdata(-1) as *mut *mut Conn;
-    if ptr.is_null() {
-        L.errorstr("could not retrieve connection information");
-    }
-    unsafe { *ptr = conn as *mut Conn };
-}
+    unsafe fn lua_dispatch_presence(L: &mut lua::ExternState) -> i32 {
+        // 2 args: nick, away message or nil
 
-pub fn deactivate_conn(L: &mut lua::State) {
-    L.pushlightuserdata(lua_require as *mut libc::c_void);
-    L.gettable(lua::REGISTRYINDEX);
-    let ptr = L.touserdata(-1) as *mut *mut Conn;
-    if ptr.is_null() {
-        L.errorstr("could not retrieve connection information");
+        L.checkbytes(1);
+        L.settop(2);
+
+        L.pushstring(EVT_PRESENCE);
+        L.insert(1);
+        dispatch_event_inner(L, [], false);
+        0
     }
-    unsafe { *ptr = ptr::mut_null() };
-}
 
-lua_extern! {
-    unsafe fn lua_addhandler(L: &mut lua::ExternState) -> i32 {
-        // 2 args: event, func
+    unsafe fn lua_dispatch_alt_nick(L: &mut lua::ExternState) -> i32 {
+        // 2 args: nick, configured nick
 
         L.checkbytes(1);
-        L.checktype(2, lua::Type::Function);
+        L.checkbytes(2);
+        L.settop(2);
 
-        L.settop(2); // throw away any extra values
+        L.pushstring(EVT_ALTNICK);
+        L.insert(1);
+        dispatch_event_inner(L, [], false);
+        0
+    }
 
-        // get or create handler table; key is lua_addhandler
-        L.pushlightuserdata(lua_addhandler as *mut libc::c_void);
-        L.gettable(lua::REGISTRYINDEX);
-        if !L.istable(3) {
-            L.pop(1);
-            L.newtable();
-            L.pushlightuserdata(lua_addhandler as *mut libc::c_void);
-            L.pushvalue(3);
-            L.settable(lua::REGISTRYINDEX);
-        }
-        // table is stack entry 3
+    unsafe fn lua_dispatch_kicked(L: &mut lua::ExternState) -> i32 {
+        // 4 args: channel, kicking User, reason, whether we'll rejoin
 
-        // get or create the array
-        L.pushvalue(1); // copy the event to the top
-        L.gettable(3);
-        if !L.istable(4) {
-            L.pop(1);
-            L.newtable();
-            L.pushvalue(1); // copy event to top
-            L.pushvalue(4);
-            L.settable(3);
+        let chan = L.checkbytes(1);
+        let userptr = L.touserdata(2) as *irc::User;
+        let reason = L.checkbytes(3);
+        let rejoin = L.toboolean(4);
+
+        L.settop(0);
+        L.pushstring(EVT_KICKED);
+        L.pushbytes(chan);
+        push_user(L, &*userptr);
+        L.pushbytes(reason);
+        L.pushboolean(rejoin);
+        dispatch_event_inner(L, [], false);
+        0
+    }
+
+    unsafe fn lua_dispatch_dcc(L: &mut lua::ExternState) -> i32 {
+        // 2 args: sending User, Offer
+
+        let userptr = L.touserdata(1) as *irc::User;
+        L.argcheck(userptr.is_not_null(), 1, "expected User");
+        let offerptr = L.touserdata(2) as *Offer;
+        L.argcheck(offerptr.is_not_null(), 2, "expected Offer");
+
+        // the reply to one of our passive offers goes to the waiting transfer instead
+        if dcc::passive_reply(L, &*offerptr) {
+            return 0;
         }
-        // array is stack entry 4
+        L.settop(0);
+        L.pushstring(EVT_DCC);
+        push_user(L, &*userptr);
+        dcc::push_offer(L, (*userptr).nick(), &*offerptr);
+        dispatch_event_inner(L, [], false);
+        0
+    }
+
+    unsafe fn lua_dispatch_watch(L: &mut lua::ExternState) -> i32 {
+        // 2 args: nick, whether it's online
 
-        let len = L.objlen(4); // get table length
-        L.pushinteger(len as int + 1);
-        L.pushvalue(2); // copy function to top
-        L.settable(4); // set ary[len+1]=func
-        // and return
+        let nick = L.checkbytes(1).to_owned();
+        let online = L.toboolean(2);
+
+        // the nick's irc.watch callbacks first, and then irc.WATCH
+        L.settop(0);
+        push_watch_event(L, nick);
+        L.pushbytes(nick);
+        L.pushboolean(online);
+        dispatch_event_inner(L, [], false);
+        L.settop(0);
+        L.pushstring(EVT_WATCH);
+        L.pushbytes(nick);
+        L.pushboolean(online);
+        dispatch_event_inner(L, [], false);
         0
     }
 
-// *** IRC package functions ***
+    unsafe fn lua_dispatch_clear_chat(L: &mut lua::ExternState) -> i32 {
+        // 3 args: channel, nick or nil, timeout in seconds or nil
 
-    unsafe fn lua_host(L: &mut lua::ExternState) -> i32 {
-        // 0 args
+        let chan = L.checkbytes(1);
+        let nick = if L.isnil(2) { None } else { Some(L.checkbytes(2)) };
+        let duration = if L.isnil(3) { None } else { Some(L.tointeger(3)) };
 
-        let conn = getconn(L);
+        L.settop(0);
+        L.pushstring(EVT_CLEARCHAT);
+        L.pushbytes(chan);
+        match nick {
+            None => L.pushnil(),
+            Some(nick) => L.pushbytes(nick)
+        }
+        match duration {
+            None => L.pushnil(),
+            Some(secs) => L.pushinteger(secs)
+        }
+        dispatch_event_inner(L, [], false);
+        0
+    }
 
-        // construct our response on the stack
-        let mut buf = [0u8, ..64];
-        let n = {
-            let mut w = BufWriter::new(buf);
-            match write!(&mut w, "{}", conn.host()).and_then(|_| w.tell()) {
-                Ok(n) => n,
-                Err(e) => {
-                    drop(e);
-                    L.errorstr("could not format host");
+    unsafe fn lua_dispatch_mode_change(L: &mut lua::ExternState) -> i32 {
+        // 3 args: channel, setting User or nil, ModeChange slice
+
+        let chan = L.checkbytes(1);
+        let userptr = L.touserdata(2) as *irc::User;
+        let changesptr = L.touserdata(3) as *&[users::ModeChange];
+        L.argcheck(changesptr.is_not_null(), 3, "expected changes");
+        let changes = *changesptr;
+
+        L.settop(0);
+        L.pushstring(EVT_MODECHANGE);
+        L.pushbytes(chan);
+        if userptr.is_null() {
+            L.pushnil();
+        } else {
+            push_user(L, &*userptr);
+        }
+        L.createtable(changes.len() as i32, 0);
+        for (i, change) in changes.iter().enumerate() {
+            L.createtable(0, 4);
+            L.pushstring(if change.adding { "+" } else { "-" });
+            L.setfield(-2, "sign");
+            L.pushbytes([change.mode]);
+            L.setfield(-2, "mode");
+            match change.param {
+                None => (),
+                Some(ref param) => {
+                    L.pushbytes(param.as_slice());
+                    L.setfield(-2, "param");
                 }
             }
-        };
-        L.pushbytes(buf.slice_to(n as uint));
-        1
+            match change.nick {
+                None => (),
+                Some(ref nick) => {
+                    L.pushbytes(nick.as_slice());
+                    L.setfield(-2, "nick");
+                }
+            }
+            L.rawseti(-2, i as i32 + 1);
+        }
+        dispatch_event_inner(L, [], false);
+        0
     }
 
-    unsafe fn lua_me(L: &mut lua::ExternState) -> i32 {
-        // 0 args
+    unsafe fn lua_dispatch_host_change(L: &mut lua::ExternState) -> i32 {
+        // 3 args: old User, new username, new host
 
-        let conn = getconn(L);
+        let userptr = L.touserdata(1) as *irc::User;
+        L.argcheck(userptr.is_not_null(), 1, "expected User");
+        let nick = (*userptr).nick();
+        let user = L.checkbytes(2);
+        let host = L.checkbytes(3);
 
-        // return a user table
-        push_user(L, conn.me());
-        1
+        L.settop(0);
+        L.pushstring(EVT_HOSTCHANGE);
+        push_user(L, &*userptr);
+        push_user_parts(L, nick, user, host);
+        dispatch_event_inner(L, [], false);
+        0
     }
 
-    unsafe fn lua_privmsg(L: &mut lua::ExternState) -> i32 {
-        // 2 args: dst, message
-
-        let dst = L.checkbytes(1);
-        let msg = L.checkbytes(2);
+    unsafe fn lua_dispatch_tick(L: &mut lua::ExternState) -> i32 {
+        // 0 args
 
-        let conn = getconn(L);
+        L.settop(0); // clear the stack
 
-        conn.privmsg(dst, msg);
+        L.pushstring(EVT_TICK);
+        L.pushstring(EVT_TICK);
+        push_handlers(L);
+        let found = L.istable(-1) && L.objlen(-1) > 0;
+        L.pop(1);
+        if found {
+            dispatch_event_inner(L, [], false);
+        }
         0
     }
 
-    unsafe fn lua_notice(L: &mut lua::ExternState) -> i32 {
-        // 2 args: dst, message
+    unsafe fn lua_print_handlers(L: &mut lua::ExternState) -> i32 {
+        // 1 arg: event filter, or nil for all events
 
-        let dst = L.checkbytes(1);
-        let msg = L.checkbytes(2);
+        let filter = if L.isnoneornil(1) { None } else { Some(L.checkbytes(1).to_owned()) };
 
-        let conn = getconn(L);
+        // plugin -> event -> info
+        let mut plugins: TreeMap<~str, TreeMap<~str, HandlerInfo>> = TreeMap::new();
+        L.pushlightuserdata(lua_addhandler as *mut libc::c_void);
+        L.gettable(lua::REGISTRYINDEX);
+        if L.istable(-1) {
+            let handlers = L.gettop();
+            L.pushnil();
+            while L.next(handlers) {
+                let event = L.tobytes(-2).map_or(~[], |s| s.to_owned());
+                if filter.as_ref().map_or(false, |f| *f != event) || !L.istable(-1) {
+                    L.pop(1);
+                    continue;
+                }
+                let event = str::from_utf8_lossy(event).into_owned();
+                for i in range_inclusive(1, L.objlen(-1) as i32) {
+                    L.rawgeti(-1, i);
+                    L.getfield(-1, "plugin");
+                    let plugin = L.tostring(-1).map_or(~"(unknown)", |s| s.to_owned());
+                    L.getfield(-2, "errors");
+                    let errors = L.tointeger(-1) as uint;
+                    L.getfield(-3, "lasterror");
+                    let lasterror = L.tostring(-1).map(|s| s.to_owned());
+                    L.pop(4);
+                    let events = plugins.find_or_insert_with(plugin, |_| TreeMap::new());
+                    let info = events.find_or_insert_with(event.clone(), |_| {
+                        HandlerInfo { count: 0, errors: 0, lasterror: None }
+                    });
+                    info.count += 1;
+                    info.errors += errors;
+                    if lasterror.is_some() {
+                        info.lasterror = lasterror;
+                    }
+                }
+                L.pop(1); // pop the list, leaving the key for next
+            }
+        }
+        L.pop(1);
 
-        conn.notice(dst, msg);
+        if plugins.is_empty() {
+            println!("No handlers are registered");
+        }
+        for (plugin, events) in plugins.iter() {
+            println!("{}:", *plugin);
+            for (event, info) in events.iter() {
+                let s = if info.count == 1 { "" } else { "s" };
+                match info.lasterror {
+                    None => println!("  {}: {} handler{}", *event, info.count, s),
+                    Some(ref e) => {
+                        println!("  {}: {} handler{}, {} errors, last: {}", *event, info.count,
+                                 s, info.errors, *e)
+                    }
+                }
+            }
+        }
         0
     }
+
+    unsafe fn lua_clear_handlers(L: &mut lua::ExternState) -> i32 {
+        // 1 arg: plugin
+
+        let plugin = L.checkbytes(1).to_owned();
+        let mut count = 0;
+        L.pushlightuserdata(lua_addhandler as *mut libc::c_void);
+        L.gettable(lua::REGISTRYINDEX);
+        if L.istable(-1) {
+            let handlers = L.gettop();
+            L.pushnil();
+            while L.next(handlers) {
+                let list = L.gettop();
+                if L.istable(list) {
+                    // walk backwards, since removing shifts later entries down
+                    let mut i = L.objlen(list) as i32;
+                    while i >= 1 {
+                        L.rawgeti(list, i);
+                        L.getfield(-1, "plugin");
+                        let matches = L.tobytes(-1).map_or(false, |p| p == plugin.as_slice());
+                        L.pop(1);
+                        if matches {
+                            let entry = L.gettop();
+                            remove_handler(L, list, entry);
+                            count += 1;
+                        }
+                        L.pop(1);
+                        i -= 1;
+                    }
+                }
+                L.pop(1); // pop the list, leaving the key for next
+            }
+        }
+        L.pop(1);
+        // clearing existing fields is allowed while traversing
+        L.getfield(lua::REGISTRYINDEX, COMMANDS);
+        if L.istable(-1) {
+            let commands = L.gettop();
+            L.pushnil();
+            while L.next(commands) {
+                L.getfield(-1, "plugin");
+                let matches = L.tobytes(-1).map_or(false, |p| p == plugin.as_slice());
+                L.pop(2); // pop the plugin and the entry, leaving the key for next
+                if matches {
+                    L.pushvalue(-1);
+                    L.pushnil();
+                    L.settable(commands);
+                    count += 1;
+                }
+            }
+        }
+        L.pop(1);
+        L.pushinteger(count);
+        1
+    }
+
+    unsafe fn lua_dispatch_reloaded(L: &mut lua::ExternState) -> i32 {
+        // 0 or 1 args: the only plugin to dispatch to, or nil for all of them
+
+        let plugin = if L.isnoneornil(1) { None } else { Some(L.checkbytes(1).to_owned()) };
+        L.settop(0); // clear the stack
+
+        L.pushstring(EVT_RELOADED);
+
+        dispatch_event_to(L, [], true, plugin.as_ref().map(|p| p.as_slice()));
+        0
+    }
+}
+
+unsafe fn dispatch_event_inner(L: &mut lua::ExternState, categories: &[~str],
+                               wildcard: bool) -> bool {
+    dispatch_event_to(L, categories, wildcard, None)
+}
+
+/// Dispatches the event on the stack like dispatch_event_inner, but if `plugin` is given
+/// only that plugin's handlers are called. Returns whether a handler consumed the event.
+unsafe fn dispatch_event_to(L: &mut lua::ExternState, categories: &[~str], wildcard: bool,
+                            plugin: Option<&[u8]>) -> bool {
+    // our event arguments are all on the stack
+    let nargs = L.gettop();
+    // collect the handlers for the event followed by the category and wildcard handlers
+    // into a new list, since handlers may add or remove handlers
+    L.newtable();
+    let list = L.gettop();
+    L.pushvalue(1); // event name
+    let mut len = append_handlers(L, list, 0);
+    for category in categories.iter() {
+        L.pushstring(category.as_slice());
+        len = append_handlers(L, list, len);
+    }
+    if wildcard {
+        L.pushstring(EVT_WILDCARD);
+        len = append_handlers(L, list, len);
+    }
+    sort_by_priority(L, list, len);
+    // call each handler with a copy of the arguments, until one returns true
+    let mut consumed = false;
+    for i in range_inclusive(1, len) {
+        L.rawgeti(list, i);
+        let wanted = plugin.map_or(true, |plugin| {
+            L.getfield(-1, "plugin");
+            let matches = L.tobytes(-1).map_or(false, |p| p == plugin);
+            L.pop(1);
+            matches
+        });
+        if wanted && prepare_entry(L) && call_handler(L, nargs, 1) {
+            consumed = L.type_(-1) == Some(lua::Type::Boolean) && L.toboolean(-1);
+            L.pop(1); // pop result
+            if consumed {
+                L.pop(1); // pop handler entry
+                break;
+            }
+        }
+        L.pop(1); // pop handler entry
+    }
+    L.pushnil();
+    L.setfield(lua::REGISTRYINDEX, super::CURRENT_PLUGIN);
+    consumed
+}
+
+/// Dispatches the event on the stack to its handlers as a filter on its last argument.
+/// Each handler may return false to suppress the value, or a string to replace it for
+/// the remaining handlers. Pushes the final value, or false if it was suppressed.
+/// Wildcard handlers are not called.
+unsafe fn dispatch_filter_inner(L: &mut lua::ExternState) {
+    let nargs = L.gettop();
+    L.newtable();
+    let list = L.gettop();
+    L.pushvalue(1); // event name
+    let len = append_handlers(L, list, 0);
+    for i in range_inclusive(1, len) {
+        L.rawgeti(list, i);
+        if prepare_entry(L) && call_handler(L, nargs, 1) {
+            match L.type_(-1) {
+                Some(lua::Type::Boolean) if !L.toboolean(-1) => {
+                    L.pushboolean(false);
+                    L.replace(nargs);
+                    L.pop(2); // pop result and handler entry
+                    break;
+                }
+                Some(lua::Type::String) => {
+                    L.replace(nargs);
+                }
+                _ => {
+                    L.pop(1);
+                }
+            }
+        }
+        L.pop(1); // pop handler entry
+    }
+    L.pushnil();
+    L.setfield(lua::REGISTRYINDEX, super::CURRENT_PLUGIN);
+    L.pushvalue(nargs);
+}
+
+/// Checks the handler entry on top of the stack before calling it, returning whether
+/// it should still be called. `once` handlers are unregistered here.
+unsafe fn prepare_entry(L: &mut lua::ExternState) -> bool {
+    L.getfield(-1, "removed");
+    let removed = L.toboolean(-1);
+    L.pop(1);
+    if removed {
+        return false;
+    }
+    L.getfield(-1, "once");
+    let once = L.toboolean(-1);
+    L.pop(1);
+    if once {
+        // remove it before calling, so it can't be called again even if it
+        // causes another event to be dispatched
+        let entry = L.gettop();
+        unregister_handler(L, entry);
+    }
+    true
+}
+
+/// Pushes the handler array for the event on top of the stack, replacing the event
+/// Pushes nil instead if there are no handlers for the event.
+unsafe fn push_handlers(L: &mut lua::ExternState) {
+    L.pushlightuserdata(lua_addhandler as *mut libc::c_void);
+    L.gettable(lua::REGISTRYINDEX);
+    if !L.istable(-1) {
+        L.pop(2);
+        L.pushnil();
+        return;
+    }
+    L.insert(-2); // move the handler table behind the event
+    L.gettable(-2);
+    L.remove(-2); // remove the handler table
+    if !L.istable(-1) {
+        L.pop(1);
+        L.pushnil();
+    }
+}
+
+/// Returns whether there are any handlers for the event at 1, including category and
+/// wildcard handlers
+unsafe fn has_handlers(L: &mut lua::ExternState, categories: &[~str]) -> bool {
+    L.pushvalue(1);
+    if has_handlers_for(L) {
+        return true;
+    }
+    for category in categories.iter() {
+        L.pushstring(category.as_slice());
+        if has_handlers_for(L) {
+            return true;
+        }
+    }
+    L.pushstring(EVT_WILDCARD);
+    has_handlers_for(L)
+}
+
+/// Pushes the table of open batches, creating it if needed
+unsafe fn push_batches(L: &mut lua::ExternState) {
+    L.getfield(lua::REGISTRYINDEX, BATCHES);
+    if !L.istable(-1) {
+        L.pop(1);
+        L.newtable();
+        L.pushvalue(-1);
+        L.setfield(lua::REGISTRYINDEX, BATCHES);
+    }
+}
+
+/// Pushes the open batch that the line being dispatched belongs to, or nil
+unsafe fn push_current_batch(L: &mut lua::ExternState) {
+    push_batches(L);
+    super::push_tag(L, "batch");
+    if L.isnil(-1) {
+        L.pop(2);
+        L.pushnil();
+        return;
+    }
+    L.gettable(-2);
+    L.replace(-2);
+}
+
+/// Opens or closes a batch for the arguments of a BATCH line. Closing a batch dispatches
+/// the BATCH event with its lines and the tags of the line that opened it. The stack must
+/// be empty.
+unsafe fn update_batches(L: &mut lua::ExternState, args: &[~[u8]]) {
+    let (open, id) = match args[0].head() {
+        Some(&c) if c == '+' as u8 => (true, args[0].slice_from(1)),
+        Some(&c) if c == '-' as u8 => (false, args[0].slice_from(1)),
+        _ => return
+    };
+    push_batches(L);
+    if open {
+        if args.len() < 2 {
+            L.pop(1);
+            return;
+        }
+        L.createtable(0, 5);
+        L.pushbytes(id);
+        L.setfield(-2, "id");
+        L.pushbytes(args[1]);
+        L.setfield(-2, "type");
+        L.createtable((args.len() - 2) as i32, 0);
+        for (i, arg) in args.slice_from(2).iter().enumerate() {
+            L.pushbytes(*arg);
+            L.rawseti(-2, i as i32 + 1);
+        }
+        L.setfield(-2, "params");
+        L.newtable();
+        L.setfield(-2, "lines");
+        super::push_tags(L);
+        L.setfield(-2, "tags");
+        L.pushbytes(id);
+        L.insert(-2);
+        L.settable(-3);
+        L.pop(1);
+        return;
+    }
+    L.pushbytes(id);
+    L.gettable(-2);
+    L.pushbytes(id);
+    L.pushnil();
+    L.settable(-4);
+    L.remove(1); // the batches
+    if !L.istable(1) {
+        L.settop(0);
+        return;
+    }
+    L.pushstring(EVT_BATCH);
+    L.getfield(1, "type");
+    L.getfield(1, "params");
+    L.getfield(1, "lines");
+    L.getfield(1, "tags");
+    L.remove(1);
+    dispatch_event_inner(L, [], false);
+    L.settop(0);
+}
+
+/// Adds the event on the stack to the lines of the batch it belongs to, as an array of
+/// the arguments a handler receives, with n set to their count, and the line's tags and
+/// time
+unsafe fn record_batch_line(L: &mut lua::ExternState) {
+    let nargs = L.gettop();
+    push_current_batch(L);
+    L.getfield(-1, "lines");
+    L.createtable(nargs, 1);
+    for i in range_inclusive(1, nargs) {
+        L.pushvalue(i);
+        L.rawseti(-2, i);
+    }
+    L.pushinteger(nargs as int);
+    L.setfield(-2, "n");
+    super::push_tags(L);
+    L.setfield(-2, "tags");
+    super::push_event_time(L);
+    L.setfield(-2, "time");
+    let len = L.objlen(-2) as i32;
+    L.rawseti(-2, len + 1);
+    L.pop(2);
+}
+
+/// Returns whether there are any handlers for the event on top of the stack, popping it
+unsafe fn has_handlers_for(L: &mut lua::ExternState) -> bool {
+    push_handlers(L);
+    let found = L.istable(-1) && L.objlen(-1) > 0;
+    L.pop(1);
+    found
+}
+
+/// Appends the handlers for the event on top of the stack to the list at index `list`,
+/// which has length `len`. Pops the event and returns the new length of the list.
+unsafe fn append_handlers(L: &mut lua::ExternState, list: i32, len: i32) -> i32 {
+    push_handlers(L);
+    let mut len = len;
+    if L.istable(-1) {
+        for i in range_inclusive(1, L.objlen(-1) as i32) {
+            L.rawgeti(-1, i);
+            len += 1;
+            L.rawseti(list, len);
+        }
+    }
+    L.pop(1);
+    len
+}
+
+/// Calls the handler entry on top of the stack with the event arguments at 1..nargs
+/// Leaves the entry on the stack, with `nresults` results above it if the call succeeded.
+/// Returns whether the call succeeded.
+unsafe fn call_handler(L: &mut lua::ExternState, nargs: i32, nresults: i32) -> bool {
+    // note the plugin that's running, then push the function and the runner that
+    // calls it as a coroutine
+    L.getfield(-1, "plugin");
+    L.setfield(lua::REGISTRYINDEX, super::CURRENT_PLUGIN);
+    L.getfield(lua::REGISTRYINDEX, HANDLER_RUNNER);
+    L.getfield(-2, "fn");
+    // copy all the arguments; deep-copy the sender table
+    for i in range_inclusive(1, nargs) {
+        if L.istable(i) {
+            // copy it
+            L.newtable();
+            L.pushnil();
+            while L.next(i) {
+                L.pushvalue(-2); // copy the key
+                L.insert(-2); // move it behind the value
+                L.settable(-4); // set key=value in the new table
+                // leave behind the key for next
+            }
+        } else {
+            L.pushvalue(i);
+        }
+    }
+    let plugin = super::current_plugin(L);
+    let plugin = super::describe_plugin(L, plugin.as_slice());
+    let event = L.describe(1);
+    let watchdog = super::getwatchdog(L);
+    watchdog.started(plugin.as_slice(), event.as_slice());
+    let result = L.pcall(nargs + 1, nresults, 0);
+    watchdog.finished();
+    match result {
+        Ok(()) => true,
+        Err(e) => {
+            let msg = L.describe(-1);
+            L.pop(1);
+            println!("Error in plugin {} dispatching IRC event {}: {}: {}",
+                     plugin, event, e, msg);
+            // remember it on the entry for /handlers
+            L.getfield(-1, "errors");
+            let errors = L.tointeger(-1);
+            L.pop(1);
+            L.pushinteger(errors + 1);
+            L.setfield(-2, "errors");
+            L.pushstring(msg.as_slice());
+            L.setfield(-2, "lasterror");
+            false
+        }
+    }
+}
+
+/// Pushes the table of registered commands, creating it if needed
+unsafe fn push_commands(L: &mut lua::ExternState) {
+    L.getfield(lua::REGISTRYINDEX, COMMANDS);
+    if !L.istable(-1) {
+        L.pop(1);
+        L.newtable();
+        L.pushvalue(-1);
+        L.setfield(lua::REGISTRYINDEX, COMMANDS);
+    }
+}
+
+/// Stores the prefix that starts a command in a channel message
+pub unsafe fn store_command_prefix(L: &mut lua::ExternState, prefix: &str) {
+    L.pushstring(prefix);
+    L.setfield(lua::REGISTRYINDEX, COMMAND_PREFIX);
+}
+
+/// Runs the command in a PRIVMSG from the user, if there is one. In a channel the
+/// message must start with the command prefix, which is optional in a private message.
+/// The stack must be empty.
+unsafe fn dispatch_command(L: &mut lua::ExternState, user: &irc::User, dst: &[u8],
+                           text: &[u8]) {
+    let private = super::casemapping(L).eq(dst, getconn(L).me().nick());
+    L.getfield(lua::REGISTRYINDEX, COMMAND_PREFIX);
+    let prefix = L.tostring(-1).map_or(~"", |p| p.to_owned());
+    L.pop(1);
+    let text = if text.starts_with(prefix.as_bytes()) {
+        text.slice_from(prefix.len())
+    } else if private {
+        text
+    } else {
+        return;
+    };
+    let text = str::from_utf8_lossy(text).into_owned();
+    let line = text.trim_right();
+    let (name, rest) = match line.find(|c: char| c.is_whitespace()) {
+        None => (line, ""),
+        Some(i) => (line.slice_to(i), line.slice_from(i).trim_left())
+    };
+    if name.is_empty() {
+        return;
+    }
+    let name = name.to_ascii_lower();
+
+    L.getfield(lua::REGISTRYINDEX, COMMANDS);
+    if !L.istable(-1) {
+        L.pop(1);
+        if name.as_slice() == "help" {
+            send_help(L, prefix, user.nick(), rest);
+        }
+        return;
+    }
+    L.getfield(-1, name.as_slice());
+    L.remove(-2);
+    if !L.istable(-1) {
+        L.pop(1);
+        // plugins may register their own help command instead
+        if name.as_slice() == "help" {
+            send_help(L, prefix, user.nick(), rest);
+        }
+        return;
+    }
+    // the command's entry is stack entry 1
+
+    let target = if private { user.nick().to_owned() } else { dst.to_owned() };
+    let args: ~[&str] = rest.words().collect();
+    L.getfield(1, "minargs");
+    let minargs = L.tointeger(-1) as uint;
+    L.pop(1);
+    if args.len() < minargs {
+        L.getfield(1, "plugin");
+        let plugin = L.tostring(-1).map_or(~"(unknown)", |p| p.to_owned());
+        L.getfield(1, "help");
+        let mut msg = format!("{}{} needs at least {} arguments", prefix, name, minargs);
+        match L.tostring(-1) {
+            None => (),
+            Some(help) => msg.push_str(format!(": {}", help))
+        }
+        L.pop(3);
+        // the usage goes to whoever ran it, like the built-in help command's replies
+        let origin = outbound::Plugin(plugin);
+        getoutbound(L).notice(getconn(L), origin, user.nick(), msg.as_bytes());
+        return;
+    }
+
+    // call it like a handler, with its name, the reply context and the arguments
+    L.pushstring(format!("{}{}", prefix, name).as_slice());
+    L.createtable(0, 6);
+    L.pushstring(name.as_slice());
+    L.setfield(-2, "name");
+    push_sender(L, user);
+    L.setfield(-2, "sender");
+    if !private {
+        L.pushbytes(dst);
+        L.setfield(-2, "channel");
+    }
+    L.pushbytes(target);
+    L.setfield(-2, "target");
+    L.pushstring(rest);
+    L.setfield(-2, "text");
+    match L.loadstring("local f, dst = ...; return function(text) return f(dst, text) end") {
+        Ok(()) => (),
+        Err(_) => {
+            let msg = L.describe(-1);
+            L.errorstr(msg.as_slice());
+        }
+    }
+    L.pushcfunction(lua_privmsg);
+    L.pushbytes(target);
+    L.call(2, 1);
+    L.setfield(-2, "reply");
+    for arg in args.iter() {
+        L.pushstring(*arg);
+    }
+    L.pushvalue(1);
+    L.remove(1);
+    let nargs = L.gettop() - 1;
+    call_handler(L, nargs, 0);
+    L.pop(1); // pop the command's entry
+    L.pushnil();
+    L.setfield(lua::REGISTRYINDEX, super::CURRENT_PLUGIN);
+}
+
+/// Replies to the built-in help command with notices to the user. With no argument or
+/// a page number it lists a page of the registered commands and their help, and with
+/// a command name it gives that command's help.
+unsafe fn send_help(L: &mut lua::ExternState, prefix: &str, nick: &[u8], arg: &str) {
+    let mut commands = ~[];
+    L.getfield(lua::REGISTRYINDEX, COMMANDS);
+    if L.istable(-1) {
+        let table = L.gettop();
+        L.pushnil();
+        while L.next(table) {
+            let name = L.tostring(-2).map_or(~"", |s| s.to_owned());
+            L.getfield(-1, "help");
+            let help = L.tostring(-1).map(|s| s.to_owned());
+            L.pop(2); // pop the help and the entry, leaving the key for next
+            commands.push((name, help));
+        }
+    }
+    L.pop(1);
+    commands.sort_by(|&(ref a, _), &(ref b, _)| a.cmp(b));
+
+    let arg = arg.trim();
+    let mut lines = ~[];
+    match from_str::<uint>(arg) {
+        _ if commands.is_empty() => lines.push(~"No commands are registered"),
+        None if !arg.is_empty() => {
+            let name = if arg.starts_with(prefix) { arg.slice_from(prefix.len()) } else { arg };
+            let name = name.to_ascii_lower();
+            match commands.iter().find(|&&(ref n, _)| *n == name) {
+                None => lines.push(format!("No such command {}{}", prefix, name)),
+                Some(&(ref name, ref help)) => lines.push(help_line(prefix, *name, help))
+            }
+        }
+        page => {
+            let pages = (commands.len() + HELP_PAGE_LINES - 1) / HELP_PAGE_LINES;
+            let page = page.unwrap_or(1).max(1).min(pages);
+            let start = (page - 1) * HELP_PAGE_LINES;
+            for &(ref name, ref help) in commands.slice_from(start).iter().take(HELP_PAGE_LINES) {
+                lines.push(help_line(prefix, *name, help));
+            }
+            if page < pages {
+                lines.push(format!("Page {} of {}, use {}help {} for more", page, pages,
+                                   prefix, page + 1));
+            }
+        }
+    }
+
+    let conn = getconn(L);
+    let out = getoutbound(L);
+    for line in lines.iter() {
+        out.notice(conn, outbound::Help, nick, line.as_bytes());
+    }
+}
+
+fn help_line(prefix: &str, name: &str, help: &Option<~str>) -> ~str {
+    match *help {
+        None => format!("{}{}", prefix, name),
+        Some(ref help) => format!("{}{} - {}", prefix, name, help)
+    }
+}
+
+/// Returns whether the bytes can be sent as a middle parameter of a line
+fn valid_param(param: &[u8]) -> bool {
+    !param.is_empty() && param[0] != ':' as u8 &&
+        !param.iter().any(|&b| b == ' ' as u8 || b == '\r' as u8 || b == '\n' as u8 || b == 0)
+}
+
+/// Replaces the function at `idx` with one that calls it without its first argument
+unsafe fn skip_first_arg(L: &mut lua::ExternState, idx: i32) {
+    match L.loadstring("local f = ...; return function(_, ...) return f(...) end") {
+        Ok(()) => (),
+        Err(_) => {
+            let msg = L.describe(-1);
+            L.errorstr(msg.as_slice());
+        }
+    }
+    L.pushvalue(idx);
+    L.call(1, 1);
+    L.replace(idx);
+}
+
+/// Replaces the irc.on or irc.emit event name at 1 with its handler table key
+unsafe fn set_bus_event(L: &mut lua::ExternState) {
+    let mut key = BUS_PREFIX.as_bytes().to_owned();
+    key.push_all(L.checkbytes(1));
+    L.pushbytes(key);
+    L.replace(1);
+}
+
+/// Pushes the handler table key of the nick's irc.watch callbacks
+unsafe fn push_watch_event(L: &mut lua::ExternState, nick: &[u8]) {
+    let mut key = WATCH_PREFIX.as_bytes().to_owned();
+    key.push_all(super::casemapping(L).lower(nick));
+    L.pushbytes(key);
+}
+
+/// Stops watching the nick if the handler entry at index `entry` was one of its
+/// irc.watch callbacks, and the last one
+unsafe fn unwatch_if_unused(L: &mut lua::ExternState, entry: i32) {
+    L.getfield(entry, "event");
+    let nick = match L.tobytes(-1) {
+        Some(event) if event.starts_with(WATCH_PREFIX.as_bytes()) => {
+            event.slice_from(WATCH_PREFIX.len()).to_owned()
+        }
+        _ => {
+            L.pop(1);
+            return;
+        }
+    };
+    push_handlers(L);
+    let unused = !L.istable(-1) || L.objlen(-1) == 0;
+    L.pop(1);
+    if unused {
+        super::getwatch(L).remove(&super::casemapping(L), nick);
+    }
+}
+
+/// Registers the function at 2 as a handler for the event at 1 with the optional
+/// priority at 3, returning the handle
+unsafe fn add_handler(L: &mut lua::ExternState, once: bool) -> i32 {
+    L.checkbytes(1);
+    L.checktype(2, lua::Type::Function);
+    let priority = L.optinteger(3, 0);
+
+    L.settop(2); // throw away any extra values
+
+    // get or create handler table; key is lua_addhandler
+    L.pushlightuserdata(lua_addhandler as *mut libc::c_void);
+    L.gettable(lua::REGISTRYINDEX);
+    if !L.istable(3) {
+        L.pop(1);
+        L.newtable();
+        L.pushlightuserdata(lua_addhandler as *mut libc::c_void);
+        L.pushvalue(3);
+        L.settable(lua::REGISTRYINDEX);
+    }
+    // table is stack entry 3
+
+    // get or create the array
+    L.pushvalue(1); // copy the event to the top
+    L.gettable(3);
+    if !L.istable(4) {
+        L.pop(1);
+        L.newtable();
+        L.pushvalue(1); // copy event to top
+        L.pushvalue(4);
+        L.settable(3);
+    }
+    // array is stack entry 4
+
+    // create the handler entry, which remembers the plugin that registered it
+    L.createtable(0, 5);
+    L.pushvalue(2); // copy function to top
+    L.setfield(-2, "fn");
+    L.getfield(lua::REGISTRYINDEX, super::CURRENT_PLUGIN);
+    L.setfield(-2, "plugin");
+    L.pushvalue(1); // copy event to top
+    L.setfield(-2, "event");
+    L.pushinteger(priority);
+    L.setfield(-2, "priority");
+    if once {
+        L.pushboolean(true);
+        L.setfield(-2, "once");
+    }
+    // entry is stack entry 5
+
+    // the array is kept sorted by priority, highest first, and handlers with the same
+    // priority are called in the order they were added
+    let mut i = L.objlen(4) as i32;
+    while i >= 1 {
+        L.rawgeti(4, i);
+        if priority_of(L, -1) >= priority {
+            L.pop(1);
+            break;
+        }
+        L.rawseti(4, i + 1);
+        i -= 1;
+    }
+    L.pushvalue(5);
+    L.rawseti(4, i + 1);
+    // and return the handle
+    1
+}
+
+/// Returns the priority of the handler entry at the given index
+unsafe fn priority_of(L: &mut lua::ExternState, entry: i32) -> int {
+    L.getfield(entry, "priority");
+    let priority = L.tointeger(-1);
+    L.pop(1);
+    priority
+}
+
+/// Sorts the first `len` handler entries in the list at `list` by priority, highest
+/// first, keeping entries with the same priority in order
+unsafe fn sort_by_priority(L: &mut lua::ExternState, list: i32, len: i32) {
+    for i in range_inclusive(2, len) {
+        L.rawgeti(list, i);
+        let entry = L.gettop();
+        let priority = priority_of(L, entry);
+        let mut j = i - 1;
+        while j >= 1 {
+            L.rawgeti(list, j);
+            if priority_of(L, -1) >= priority {
+                L.pop(1);
+                break;
+            }
+            L.rawseti(list, j + 1);
+            j -= 1;
+        }
+        L.rawseti(list, j + 1); // pops the entry
+    }
+}
+
+/// Unregisters the handler entry at the given (absolute) index
+/// Returns whether the entry was registered.
+unsafe fn unregister_handler(L: &mut lua::ExternState, entry: i32) -> bool {
+    L.getfield(entry, "event");
+    push_handlers(L);
+    if !L.istable(-1) {
+        L.pop(1);
+        return false;
+    }
+    let list = L.gettop();
+    let found = remove_handler(L, list, entry);
+    L.pop(1);
+    found
+}
+
+/// Removes the handler entry at index `entry` from the handler list at index `list`
+/// Returns whether the entry was found.
+unsafe fn remove_handler(L: &mut lua::ExternState, list: i32, entry: i32) -> bool {
+    let len = L.objlen(list) as i32;
+    let mut found = false;
+    for i in range_inclusive(1, len) {
+        L.rawgeti(list, i);
+        if found {
+            // shift this entry down to fill the gap
+            L.rawseti(list, i - 1);
+        } else {
+            found = L.rawequal(-1, entry);
+            L.pop(1);
+        }
+    }
+    if found {
+        L.pushnil();
+        L.rawseti(list, len);
+        // mark it, in case a dispatch in progress still has it
+        L.pushboolean(true);
+        L.setfield(entry, "removed");
+    }
+    found
+}
+
+/// Pushes the event name for a numeric reply, e.g. "001"
+unsafe fn push_numeric(L: &mut lua::ExternState, code: uint) {
+    // construct our string on the stack
+    let mut buf = [0u8, ..64];
+    let n = {
+        let mut w = BufWriter::new(buf);
+        match write!(&mut w, "{:03u}", code).and_then(|_| w.tell()) {
+            Ok(n) => n,
+            Err(e) => {
+                drop(e);
+                L.errorstr("could not format IRCCode");
+            }
+        }
+    };
+    L.pushbytes(buf.slice_to(n as uint));
+}
+
+unsafe fn push_user(L: &mut lua::ExternState, user: &irc::User) {
+    L.createtable(0, 4);
+    L.pushbytes(user.raw());
+    L.setfield(-2, "raw");
+    L.pushbytes(user.nick());
+    L.setfield(-2, "nick");
+    match user.user() {
+        None => L.pushnil(),
+        Some(v) => L.pushbytes(v)
+    }
+    L.setfield(-2, "user");
+    match user.host() {
+        None => L.pushnil(),
+        Some(v) => L.pushbytes(v)
+    }
+    L.setfield(-2, "host");
+}
+
+/// Pushes a User table for the sender of the IRC line being dispatched, with the line's
+/// tags and time, and on Twitch its badges and emotes
+unsafe fn push_sender(L: &mut lua::ExternState, user: &irc::User) {
+    push_user(L, user);
+    super::push_tags(L);
+    L.setfield(-2, "tags");
+    super::push_event_time(L);
+    L.setfield(-2, "time");
+    super::push_tag(L, "badges");
+    let badges = L.tostring(-1).map(|value| twitch::badges(value));
+    L.pop(1);
+    match badges {
+        None => (),
+        Some(badges) => {
+            L.createtable(0, badges.len() as i32);
+            for &(ref name, ref version) in badges.iter() {
+                L.pushstring(*version);
+                L.setfield(-2, *name);
+            }
+            L.setfield(-2, "badges");
+        }
+    }
+    super::push_tag(L, "emotes");
+    let emotes = L.tostring(-1).map(|value| twitch::emotes(value));
+    L.pop(1);
+    match emotes {
+        None => (),
+        Some(emotes) => {
+            L.createtable(emotes.len() as i32, 0);
+            for (i, &(ref id, first, last)) in emotes.iter().enumerate() {
+                L.createtable(0, 3);
+                L.pushstring(*id);
+                L.setfield(-2, "id");
+                // Lua counts from 1
+                L.pushinteger(first as int + 1);
+                L.setfield(-2, "first");
+                L.pushinteger(last as int + 1);
+                L.setfield(-2, "last");
+                L.rawseti(-2, i as i32 + 1);
+            }
+            L.setfield(-2, "emotes");
+        }
+    }
+}
+
+/// Pushes a User table for nick!user@host
+unsafe fn push_user_parts(L: &mut lua::ExternState, nick: &[u8], user: &[u8], host: &[u8]) {
+    L.createtable(0, 4);
+    L.pushbytes([nick, bytes!("!"), user, bytes!("@"), host].concat_vec());
+    L.setfield(-2, "raw");
+    L.pushbytes(nick);
+    L.setfield(-2, "nick");
+    L.pushbytes(user);
+    L.setfield(-2, "user");
+    L.pushbytes(host);
+    L.setfield(-2, "host");
+}
+
+/// Summary of a plugin's handlers for one event, for /handlers
+struct HandlerInfo {
+    count: uint,
+    errors: uint,
+    lasterror: Option<~str>
+}
+
+/// The connection state that's available while Lua code is running
+struct Active {
+    conn: *mut Conn<'static>,
+    out: *mut Outbound,
+    tasks: *mut Tasks
+}
+
+/// Creates the runner for handlers and irc.await, given the number of instructions a
+/// handler may run each time it's resumed, if limited
+pub unsafe fn store_runner(L: &mut lua::ExternState, limit: Option<uint>) {
+    match L.loadstring(AWAIT_SRC) {
+        Ok(()) => (),
+        Err(_) => {
+            let msg = L.describe(-1);
+            L.errorstr(format!("could not load irc.await support: {}", msg).as_slice());
+        }
+    }
+    L.pushcfunction(lua_addhandler);
+    L.pushcfunction(lua_removehandler);
+    L.pushstring(EVT_TICK);
+    match limit {
+        None => L.pushnil(),
+        Some(n) => L.pushinteger(n as int)
+    }
+    L.pushcfunction(lua_expired);
+    L.call(5, 2);
+    L.setfield(lua::REGISTRYINDEX, AWAIT);
+    L.setfield(lua::REGISTRYINDEX, HANDLER_RUNNER);
+}
+
+/// Inserts the handler runner below the function and the `nargs` arguments on top of the
+/// stack, so that calling the runner calls the function with the limits handlers have
+pub fn insert_runner(L: &mut lua::State, nargs: i32) {
+    L.getfield(lua::REGISTRYINDEX, HANDLER_RUNNER);
+    L.insert(-(nargs + 2));
+}
+
+/// Creates the storage for the active connection state
+/// It's kept in the registry under lua_require as a lightuserdata, and is created
+/// up front since other packages need it too.
+pub unsafe fn store_active(L: &mut lua::ExternState) {
+    L.pushlightuserdata(lua_require as *mut libc::c_void);
+    let active = L.newuserdata(mem::size_of::<Active>()) as *mut Active;
+    *active = Active { conn: ptr::mut_null(), out: ptr::mut_null(), tasks: ptr::mut_null() };
+    L.settable(lua::REGISTRYINDEX);
+}
+
+// unsafe because the Active isn't really 'static
+unsafe fn getactive(L: &mut lua::ExternState) -> &'static mut Active {
+    L.pushlightuserdata(lua_require as *mut libc::c_void);
+    L.gettable(lua::REGISTRYINDEX);
+    let ptr = L.touserdata(-1) as *mut Active;
+    if ptr.is_null() {
+        L.errorstr("could not retrieve connection information");
+    }
+    L.pop(1);
+    if (*ptr).conn.is_null() {
+        L.errorstr("no active connection");
+    }
+    &mut *ptr
+}
+
+// unsafe because the Conn isn't really 'static
+pub unsafe fn getconn(L: &mut lua::ExternState) -> &'static mut Conn<'static> {
+    &mut *getactive(L).conn
+}
+
+// unsafe because the Outbound isn't really 'static
+pub unsafe fn getoutbound(L: &mut lua::ExternState) -> &'static mut Outbound {
+    &mut *getactive(L).out
+}
+
+// unsafe because the Tasks isn't really 'static
+pub unsafe fn gettasks(L: &mut lua::ExternState) -> &'static mut Tasks {
+    &mut *getactive(L).tasks
+}
+
+/// Makes the Conn, Outbound and Tasks available to Lua until deactivate_conn() is called
+pub fn activate_conn(L: &mut lua::State, conn: &mut Conn, out: &mut Outbound,
+                     tasks: &mut Tasks) {
+    L.pushlightuserdata(lua_require as *mut libc::c_void);
+    L.gettable(lua::REGISTRYINDEX);
+    let ptr = L.touserdata(-1) as *mut Active;
+    L.pop(1);
+    if ptr.is_null() {
+        // the packages haven't been set up
+        return;
+    }
+    unsafe {
+        (*ptr).conn = conn as *mut Conn as *mut Conn<'static>;
+        (*ptr).out = out as *mut Outbound;
+        (*ptr).tasks = tasks as *mut Tasks;
+    }
+}
+
+pub fn deactivate_conn(L: &mut lua::State) {
+    L.pushlightuserdata(lua_require as *mut libc::c_void);
+    L.gettable(lua::REGISTRYINDEX);
+    let ptr = L.touserdata(-1) as *mut Active;
+    L.pop(1);
+    if ptr.is_null() {
+        return;
+    }
+    unsafe {
+        (*ptr).conn = ptr::mut_null();
+        (*ptr).out = ptr::mut_null();
+        (*ptr).tasks = ptr::mut_null();
+    }
+}
+
+/// Forgets the open batches, e.g. on a new connection, where they'll never be closed
+pub fn clear_batches(L: &mut lua::State) {
+    L.pushnil();
+    L.setfield(lua::REGISTRYINDEX, BATCHES);
+}
+
+lua_extern! {
+    unsafe fn lua_addhandler(L: &mut lua::ExternState) -> i32 {
+        // 2 or 3 args: event, func, [priority]
+
+        add_handler(L, false)
+    }
+
+    unsafe fn lua_once(L: &mut lua::ExternState) -> i32 {
+        // 2 or 3 args: event, func, [priority]
+
+        add_handler(L, true)
+    }
+
+    unsafe fn lua_on(L: &mut lua::ExternState) -> i32 {
+        // 2 or 3 args: name, func, [priority]
+
+        set_bus_event(L);
+        L.checktype(2, lua::Type::Function);
+        // bus handlers only receive the emitted values
+        skip_first_arg(L, 2);
+        add_handler(L, false)
+    }
+
+    unsafe fn lua_addcommand(L: &mut lua::ExternState) -> i32 {
+        // 2 or 3 args: name, func, [options]
+
+        let name = match str::from_utf8(L.checkbytes(1)) {
+            Some(s) if !s.is_empty() && !s.chars().any(|c| c.is_whitespace()) => {
+                s.to_ascii_lower()
+            }
+            _ => L.argerror(1, "invalid command name")
+        };
+        L.checktype(2, lua::Type::Function);
+        let has_opts = !L.isnoneornil(3);
+        if has_opts {
+            L.checktype(3, lua::Type::Table);
+        }
+        L.settop(3);
+        // commands are called with their name first, like handlers with their event
+        skip_first_arg(L, 2);
+        push_commands(L);
+        // commands table is stack entry 4
+
+        // a plugin may replace its own command, but not another plugin's
+        L.getfield(4, name.as_slice());
+        if L.istable(-1) {
+            L.getfield(-1, "plugin");
+            let owner = L.tobytes(-1).map(|p| p.to_owned());
+            L.getfield(lua::REGISTRYINDEX, super::CURRENT_PLUGIN);
+            let current = L.tobytes(-1).map(|p| p.to_owned());
+            if owner != current {
+                let owner = owner.map_or(~"(unknown)", |p| str::from_utf8_lossy(p).into_owned());
+                let msg = format!("command `{}' is already registered by plugin {}", name, owner);
+                L.errorstr(msg.as_slice());
+            }
+            L.pop(2);
+        }
+        L.pop(1);
+
+        L.createtable(0, 5);
+        L.pushvalue(2);
+        L.setfield(-2, "fn");
+        L.getfield(lua::REGISTRYINDEX, super::CURRENT_PLUGIN);
+        L.setfield(-2, "plugin");
+        L.pushstring(name.as_slice());
+        L.setfield(-2, "name");
+        if has_opts {
+            L.getfield(3, "help");
+            if !L.isnil(-1) && L.type_(-1) != Some(lua::Type::String) {
+                L.argerror(3, "help must be a string");
+            }
+            L.setfield(-2, "help");
+            L.getfield(3, "minargs");
+            if !L.isnil(-1) && L.type_(-1) != Some(lua::Type::Number) {
+                L.argerror(3, "minargs must be a number");
+            }
+            L.setfield(-2, "minargs");
+        }
+        L.setfield(4, name.as_slice());
+        0
+    }
+
+    unsafe fn lua_removecommand(L: &mut lua::ExternState) -> i32 {
+        // 1 arg: name
+
+        let name = str::from_utf8_lossy(L.checkbytes(1)).into_owned().to_ascii_lower();
+        L.settop(1);
+        push_commands(L);
+        L.getfield(2, name.as_slice());
+        let mut removed = false;
+        if L.istable(-1) {
+            L.getfield(-1, "plugin");
+            let owner = L.tobytes(-1).map(|p| p.to_owned());
+            L.getfield(lua::REGISTRYINDEX, super::CURRENT_PLUGIN);
+            let current = L.tobytes(-1).map(|p| p.to_owned());
+            L.pop(2);
+            if owner == current {
+                L.pushnil();
+                L.setfield(2, name.as_slice());
+                removed = true;
+            }
+        }
+        L.pushboolean(removed);
+        1
+    }
+
+    unsafe fn lua_emit(L: &mut lua::ExternState) -> i32 {
+        // 1 or more args: name, values...
+
+        set_bus_event(L);
+        // dispatching clears the current plugin, so restore it for the emitting plugin
+        L.getfield(lua::REGISTRYINDEX, super::CURRENT_PLUGIN);
+        let caller = L.tobytes(-1).map(|p| p.to_owned());
+        L.pop(1);
+        dispatch_event_inner(L, [], false);
+        match caller {
+            None => L.pushnil(),
+            Some(p) => L.pushbytes(p)
+        }
+        L.setfield(lua::REGISTRYINDEX, super::CURRENT_PLUGIN);
+        0
+    }
+
+    unsafe fn lua_removehandler(L: &mut lua::ExternState) -> i32 {
+        // 1 arg: handle
+
+        L.checktype(1, lua::Type::Table);
+
+        L.settop(1); // throw away any extra values
+
+        let found = unregister_handler(L, 1);
+        if found {
+            unwatch_if_unused(L, 1);
+        }
+        L.pushboolean(found);
+        1
+    }
+
+// *** IRC package functions ***
+
+    unsafe fn lua_host(L: &mut lua::ExternState) -> i32 {
+        // 0 args
+
+        let conn = getconn(L);
+
+        // construct our response on the stack
+        let mut buf = [0u8, ..64];
+        let n = {
+            let mut w = BufWriter::new(buf);
+            match write!(&mut w, "{}", conn.host()).and_then(|_| w.tell()) {
+                Ok(n) => n,
+                Err(e) => {
+                    drop(e);
+                    L.errorstr("could not format host");
+                }
+            }
+        };
+        L.pushbytes(buf.slice_to(n as uint));
+        1
+    }
+
+    unsafe fn lua_me(L: &mut lua::ExternState) -> i32 {
+        // 0 args
+
+        let conn = getconn(L);
+
+        // return a user table
+        push_user(L, conn.me());
+        1
+    }
+
+    unsafe fn lua_privmsg(L: &mut lua::ExternState) -> i32 {
+        // 2 args: dst, message
+
+        let dst = L.checkbytes(1);
+        let msg = L.checkbytes(2);
+
+        let conn = getconn(L);
+        let out = getoutbound(L);
+
+        out.privmsg(conn, outbound::Plugin(super::current_plugin(L)), dst, msg);
+        0
+    }
+
+    unsafe fn lua_notice(L: &mut lua::ExternState) -> i32 {
+        // 2 args: dst, message
+
+        let dst = L.checkbytes(1);
+        let msg = L.checkbytes(2);
+
+        let conn = getconn(L);
+        let out = getoutbound(L);
+
+        out.notice(conn, outbound::Plugin(super::current_plugin(L)), dst, msg);
+        0
+    }
+
+    unsafe fn lua_stripformat(L: &mut lua::ExternState) -> i32 {
+        // 1 arg: text
+
+        let text = L.checkbytes(1);
+        L.pushbytes(format::strip(text));
+        1
+    }
+
+    unsafe fn lua_maskmatch(L: &mut lua::ExternState) -> i32 {
+        // 2 args: mask, user
+
+        let mask = L.checkbytes(1);
+        if L.istable(2) {
+            L.getfield(2, "raw");
+            L.replace(2);
+        }
+        let prefix = L.checkbytes(2);
+        L.pushboolean(mask::matches(mask, prefix));
+        1
+    }
+
+    unsafe fn lua_session(L: &mut lua::ExternState) -> i32 {
+        // 0 args
+
+        super::push_session(L);
+        1
+    }
+
+    unsafe fn lua_server(L: &mut lua::ExternState) -> i32 {
+        // 0 args
+
+        super::push_server(L);
+        1
+    }
+
+    unsafe fn lua_msgid(L: &mut lua::ExternState) -> i32 {
+        // 0 args
+
+        super::push_tag(L, "msgid");
+        1
+    }
+
+    unsafe fn lua_tags(L: &mut lua::ExternState) -> i32 {
+        // 0 args
+
+        super::push_tags(L);
+        1
+    }
+
+    unsafe fn lua_isupport(L: &mut lua::ExternState) -> i32 {
+        // 0 args
+
+        super::push_isupport(L);
+        1
+    }
+
+    unsafe fn lua_expired(L: &mut lua::ExternState) -> i32 {
+        // no args
+
+        L.pushboolean(super::getwatchdog(L).expired());
+        1
+    }
+
+    unsafe fn lua_lag(L: &mut lua::ExternState) -> i32 {
+        // 0 args
+
+        super::push_lag(L);
+        1
+    }
+
+    unsafe fn lua_time(L: &mut lua::ExternState) -> i32 {
+        // 0 args
+
+        super::push_event_time(L);
+        1
+    }
+
+    unsafe fn lua_replayed(L: &mut lua::ExternState) -> i32 {
+        // 0 args
+
+        L.pushboolean(super::is_replayed(L));
+        1
+    }
+
+    unsafe fn lua_away(L: &mut lua::ExternState) -> i32 {
+        // 1 arg: nick
+
+        let nick = L.checkbytes(1);
+        super::push_away(L, nick);
+        1
+    }
+
+    unsafe fn lua_watch(L: &mut lua::ExternState) -> i32 {
+        // 2 args: nick, callback
+
+        let nick = L.checkbytes(1).to_owned();
+        L.checktype(2, lua::Type::Function);
+        L.settop(2);
+
+        let online = super::getwatch(L).add(&super::casemapping(L), nick);
+        // the callbacks are handlers of an event of the nick's own
+        push_watch_event(L, nick);
+        L.replace(1);
+        skip_first_arg(L, 2);
+        add_handler(L, false);
+        match online {
+            None => L.pushnil(),
+            Some(online) => L.pushboolean(online)
+        }
+        2
+    }
+
+    unsafe fn lua_roomstate(L: &mut lua::ExternState) -> i32 {
+        // 1 arg: channel
+
+        let channel = L.checkbytes(1);
+        super::push_roomstate(L, channel);
+        1
+    }
+
+    unsafe fn lua_userstate(L: &mut lua::ExternState) -> i32 {
+        // 1 optional arg: channel
+
+        let channel = if L.isnoneornil(1) { None } else { Some(L.checkbytes(1)) };
+        super::push_userstate(L, channel);
+        1
+    }
+
+    unsafe fn lua_user(L: &mut lua::ExternState) -> i32 {
+        // 1 arg: nick
+
+        let nick = L.checkbytes(1);
+        super::push_user_info(L, nick);
+        1
+    }
+
+    unsafe fn lua_hostmask(L: &mut lua::ExternState) -> i32 {
+        // 1 arg: nick
+
+        let nick = L.checkbytes(1);
+        super::push_hostmask(L, nick);
+        1
+    }
+
+    unsafe fn lua_batch(L: &mut lua::ExternState) -> i32 {
+        // 0 args
+
+        push_current_batch(L);
+        if L.istable(-1) {
+            // give out a copy without the lines collected so far
+            L.createtable(0, 3);
+            L.getfield(-2, "id");
+            L.setfield(-2, "id");
+            L.getfield(-2, "type");
+            L.setfield(-2, "type");
+            L.getfield(-2, "params");
+            L.setfield(-2, "params");
+        }
+        1
+    }
+
+    unsafe fn lua_history(L: &mut lua::ExternState) -> i32 {
+        // 3 args: target, limit or nil, callback
+
+        let target = L.checkbytes(1);
+        let limit = L.optinteger(2, HISTORY_LIMIT);
+        L.checktype(3, lua::Type::Function);
+        if target.is_empty() || target.iter().any(|&b| b == ' ' as u8 || b == ':' as u8) {
+            L.argerror(1, "invalid target");
+        }
+        if limit <= 0 {
+            L.argerror(2, "limit must be positive");
+        }
+
+        L.getfield(lua::REGISTRYINDEX, HISTORY_WAITER);
+        L.pushvalue(1);
+        L.pushvalue(3);
+        L.call(2, 0);
+        let line = [bytes!("CHATHISTORY LATEST "), target,
+                    format!(" * {}", limit).as_bytes()].concat_vec();
+        let origin = outbound::Plugin(super::current_plugin(L));
+        getoutbound(L).send_raw(getconn(L), origin, line.as_slice());
+        0
+    }
+
+    unsafe fn lua_query(L: &mut lua::ExternState) -> i32 {
+        // 3 args: command, params or nil, callback
+
+        let command = match str::from_utf8(L.checkbytes(1)) {
+            None => L.argerror(1, "invalid command"),
+            Some(s) => s.to_ascii_upper()
+        };
+        if !L.isnoneornil(2) {
+            L.checktype(2, lua::Type::Table);
+        }
+        L.checktype(3, lua::Type::Function);
+        let query_only = match QUERY_COMMANDS.iter().find(|&&(c, _)| c == command.as_slice()) {
+            None => L.argerror(1, format!("irc.query can't send {}", command).as_slice()),
+            Some(&(_, query_only)) => query_only
+        };
+        let mut line = command.as_bytes().to_owned();
+        let nparams = if L.isnoneornil(2) { 0 } else { L.objlen(2) as i32 };
+        if query_only && nparams > 1 {
+            L.argerror(2, format!("{} only queries with 1 parameter", command).as_slice());
+        }
+        for i in range_inclusive(1, nparams) {
+            L.rawgeti(2, i);
+            match L.tobytes(-1) {
+                Some(param) if valid_param(param) => {
+                    line.push(' ' as u8);
+                    line.push_all(param);
+                }
+                _ => L.argerror(2, format!("invalid parameter {}", i).as_slice())
+            }
+            L.pop(1);
+        }
+
+        L.getfield(lua::REGISTRYINDEX, QUERY_WAITER);
+        L.pushstring(command.as_slice());
+        L.pushbytes(line.as_slice());
+        super::push_labeled_response(L);
+        L.pushvalue(3);
+        L.call(4, 0);
+        0
+    }
+
+    unsafe fn lua_send_query(L: &mut lua::ExternState) -> i32 {
+        // 1 arg: line
+
+        let line = L.checkbytes(1);
+        let origin = outbound::Plugin(super::current_plugin(L));
+        getoutbound(L).send_raw(getconn(L), origin, line);
+        0
+    }
+
+    unsafe fn lua_members(L: &mut lua::ExternState) -> i32 {
+        // 1 arg: channel
+
+        let channel = L.checkbytes(1);
+        super::push_members(L, channel);
+        1
+    }
+
+    unsafe fn lua_mode(L: &mut lua::ExternState) -> i32 {
+        // 3 args: channel, modes, params or nil
+
+        let channel = L.checkbytes(1);
+        let modes = L.checkbytes(2);
+        if !L.isnoneornil(3) {
+            L.checktype(3, lua::Type::Table);
+        }
+        if !valid_param(channel) {
+            L.argerror(1, "invalid channel");
+        }
+        if !valid_param(modes) {
+            L.argerror(2, "invalid modes");
+        }
+        let mut args = ~[modes.to_owned()];
+        let nparams = if L.isnoneornil(3) { 0 } else { L.objlen(3) as i32 };
+        for i in range_inclusive(1, nparams) {
+            L.rawgeti(3, i);
+            match L.tobytes(-1) {
+                Some(param) if valid_param(param) => args.push(param.to_owned()),
+                _ => L.argerror(3, format!("invalid parameter {}", i).as_slice())
+            }
+            L.pop(1);
+        }
+
+        let conn = getconn(L);
+        let out = getoutbound(L);
+        for line in super::getusers(L).mode_lines(channel, args.as_slice()).iter() {
+            out.send_raw(conn, outbound::Plugin(super::current_plugin(L)), line.as_slice());
+        }
+        0
+    }
+
+    unsafe fn lua_reply_to(L: &mut lua::ExternState) -> i32 {
+        // 3 args: msgid, dst, message
+
+        let msgid = L.checkbytes(1);
+        let dst = L.checkbytes(2);
+        let msg = L.checkbytes(3);
+
+        let msgid = match str::from_utf8(msgid) {
+            None => L.argerror(1, "invalid msgid"),
+            Some(s) => s.to_owned()
+        };
+        let conn = getconn(L);
+        let out = getoutbound(L);
+
+        let origin = outbound::Plugin(super::current_plugin(L));
+        out.privmsg_tagged(conn, origin, [(~"+draft/reply", msgid)], dst, msg);
+        0
+    }
+
+    unsafe fn lua_paste(L: &mut lua::ExternState) -> i32 {
+        // 2 args: text, callback
+
+        let text = L.checkbytes(1).to_owned();
+        L.checktype(2, lua::Type::Function);
+        let (url, field) = match super::paste_endpoint(L) {
+            None => L.errorstr("no paste service is configured in general.paste_url"),
+            Some(endpoint) => endpoint
+        };
+
+        task::spawn(L, "paste upload", 2, proc() {
+            let res = match field {
+                None => http::post(url, "text/plain; charset=utf-8", text.as_slice()),
+                Some(field) => {
+                    let body = format!("{}={}", field, http::form_encode(text));
+                    http::post(url, "application/x-www-form-urlencoded", body.as_bytes())
+                }
+            };
+            // the service replies with the URL, possibly among other text
+            let res = res.and_then(|body| {
+                let body = str::from_utf8_lossy(body).into_owned();
+                let found = body.words().find(|w| {
+                    w.starts_with("http://") || w.starts_with("https://")
+                }).map(|w| w.to_owned());
+                found.ok_or(~"the paste service didn't reply with a URL")
+            });
+            proc(L: &mut lua::State) -> i32 {
+                match res {
+                    Ok(url) => {
+                        L.pushstring(url.as_slice());
+                        1
+                    }
+                    Err(e) => {
+                        L.pushnil();
+                        L.pushstring(e.as_slice());
+                        2
+                    }
+                }
+            }
+        });
+        0
+    }
+
+    unsafe fn lua_plugin(L: &mut lua::ExternState) -> i32 {
+        // 1 arg: metadata
+
+        L.checktype(1, lua::Type::Table);
+        L.getfield(lua::REGISTRYINDEX, super::CURRENT_PLUGIN);
+        let plugin = match L.tostring(-1) {
+            None => L.errorstr("irc.plugin must be called by a plugin"),
+            Some(s) => s.to_owned()
+        };
+        L.pop(1);
+
+        L.createtable(0, 4);
+        for &field in ["name", "version", "description", "author"].iter() {
+            L.getfield(1, field);
+            match L.type_(-1) {
+                Some(lua::Type::String) | Some(lua::Type::Number) => L.setfield(-2, field),
+                None | Some(lua::Type::Nil) => L.pop(1),
+                _ => {
+                    let msg = format!("{} must be a string", field);
+                    L.argerror(1, msg.as_slice())
+                }
+            }
+        }
+        // the plugins table exists once any plugin has been loaded
+        L.getfield(lua::REGISTRYINDEX, super::PLUGINS);
+        L.checktype(-1, lua::Type::Table);
+        L.insert(-2);
+        L.setfield(-2, plugin.as_slice());
+        0
+    }
+
+    unsafe fn lua_lower(L: &mut lua::ExternState) -> i32 {
+        // 1 arg: nick or channel
+
+        let s = L.checkbytes(1);
+        L.pushbytes(super::casemapping(L).lower(s));
+        1
+    }
+
+    unsafe fn lua_eq(L: &mut lua::ExternState) -> i32 {
+        // 2 args: nick or channel, nick or channel
+
+        let a = L.checkbytes(1);
+        let b = L.checkbytes(2);
+        L.pushboolean(super::casemapping(L).eq(a, b));
+        1
+    }
 }
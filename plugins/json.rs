@@ -0,0 +1,195 @@
+//! Lua JSON library
+//!
+//! Vends a package named 'json' with functions for converting between JSON
+//! text and Lua values.
+//!
+//! json.encode(value): Returns the JSON text for the given value. Tables with
+//! only the keys 1..n are encoded as arrays, all other tables are encoded as
+//! objects and must have string (or number) keys. Empty tables are encoded as
+//! objects.
+//!
+//! json.decode(text): Returns the Lua value for the given JSON text. If the
+//! text could not be parsed, returns nil followed by an error message.
+//!
+//! json.null: A sentinel value used to represent JSON null. Decoding produces
+//! it for null values, and encoding turns it into null. Note that nil cannot be
+//! stored in a table, which is why the sentinel is necessary.
+
+#[allow(uppercase_variables)];
+
+use lua;
+use serialize::json;
+use serialize::json::Json;
+use collections::TreeMap;
+use std::{libc, str};
+
+// maximum nesting depth for encoding, which also catches cyclic tables
+static MAX_DEPTH: uint = 128;
+
+// the address of this value is used as json.null
+static NULL: u8 = 0;
+
+fn null_ptr() -> *mut libc::c_void {
+    &NULL as *u8 as *mut libc::c_void
+}
+
+lua_extern_pub! {
+    unsafe fn lua_require(L: &mut lua::ExternState) -> i32 {
+        // 1 argument is passed: modname
+
+        L.newtable();
+        L.registerlib(None, [
+            ("encode", lua_encode),
+            ("decode", lua_decode)
+        ]);
+
+        L.pushlightuserdata(null_ptr());
+        L.setfield(-2, "null");
+
+        1
+    }
+}
+
+lua_extern! {
+    unsafe fn lua_encode(L: &mut lua::ExternState) -> i32 {
+        // 1 arg: value
+
+        L.checkany(1);
+        L.settop(1);
+
+        let json = to_json(L, 1, 0);
+        L.pushstring(json.to_str().as_slice());
+        1
+    }
+
+    unsafe fn lua_decode(L: &mut lua::ExternState) -> i32 {
+        // 1 arg: text
+
+        let text = L.checkbytes(1);
+        let text = match str::from_utf8(text) {
+            None => {
+                L.pushnil();
+                L.pushstring("JSON text is not valid UTF-8");
+                return 2;
+            }
+            Some(s) => s
+        };
+        match json::from_str(text) {
+            Ok(json) => {
+                push_json(L, &json);
+                1
+            }
+            Err(e) => {
+                L.pushnil();
+                L.pushstring(e.to_str().as_slice());
+                2
+            }
+        }
+    }
+}
+
+/// Converts the Lua value at the given (absolute) index into Json
+unsafe fn to_json(L: &mut lua::ExternState, idx: i32, depth: uint) -> Json {
+    match L.type_(idx) {
+        None | Some(lua::Type::Nil) => json::Null,
+        Some(lua::Type::Boolean) => json::Boolean(L.toboolean(idx)),
+        Some(lua::Type::Number) => json::Number(L.tonumber(idx)),
+        Some(lua::Type::String) => {
+            let s = str::from_utf8_lossy(L.tobytes(idx).unwrap());
+            json::String(s.into_owned())
+        }
+        Some(lua::Type::LightUserdata) if L.touserdata(idx) == null_ptr() => json::Null,
+        Some(lua::Type::Table) => {
+            if depth >= MAX_DEPTH {
+                L.errorstr("table is too deeply nested (or cyclic) to encode");
+            }
+            if !L.checkstack(4) {
+                L.errorstr("stack overflow while encoding");
+            }
+            if is_array(L, idx) {
+                let len = L.objlen(idx) as i32;
+                let mut list = ~[];
+                for i in range(1, len + 1) {
+                    L.rawgeti(idx, i);
+                    list.push(to_json(L, L.gettop(), depth + 1));
+                    L.pop(1);
+                }
+                json::List(list)
+            } else {
+                let mut obj = ~TreeMap::new();
+                L.pushnil();
+                while L.next(idx) {
+                    // copy the key so converting it doesn't confuse next()
+                    L.pushvalue(-2);
+                    let key = match L.type_(-1) {
+                        Some(lua::Type::String) | Some(lua::Type::Number) => {
+                            str::from_utf8_lossy(L.tobytes(-1).unwrap()).into_owned()
+                        }
+                        _ => L.errorstr("table keys must be strings or numbers to encode")
+                    };
+                    L.pop(1);
+                    obj.insert(key, to_json(L, L.gettop(), depth + 1));
+                    L.pop(1); // pop value, leave key for next()
+                }
+                json::Object(obj)
+            }
+        }
+        Some(_) => {
+            let tname = L.typename(idx);
+            L.errorstr(format!("cannot encode value of type {}", tname).as_slice())
+        }
+    }
+}
+
+/// Returns whether the table at the given (absolute) index has only the keys 1..n, n > 0
+unsafe fn is_array(L: &mut lua::ExternState, idx: i32) -> bool {
+    let len = L.objlen(idx);
+    if len == 0 {
+        return false;
+    }
+    let mut count = 0;
+    L.pushnil();
+    while L.next(idx) {
+        count += 1;
+        L.pop(1);
+    }
+    if count != len {
+        return false;
+    }
+    for i in range(1, len as i32 + 1) {
+        L.rawgeti(idx, i);
+        let missing = L.isnil(-1);
+        L.pop(1);
+        if missing {
+            return false;
+        }
+    }
+    true
+}
+
+/// Pushes the Lua representation of the Json onto the stack
+unsafe fn push_json(L: &mut lua::ExternState, json: &Json) {
+    if !L.checkstack(3) {
+        L.errorstr("JSON is too deeply nested to decode");
+    }
+    match *json {
+        json::Null => L.pushlightuserdata(null_ptr()),
+        json::Boolean(b) => L.pushboolean(b),
+        json::Number(n) => L.pushnumber(n),
+        json::String(ref s) => L.pushstring(s.as_slice()),
+        json::List(ref list) => {
+            L.createtable(list.len() as i32, 0);
+            for (i, elem) in list.iter().enumerate() {
+                push_json(L, elem);
+                L.rawseti(-2, i as i32 + 1);
+            }
+        }
+        json::Object(ref obj) => {
+            L.createtable(0, obj.len() as i32);
+            for (key, elem) in obj.iter() {
+                push_json(L, elem);
+                L.setfield(-2, key.as_slice());
+            }
+        }
+    }
+}
@@ -0,0 +1,88 @@
+//! Lua logging library
+//!
+//! Vends a package named 'log' that writes messages through the bot's own
+//! logging, tagged with the name of the plugin that logged them. Plugins
+//! should use this instead of print(), whose output interleaves with the bot's.
+//!
+//! log.debug(fmt, ...)
+//! log.info(fmt, ...)
+//! log.warn(fmt, ...)
+//! log.error(fmt, ...)
+//!
+//! If more than one argument is given, the message is built with
+//! string.format(fmt, ...). As with the rest of the bot, which levels are shown
+//! is controlled with the RUST_LOG environment variable, e.g.
+//! RUST_LOG=rustirc=info.
+
+#[allow(uppercase_variables)];
+
+use lua;
+use std::str;
+
+lua_extern_pub! {
+    unsafe fn lua_require(L: &mut lua::ExternState) -> i32 {
+        // 1 argument is passed: modname
+
+        L.newtable();
+        L.registerlib(None, [
+            ("debug", lua_debug),
+            ("info", lua_info),
+            ("warn", lua_warn),
+            ("error", lua_error)
+        ]);
+
+        1
+    }
+}
+
+lua_extern! {
+    unsafe fn lua_debug(L: &mut lua::ExternState) -> i32 {
+        // 1+ args: fmt, ...
+
+        let msg = format_message(L);
+        let plugin = super::current_plugin(L);
+        debug!("[{}] {}", super::describe_plugin(L, plugin.as_slice()), msg);
+        0
+    }
+
+    unsafe fn lua_info(L: &mut lua::ExternState) -> i32 {
+        // 1+ args: fmt, ...
+
+        let msg = format_message(L);
+        let plugin = super::current_plugin(L);
+        info!("[{}] {}", super::describe_plugin(L, plugin.as_slice()), msg);
+        0
+    }
+
+    unsafe fn lua_warn(L: &mut lua::ExternState) -> i32 {
+        // 1+ args: fmt, ...
+
+        let msg = format_message(L);
+        let plugin = super::current_plugin(L);
+        warn!("[{}] {}", super::describe_plugin(L, plugin.as_slice()), msg);
+        0
+    }
+
+    unsafe fn lua_error(L: &mut lua::ExternState) -> i32 {
+        // 1+ args: fmt, ...
+
+        let msg = format_message(L);
+        let plugin = super::current_plugin(L);
+        error!("[{}] {}", super::describe_plugin(L, plugin.as_slice()), msg);
+        0
+    }
+}
+
+/// Builds the message from the arguments, using string.format() if there's more than one
+unsafe fn format_message(L: &mut lua::ExternState) -> ~str {
+    L.checkbytes(1);
+    let nargs = L.gettop();
+    if nargs > 1 {
+        L.getglobal("string");
+        L.getfield(-1, "format");
+        L.remove(-2);
+        L.insert(1);
+        L.call(nargs, 1);
+    }
+    str::from_utf8_lossy(L.checkbytes(1)).into_owned()
+}
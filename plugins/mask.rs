@@ -0,0 +1,52 @@
+//! IRC-style wildcard matching of hostmasks
+//!
+//! Masks use `*` to match any run of characters and `?` to match exactly one.
+//! Matching is case-insensitive.
+
+use std::ascii::AsciiCast;
+
+/// Returns whether the mask matches the user prefix, e.g. `*!*@*.example.com`
+/// against `nick!user@host.example.com`. A mask without `!` or `@` is taken to be
+/// a nick mask, so `foo*` is the same as `foo*!*@*`.
+pub fn matches(mask: &[u8], prefix: &[u8]) -> bool {
+    if mask.iter().any(|&b| b == '!' as u8 || b == '@' as u8) {
+        glob(mask, prefix)
+    } else {
+        let nick = match prefix.iter().position(|&b| b == '!' as u8 || b == '@' as u8) {
+            None => prefix,
+            Some(idx) => prefix.slice_to(idx)
+        };
+        glob(mask, nick)
+    }
+}
+
+/// Matches text against a glob pattern of `*` and `?`
+pub fn glob(pat: &[u8], text: &[u8]) -> bool {
+    let (mut p, mut t) = (0u, 0u);
+    // the position after the last `*` seen, and the text position it was tried at
+    let mut star: Option<(uint, uint)> = None;
+    while t < text.len() {
+        if p < pat.len() && pat[p] == '*' as u8 {
+            p += 1;
+            star = Some((p, t));
+        } else if p < pat.len() && (pat[p] == '?' as u8 || eq(pat[p], text[t])) {
+            p += 1;
+            t += 1;
+        } else {
+            match star {
+                None => return false,
+                Some((sp, st)) => {
+                    // let the last `*` swallow one more character
+                    p = sp;
+                    t = st + 1;
+                    star = Some((sp, st + 1));
+                }
+            }
+        }
+    }
+    pat.slice_from(p).iter().all(|&b| b == '*' as u8)
+}
+
+fn eq(a: u8, b: u8) -> bool {
+    a.to_ascii().to_lower() == b.to_ascii().to_lower()
+}
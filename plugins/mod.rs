@@ -4,26 +4,144 @@
 
 use lua;
 use config;
+use casemap;
+use casemap::CaseMapping;
+use isupport::ISupport;
+use tags;
+use feed;
+use dcc::Offer;
+use outbound::Outbound;
+use Cmd;
 use std::{io, libc, str};
+use time;
 
 static ERROR_HANDLER: &'static str = "error_handler";
+// registry key for the name of the plugin whose code is currently running
+static CURRENT_PLUGIN: &'static str = "current_plugin";
+// registry key for the name of the server's casemapping
+static CASEMAPPING: &'static str = "casemapping";
+// registry key for the table of the server's ISUPPORT tokens
+static ISUPPORT: &'static str = "isupport";
+// registry key for the connection's lag in seconds, from the keepalive PINGs
+static LAG: &'static str = "lag";
+// registry key for whether the server has labeled-response
+static LABELED_RESPONSE: &'static str = "labeled_response";
+// registry key for the id of the current connection
+static SESSION: &'static str = "session";
+// registry key for the configured name of the connection's server
+static SERVER: &'static str = "server";
+// registry key for the message tags of the event being dispatched
+static TAGS: &'static str = "tags";
+// registry key for whether the event being dispatched was played back by a bouncer
+static REPLAYED: &'static str = "replayed";
+// registry key for the time the event being dispatched was sent, from server-time
+static EVENT_TIME: &'static str = "event_time";
+// registry key for the paste endpoint's url and form field
+static PASTE: &'static str = "paste";
+// registry key for the table of loaded plugins, mapping each to the metadata it
+// registered with irc.plugin
+static PLUGINS: &'static str = "plugins";
+// the plugin name for Lua run from the console
+static CONSOLE_PLUGIN: &'static str = "console";
+// registry key for the state of users, a lightuserdata pointing to the Users
+static USERS: &'static str = "users";
+// registry key for the watched nicks, a lightuserdata pointing to the Watch
+static WATCH: &'static str = "watch";
+// registry key for the Twitch channel state, a lightuserdata pointing to the Twitch
+static TWITCH: &'static str = "twitch";
+// registry key for the handler watchdog, a lightuserdata pointing to the Watchdog
+static WATCHDOG: &'static str = "watchdog";
+// registry key for the table of per-plugin config sections
+static PLUGIN_CONFIGS: &'static str = "plugin_configs";
+
+// Compiles a MoonScript or Fennel plugin with the compiler found on package.path, and
+// loads the result. Called with the source, the file extension and the file name.
+static COMPILE_SRC: &'static str = r#"
+local source, ext, filename = ...
+local function compiler(module, language)
+    local ok, m = pcall(require, module)
+    if not ok then
+        error(language .. " plugins need the " .. module .. " module: " .. tostring(m), 0)
+    end
+    return m
+end
+if ext == "moon" then
+    local code, err = compiler("moonscript.base", "MoonScript").to_lua(source)
+    if not code then error(filename .. ": " .. tostring(err), 0) end
+    source = code
+else
+    source = compiler("fennel", "Fennel").compileString(source, {filename = filename})
+end
+local f, err = loadstring(source, "@" .. filename)
+if not f then error(err, 0) end
+return f
+"#;
+
+/// Extensions of plugin files. Plugins other than .lua are compiled to Lua when loaded.
+static PLUGIN_EXTENSIONS: &'static [&'static str] = &["lua", "moon", "fnl"];
+
+/// Milliseconds between checks for changed plugin files
+pub static WATCH_INTERVAL: u64 = 2000;
 
 /// Manages the Lua state for plugins
 pub struct PluginManager {
     priv state: lua::State,
-    priv plugin_dir: Path
+    priv config: config::Config,
+    priv server: uint, // index of the connection's server in config.servers
+    priv casemap: CaseMapping,
+    priv isupport: ISupport,
+    priv labeled_response: bool,
+    priv lag: Option<f64>,
+    priv session: ~str,
+    priv tasks: task::Tasks,
+    priv mtimes: ~[(Path, u64)], // modification times of the plugin files when they were loaded
+    priv natives: ~[~native::Plugin],
+    priv users: ~users::Users, // boxed so the registry can point to it
+    priv watch: ~watch::Watch, // likewise
+    priv twitch: ~twitch::Twitch, // likewise
+    priv watchdog: ~watchdog::Watchdog // likewise
 }
 
 impl PluginManager {
-    /// Creates a new PluginManager and loads all the plugins
-    pub fn new(conf: &config::Config) -> PluginManager {
+    /// Creates a new PluginManager for the connection to conf.servers[server] with the given
+    /// session id and loads all the plugins. Work scheduled by plugins is sent over `cmd_tx`.
+    pub fn new(conf: &config::Config, server: uint, session: &str, cmd_tx: Sender<Cmd>)
+               -> PluginManager {
         let L = lua::State::new();
 
-        let mut manager = PluginManager { state: L, plugin_dir: conf.plugin_dir.clone() };
+        let mut manager = PluginManager { state: L, config: conf.clone(), server: server,
+                                          casemap: casemap::Rfc1459, isupport: ISupport::new(),
+                                          labeled_response: false, lag: None,
+                                          session: session.to_owned(),
+                                          tasks: task::Tasks::new(cmd_tx),
+                                          mtimes: ~[], natives: ~[],
+                                          users: ~users::Users::new(),
+                                          watch: ~watch::Watch::new(conf.servers[server].watch),
+                                          twitch: ~twitch::Twitch::new(),
+                                          watchdog: ~watchdog::Watchdog::new(conf.handler_timeout)
+                                        };
         manager.setup();
+        manager.load_natives();
+        manager.mtimes = scan_plugins(manager.config.plugin_paths);
         manager
     }
 
+    /// Creates and loads the native plugins
+    fn load_natives(&mut self) {
+        self.natives = native::builtin(&self.config);
+        for plugin in self.natives.mut_iter() {
+            let section = self.config.plugin_configs.as_ref().and_then(|t| t.lookup(plugin.name()));
+            plugin.on_load(section);
+        }
+    }
+
+    fn unload_natives(&mut self) {
+        for plugin in self.natives.mut_iter() {
+            plugin.on_unload();
+        }
+        self.natives = ~[];
+    }
+
     fn setup(&mut self) {
         let L = &mut self.state;
         L.openlibs();
@@ -37,10 +155,51 @@ impl PluginManager {
         }
         L.setfield(lua::REGISTRYINDEX, ERROR_HANDLER);
 
+        L.pushstring(self.casemap.name());
+        L.setfield(lua::REGISTRYINDEX, CASEMAPPING);
+        store_isupport(L, &self.isupport);
+        L.pushboolean(self.labeled_response);
+        L.setfield(lua::REGISTRYINDEX, LABELED_RESPONSE);
+        match self.lag {
+            None => L.pushnil(),
+            Some(lag) => L.pushnumber(lag)
+        }
+        L.setfield(lua::REGISTRYINDEX, LAG);
+        L.pushstring(self.session.as_slice());
+        L.setfield(lua::REGISTRYINDEX, SESSION);
+        L.pushstring(self.config.servers[self.server].name.as_slice());
+        L.setfield(lua::REGISTRYINDEX, SERVER);
+        L.pushlightuserdata(&*self.users as *users::Users as *mut libc::c_void);
+        L.setfield(lua::REGISTRYINDEX, USERS);
+        L.pushlightuserdata(&mut *self.watch as *mut watch::Watch as *mut libc::c_void);
+        L.setfield(lua::REGISTRYINDEX, WATCH);
+        L.pushlightuserdata(&*self.twitch as *twitch::Twitch as *mut libc::c_void);
+        L.setfield(lua::REGISTRYINDEX, TWITCH);
+        L.pushlightuserdata(&mut *self.watchdog as *mut watchdog::Watchdog as *mut libc::c_void);
+        L.setfield(lua::REGISTRYINDEX, WATCHDOG);
+        match self.config.paste_url {
+            None => (),
+            Some(ref url) => {
+                L.createtable(0, 2);
+                L.pushstring(url.as_slice());
+                L.setfield(-2, "url");
+                match self.config.paste_field {
+                    None => (),
+                    Some(ref field) => {
+                        L.pushstring(field.as_slice());
+                        L.setfield(-2, "field");
+                    }
+                }
+                L.setfield(lua::REGISTRYINDEX, PASTE);
+            }
+        }
+
         // set up our packages for loading
         L.getfield(lua::REGISTRYINDEX, ERROR_HANDLER);
         L.pushcfunction(lua_setup_packages);
-        match L.pcall(0, 0, -2) {
+        L.pushlightuserdata(&self.config as *config::Config as *mut libc::c_void);
+        L.pushinteger(self.server as int);
+        match L.pcall(2, 0, -4) {
             Ok(()) => (),
             Err(e) => {
                 fail!("Error setting up lua packages: {}: {}", e, L.describe(-1));
@@ -48,57 +207,33 @@ impl PluginManager {
         }
         L.pop(1); // pop error handler
 
-        match io::fs::readdir(&self.plugin_dir) {
-            Err(e) => {
-                println!("Warning: Could not read plugin dir `{}': {}",
-                         self.plugin_dir.display(), e);
-            }
-            Ok(paths) => {
-                for path in paths.iter() {
-                    if path.as_vec() == bytes!(".") || path.as_vec() == bytes!("..") {
-                        continue;
-                    }
-                    if !path.is_file() { continue; }
-                    if path.extension() == Some(bytes!("lua")) {
-                        // found a plugin
-                        debug!("Loading plugin {}", path.filename_display());
-                        L.getfield(lua::REGISTRYINDEX, ERROR_HANDLER);
-                        match L.loadfile(Some(path)) {
-                            Ok(()) => (),
-                            Err(_) => {
-                                println!("Error loading plugin {}: {}", path.filename_display(),
-                                         L.describe(-1));
-                                L.pop(2); // pop error, error handler
-                                continue;
-                            }
-                        }
-                        // call the plugin's chunk with a single argument, the name of the plugin
-                        let name = str::from_utf8_lossy(path.filestem().unwrap());
-                        L.pushstring(name.as_slice());
-                        match L.pcall(1, 0, -3) {
-                            Ok(()) => (),
-                            Err(e) => {
-                                println!("Error running plugin {}: {}: {}",
-                                         path.filename_display(), e, L.describe(-1));
-                                L.pop(2); // pop error, error handler
-                                continue;
-                            }
-                        }
-                        L.pop(1); // pop error handler
-                    }
-                }
+        // plugins are known by their file names, so only the first file with a name is used
+        let mut loaded: ~[~[u8]] = ~[];
+        for path in find_plugins(self.config.plugin_paths).iter() {
+            let name = path.filestem().unwrap().to_owned();
+            if loaded.contains(&name) {
+                println!("Warning: Skipping plugin {}, a plugin with that name is already loaded",
+                         path.display());
+                continue;
             }
+            load_file(L, &mut *self.watchdog, path, &self.config.plugin_data_dir);
+            loaded.push(name);
         }
     }
 
     /// Reloads all plugins
-    pub fn reload_plugins(&mut self, conn: &mut irc::conn::Conn) {
+    pub fn reload_plugins(&mut self, conn: &mut irc::conn::Conn, out: &mut Outbound) {
         // do this by setting up a brand new lua::State and re-initializing
+        self.tasks.close_all_streams();
+        self.unload_natives();
         self.state = lua::State::new();
         self.setup();
+        self.load_natives();
+        self.mtimes = scan_plugins(self.config.plugin_paths);
+        out.reset_quotas();
 
         // dispatch the RELOADED event
-        irc::activate_conn(&mut self.state, conn);
+        irc::activate_conn(&mut self.state, conn, out, &mut self.tasks);
         self.state.getfield(lua::REGISTRYINDEX, ERROR_HANDLER);
         self.state.pushcfunction(irc::lua_dispatch_reloaded);
         match self.state.pcall(0, 0, -2) {
@@ -112,9 +247,407 @@ impl PluginManager {
         irc::deactivate_conn(&mut self.state);
     }
 
+    /// Evaluates a Lua expression in the plugins' state, returning whether it's true
+    pub fn eval(&mut self, conn: &mut irc::conn::Conn, out: &mut Outbound, expr: &str)
+                -> Result<bool, ~str> {
+        irc::activate_conn(&mut self.state, conn, out, &mut self.tasks);
+        self.state.getfield(lua::REGISTRYINDEX, ERROR_HANDLER);
+        let code = format!("return {}", expr);
+        let res = match self.state.loadstring(code) {
+            Ok(()) => match self.state.pcall(0, 1, -2) {
+                Ok(()) => Ok(self.state.toboolean(-1)),
+                Err(e) => Err(format!("{}: {}", e, self.state.describe(-1)))
+            },
+            Err(e) => Err(format!("{}: {}", e, self.state.describe(-1)))
+        };
+        self.state.pop(2);
+        irc::deactivate_conn(&mut self.state);
+        res
+    }
+
+    /// Runs a line of Lua from the console in the plugins' state, returning its results
+    /// separated by tabs. Like the standalone Lua REPL, an expression returns its value.
+    pub fn run_lua(&mut self, conn: &mut irc::conn::Conn, out: &mut Outbound, code: &str)
+                   -> Result<~str, ~str> {
+        irc::activate_conn(&mut self.state, conn, out, &mut self.tasks);
+        self.state.getfield(lua::REGISTRYINDEX, ERROR_HANDLER);
+        let base = self.state.gettop();
+        let mut loaded = self.state.loadstring(format!("return {}", code)).is_ok();
+        if !loaded {
+            self.state.pop(1);
+            loaded = self.state.loadstring(code).is_ok();
+        }
+        let res = if !loaded {
+            Err(self.state.describe(-1))
+        } else {
+            // anything the code registers or sends is attributed to the console
+            self.state.pushstring(CONSOLE_PLUGIN);
+            self.state.setfield(lua::REGISTRYINDEX, CURRENT_PLUGIN);
+            match self.state.pcall(0, lua::MULTRET, base) {
+                Ok(()) => {
+                    let mut results = ~[];
+                    for i in range(base + 1, self.state.gettop() + 1) {
+                        results.push(self.state.describe(i));
+                    }
+                    Ok(results.connect("\t"))
+                }
+                Err(e) => Err(format!("{}: {}", e, self.state.describe(-1)))
+            }
+        };
+        self.state.settop(base - 1);
+        self.state.pushnil();
+        self.state.setfield(lua::REGISTRYINDEX, CURRENT_PLUGIN);
+        irc::deactivate_conn(&mut self.state);
+        res
+    }
+
+    /// Loads the named plugin from the plugin dir, unloading it first if it's loaded, and
+    /// dispatches irc.RELOADED to it alone. Other plugins are left as they are.
+    pub fn load_plugin(&mut self, conn: &mut irc::conn::Conn, out: &mut Outbound,
+                       name: &str) -> Result<(), ~str> {
+        if name.is_empty() || name.contains_char('/') {
+            return Err(format!("invalid plugin name `{}'", name));
+        }
+        let path = find_plugins(self.config.plugin_paths).move_iter().find(|p| {
+            p.filestem() == Some(name.as_bytes())
+        });
+        let path = match path {
+            None => return Err(format!("no plugin file named {}", name)),
+            Some(p) => p
+        };
+        self.unload_plugin(name);
+        if !load_file(&mut self.state, &mut *self.watchdog, &path,
+                      &self.config.plugin_data_dir) {
+            return Err(format!("plugin {} failed to load", name));
+        }
+        self.mtimes = scan_plugins(self.config.plugin_paths);
+
+        irc::activate_conn(&mut self.state, conn, out, &mut self.tasks);
+        self.state.getfield(lua::REGISTRYINDEX, ERROR_HANDLER);
+        self.state.pushcfunction(irc::lua_dispatch_reloaded);
+        self.state.pushstring(name);
+        match self.state.pcall(1, 0, -3) {
+            Ok(()) => (),
+            Err(e) => {
+                println!("Error dispatching RELOADED event: {}: {}", e, self.state.describe(-1));
+                self.state.pop(1);
+            }
+        }
+        self.state.pop(1);
+        irc::deactivate_conn(&mut self.state);
+        Ok(())
+    }
+
+    /// Unloads the named plugin by removing its handlers and callbacks and closing its
+    /// streams, returning how many handlers it had. Globals the plugin set are left alone.
+    pub fn unload_plugin(&mut self, name: &str) -> uint {
+        let count = self.clear_handlers(name);
+        for &id in task::clear_callbacks(&mut self.state, name).iter() {
+            self.tasks.close_stream(id);
+        }
+        self.state.pushnil();
+        set_plugin_info(&mut self.state, name);
+        count
+    }
+
+    /// Prints the loaded plugins with the metadata they registered
+    pub fn print_plugins(&mut self) {
+        for plugin in self.natives.iter() {
+            println!("{} (native)", plugin.name());
+        }
+        self.state.getfield(lua::REGISTRYINDEX, ERROR_HANDLER);
+        self.state.pushcfunction(lua_print_plugins);
+        match self.state.pcall(0, 0, -2) {
+            Ok(()) => (),
+            Err(e) => {
+                println!("Error listing plugins: {}: {}", e, self.state.describe(-1));
+                self.state.pop(1);
+            }
+        }
+        self.state.pop(1);
+    }
+
+    /// Returns the names of the loaded plugins, native plugins first
+    pub fn plugin_names(&mut self) -> ~[~str] {
+        let mut names: ~[~str] = self.natives.iter().map(|p| {
+            format!("{} (native)", p.name())
+        }).collect();
+        let mut lua_names = ~[];
+        self.state.getfield(lua::REGISTRYINDEX, PLUGINS);
+        if self.state.istable(-1) {
+            let table = self.state.gettop();
+            self.state.pushnil();
+            while self.state.next(table) {
+                lua_names.push(self.state.tostring(-2).map_or(~"(unknown)", |s| s.to_owned()));
+                self.state.pop(1); // pop the metadata, leaving the key for next
+            }
+        }
+        self.state.pop(1);
+        lua_names.sort();
+        names.push_all_move(lua_names);
+        names
+    }
+
+    /// Returns whether any plugin file was added, removed or modified since plugins were
+    /// last loaded
+    pub fn plugins_changed(&self) -> bool {
+        scan_plugins(self.config.plugin_paths) != self.mtimes
+    }
+
+    /// Sets the server's ISUPPORT tokens, for irc.isupport() and the casemapping and
+    /// channel modes used by plugins and the user state
+    pub fn set_isupport(&mut self, isupport: &ISupport) {
+        self.isupport = isupport.clone();
+        self.casemap = isupport.casemapping();
+        self.users.set_isupport(isupport);
+        self.watch.set_isupport(isupport);
+        self.twitch.set_isupport(isupport);
+        self.state.pushstring(self.casemap.name());
+        self.state.setfield(lua::REGISTRYINDEX, CASEMAPPING);
+        store_isupport(&mut self.state, isupport);
+    }
+
+    /// Sets whether the server has labeled-response, which irc.query and the watched nicks'
+    /// polls correlate replies with
+    pub fn set_labeled_response(&mut self, enabled: bool) {
+        self.watch.set_labeled_response(enabled);
+        if enabled != self.labeled_response {
+            self.labeled_response = enabled;
+            self.state.pushboolean(enabled);
+            self.state.setfield(lua::REGISTRYINDEX, LABELED_RESPONSE);
+        }
+    }
+
+    /// Sets the connection's lag in seconds, for irc.lag()
+    pub fn set_lag(&mut self, lag: Option<f64>) {
+        self.lag = lag;
+        match lag {
+            None => self.state.pushnil(),
+            Some(lag) => self.state.pushnumber(lag)
+        }
+        self.state.setfield(lua::REGISTRYINDEX, LAG);
+    }
+
+    /// Dispatches irc.GREET for a configured greeting, letting plugins change or suppress it
+    /// Returns the greeting to send, if any.
+    pub fn filter_greeting(&mut self, conn: &mut irc::conn::Conn, out: &mut Outbound,
+                           user: &::irc::User, chan: &[u8], msg: &[u8]) -> Option<~[u8]> {
+        irc::activate_conn(&mut self.state, conn, out, &mut self.tasks);
+        self.state.getfield(lua::REGISTRYINDEX, ERROR_HANDLER);
+        self.state.pushcfunction(irc::lua_dispatch_greet);
+        self.state.pushlightuserdata(user as *::irc::User as *mut libc::c_void);
+        self.state.pushbytes(chan);
+        self.state.pushbytes(msg);
+        let result = match self.state.pcall(3, 1, -5) {
+            Ok(()) => self.state.tobytes(-1).map(|s| s.to_owned()),
+            Err(e) => {
+                println!("Error dispatching GREET event: {}: {}", e, self.state.describe(-1));
+                // send the greeting unchanged
+                Some(msg.to_owned())
+            }
+        };
+        self.state.pop(2);
+        irc::deactivate_conn(&mut self.state);
+        result
+    }
+
+    /// Dispatches a new item from the named feed
+    pub fn dispatch_feed_item(&mut self, conn: &mut irc::conn::Conn, out: &mut Outbound,
+                              feed: &str, item: &feed::Item) {
+        irc::activate_conn(&mut self.state, conn, out, &mut self.tasks);
+        self.state.getfield(lua::REGISTRYINDEX, ERROR_HANDLER);
+        self.state.pushcfunction(irc::lua_dispatch_feed_item);
+        self.state.pushstring(feed);
+        self.state.createtable(0, 4);
+        self.state.pushstring(item.title);
+        self.state.setfield(-2, "title");
+        self.state.pushstring(item.link);
+        self.state.setfield(-2, "link");
+        self.state.pushstring(item.date);
+        self.state.setfield(-2, "date");
+        self.state.pushstring(item.id);
+        self.state.setfield(-2, "id");
+        match self.state.pcall(2, 0, -4) {
+            Ok(()) => (),
+            Err(e) => {
+                println!("Error dispatching feed item: {}: {}", e, self.state.describe(-1));
+                self.state.pop(1);
+            }
+        }
+        self.state.pop(1);
+        irc::deactivate_conn(&mut self.state);
+    }
+
+    /// Runs the periodic work for plugins, such as irc.await timeouts
+    pub fn dispatch_tick(&mut self, conn: &mut irc::conn::Conn, out: &mut Outbound) {
+        self.watch.tick(conn, out);
+        irc::activate_conn(&mut self.state, conn, out, &mut self.tasks);
+        self.state.getfield(lua::REGISTRYINDEX, ERROR_HANDLER);
+        self.state.pushcfunction(irc::lua_dispatch_tick);
+        match self.state.pcall(0, 0, -2) {
+            Ok(()) => (),
+            Err(e) => {
+                println!("Error dispatching tick: {}: {}", e, self.state.describe(-1));
+                self.state.pop(1);
+            }
+        }
+        self.state.pop(1);
+        irc::deactivate_conn(&mut self.state);
+    }
+
+    /// Calls the plugin callback with the given id, if it still exists, with the arguments
+    /// pushed by `push`. If `last` is true, the callback and any stream attached to it are
+    /// released.
+    pub fn run_callback(&mut self, conn: &mut irc::conn::Conn, out: &mut Outbound, id: uint,
+                        push: task::Pusher, last: bool) {
+        if last {
+            self.tasks.close_stream(id);
+        }
+        irc::activate_conn(&mut self.state, conn, out, &mut self.tasks);
+        self.state.getfield(lua::REGISTRYINDEX, ERROR_HANDLER);
+        match task::push_callback(&mut self.state, id, last) {
+            None => {
+                self.state.pop(1);
+            }
+            Some(plugin) => {
+                let nargs = push(&mut self.state);
+                // run it like a handler, so it has the same limits
+                irc::insert_runner(&mut self.state, nargs);
+                self.watchdog.started(plugin.as_slice(), "callback");
+                let result = self.state.pcall(nargs + 1, 0, -(nargs + 3));
+                self.watchdog.finished();
+                match result {
+                    Ok(()) => (),
+                    Err(e) => {
+                        println!("Error in plugin {} running callback: {}: {}",
+                                 plugin, e, self.state.describe(-1));
+                        self.state.pop(1);
+                    }
+                }
+                self.state.pop(1);
+                self.state.pushnil();
+                self.state.setfield(lua::REGISTRYINDEX, CURRENT_PLUGIN);
+            }
+        }
+        irc::deactivate_conn(&mut self.state);
+    }
+
+    /// Prints the registered handlers grouped by plugin and event, optionally only those
+    /// for the given event
+    pub fn print_handlers(&mut self, event: Option<&str>) {
+        self.state.getfield(lua::REGISTRYINDEX, ERROR_HANDLER);
+        self.state.pushcfunction(irc::lua_print_handlers);
+        match event {
+            None => self.state.pushnil(),
+            Some(event) => self.state.pushstring(event)
+        }
+        match self.state.pcall(1, 0, -3) {
+            Ok(()) => (),
+            Err(e) => {
+                println!("Error listing handlers: {}: {}", e, self.state.describe(-1));
+                self.state.pop(1);
+            }
+        }
+        self.state.pop(1);
+    }
+
+    /// Removes all the handlers registered by the plugin, returning how many there were
+    pub fn clear_handlers(&mut self, plugin: &str) -> uint {
+        self.state.getfield(lua::REGISTRYINDEX, ERROR_HANDLER);
+        self.state.pushcfunction(irc::lua_clear_handlers);
+        self.state.pushstring(plugin);
+        let count = match self.state.pcall(1, 1, -3) {
+            Ok(()) => self.state.tointeger(-1) as uint,
+            Err(e) => {
+                println!("Error clearing handlers: {}: {}", e, self.state.describe(-1));
+                0
+            }
+        };
+        self.state.pop(2);
+        count
+    }
+
     /// Dispatches an IRC event
-    pub fn dispatch_irc_event(&mut self, conn: &mut irc::conn::Conn, event: &irc::conn::Event) {
-        irc::activate_conn(&mut self.state, conn);
+    /// The tags, and whether a bouncer replayed it, are available to handlers while it's
+    /// dispatched. If the event changes a user's away status or host, the PRESENCE or
+    /// HOSTCHANGE event is dispatched after it, WATCH for each watched nick it shows
+    /// came online or went offline, CLEARCHAT if it's Twitch's CLEARCHAT, and MODECHANGE
+    /// if it's a MODE line for one of the bot's channels.
+    pub fn dispatch_irc_event(&mut self, conn: &mut irc::conn::Conn, out: &mut Outbound,
+                              event: &irc::conn::Event, tags: tags::Tags, replayed: bool) {
+        let change = match *event {
+            irc::conn::Connected => {
+                self.users.clear();
+                self.watch.clear();
+                self.twitch.clear();
+                irc::clear_batches(&mut self.state);
+                None
+            }
+            irc::conn::LineReceived(ref line) => {
+                self.users.line_received(conn.me().nick(), line, tags)
+            }
+            _ => None
+        };
+        let watched = match *event {
+            irc::conn::LineReceived(ref line) => {
+                self.watch.line_received(conn, out, &self.casemap, line, tags)
+            }
+            _ => ~[]
+        };
+        let cleared = match *event {
+            irc::conn::LineReceived(ref line) => {
+                self.twitch.line_received(conn.me().nick(), line, tags)
+            }
+            _ => None
+        };
+        self.dispatch_line(conn, out, event, tags, replayed);
+        for &(ref nick, online) in watched.iter() {
+            self.dispatch_watch(conn, out, nick.as_slice(), online);
+        }
+        match cleared {
+            None => (),
+            Some((chan, nick, duration)) => {
+                self.dispatch_clear_chat(conn, out, chan.as_slice(), nick, duration);
+            }
+        }
+        match (change, event) {
+            (Some(users::Away(nick, msg)), _) => self.dispatch_presence(conn, out, nick, msg),
+            (Some(users::Host(_, user, host)), &irc::conn::LineReceived(ref line)) => {
+                match line.prefix {
+                    None => (),
+                    Some(ref old) => self.dispatch_host_change(conn, out, old, user, host)
+                }
+            }
+            (Some(users::Modes(chan, changes)), &irc::conn::LineReceived(ref line)) => {
+                self.dispatch_mode_change(conn, out, chan.as_slice(), line.prefix.as_ref(),
+                                          changes.as_slice());
+            }
+            _ => ()
+        }
+    }
+
+    /// Dispatches the event to the native plugins, and then to Lua unless one consumed it
+    fn dispatch_line(&mut self, conn: &mut irc::conn::Conn, out: &mut Outbound,
+                     event: &irc::conn::Event, tags: tags::Tags, replayed: bool) {
+        for plugin in self.natives.mut_iter() {
+            if plugin.on_event(conn, out, event, tags, replayed) {
+                return;
+            }
+        }
+        irc::activate_conn(&mut self.state, conn, out, &mut self.tasks);
+        self.state.createtable(0, tags.len() as i32);
+        for &(ref name, ref value) in tags.iter() {
+            self.state.pushstring(*value);
+            self.state.setfield(-2, *name);
+        }
+        self.state.setfield(lua::REGISTRYINDEX, TAGS);
+        self.state.pushboolean(replayed);
+        self.state.setfield(lua::REGISTRYINDEX, REPLAYED);
+        match tags::server_time(tags) {
+            None => self.state.pushnil(),
+            Some(t) => self.state.pushnumber(t)
+        }
+        self.state.setfield(lua::REGISTRYINDEX, EVENT_TIME);
         self.state.getfield(lua::REGISTRYINDEX, ERROR_HANDLER);
         self.state.pushcfunction(irc::lua_dispatch_event);
         self.state.pushlightuserdata(event as *irc::conn::Event as *mut libc::c_void);
@@ -126,12 +659,750 @@ impl PluginManager {
             }
         }
         self.state.pop(1);
+        self.state.pushnil();
+        self.state.setfield(lua::REGISTRYINDEX, TAGS);
+        self.state.pushnil();
+        self.state.setfield(lua::REGISTRYINDEX, REPLAYED);
+        self.state.pushnil();
+        self.state.setfield(lua::REGISTRYINDEX, EVENT_TIME);
+        irc::deactivate_conn(&mut self.state);
+    }
+
+    /// Dispatches irc.ALTNICK, when the bot registered with an alternate nick
+    pub fn dispatch_alt_nick(&mut self, conn: &mut irc::conn::Conn, out: &mut Outbound,
+                             nick: &[u8], configured: &[u8]) {
+        irc::activate_conn(&mut self.state, conn, out, &mut self.tasks);
+        self.state.getfield(lua::REGISTRYINDEX, ERROR_HANDLER);
+        self.state.pushcfunction(irc::lua_dispatch_alt_nick);
+        self.state.pushbytes(nick);
+        self.state.pushbytes(configured);
+        match self.state.pcall(2, 0, -4) {
+            Ok(()) => (),
+            Err(e) => {
+                println!("Error dispatching ALTNICK event: {}: {}", e, self.state.describe(-1));
+                self.state.pop(1);
+            }
+        }
+        self.state.pop(1);
+        irc::deactivate_conn(&mut self.state);
+    }
+
+    /// Dispatches irc.KICKED, when the bot was kicked from a channel
+    pub fn dispatch_kicked(&mut self, conn: &mut irc::conn::Conn, out: &mut Outbound,
+                           chan: &[u8], kicker: &irc::User, reason: &[u8],
+                           rejoin: bool) {
+        irc::activate_conn(&mut self.state, conn, out, &mut self.tasks);
+        self.state.getfield(lua::REGISTRYINDEX, ERROR_HANDLER);
+        self.state.pushcfunction(irc::lua_dispatch_kicked);
+        self.state.pushbytes(chan);
+        self.state.pushlightuserdata(kicker as *irc::User as *mut libc::c_void);
+        self.state.pushbytes(reason);
+        self.state.pushboolean(rejoin);
+        match self.state.pcall(4, 0, -6) {
+            Ok(()) => (),
+            Err(e) => {
+                println!("Error dispatching KICKED event: {}: {}", e, self.state.describe(-1));
+                self.state.pop(1);
+            }
+        }
+        self.state.pop(1);
+        irc::deactivate_conn(&mut self.state);
+    }
+
+    /// Dispatches irc.DCC, when a user offered the bot a file
+    pub fn dispatch_dcc(&mut self, conn: &mut irc::conn::Conn, out: &mut Outbound,
+                        user: &irc::User, offer: &Offer) {
+        irc::activate_conn(&mut self.state, conn, out, &mut self.tasks);
+        self.state.getfield(lua::REGISTRYINDEX, ERROR_HANDLER);
+        self.state.pushcfunction(irc::lua_dispatch_dcc);
+        self.state.pushlightuserdata(user as *irc::User as *mut libc::c_void);
+        self.state.pushlightuserdata(offer as *Offer as *mut libc::c_void);
+        match self.state.pcall(2, 0, -4) {
+            Ok(()) => (),
+            Err(e) => {
+                println!("Error dispatching DCC event: {}: {}", e, self.state.describe(-1));
+                self.state.pop(1);
+            }
+        }
+        self.state.pop(1);
+        irc::deactivate_conn(&mut self.state);
+    }
+
+    /// Dispatches a watched nick coming online or going offline
+    fn dispatch_watch(&mut self, conn: &mut irc::conn::Conn, out: &mut Outbound, nick: &[u8],
+                      online: bool) {
+        irc::activate_conn(&mut self.state, conn, out, &mut self.tasks);
+        self.state.getfield(lua::REGISTRYINDEX, ERROR_HANDLER);
+        self.state.pushcfunction(irc::lua_dispatch_watch);
+        self.state.pushbytes(nick);
+        self.state.pushboolean(online);
+        match self.state.pcall(2, 0, -4) {
+            Ok(()) => (),
+            Err(e) => {
+                println!("Error dispatching WATCH event: {}: {}", e, self.state.describe(-1));
+                self.state.pop(1);
+            }
+        }
+        self.state.pop(1);
         irc::deactivate_conn(&mut self.state);
     }
+
+    /// Dispatches Twitch's CLEARCHAT, when a user's messages or a whole channel's were
+    /// cleared
+    fn dispatch_clear_chat(&mut self, conn: &mut irc::conn::Conn, out: &mut Outbound,
+                           chan: &[u8], nick: Option<~[u8]>, duration: Option<uint>) {
+        irc::activate_conn(&mut self.state, conn, out, &mut self.tasks);
+        self.state.getfield(lua::REGISTRYINDEX, ERROR_HANDLER);
+        self.state.pushcfunction(irc::lua_dispatch_clear_chat);
+        self.state.pushbytes(chan);
+        match nick {
+            None => self.state.pushnil(),
+            Some(nick) => self.state.pushbytes(nick.as_slice())
+        }
+        match duration {
+            None => self.state.pushnil(),
+            Some(secs) => self.state.pushinteger(secs as int)
+        }
+        match self.state.pcall(3, 0, -5) {
+            Ok(()) => (),
+            Err(e) => {
+                println!("Error dispatching CLEARCHAT event: {}: {}", e,
+                         self.state.describe(-1));
+                self.state.pop(1);
+            }
+        }
+        self.state.pop(1);
+        irc::deactivate_conn(&mut self.state);
+    }
+
+    /// Dispatches the modes a MODE line set and unset in one of the bot's channels
+    fn dispatch_mode_change(&mut self, conn: &mut irc::conn::Conn, out: &mut Outbound,
+                            chan: &[u8], setter: Option<&irc::User>,
+                            changes: &[users::ModeChange]) {
+        irc::activate_conn(&mut self.state, conn, out, &mut self.tasks);
+        self.state.getfield(lua::REGISTRYINDEX, ERROR_HANDLER);
+        self.state.pushcfunction(irc::lua_dispatch_mode_change);
+        self.state.pushbytes(chan);
+        match setter {
+            None => self.state.pushnil(),
+            Some(user) => self.state.pushlightuserdata(user as *irc::User as *mut libc::c_void)
+        }
+        self.state.pushlightuserdata(&changes as *&[users::ModeChange] as *mut libc::c_void);
+        match self.state.pcall(3, 0, -5) {
+            Ok(()) => (),
+            Err(e) => {
+                println!("Error dispatching MODECHANGE event: {}: {}", e,
+                         self.state.describe(-1));
+                self.state.pop(1);
+            }
+        }
+        self.state.pop(1);
+        irc::deactivate_conn(&mut self.state);
+    }
+
+    /// Dispatches a change in a user's username and host
+    fn dispatch_host_change(&mut self, conn: &mut irc::conn::Conn, out: &mut Outbound,
+                            old: &irc::User, user: ~[u8], host: ~[u8]) {
+        irc::activate_conn(&mut self.state, conn, out, &mut self.tasks);
+        self.state.getfield(lua::REGISTRYINDEX, ERROR_HANDLER);
+        self.state.pushcfunction(irc::lua_dispatch_host_change);
+        self.state.pushlightuserdata(old as *irc::User as *mut libc::c_void);
+        self.state.pushbytes(user.as_slice());
+        self.state.pushbytes(host.as_slice());
+        match self.state.pcall(3, 0, -5) {
+            Ok(()) => (),
+            Err(e) => {
+                println!("Error dispatching HOSTCHANGE event: {}: {}", e,
+                         self.state.describe(-1));
+                self.state.pop(1);
+            }
+        }
+        self.state.pop(1);
+        irc::deactivate_conn(&mut self.state);
+    }
+
+    /// Dispatches a change in a user's away status
+    fn dispatch_presence(&mut self, conn: &mut irc::conn::Conn, out: &mut Outbound,
+                         nick: ~[u8], msg: Option<~[u8]>) {
+        irc::activate_conn(&mut self.state, conn, out, &mut self.tasks);
+        self.state.getfield(lua::REGISTRYINDEX, ERROR_HANDLER);
+        self.state.pushcfunction(irc::lua_dispatch_presence);
+        self.state.pushbytes(nick.as_slice());
+        match msg {
+            None => self.state.pushnil(),
+            Some(msg) => self.state.pushbytes(msg.as_slice())
+        }
+        match self.state.pcall(2, 0, -4) {
+            Ok(()) => (),
+            Err(e) => {
+                println!("Error dispatching PRESENCE event: {}: {}", e, self.state.describe(-1));
+                self.state.pop(1);
+            }
+        }
+        self.state.pop(1);
+        irc::deactivate_conn(&mut self.state);
+    }
+}
+
+impl Drop for PluginManager {
+    fn drop(&mut self) {
+        self.unload_natives();
+    }
+}
+
+/// Loads and runs the plugin file, returning whether it succeeded. Sandboxed plugins get
+/// a dir named after them in data_dir.
+fn load_file(L: &mut lua::State, watchdog: &mut watchdog::Watchdog, path: &Path,
+             data_dir: &Path) -> bool {
+    debug!("Loading plugin {}", path.filename_display());
+    L.getfield(lua::REGISTRYINDEX, ERROR_HANDLER);
+    let loaded = if path.extension() == Some(bytes!("lua")) {
+        L.loadfile(Some(path)).is_ok()
+    } else {
+        compile_file(L, path)
+    };
+    if !loaded {
+        println!("Error loading plugin {}: {}", path.filename_display(), L.describe(-1));
+        L.pop(2); // pop error, error handler
+        return false;
+    }
+    // call the plugin's chunk with the name of the plugin and its config section
+    let name = str::from_utf8_lossy(path.filestem().unwrap());
+    L.newtable();
+    set_plugin_info(L, name.as_slice());
+    L.pushstring(name.as_slice());
+    L.setfield(lua::REGISTRYINDEX, CURRENT_PLUGIN);
+    L.pushstring(name.as_slice());
+    push_plugin_config(L, name.as_slice());
+    L.getfield(-1, "sandbox");
+    let sandboxed = L.toboolean(-1);
+    L.pop(1);
+    if sandboxed {
+        let config = L.gettop();
+        match sandbox::push_env(L, &data_dir.join(name.as_slice()), config) {
+            Ok(()) => {
+                L.setfenv(-4);
+            }
+            Err(e) => {
+                println!("Error sandboxing plugin {}: {}", path.filename_display(), e);
+                L.pop(4); // pop config, name, chunk, error handler
+                L.pushnil();
+                L.setfield(lua::REGISTRYINDEX, CURRENT_PLUGIN);
+                L.pushnil();
+                set_plugin_info(L, name.as_slice());
+                return false;
+            }
+        }
+    }
+    // run it like a handler, so it has the same limits
+    irc::insert_runner(L, 2);
+    watchdog.started(name.as_slice(), "load");
+    let res = L.pcall(3, 0, -5);
+    watchdog.finished();
+    L.pushnil();
+    L.setfield(lua::REGISTRYINDEX, CURRENT_PLUGIN);
+    match res {
+        Ok(()) => (),
+        Err(e) => {
+            println!("Error running plugin {}: {}: {}", path.filename_display(), e,
+                     L.describe(-1));
+            L.pop(2); // pop error, error handler
+            L.pushnil();
+            set_plugin_info(L, name.as_slice());
+            return false;
+        }
+    }
+    L.pop(1); // pop error handler
+    true
+}
+
+/// Compiles a MoonScript or Fennel plugin to Lua and loads it. Like loadfile, this
+/// pushes the chunk and returns true, or pushes an error message and returns false.
+fn compile_file(L: &mut lua::State, path: &Path) -> bool {
+    let source = match io::File::open(path).and_then(|mut f| f.read_to_end()) {
+        Ok(s) => s,
+        Err(e) => {
+            L.pushstring(e.to_str());
+            return false;
+        }
+    };
+    match L.loadstring(COMPILE_SRC) {
+        Ok(()) => (),
+        Err(_) => return false
+    }
+    L.pushbytes(source);
+    L.pushbytes(path.extension().unwrap());
+    L.pushbytes(path.filename().unwrap());
+    L.pcall(3, 1, 0).is_ok()
+}
+
+/// Pushes the plugin's [plugins.<name>] config section, or an empty table
+fn push_plugin_config(L: &mut lua::State, plugin: &str) {
+    L.getfield(lua::REGISTRYINDEX, PLUGIN_CONFIGS);
+    if L.istable(-1) {
+        L.getfield(-1, plugin);
+        L.remove(-2);
+    }
+    if !L.istable(-1) {
+        L.pop(1);
+        L.newtable();
+    }
+}
+
+/// Stores a table of the ISUPPORT tokens in the registry, for irc.isupport()
+fn store_isupport(L: &mut lua::State, isupport: &ISupport) {
+    L.createtable(0, isupport.tokens().len() as i32);
+    for &(ref name, ref value) in isupport.tokens().iter() {
+        L.pushstring(*value);
+        L.setfield(-2, *name);
+    }
+    L.setfield(lua::REGISTRYINDEX, ISUPPORT);
+}
+
+/// Pops the value on top of the stack and stores it as the plugin's metadata, or with
+/// nil marks the plugin as not loaded
+fn set_plugin_info(L: &mut lua::State, plugin: &str) {
+    L.getfield(lua::REGISTRYINDEX, PLUGINS);
+    if !L.istable(-1) {
+        L.pop(1);
+        L.newtable();
+        L.pushvalue(-1);
+        L.setfield(lua::REGISTRYINDEX, PLUGINS);
+    }
+    L.insert(-2);
+    L.setfield(-2, plugin);
+    L.pop(1);
+}
+
+/// Returns the plugin's name for messages, with the version it registered, if any
+unsafe fn describe_plugin(L: &mut lua::ExternState, plugin: &str) -> ~str {
+    L.getfield(lua::REGISTRYINDEX, PLUGINS);
+    let mut desc = plugin.to_owned();
+    if L.istable(-1) {
+        L.getfield(-1, plugin);
+        if L.istable(-1) {
+            L.getfield(-1, "version");
+            match L.tostring(-1) {
+                None => (),
+                Some(version) => desc = format!("{} {}", plugin, version)
+            }
+            L.pop(1);
+        }
+        L.pop(1);
+    }
+    L.pop(1);
+    desc
+}
+
+/// Returns the path and modification time of each plugin file, sorted by path
+fn scan_plugins(paths: &[Path]) -> ~[(Path, u64)] {
+    let mut plugins: ~[(Path, u64)] = find_plugins(paths).move_iter().filter_map(|p| {
+        match p.stat() {
+            Ok(st) => Some((p, st.modified)),
+            Err(_) => None
+        }
+    }).collect();
+    plugins.sort_by(|&(ref a, _), &(ref b, _)| a.as_vec().cmp(&b.as_vec()));
+    plugins
+}
+
+/// Returns the plugin files matched by the configured plugin paths, in load order
+fn find_plugins(paths: &[Path]) -> ~[Path] {
+    let mut found: ~[Path] = ~[];
+    for path in paths.iter() {
+        let pattern = if !has_glob(path.as_vec()) && path.is_dir() {
+            path.join("*")
+        } else {
+            path.clone()
+        };
+        let comps: ~[&[u8]] = pattern.components().collect();
+        let root = Path::new(if pattern.is_absolute() { "/" } else { "." });
+        let mut matched = ~[];
+        expand(root, comps, &mut matched);
+        matched.sort_by(|a, b| a.as_vec().cmp(&b.as_vec()));
+        for p in matched.move_iter() {
+            // ** can reach the same file more than once
+            if !found.contains(&p) {
+                found.push(p);
+            }
+        }
+    }
+    found
+}
+
+/// Adds the plugin files under dir that match the remaining pattern components
+fn expand(dir: Path, comps: &[&[u8]], out: &mut ~[Path]) {
+    if comps.is_empty() {
+        let is_plugin = dir.extension().map_or(false, |ext| {
+            PLUGIN_EXTENSIONS.iter().any(|e| e.as_bytes() == ext)
+        });
+        if is_plugin && dir.is_file() {
+            out.push(dir);
+        }
+        return;
+    }
+    let (comp, rest) = (comps[0], comps.slice_from(1));
+    if comp == bytes!("**") {
+        // match no directories, or descend one and keep the **
+        expand(dir.clone(), rest, out);
+        for sub in readdir(&dir).move_iter().filter(|p| p.is_dir()) {
+            expand(sub, comps, out);
+        }
+    } else if has_glob(comp) {
+        for entry in readdir(&dir).move_iter() {
+            if entry.filename().map_or(false, |name| mask::glob(comp, name)) {
+                expand(entry, rest, out);
+            }
+        }
+    } else {
+        expand(dir.join(comp), rest, out);
+    }
+}
+
+fn readdir(dir: &Path) -> ~[Path] {
+    if !dir.is_dir() {
+        return ~[];
+    }
+    match io::fs::readdir(dir) {
+        Ok(paths) => paths,
+        Err(e) => {
+            println!("Warning: Could not read plugin dir `{}': {}", dir.display(), e);
+            ~[]
+        }
+    }
+}
+
+fn has_glob(s: &[u8]) -> bool {
+    s.iter().any(|&b| b == '*' as u8 || b == '?' as u8)
+}
+
+/// Returns the name of the plugin whose code is currently running
+unsafe fn current_plugin(L: &mut lua::ExternState) -> ~str {
+    L.getfield(lua::REGISTRYINDEX, CURRENT_PLUGIN);
+    let name = match L.tostring(-1) {
+        None => ~"(unknown)",
+        Some(s) => s.to_owned()
+    };
+    L.pop(1);
+    name
+}
+
+/// Returns the server's casemapping
+unsafe fn casemapping(L: &mut lua::ExternState) -> CaseMapping {
+    L.getfield(lua::REGISTRYINDEX, CASEMAPPING);
+    let casemap = L.tostring(-1).and_then(CaseMapping::from_name).unwrap_or(casemap::Rfc1459);
+    L.pop(1);
+    casemap
+}
+
+/// Pushes the value of the current event's tag, or nil
+unsafe fn push_tag(L: &mut lua::ExternState, name: &str) {
+    L.getfield(lua::REGISTRYINDEX, TAGS);
+    if L.istable(-1) {
+        L.getfield(-1, name);
+        L.replace(-2);
+    }
+}
+
+/// Pushes a copy of the message tags of the event being dispatched, which is empty
+/// outside of an IRC event
+unsafe fn push_tags(L: &mut lua::ExternState) {
+    push_table_copy(L, TAGS);
+}
+
+/// Pushes a copy of the server's ISUPPORT tokens
+unsafe fn push_isupport(L: &mut lua::ExternState) {
+    push_table_copy(L, ISUPPORT);
+}
+
+/// Pushes a copy of the registry table with the key, or an empty table if there isn't one
+unsafe fn push_table_copy(L: &mut lua::ExternState, key: &str) {
+    L.newtable();
+    L.getfield(lua::REGISTRYINDEX, key);
+    if L.istable(-1) {
+        L.pushnil();
+        while L.next(-2) {
+            L.pushvalue(-2); // copy the key
+            L.insert(-2); // move it behind the value
+            L.settable(-5); // set key=value in the copy
+        }
+    }
+    L.pop(1);
+}
+
+/// Returns whether the event being dispatched was played back by a bouncer
+unsafe fn is_replayed(L: &mut lua::ExternState) -> bool {
+    L.getfield(lua::REGISTRYINDEX, REPLAYED);
+    let replayed = L.toboolean(-1);
+    L.pop(1);
+    replayed
+}
+
+/// Pushes the time the event being dispatched was sent, from its server-time tag, or
+/// else the current time, in seconds since the epoch
+unsafe fn push_event_time(L: &mut lua::ExternState) {
+    L.getfield(lua::REGISTRYINDEX, EVENT_TIME);
+    if L.isnil(-1) {
+        L.pop(1);
+        let now = time::get_time();
+        L.pushnumber(now.sec as f64 + now.nsec as f64 / 1e9);
+    }
+}
+
+unsafe fn getusers(L: &mut lua::ExternState) -> &'static users::Users {
+    L.getfield(lua::REGISTRYINDEX, USERS);
+    let users = L.touserdata(-1) as *users::Users;
+    L.pop(1);
+    if users.is_null() {
+        L.errorstr("could not retrieve user state");
+    }
+    &*users
+}
+
+unsafe fn getwatch(L: &mut lua::ExternState) -> &'static mut watch::Watch {
+    L.getfield(lua::REGISTRYINDEX, WATCH);
+    let watch = L.touserdata(-1) as *mut watch::Watch;
+    L.pop(1);
+    if watch.is_null() {
+        L.errorstr("could not retrieve the watched nicks");
+    }
+    &mut *watch
+}
+
+unsafe fn getwatchdog(L: &mut lua::ExternState) -> &'static mut watchdog::Watchdog {
+    L.getfield(lua::REGISTRYINDEX, WATCHDOG);
+    let watchdog = L.touserdata(-1) as *mut watchdog::Watchdog;
+    L.pop(1);
+    if watchdog.is_null() {
+        L.errorstr("could not retrieve the handler watchdog");
+    }
+    &mut *watchdog
+}
+
+unsafe fn gettwitch(L: &mut lua::ExternState) -> &'static twitch::Twitch {
+    L.getfield(lua::REGISTRYINDEX, TWITCH);
+    let twitch = L.touserdata(-1) as *twitch::Twitch;
+    L.pop(1);
+    if twitch.is_null() {
+        L.errorstr("could not retrieve the Twitch state");
+    }
+    &*twitch
+}
+
+/// Pushes the user's away message, or nil if they aren't known to be away
+unsafe fn push_away(L: &mut lua::ExternState, nick: &[u8]) {
+    match getusers(L).away(nick) {
+        None => L.pushnil(),
+        Some(msg) => L.pushbytes(msg)
+    }
+}
+
+/// Pushes a table of the channel's members, mapping each nick to their prefixes, or nil
+/// if the bot isn't in the channel
+unsafe fn push_members(L: &mut lua::ExternState, channel: &[u8]) {
+    match getusers(L).members(channel) {
+        None => L.pushnil(),
+        Some(members) => {
+            L.createtable(0, members.len() as i32);
+            for member in members.iter() {
+                L.pushbytes(member.value.nick);
+                L.pushbytes(member.value.prefixes);
+                L.settable(-3);
+            }
+        }
+    }
+}
+
+/// Pushes the user's nick!user@host, or nil if their host isn't known
+unsafe fn push_hostmask(L: &mut lua::ExternState, nick: &[u8]) {
+    match getusers(L).host(nick) {
+        None => L.pushnil(),
+        Some((user, host)) => {
+            L.pushbytes([nick, bytes!("!"), user, bytes!("@"), host].concat_vec());
+        }
+    }
+}
+
+/// Pushes a table of the tags, by name
+unsafe fn push_tag_table(L: &mut lua::ExternState, tags: &[(~str, ~str)]) {
+    L.createtable(0, tags.len() as i32);
+    for &(ref name, ref value) in tags.iter() {
+        L.pushstring(*value);
+        L.setfield(-2, *name);
+    }
+}
+
+/// Pushes the tags of the Twitch channel's last ROOMSTATE, or nil if the bot isn't in it
+unsafe fn push_roomstate(L: &mut lua::ExternState, channel: &[u8]) {
+    match gettwitch(L).room(channel) {
+        None => L.pushnil(),
+        Some(tags) => push_tag_table(L, tags.as_slice())
+    }
+}
+
+/// Pushes the bot's USERSTATE tags in the Twitch channel, or its GLOBALUSERSTATE tags
+/// without one, or nil if the server hasn't sent them
+unsafe fn push_userstate(L: &mut lua::ExternState, channel: Option<&[u8]>) {
+    match gettwitch(L).user(channel) {
+        None => L.pushnil(),
+        Some(tags) => push_tag_table(L, tags.as_slice())
+    }
+}
+
+/// Pushes a table of what's known about the user, or nil if nothing is
+unsafe fn push_user_info(L: &mut lua::ExternState, nick: &[u8]) {
+    let users = getusers(L);
+    let host = users.host(nick);
+    let account = users.account(nick);
+    let away = users.away(nick);
+    let channels = users.channels(nick);
+    if host.is_none() && account.is_none() && away.is_none() && channels.is_empty() {
+        L.pushnil();
+        return;
+    }
+    L.createtable(0, 6);
+    // the nick as the user's channels show it, if they share one
+    L.pushbytes(channels.head().map_or(nick, |&(_, member)| member.nick.as_slice()));
+    L.setfield(-2, "nick");
+    match host {
+        None => (),
+        Some((user, host)) => {
+            L.pushbytes(user);
+            L.setfield(-2, "user");
+            L.pushbytes(host);
+            L.setfield(-2, "host");
+        }
+    }
+    match account {
+        None => (),
+        Some(account) => {
+            L.pushbytes(account);
+            L.setfield(-2, "account");
+        }
+    }
+    match away {
+        None => (),
+        Some(msg) => {
+            L.pushbytes(msg);
+            L.setfield(-2, "away");
+        }
+    }
+    L.createtable(0, channels.len() as i32);
+    for &(channel, member) in channels.iter() {
+        L.pushbytes(channel);
+        L.pushbytes(member.prefixes);
+        L.settable(-3);
+    }
+    L.setfield(-2, "channels");
+}
+
+/// Returns the configured paste endpoint and form field, if any
+unsafe fn paste_endpoint(L: &mut lua::ExternState) -> Option<(~str, Option<~str>)> {
+    L.getfield(lua::REGISTRYINDEX, PASTE);
+    if !L.istable(-1) {
+        L.pop(1);
+        return None;
+    }
+    L.getfield(-1, "url");
+    L.getfield(-2, "field");
+    let url = L.tostring(-2).map(|s| s.to_owned());
+    let field = L.tostring(-1).map(|s| s.to_owned());
+    L.pop(3);
+    url.map(|url| (url, field))
+}
+
+/// Pushes the id of the current connection
+unsafe fn push_session(L: &mut lua::ExternState) {
+    L.getfield(lua::REGISTRYINDEX, SESSION);
+}
+
+/// Pushes the configured name of the connection's server
+unsafe fn push_server(L: &mut lua::ExternState) {
+    L.getfield(lua::REGISTRYINDEX, SERVER);
+}
+
+/// Pushes the connection's lag in seconds, or nil if it isn't known yet
+unsafe fn push_lag(L: &mut lua::ExternState) {
+    L.getfield(lua::REGISTRYINDEX, LAG);
+}
+
+/// Pushes whether the server has labeled-response
+unsafe fn push_labeled_response(L: &mut lua::ExternState) {
+    L.getfield(lua::REGISTRYINDEX, LABELED_RESPONSE);
 }
 
 lua_extern! {
+    unsafe fn lua_print_plugins(L: &mut lua::ExternState) -> i32 {
+        // 0 args
+
+        // plugin -> (name, version, description, author), sorted by plugin
+        let mut plugins = ~[];
+        L.getfield(lua::REGISTRYINDEX, PLUGINS);
+        if L.istable(-1) {
+            let table = L.gettop();
+            L.pushnil();
+            while L.next(table) {
+                let plugin = L.tostring(-2).map_or(~"(unknown)", |s| s.to_owned());
+                let mut fields = ~[];
+                for &field in ["name", "version", "description", "author"].iter() {
+                    L.getfield(-1, field);
+                    fields.push(L.tostring(-1).map(|s| s.to_owned()));
+                    L.pop(1);
+                }
+                plugins.push((plugin, fields));
+                L.pop(1); // pop the metadata, leaving the key for next
+            }
+        }
+        L.pop(1);
+        plugins.sort_by(|&(ref a, _), &(ref b, _)| a.cmp(b));
+
+        if plugins.is_empty() {
+            println!("No plugins are loaded");
+        }
+        for &(ref plugin, ref fields) in plugins.iter() {
+            let mut line = plugin.clone();
+            match (&fields[0], &fields[1]) {
+                (&Some(ref name), &Some(ref version)) => {
+                    line.push_str(format!(": {} {}", name, version));
+                }
+                (&Some(ref name), &None) => line.push_str(format!(": {}", name)),
+                (&None, &Some(ref version)) => line.push_str(format!(" {}", version)),
+                (&None, &None) => ()
+            }
+            match fields[3] {
+                None => (),
+                Some(ref author) => line.push_str(format!(" by {}", author))
+            }
+            println!("{}", line);
+            match fields[2] {
+                None => (),
+                Some(ref description) => println!("    {}", description)
+            }
+        }
+        0
+    }
+
     unsafe fn lua_setup_packages(L: &mut lua::ExternState) -> i32 {
+        // 2 args: config, server index
+
+        let conf = L.touserdata(1) as *config::Config;
+        L.argcheck(conf.is_not_null(), 1, "expected Config");
+        let server = L.checkinteger(2) as uint;
+        bot::store_config(L, &*conf, server);
+        match (*conf).plugin_configs {
+            None => (),
+            Some(ref configs) => {
+                bot::push_toml(L, configs);
+                L.setfield(lua::REGISTRYINDEX, PLUGIN_CONFIGS);
+            }
+        }
+        process::store_config(L, &*conf);
+        dcc::store_config(L, &(*conf).dcc);
+        sandbox::setup(L);
+        irc::store_active(L);
+        irc::store_runner(L, (*conf).handler_instruction_limit);
+        irc::store_command_prefix(L, (*conf).command_prefix);
+
         // insert our package loaders into package.preload
         L.getglobal("package");
         L.getfield(-1, "preload");
@@ -140,9 +1411,66 @@ lua_extern! {
         L.pushcfunction(irc::lua_require);
         L.setfield(-2, "irc");
 
+        // bot
+        L.pushcfunction(bot::lua_require);
+        L.setfield(-2, "bot");
+
+        // log
+        L.pushcfunction(log::lua_require);
+        L.setfield(-2, "log");
+
+        // json
+        L.pushcfunction(json::lua_require);
+        L.setfield(-2, "json");
+
+        // re
+        L.pushcfunction(re::lua_require);
+        L.setfield(-2, "re");
+
+        // crypto
+        L.pushcfunction(crypto::lua_require);
+        L.setfield(-2, "crypto");
+
+        // dns
+        L.pushcfunction(dns::lua_require);
+        L.setfield(-2, "dns");
+
+        // tcp
+        L.pushcfunction(tcp::lua_require);
+        L.setfield(-2, "tcp");
+
+        // proc
+        L.pushcfunction(process::lua_require);
+        L.setfield(-2, "proc");
+
+        // dcc
+        L.pushcfunction(dcc::lua_require);
+        L.setfield(-2, "dcc");
+
         L.pop(2);
         0
     }
 }
 
 mod irc;
+mod bot;
+mod log;
+mod json;
+mod re;
+mod crypto;
+mod dns;
+mod tcp;
+mod process;
+mod dcc;
+pub mod native;
+mod ctcp;
+mod sandbox;
+mod task;
+mod users;
+mod watch;
+mod twitch;
+mod watchdog;
+mod numerics;
+mod format;
+pub mod mask;
+mod utf8;
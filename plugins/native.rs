@@ -0,0 +1,39 @@
+//! Native plugins
+//!
+//! Plugins written in Rust are compiled into the bot, for features that need to see
+//! every event cheaply or keep state that's awkward to hold in Lua. They are created
+//! by `builtin` for each connection, and see each IRC event before Lua plugins do.
+//! Reloading plugins reloads native plugins too, but /load and /unload only affect
+//! Lua plugins.
+
+use config;
+use super::ctcp;
+use toml;
+use outbound::Outbound;
+use irc::conn::{Conn, Event};
+
+/// A plugin compiled into the bot
+pub trait Plugin {
+    /// Returns the plugin's name, as used for its [plugins.<name>] config section
+    fn name(&self) -> &'static str;
+
+    /// Called when the plugin is loaded, with its config section if there is one
+    fn on_load(&mut self, _conf: Option<&toml::Value>) {}
+
+    /// Called with each IRC event, its tags, and whether a bouncer replayed it, which
+    /// a plugin shouldn't answer. Returning true consumes the event, so later native
+    /// plugins and Lua plugins don't see it.
+    fn on_event(&mut self, _conn: &mut Conn, _out: &mut Outbound, _event: &Event,
+                _tags: &[(~str, ~str)], _replayed: bool) -> bool {
+        false
+    }
+
+    /// Called when the plugin is unloaded, before plugins are reloaded or the
+    /// connection is closed
+    fn on_unload(&mut self) {}
+}
+
+/// Returns a new instance of each native plugin, in the order they see events
+pub fn builtin(_conf: &config::Config) -> ~[~Plugin] {
+    ~[~ctcp::Ctcp::new() as ~Plugin]
+}
@@ -0,0 +1,175 @@
+//! Lua subprocess library
+//!
+//! Vends a package named 'proc' for running external programs, such as fortune
+//! or uptime, without blocking the connection.
+//!
+//! proc.run(cmd, args, callback): Runs cmd with the array of string args in the
+//! background and later calls callback with its output and exit status. If the
+//! program couldn't be started, was killed, or ran longer than the configured
+//! timeout, callback is called with nil followed by an error message instead.
+//!
+//! Only programs listed in general.proc_allow can be run, and cmd must match an
+//! entry exactly. The program is run directly rather than through a shell, with
+//! no input. Only standard output is captured, and output beyond
+//! general.proc_output_limit bytes is discarded.
+//!
+//! proc.run may only be called while handling an event or callback.
+
+#[allow(uppercase_variables)];
+
+use lua;
+use config;
+use super::task;
+use std::{libc, str, task};
+use std::io::{IoError, EndOfFile};
+use std::io::process;
+use std::io::process::{Process, ProcessConfig, ExitStatus, ExitSignal};
+use std::io::timer::Timer;
+
+/// Stores the allowlist and limits from the config
+pub unsafe fn store_config(L: &mut lua::ExternState, conf: &config::Config) {
+    L.pushlightuserdata(lua_require as *mut libc::c_void);
+    L.createtable(0, 3);
+
+    L.createtable(0, conf.proc_allow.len() as i32);
+    for cmd in conf.proc_allow.iter() {
+        L.pushboolean(true);
+        L.setfield(-2, cmd.as_slice());
+    }
+    L.setfield(-2, "allow");
+    L.pushinteger(conf.proc_timeout as int);
+    L.setfield(-2, "timeout");
+    L.pushinteger(conf.proc_output_limit as int);
+    L.setfield(-2, "limit");
+
+    L.settable(lua::REGISTRYINDEX);
+}
+
+lua_extern_pub! {
+    unsafe fn lua_require(L: &mut lua::ExternState) -> i32 {
+        // 1 argument is passed: modname
+
+        L.newtable();
+        L.registerlib(None, [
+            ("run", lua_run)
+        ]);
+        1
+    }
+}
+
+lua_extern! {
+    unsafe fn lua_run(L: &mut lua::ExternState) -> i32 {
+        // 3 args: cmd, args, callback
+
+        let cmd = match str::from_utf8(L.checkbytes(1)) {
+            None => L.argerror(1, "invalid command"),
+            Some(s) => s.to_owned()
+        };
+        L.checktype(2, lua::Type::Table);
+        let mut args = ~[];
+        for i in range(1, L.objlen(2) as i32 + 1) {
+            L.rawgeti(2, i);
+            match L.tobytes(-1).and_then(str::from_utf8) {
+                None => L.argerror(2, "expected an array of strings"),
+                Some(s) => args.push(s.to_owned())
+            }
+            L.pop(1);
+        }
+        L.checktype(3, lua::Type::Function);
+
+        L.pushlightuserdata(lua_require as *mut libc::c_void);
+        L.gettable(lua::REGISTRYINDEX);
+        L.getfield(-1, "allow");
+        L.getfield(-1, cmd.as_slice());
+        if !L.toboolean(-1) {
+            let msg = format!("command `{}' is not allowed by general.proc_allow", cmd);
+            L.errorstr(msg.as_slice());
+        }
+        L.pop(2);
+        L.getfield(-1, "timeout");
+        let timeout = L.tointeger(-1) as u64;
+        L.getfield(-2, "limit");
+        let limit = L.tointeger(-1) as uint;
+        L.pop(3);
+
+        task::spawn(L, "proc run", 3, proc() {
+            let res = run(cmd, args, timeout, limit);
+            proc(L: &mut lua::State) -> i32 {
+                match res {
+                    Ok((output, status)) => {
+                        L.pushbytes(output.as_slice());
+                        L.pushinteger(status);
+                    }
+                    Err(e) => {
+                        L.pushnil();
+                        L.pushstring(e.as_slice());
+                    }
+                }
+                2
+            }
+        });
+        0
+    }
+}
+
+/// Runs the command, returning the start of its output and its exit status
+fn run(cmd: ~str, args: ~[~str], timeout: u64, limit: uint) -> Result<(~[u8], int), ~str> {
+    let opts = ProcessConfig {
+        program: cmd.as_slice(),
+        args: args.as_slice(),
+        stdin: process::Ignored,
+        stdout: process::CreatePipe(false, true),
+        stderr: process::Ignored,
+        .. ProcessConfig::new()
+    };
+    let mut child = match Process::configure(opts) {
+        Ok(p) => p,
+        Err(e) => return Err(format!("could not run `{}': {}", cmd, e))
+    };
+
+    // kill the child if it's still running after the timeout
+    let pid = child.id();
+    let (done_tx, done_rx) = channel::<()>();
+    let (killed_tx, killed_rx) = channel::<()>();
+    task::task().named("proc timeout").spawn(proc() {
+        let mut timer = match Timer::new() {
+            Ok(t) => t,
+            Err(_) => return
+        };
+        let expired = timer.oneshot(timeout * 1000);
+        select! (
+            _ = expired.recv() => {
+                killed_tx.try_send(());
+                let _ = Process::kill(pid, libc::SIGKILL as int);
+            },
+            _ = done_rx.recv_opt() => ()
+        )
+    });
+
+    // keep reading past the limit, so the child doesn't block on a full pipe
+    let mut output = ~[];
+    {
+        let stdout = child.stdout.get_mut_ref();
+        let mut buf = [0u8, ..4096];
+        loop {
+            match stdout.read(buf) {
+                Ok(n) => {
+                    let keep = n.min(limit - output.len());
+                    output.push_all(buf.slice_to(keep));
+                }
+                Err(IoError { kind: EndOfFile, .. }) => break,
+                Err(e) => return Err(format!("could not read output of `{}': {}", cmd, e))
+            }
+        }
+    }
+    let status = child.wait();
+    done_tx.try_send(());
+
+    if killed_rx.try_recv().is_ok() {
+        return Err(format!("`{}' timed out after {} seconds", cmd, timeout));
+    }
+    match status {
+        ExitStatus(code) => Ok((output, code)),
+        ExitSignal(sig) => Err(format!("`{}' was killed by signal {}", cmd, sig))
+    }
+}
@@ -0,0 +1,114 @@
+//! Lua regular expression library
+//!
+//! Vends a package named 're' that exposes Rust regular expressions, for the
+//! cases where Lua patterns aren't expressive enough (alternation, repetition
+//! of groups, case-insensitive matching, etc). Patterns use the syntax of the
+//! Rust regex library. Any text that is not valid UTF-8 has the invalid
+//! sequences replaced with U+FFFD before matching.
+//!
+//! re.match(pattern, text): Returns the captures of the first match, or nil if
+//! there's no match. If the pattern has no capture groups the whole match is
+//! returned instead. Groups that didn't participate in the match are nil.
+//!
+//! re.find_all(pattern, text): Returns an array of all non-overlapping matches.
+//!
+//! re.replace(pattern, text, replacement, [n]): Replaces the first n matches
+//! (or all matches, if n is not given) with the replacement, which may refer to
+//! capture groups with $1 or $name. Returns the new text and the number of
+//! replacements made.
+//!
+//! An invalid pattern raises an error.
+
+#[allow(uppercase_variables)];
+
+use lua;
+use regex::Regex;
+use std::str;
+
+lua_extern_pub! {
+    unsafe fn lua_require(L: &mut lua::ExternState) -> i32 {
+        // 1 argument is passed: modname
+
+        L.newtable();
+        L.registerlib(None, [
+            ("match", lua_match),
+            ("find_all", lua_find_all),
+            ("replace", lua_replace)
+        ]);
+
+        1
+    }
+}
+
+lua_extern! {
+    unsafe fn lua_match(L: &mut lua::ExternState) -> i32 {
+        // 2 args: pattern, text
+
+        let re = checkregex(L, 1);
+        let text = str::from_utf8_lossy(L.checkbytes(2));
+
+        let caps = match re.captures(text.as_slice()) {
+            None => {
+                L.pushnil();
+                return 1;
+            }
+            Some(caps) => caps
+        };
+        if caps.len() == 1 {
+            L.pushstring(caps.at(0));
+            return 1;
+        }
+        if !L.checkstack(caps.len() as i32) {
+            L.errorstr("too many captures");
+        }
+        for i in range(1, caps.len()) {
+            match caps.pos(i) {
+                None => L.pushnil(),
+                Some(_) => L.pushstring(caps.at(i))
+            }
+        }
+        caps.len() as i32 - 1
+    }
+
+    unsafe fn lua_find_all(L: &mut lua::ExternState) -> i32 {
+        // 2 args: pattern, text
+
+        let re = checkregex(L, 1);
+        let text = str::from_utf8_lossy(L.checkbytes(2));
+        let text = text.as_slice();
+
+        L.newtable();
+        for (i, (start, end)) in re.find_iter(text).enumerate() {
+            L.pushstring(text.slice(start, end));
+            L.rawseti(-2, i as i32 + 1);
+        }
+        1
+    }
+
+    unsafe fn lua_replace(L: &mut lua::ExternState) -> i32 {
+        // 3 or 4 args: pattern, text, replacement, [n]
+
+        let re = checkregex(L, 1);
+        let text = str::from_utf8_lossy(L.checkbytes(2));
+        let text = text.as_slice();
+        let rep = str::from_utf8_lossy(L.checkbytes(3));
+        let limit = L.optinteger(4, 0);
+        L.argcheck(limit >= 0, 4, "expected non-negative count");
+
+        let count = re.find_iter(text).count();
+        let count = if limit > 0 && (limit as uint) < count { limit as uint } else { count };
+        let result = re.replacen(text, count, rep.as_slice());
+        L.pushstring(result.as_slice());
+        L.pushinteger(count as int);
+        2
+    }
+}
+
+/// Compiles the pattern at the given index, raising a Lua error if it's invalid
+unsafe fn checkregex(L: &mut lua::ExternState, narg: i32) -> Regex {
+    let pat = str::from_utf8_lossy(L.checkbytes(narg));
+    match Regex::new(pat.as_slice()) {
+        Ok(re) => re,
+        Err(e) => L.errorstr(format!("invalid pattern: {}", e).as_slice())
+    }
+}
@@ -0,0 +1,157 @@
+//! Sandboxes for untrusted plugins
+//!
+//! A plugin whose [plugins.<name>] config section sets `sandbox = true` runs with its
+//! own globals instead of the shared ones. Its globals hold the basic functions and
+//! copies of the string, table, math and coroutine libraries. os only has time, clock,
+//! date and difftime, and io only has open, lines and type, which take paths relative
+//! to the plugin's data dir and refuse absolute paths and `..`. load, loadstring,
+//! loadfile, dofile, getfenv, setfenv, debug and package are missing, and require only
+//! loads the bot's own packages, such as irc and json. getmetatable only returns
+//! metatables the plugin set itself, and setmetatable can't replace any others, as the
+//! string metatable and those of the bot's packages are shared.
+//!
+//! The tcp, proc and dcc packages reach beyond the bot and its data dir, so a sandboxed
+//! plugin can only require them if they're listed in the `allow` array of its config
+//! section, e.g. `allow = ["tcp"]`. Its bot.config has no channel keys. No plugin's
+//! bot.config has the server password, SASL or NickServ settings.
+//!
+//! The sandbox reduces what a plugin can do to the bot's host, not to the bot. The
+//! bot's packages are shared with other plugins.
+
+#[allow(uppercase_variables)];
+
+use lua;
+use std::io;
+
+// registry key for the function that makes a sandbox's globals
+static MAKE_ENV: &'static str = "sandbox_make_env";
+
+// Called with the real require, io.open and package.preload. Returns a function that
+// takes the data dir and the allow list and returns the globals for a sandbox.
+static SANDBOX_SRC: &'static str = r#"
+local require, open, preload = ...
+local G, io, os = _G, io, os
+local getmetatable, setmetatable = getmetatable, setmetatable
+local basic = {"assert", "error", "ipairs", "next", "pairs", "pcall", "print", "rawequal",
+               "rawget", "rawset", "select", "tonumber", "tostring", "type", "unpack",
+               "xpcall", "_VERSION"}
+local libs = {"string", "table", "math", "coroutine"}
+local gated = {tcp = true, proc = true, dcc = true} -- only with the plugin's allow list
+
+-- bot, but with the channel keys taken out of bot.config
+local function strip(server)
+    for _, chan in ipairs(server.autojoin) do chan.password = nil end
+end
+local bot = setmetatable({}, {
+    __index = function(_, key)
+        local val = require("bot")[key]
+        if key == "config" then
+            strip(val.server)
+            for _, server in ipairs(val.servers) do strip(server) end
+        end
+        return val
+    end,
+    __metatable = false
+})
+
+local function copy(t)
+    local c = {}
+    for k, v in pairs(t) do c[k] = v end
+    return c
+end
+
+return function(dir, allow)
+    local allowed = {}
+    if type(allow) == "table" then
+        for _, name in ipairs(allow) do allowed[name] = true end
+    end
+
+    local function path(name)
+        if type(name) ~= "string" or name == "" or name:sub(1, 1) == "/" then
+            error("expected a path relative to the plugin's data dir", 3)
+        end
+        for comp in name:gmatch("[^/]+") do
+            if comp == ".." then
+                error("paths outside the plugin's data dir are not allowed", 3)
+            end
+        end
+        return dir .. "/" .. name
+    end
+
+    local env = {}
+    for _, name in ipairs(basic) do env[name] = G[name] end
+    for _, name in ipairs(libs) do env[name] = copy(G[name]) end
+    env.os = {time = os.time, clock = os.clock, date = os.date, difftime = os.difftime}
+    env.io = {
+        open = function(name, mode) return open(path(name), mode) end,
+        lines = function(name) return io.lines(path(name)) end,
+        type = io.type
+    }
+    -- metatables the sandbox set; keys are weak so they don't keep them alive
+    local owned = setmetatable({}, {__mode = "k"})
+    env.getmetatable = function(val)
+        local mt = getmetatable(val)
+        if owned[mt] then return mt end
+        return nil
+    end
+    env.setmetatable = function(t, mt)
+        local cur = getmetatable(t)
+        if cur ~= nil and not owned[cur] then
+            error("cannot change a metatable the plugin didn't set", 2)
+        end
+        if mt ~= nil then owned[mt] = true end
+        return setmetatable(t, mt)
+    end
+    env.require = function(name)
+        if preload[name] == nil or (gated[name] and not allowed[name]) then
+            error("module '" .. tostring(name) .. "' is not available in the sandbox", 2)
+        end
+        if name == "bot" then return bot end
+        return require(name)
+    end
+    env._G = env
+    return env
+end
+"#;
+
+/// Loads the sandbox support. Must be called after the standard libraries are opened.
+pub unsafe fn setup(L: &mut lua::ExternState) {
+    match L.loadstring(SANDBOX_SRC) {
+        Ok(()) => (),
+        Err(_) => {
+            let msg = L.describe(-1);
+            L.errorstr(format!("could not load sandbox support: {}", msg).as_slice());
+        }
+    }
+    L.getglobal("require");
+    L.getglobal("io");
+    L.getfield(-1, "open");
+    L.remove(-2);
+    L.getglobal("package");
+    L.getfield(-1, "preload");
+    L.remove(-2);
+    L.call(3, 1);
+    L.setfield(lua::REGISTRYINDEX, MAKE_ENV);
+}
+
+/// Creates the data dir if needed and pushes the globals for a plugin sandboxed in it.
+/// `config` is the index of the plugin's config section, which may hold its allow list.
+pub fn push_env(L: &mut lua::State, dir: &Path, config: i32) -> Result<(), ~str> {
+    if !dir.is_dir() {
+        match io::fs::mkdir_recursive(dir, io::UserDir) {
+            Ok(()) => (),
+            Err(e) => return Err(format!("could not create data dir {}: {}", dir.display(), e))
+        }
+    }
+    L.getfield(lua::REGISTRYINDEX, MAKE_ENV);
+    L.pushbytes(dir.as_vec());
+    L.getfield(config, "allow");
+    match L.pcall(2, 1, 0) {
+        Ok(()) => Ok(()),
+        Err(e) => {
+            let msg = format!("{}: {}", e, L.describe(-1));
+            L.pop(1);
+            Err(msg)
+        }
+    }
+}
@@ -0,0 +1,186 @@
+//! Background work for Lua plugins
+//!
+//! Library functions that would block the connection, such as DNS lookups, do their
+//! work on a separate task and then call a Lua callback back on the connection's task.
+//! Pending callbacks are kept in the registry by id. Ids are never reused, even across
+//! plugin reloads, so a result that arrives after a reload is simply dropped.
+//!
+//! Work that the plugin keeps talking to, such as a TCP connection, also has a stream
+//! of data from Lua to the task, stored under the callback's id.
+
+#[allow(uppercase_variables)];
+
+use lua;
+use Cmd;
+use irc::conn::Conn;
+use State;
+use collections::HashMap;
+use std::{mem, task};
+use super::irc;
+
+// registry key for the table of pending callbacks
+static CALLBACKS: &'static str = "callbacks";
+
+/// Pushes a callback's arguments, returning how many were pushed
+pub type Pusher = proc(&mut lua::State) -> i32;
+
+/// Data sent to a task, or None to ask it to finish
+pub type StreamMsg = Option<~[u8]>;
+
+pub struct Tasks {
+    priv cmd_tx: Sender<Cmd>,
+    priv next_id: uint,
+    priv streams: HashMap<uint, Sender<StreamMsg>>
+}
+
+/// A handle for calling a registered callback from another task
+pub struct Callback {
+    priv id: uint,
+    priv cmd_tx: Sender<Cmd>
+}
+
+impl Tasks {
+    pub fn new(cmd_tx: Sender<Cmd>) -> Tasks {
+        Tasks { cmd_tx: cmd_tx, next_id: 1, streams: HashMap::new() }
+    }
+
+    /// Attaches a stream to the callback with the given id
+    pub fn add_stream(&mut self, id: uint, tx: Sender<StreamMsg>) {
+        self.streams.insert(id, tx);
+    }
+
+    /// Sends data to the stream, returning false if it's gone
+    pub fn write_stream(&mut self, id: uint, data: ~[u8]) -> bool {
+        match self.streams.find(&id) {
+            None => false,
+            Some(tx) => tx.try_send(Some(data))
+        }
+    }
+
+    /// Asks the stream's task to finish
+    pub fn close_stream(&mut self, id: uint) {
+        match self.streams.pop(&id) {
+            None => (),
+            Some(tx) => { tx.try_send(None); }
+        }
+    }
+
+    /// Asks every stream's task to finish, e.g. because plugins are being reloaded
+    pub fn close_all_streams(&mut self) {
+        let streams = mem::replace(&mut self.streams, HashMap::new());
+        for (_, tx) in streams.move_iter() {
+            tx.try_send(None);
+        }
+    }
+}
+
+impl Callback {
+    pub fn id(&self) -> uint {
+        self.id
+    }
+
+    /// Calls the callback on the connection with the arguments pushed by `push`. If
+    /// `last` is true, the callback is released afterwards. Returns false if the
+    /// connection is gone.
+    pub fn call(&self, push: Pusher, last: bool) -> bool {
+        let id = self.id;
+        self.cmd_tx.try_send(proc(conn: &mut Conn, state: &mut State) {
+            state.plugins.run_callback(conn, &mut state.out, id, push, last);
+        })
+    }
+}
+
+impl Clone for Callback {
+    fn clone(&self) -> Callback {
+        Callback { id: self.id, cmd_tx: self.cmd_tx.clone() }
+    }
+}
+
+/// Registers the function at `idx` as a callback for the current plugin
+pub unsafe fn register(L: &mut lua::ExternState, idx: i32) -> Callback {
+    L.checktype(idx, lua::Type::Function);
+    let tasks = irc::gettasks(L);
+    let id = tasks.next_id;
+    tasks.next_id += 1;
+
+    L.getfield(lua::REGISTRYINDEX, CALLBACKS);
+    if !L.istable(-1) {
+        L.pop(1);
+        L.newtable();
+        L.pushvalue(-1);
+        L.setfield(lua::REGISTRYINDEX, CALLBACKS);
+    }
+    L.createtable(0, 2);
+    L.pushvalue(idx);
+    L.setfield(-2, "fn");
+    L.pushstring(super::current_plugin(L).as_slice());
+    L.setfield(-2, "plugin");
+    L.rawseti(-2, id as i32);
+    L.pop(1);
+
+    Callback { id: id, cmd_tx: tasks.cmd_tx.clone() }
+}
+
+/// Registers the function at `idx` as a callback for the current plugin, then runs
+/// `work` on a new task named `name`. Once the work is done, the callback is called on
+/// the connection with the arguments pushed by the Pusher it returns.
+pub unsafe fn spawn(L: &mut lua::ExternState, name: &'static str, idx: i32,
+                    work: proc() -> Pusher) {
+    let callback = register(L, idx);
+    task::task().named(name).spawn(proc() {
+        let push = work();
+        // if the connection is gone, so is the callback
+        callback.call(push, true);
+    });
+}
+
+/// Pushes the function of the callback with the given id, setting the current plugin to
+/// its owner, and removes the callback if `last` is true. Returns the plugin name, or
+/// None if there's no such callback.
+pub fn push_callback(L: &mut lua::State, id: uint, last: bool) -> Option<~str> {
+    L.getfield(lua::REGISTRYINDEX, CALLBACKS);
+    if !L.istable(-1) {
+        L.pop(1);
+        return None;
+    }
+    L.rawgeti(-1, id as i32);
+    if !L.istable(-1) {
+        L.pop(2);
+        return None;
+    }
+    if last {
+        L.pushnil();
+        L.rawseti(-3, id as i32);
+    }
+    L.getfield(-1, "plugin");
+    let plugin = L.tostring(-1).map(|s| s.to_owned());
+    L.setfield(lua::REGISTRYINDEX, super::CURRENT_PLUGIN);
+    L.getfield(-1, "fn");
+    L.insert(-3);
+    L.pop(2); // pop entry and callback table
+    plugin
+}
+
+/// Removes all the callbacks registered by the plugin, returning their ids
+pub fn clear_callbacks(L: &mut lua::State, plugin: &str) -> ~[uint] {
+    let mut ids = ~[];
+    L.getfield(lua::REGISTRYINDEX, CALLBACKS);
+    if L.istable(-1) {
+        let callbacks = L.gettop();
+        L.pushnil();
+        while L.next(callbacks) {
+            L.getfield(-1, "plugin");
+            if L.tostring(-1) == Some(plugin) {
+                ids.push(L.tointeger(-3) as uint);
+            }
+            L.pop(2); // pop plugin and entry, leaving the key for next
+        }
+        // the table can't be modified while it's being traversed
+        for &id in ids.iter() {
+            L.pushnil();
+            L.rawseti(callbacks, id as i32);
+        }
+    }
+    L.pop(1);
+    ids
+}
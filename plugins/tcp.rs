@@ -0,0 +1,182 @@
+//! Lua TCP library
+//!
+//! Vends a package named 'tcp' for talking to other services, such as a local
+//! daemon, without blocking the connection.
+//!
+//! tcp.connect(host, port, handler): Starts connecting in the background and
+//! returns a socket. handler is called as handler(sock, event, data) where event
+//! is one of:
+//!
+//! "connected": The connection was established
+//! "data": data holds bytes that were received
+//! "closed": The connection is closed, either by sock:close(), the other end, or
+//! an error. data holds the error message, if any. No more events follow.
+//!
+//! sock:write(data): Queues data to be sent, even before "connected". Returns
+//! false if the socket is already closed.
+//! sock:close(): Closes the socket. "closed" is still delivered afterwards.
+//!
+//! Sockets are closed when plugins are reloaded. tcp.connect may only be called
+//! while handling an event or callback.
+
+#[allow(uppercase_variables)];
+
+use lua;
+use resolver;
+use super::irc;
+use super::task;
+use std::{io, str, task};
+
+// registry key for the socket metatable
+static SOCKET_META: &'static str = "tcp_socket";
+
+lua_extern_pub! {
+    unsafe fn lua_require(L: &mut lua::ExternState) -> i32 {
+        // 1 argument is passed: modname
+
+        // the metatable for sockets, which are tables holding the callback id
+        L.newtable();
+        L.newtable();
+        L.registerlib(None, [
+            ("write", lua_write),
+            ("close", lua_close)
+        ]);
+        L.setfield(-2, "__index");
+        L.setfield(lua::REGISTRYINDEX, SOCKET_META);
+
+        L.newtable();
+        L.registerlib(None, [
+            ("connect", lua_connect)
+        ]);
+        1
+    }
+}
+
+lua_extern! {
+    unsafe fn lua_connect(L: &mut lua::ExternState) -> i32 {
+        // 3 args: host, port, handler
+
+        let host = str::from_utf8_lossy(L.checkbytes(1)).into_owned();
+        let port = L.checkinteger(2);
+        L.argcheck(port > 0 && port < 65536, 2, "port out of range");
+        let port = port as u16;
+        L.checktype(3, lua::Type::Function);
+        L.settop(3);
+
+        // the socket is passed to every call of the handler, so it's bound to it
+        L.createtable(0, 1);
+        L.getfield(lua::REGISTRYINDEX, SOCKET_META);
+        L.setmetatable(-2);
+        wrap_handler(L, 3);
+        let callback = task::register(L, 3);
+        let id = callback.id();
+        L.pushinteger(id as int);
+        L.setfield(-2, "id");
+
+        let (tx, rx) = channel();
+        irc::gettasks(L).add_stream(id, tx);
+
+        task::task().named("tcp connection").spawn(proc() {
+            let mut stream = match resolver::connect(host.as_slice(), port) {
+                Ok(s) => s,
+                Err(e) => {
+                    callback.call(push_event("closed", Some(e.into_bytes())), true);
+                    return;
+                }
+            };
+            if !callback.call(push_event("connected", None), false) {
+                return;
+            }
+            let mut writer = stream.clone();
+            task::task().named("tcp writer").spawn(proc() {
+                for msg in rx.iter() {
+                    match msg {
+                        Some(data) => {
+                            if writer.write(data).is_err() {
+                                break;
+                            }
+                        }
+                        None => break
+                    }
+                }
+                // wake up the reader
+                let _ = writer.close_read();
+                let _ = writer.close_write();
+            });
+            let mut buf = [0u8, ..4096];
+            loop {
+                match stream.read(buf) {
+                    Ok(n) => {
+                        let data = buf.slice_to(n).to_owned();
+                        if !callback.call(push_event("data", Some(data)), false) {
+                            break;
+                        }
+                    }
+                    Err(e) => {
+                        let err = match e.kind {
+                            io::EndOfFile => None,
+                            _ => Some(e.to_str().into_bytes())
+                        };
+                        callback.call(push_event("closed", err), true);
+                        break;
+                    }
+                }
+            }
+        });
+        1
+    }
+
+    unsafe fn lua_write(L: &mut lua::ExternState) -> i32 {
+        // 2 args: sock, data
+
+        let id = checksocket(L);
+        let data = L.checkbytes(2).to_owned();
+        L.pushboolean(irc::gettasks(L).write_stream(id, data));
+        1
+    }
+
+    unsafe fn lua_close(L: &mut lua::ExternState) -> i32 {
+        // 1 arg: sock
+
+        let id = checksocket(L);
+        irc::gettasks(L).close_stream(id);
+        0
+    }
+}
+
+/// Replaces the handler at `idx` with a function that passes the socket on top of the
+/// stack as its first argument, leaving the socket on the stack
+unsafe fn wrap_handler(L: &mut lua::ExternState, idx: i32) {
+    match L.loadstring("local f, sock = ...; return function(...) return f(sock, ...) end") {
+        Ok(()) => (),
+        Err(_) => {
+            let msg = L.describe(-1);
+            L.errorstr(msg.as_slice());
+        }
+    }
+    L.pushvalue(idx);
+    L.pushvalue(-3);
+    L.call(2, 1);
+    L.replace(idx);
+}
+
+/// Returns the callback id of the socket at 1
+unsafe fn checksocket(L: &mut lua::ExternState) -> uint {
+    L.checktype(1, lua::Type::Table);
+    L.getfield(1, "id");
+    let id = L.tointeger(-1);
+    L.pop(1);
+    L.argcheck(id > 0, 1, "expected socket");
+    id as uint
+}
+
+fn push_event(event: &'static str, data: Option<~[u8]>) -> task::Pusher {
+    proc(L: &mut lua::State) -> i32 {
+        L.pushstring(event);
+        match data {
+            None => L.pushnil(),
+            Some(data) => L.pushbytes(data.as_slice())
+        }
+        2
+    }
+}
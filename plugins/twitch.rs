@@ -0,0 +1,146 @@
+//! Twitch chat
+//!
+//! Twitch's IRC server only sends its tags and its own commands to clients that request
+//! the twitch.tv/tags, twitch.tv/commands and twitch.tv/membership capabilities, which a
+//! server with twitch = true always does. Each channel's settings, e.g. slow mode, come
+//! in ROOMSTATE lines, which after the first one only carry the settings that changed,
+//! and the bot's own badges in each channel in USERSTATE lines. Both are kept for the
+//! channels the bot is in, along with the GLOBALUSERSTATE sent once it's logged in.
+//! CLEARCHAT removes a user's messages when they're timed out or banned, or the whole
+//! channel's when it has no nick.
+//!
+//! The state is kept for the connection, across plugin reloads.
+
+use casemap;
+use casemap::{CaseMap, CaseMapping};
+use isupport::ISupport;
+use tags;
+use irc::conn::{Line, IRCCmd};
+
+/// A channel's or user's tags, by name
+pub type Tags = ~[(~str, ~str)];
+
+/// A CLEARCHAT line's channel, the nick it timed out or banned if any, and the length
+/// of the timeout in seconds if it wasn't a ban
+pub type Clear = (~[u8], Option<~[u8]>, Option<uint>);
+
+pub struct Twitch {
+    priv casemap: CaseMapping,
+    priv rooms: CaseMap<Tags>, // channel -> ROOMSTATE tags
+    priv users: CaseMap<Tags>, // channel -> the bot's USERSTATE tags
+    priv global: Option<Tags> // the bot's GLOBALUSERSTATE tags
+}
+
+impl Twitch {
+    pub fn new() -> Twitch {
+        Twitch { casemap: casemap::Rfc1459, rooms: CaseMap::new(), users: CaseMap::new(),
+                 global: None }
+    }
+
+    /// Takes the casemapping from the server's ISUPPORT
+    pub fn set_isupport(&mut self, isupport: &ISupport) {
+        self.casemap = isupport.casemapping();
+        self.rooms.set_casemapping(self.casemap);
+        self.users.set_casemapping(self.casemap);
+    }
+
+    /// Forgets every channel, e.g. on a new connection
+    pub fn clear(&mut self) {
+        self.rooms.clear();
+        self.users.clear();
+        self.global = None;
+    }
+
+    /// Returns the tags of the channel's last ROOMSTATE, if the bot is in it
+    pub fn room<'a>(&'a self, channel: &[u8]) -> Option<&'a Tags> {
+        self.rooms.find(channel)
+    }
+
+    /// Returns the bot's USERSTATE tags in the channel, or its GLOBALUSERSTATE tags
+    /// without one
+    pub fn user<'a>(&'a self, channel: Option<&[u8]>) -> Option<&'a Tags> {
+        match channel {
+            None => self.global.as_ref(),
+            Some(channel) => self.users.find(channel)
+        }
+    }
+
+    /// Updates the state from the line, returning what a CLEARCHAT line cleared
+    pub fn line_received(&mut self, me: &[u8], line: &Line, tags: &[(~str, ~str)])
+                         -> Option<Clear> {
+        let cmd = match line.command {
+            IRCCmd(ref cmd) => cmd.as_slice(),
+            _ => return None
+        };
+        if cmd == "GLOBALUSERSTATE" {
+            self.global = Some(tags.to_owned());
+            return None;
+        }
+        let channel = match line.args.head() {
+            Some(channel) => channel.as_slice(),
+            None => return None
+        };
+        match cmd {
+            "ROOMSTATE" => {
+                let room = self.rooms.find_or_insert_with(channel, |_| ~[]);
+                for &(ref name, ref value) in tags.iter() {
+                    match room.iter().position(|&(ref n, _)| n == name) {
+                        Some(i) => room[i] = (name.clone(), value.clone()),
+                        None => room.push((name.clone(), value.clone()))
+                    }
+                }
+            }
+            "USERSTATE" => {
+                self.users.insert(channel, tags.to_owned());
+            }
+            "PART" if line.prefix.as_ref().map_or(false, |u| self.casemap.eq(u.nick(), me)) => {
+                self.rooms.pop(channel);
+                self.users.pop(channel);
+            }
+            "CLEARCHAT" => {
+                let nick = line.args.get(1).map(|nick| nick.clone());
+                let duration = tags::find(tags, "ban-duration").and_then(|d| from_str(d));
+                return Some((line.args[0].clone(), nick, duration));
+            }
+            _ => ()
+        }
+        None
+    }
+}
+
+/// Parses a badges tag, e.g. "moderator/1,subscriber/12", into each badge's name and
+/// version
+pub fn badges(value: &str) -> ~[(~str, ~str)] {
+    value.split(',').filter(|b| !b.is_empty()).map(|badge| {
+        match badge.find('/') {
+            None => (badge.to_owned(), ~""),
+            Some(i) => (badge.slice_to(i).to_owned(), badge.slice_from(i + 1).to_owned())
+        }
+    }).collect()
+}
+
+/// Parses an emotes tag, e.g. "25:0-4,12-16/1902:6-10", into the id and the first and
+/// last character of each use of an emote, in the order they're given. The positions
+/// count characters from 0, not bytes.
+pub fn emotes(value: &str) -> ~[(~str, uint, uint)] {
+    let mut result = ~[];
+    for emote in value.split('/') {
+        let (id, ranges) = match emote.find(':') {
+            None => continue,
+            Some(i) => (emote.slice_to(i), emote.slice_from(i + 1))
+        };
+        for range in ranges.split(',') {
+            let (first, last) = match range.find('-') {
+                None => continue,
+                Some(i) => (from_str(range.slice_to(i)), from_str(range.slice_from(i + 1)))
+            };
+            match (first, last) {
+                (Some(first), Some(last)) if first <= last => {
+                    result.push((id.to_owned(), first, last));
+                }
+                _ => ()
+            }
+        }
+    }
+    result
+}
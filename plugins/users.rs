@@ -0,0 +1,622 @@
+//! State of the users the bot has seen
+//!
+//! Away status: with away-notify, the server sends an AWAY line whenever a user in a
+//! channel we share goes away or comes back. RPL_AWAY (from WHOIS, or in reply to a
+//! PRIVMSG) and WHO replies tell us too. Users we haven't heard about are taken to be
+//! here.
+//!
+//! Hosts: each user's username and host are remembered from the lines they send and
+//! from WHO replies. With chghost, the server sends a CHGHOST line when they change,
+//! e.g. when services apply a cloak.
+//!
+//! Accounts: the services account each user is logged in to, from the account tag of
+//! their lines (with account-tag), the JOINs of extended-join, the ACCOUNT lines of
+//! account-notify, sent when they log in or out, and WHOIS replies.
+//!
+//! What's known about a user is forgotten when they quit, or when they leave the last
+//! channel they shared with the bot, since the bot won't hear about their changes after
+//! that.
+//!
+//! Channels: the members of each channel the bot is in, with their prefixes (e.g. @
+//! for an operator), from NAMES replies, JOIN, PART, KICK, QUIT, NICK and MODE. A NAMES
+//! reply replaces the channel's members once its RPL_ENDOFNAMES comes, so members who
+//! left without the bot seeing it don't linger, and NAMES replies for channels the bot
+//! isn't in are ignored. With multi-prefix, NAMES lists all of a member's prefixes
+//! rather than just the highest, and with userhost-in-names it also gives their hosts.
+//! The prefixes and the channel modes that take parameters come from the server's
+//! ISUPPORT, and are also used to break the MODE lines of the bot's channels into the
+//! modes they set and unset, so each mode is paired with the right parameter.
+//!
+//! Nicks and channels are matched under the server's casemapping, so e.g. "Foo[m]" and
+//! "foo{M}" are the same user on an rfc1459 server.
+//!
+//! The state is kept for the connection, across plugin reloads.
+
+use casemap;
+use casemap::{CaseMap, CaseMapping};
+use isupport::ISupport;
+use tags;
+use irc::conn::{Line, IRCCmd, IRCCode};
+use std::cmp;
+
+static RPL_AWAY: uint = 301;
+static RPL_WHOISACCOUNT: uint = 330;
+static RPL_WHOREPLY: uint = 352;
+static RPL_NAMREPLY: uint = 353;
+static RPL_ENDOFNAMES: uint = 366;
+
+/// A change in a user's or channel's state
+pub enum Change {
+    Away(~[u8], Option<~[u8]>), // nick, and their away message or None if they're back
+    Host(~[u8], ~[u8], ~[u8]), // nick, new username and new host
+    Modes(~[u8], ~[ModeChange]) // channel, and the modes a MODE line changed in it
+}
+
+/// One mode set or unset by a channel MODE line
+pub struct ModeChange {
+    adding: bool, // + rather than -
+    mode: u8,
+    param: Option<~[u8]>,
+    nick: Option<~[u8]> // the member given or taken a status such as +o, as they use it
+}
+
+pub struct Users {
+    priv casemap: CaseMapping,
+    priv away: CaseMap<~[u8]>, // nick -> away message, which may be empty
+    priv hosts: CaseMap<(~[u8], ~[u8])>, // nick -> username and host
+    priv accounts: CaseMap<~[u8]>, // nick -> account, if logged in
+    priv channels: CaseMap<CaseMap<Member>>, // channel -> nick -> member
+    priv names: CaseMap<CaseMap<Member>>, // channel -> the members of a NAMES reply so far
+    priv prefixes: ~[(u8, u8)], // mode and prefix of each member status, highest first
+    priv param_modes: ~[u8], // channel modes that always take a parameter
+    priv set_param_modes: ~[u8], // channel modes that take a parameter only when set
+    priv max_modes: Option<uint> // modes with a parameter one MODE line may change
+}
+
+/// A channel member
+pub struct Member {
+    nick: ~[u8],
+    prefixes: ~[u8] // highest first, e.g. "@+"
+}
+
+impl Users {
+    pub fn new() -> Users {
+        let mut users = Users { casemap: casemap::Rfc1459, away: CaseMap::new(),
+                                hosts: CaseMap::new(), accounts: CaseMap::new(),
+                                channels: CaseMap::new(), names: CaseMap::new(), prefixes: ~[],
+                                param_modes: ~[], set_param_modes: ~[], max_modes: None };
+        // the defaults until the server's ISUPPORT says otherwise
+        users.set_isupport(&ISupport::new());
+        users
+    }
+
+    /// Takes the casemapping, member prefixes and parameter modes from the server's
+    /// ISUPPORT
+    pub fn set_isupport(&mut self, isupport: &ISupport) {
+        let casemap = isupport.casemapping();
+        self.casemap = casemap;
+        self.away.set_casemapping(casemap);
+        self.hosts.set_casemapping(casemap);
+        self.accounts.set_casemapping(casemap);
+        self.channels.set_casemapping(casemap);
+        for (_, entry) in self.channels.mut_iter() {
+            entry.value.set_casemapping(casemap);
+        }
+        self.names.set_casemapping(casemap);
+        for (_, entry) in self.names.mut_iter() {
+            entry.value.set_casemapping(casemap);
+        }
+        self.prefixes = isupport.prefixes();
+        let (param_modes, set_param_modes) = isupport.param_modes();
+        self.param_modes = param_modes;
+        self.set_param_modes = set_param_modes;
+        self.max_modes = isupport.modes();
+    }
+
+    /// Forgets everyone, e.g. on a new connection
+    pub fn clear(&mut self) {
+        self.away.clear();
+        self.hosts.clear();
+        self.accounts.clear();
+        self.channels.clear();
+        self.names.clear();
+    }
+
+    /// Returns the members of the channel, if the bot is in it
+    pub fn members<'a>(&'a self, channel: &[u8]) -> Option<&'a CaseMap<Member>> {
+        self.channels.find(channel)
+    }
+
+    /// Returns the user's away message if they're away. It's empty if the server
+    /// didn't say what it is.
+    pub fn away<'a>(&'a self, nick: &[u8]) -> Option<&'a [u8]> {
+        self.away.find(nick).map(|m| m.as_slice())
+    }
+
+    /// Returns the user's username and host, if known
+    pub fn host<'a>(&'a self, nick: &[u8]) -> Option<(&'a [u8], &'a [u8])> {
+        self.hosts.find(nick).map(|&(ref u, ref h)| (u.as_slice(), h.as_slice()))
+    }
+
+    /// Returns the account the user is logged in to, if known
+    pub fn account<'a>(&'a self, nick: &[u8]) -> Option<&'a [u8]> {
+        self.accounts.find(nick).map(|a| a.as_slice())
+    }
+
+    /// Returns the user's nick as they use it and their prefixes in each channel they
+    /// share with the bot, by the channel's name as the bot joined it
+    pub fn channels<'a>(&'a self, nick: &[u8]) -> ~[(&'a [u8], &'a Member)] {
+        self.channels.iter().filter_map(|channel| {
+            channel.value.find(nick).map(|member| (channel.key.as_slice(), member))
+        }).collect()
+    }
+
+    /// Updates the state of the user the line is about, returning the change if the
+    /// user went away, came back, changed their away message or changed their host, or
+    /// the modes if it's a MODE line for one of the bot's channels. `me` is the bot's
+    /// current nick, and `tags` the line's tags.
+    pub fn line_received(&mut self, me: &[u8], line: &Line, tags: &[(~str, ~str)])
+                         -> Option<Change> {
+        let sender = match line.prefix {
+            None => None,
+            Some(ref user) => {
+                let nick = user.nick();
+                match (user.user(), user.host()) {
+                    (Some(u), Some(h)) => self.hosts.insert(nick, (u.to_owned(), h.to_owned())),
+                    _ => ()
+                }
+                // with account-tag, the lines of a user who's logged in say to what
+                match tags::find(tags, "account") {
+                    None => (),
+                    Some(account) => self.accounts.insert(nick, account.as_bytes().to_owned())
+                }
+                Some(nick)
+            }
+        };
+        // after the sender is recorded, so that they're forgotten if they left
+        self.update_channels(me, line);
+
+        let (nick, msg): (&[u8], Option<&[u8]>) = match line.command {
+            IRCCmd(ref cmd) if cmd.as_slice() == "AWAY" => {
+                let nick = match sender {
+                    None => return None,
+                    Some(nick) => nick
+                };
+                match line.args.head() {
+                    Some(msg) if !msg.is_empty() => (nick, Some(msg.as_slice())),
+                    _ => (nick, None)
+                }
+            }
+            IRCCode(code) if code == RPL_AWAY && line.args.len() >= 3 => {
+                (line.args[1].as_slice(), Some(line.args[2].as_slice()))
+            }
+            IRCCode(code) if code == RPL_WHOREPLY && line.args.len() >= 7 => {
+                let nick = line.args[5].as_slice();
+                let host = (line.args[2].clone(), line.args[3].clone());
+                self.hosts.insert(nick, host);
+                // the flags start with H (here) or G (gone)
+                let away = line.args[6].head() == Some(&('G' as u8));
+                match self.away(nick) {
+                    // keep the message we know
+                    Some(_) if away => return None,
+                    _ => ()
+                }
+                (nick, if away { Some(bytes!("")) } else { None })
+            }
+            IRCCmd(ref cmd) if cmd.as_slice() == "CHGHOST" && line.args.len() >= 2 => {
+                return sender.map(|nick| {
+                    let (user, host) = (line.args[0].clone(), line.args[1].clone());
+                    self.hosts.insert(nick, (user.clone(), host.clone()));
+                    Host(nick.to_owned(), user, host)
+                });
+            }
+            IRCCmd(ref cmd) if cmd.as_slice() == "QUIT" => {
+                match sender {
+                    None => (),
+                    Some(nick) => self.forget(nick)
+                }
+                return None;
+            }
+            IRCCmd(ref cmd) if cmd.as_slice() == "NICK" && !line.args.is_empty() => {
+                match sender {
+                    None => (),
+                    Some(nick) => {
+                        let new = line.args[0].as_slice();
+                        match self.away.pop(nick) {
+                            None => (),
+                            Some(msg) => self.away.insert(new, msg)
+                        }
+                        match self.hosts.pop(nick) {
+                            None => (),
+                            Some(host) => self.hosts.insert(new, host)
+                        }
+                        match self.accounts.pop(nick) {
+                            None => (),
+                            Some(account) => self.accounts.insert(new, account)
+                        }
+                    }
+                }
+                return None;
+            }
+            IRCCmd(ref cmd) if cmd.as_slice() == "ACCOUNT" && !line.args.is_empty() => {
+                // with account-notify, sent when the user logs in, or out with *
+                match sender {
+                    None => (),
+                    Some(nick) => self.set_account(nick, line.args[0].as_slice())
+                }
+                return None;
+            }
+            IRCCmd(ref cmd) if cmd.as_slice() == "JOIN" && line.args.len() >= 3 => {
+                // with extended-join, JOIN gives the account, or * if there's none
+                match sender {
+                    None => (),
+                    Some(nick) => self.set_account(nick, line.args[1].as_slice())
+                }
+                return None;
+            }
+            IRCCode(code) if code == RPL_WHOISACCOUNT && line.args.len() >= 3 => {
+                self.accounts.insert(line.args[1].as_slice(), line.args[2].clone());
+                return None;
+            }
+            IRCCmd(ref cmd) if cmd.as_slice() == "MODE" && line.args.len() >= 2 &&
+                               self.channels.contains_key(line.args[0].as_slice()) => {
+                let channel = line.args[0].as_slice();
+                let changes = self.parse_modes(channel, line.args.slice_from(1));
+                self.apply_modes(channel, changes.as_slice());
+                return Some(Modes(channel.to_owned(), changes));
+            }
+            _ => return None
+        };
+
+        let changed = match (self.away.find(nick), msg) {
+            (None, None) => false,
+            (Some(old), Some(new)) => old.as_slice() != new,
+            _ => true
+        };
+        if !changed {
+            return None;
+        }
+        match msg {
+            None => { self.away.pop(nick); }
+            Some(msg) => self.away.insert(nick, msg.to_owned())
+        }
+        Some(Away(nick.to_owned(), msg.map(|m| m.to_owned())))
+    }
+
+    fn update_channels(&mut self, me: &[u8], line: &Line) {
+        let args = line.args.as_slice();
+        let nick = match line.prefix {
+            None => None,
+            Some(ref user) => Some(user.nick())
+        };
+        let from_me = nick.map_or(false, |n| self.casemap.eq(n, me));
+        match line.command {
+            IRCCode(code) if code == RPL_NAMREPLY && args.len() >= 4 &&
+                             self.channels.contains_key(args[2].as_slice()) => {
+                let channel = args[2].as_slice();
+                for name in args[3].split(|&b| b == ' ' as u8).filter(|n| !n.is_empty()) {
+                    let split = name.iter().position(|&b| !self.is_prefix(b))
+                                    .unwrap_or(name.len());
+                    let (prefixes, name) = (name.slice_to(split), name.slice_from(split));
+                    // with userhost-in-names, it's nick!user@host
+                    let nick = match name.iter().position(|&b| b == '!' as u8) {
+                        None => name,
+                        Some(bang) => {
+                            let rest = name.slice_from(bang + 1);
+                            match rest.iter().position(|&b| b == '@' as u8) {
+                                None => (),
+                                Some(at) => {
+                                    let host = (rest.slice_to(at).to_owned(),
+                                                rest.slice_from(at + 1).to_owned());
+                                    self.hosts.insert(name.slice_to(bang), host);
+                                }
+                            }
+                            name.slice_to(bang)
+                        }
+                    };
+                    let mut member = Member { nick: nick.to_owned(), prefixes: ~[] };
+                    for &p in prefixes.iter() {
+                        self.add_prefix(&mut member, p);
+                    }
+                    let casemap = self.casemap;
+                    self.names.find_or_insert_with(channel, |_| new_members(casemap))
+                              .insert(nick, member);
+                }
+            }
+            IRCCode(code) if code == RPL_ENDOFNAMES && args.len() >= 2 => {
+                let channel = args[1].as_slice();
+                let members = match self.names.pop(channel) {
+                    Some(members) if self.channels.contains_key(channel) => members,
+                    _ => return
+                };
+                let gone: ~[~[u8]] = self.channels.find(channel).unwrap().iter()
+                                         .filter(|m| !members.contains_key(m.key.as_slice()))
+                                         .map(|m| m.key.clone()).collect();
+                self.channels.insert(channel, members);
+                for nick in gone.iter() {
+                    if !self.shares_channel(nick.as_slice()) {
+                        self.forget(nick.as_slice());
+                    }
+                }
+            }
+            IRCCmd(ref cmd) if cmd.as_slice() == "JOIN" && !args.is_empty() => {
+                let nick = match nick { None => return, Some(n) => n };
+                for channel in args[0].split(|&b| b == ',' as u8) {
+                    if from_me {
+                        self.channels.insert(channel, new_members(self.casemap));
+                    }
+                    match self.channels.find_mut(channel) {
+                        None => (),
+                        Some(members) => {
+                            members.insert(nick, Member { nick: nick.to_owned(), prefixes: ~[] });
+                        }
+                    }
+                }
+            }
+            IRCCmd(ref cmd) if cmd.as_slice() == "PART" && !args.is_empty() => {
+                let nick = match nick { None => return, Some(n) => n };
+                for channel in args[0].split(|&b| b == ',' as u8) {
+                    self.leave(channel, nick, from_me);
+                }
+            }
+            IRCCmd(ref cmd) if cmd.as_slice() == "KICK" && args.len() >= 2 => {
+                let kicked_me = self.casemap.eq(args[1].as_slice(), me);
+                self.leave(args[0].as_slice(), args[1].as_slice(), kicked_me);
+            }
+            IRCCmd(ref cmd) if cmd.as_slice() == "QUIT" => {
+                let nick = match nick { None => return, Some(n) => n };
+                for (_, channel) in self.channels.mut_iter() {
+                    channel.value.pop(nick);
+                }
+            }
+            IRCCmd(ref cmd) if cmd.as_slice() == "NICK" && !args.is_empty() => {
+                let nick = match nick { None => return, Some(n) => n };
+                let new = args[0].as_slice();
+                for (_, channel) in self.channels.mut_iter() {
+                    match channel.value.pop(nick) {
+                        None => (),
+                        Some(mut member) => {
+                            member.nick = new.to_owned();
+                            channel.value.insert(new, member);
+                        }
+                    }
+                }
+            }
+            _ => ()
+        }
+    }
+
+    /// Records the user's account, or that they aren't logged in if it's *
+    fn set_account(&mut self, nick: &[u8], account: &[u8]) {
+        if account == bytes!("*") {
+            self.accounts.pop(nick);
+        } else {
+            self.accounts.insert(nick, account.to_owned());
+        }
+    }
+
+    /// Forgets what's known about the user
+    fn forget(&mut self, nick: &[u8]) {
+        self.away.pop(nick);
+        self.hosts.pop(nick);
+        self.accounts.pop(nick);
+    }
+
+    /// Returns whether the user is in any of the bot's channels
+    fn shares_channel(&self, nick: &[u8]) -> bool {
+        self.channels.iter().any(|channel| channel.value.contains_key(nick))
+    }
+
+    /// Removes the nick from the channel, or the whole channel if it's the bot leaving,
+    /// and forgets the users who no longer share a channel with the bot
+    fn leave(&mut self, channel: &[u8], nick: &[u8], me: bool) {
+        if me {
+            self.names.pop(channel);
+            match self.channels.pop(channel) {
+                None => (),
+                Some(members) => {
+                    for member in members.iter() {
+                        if !self.shares_channel(member.key.as_slice()) {
+                            self.forget(member.key.as_slice());
+                        }
+                    }
+                }
+            }
+            return;
+        }
+        match self.channels.find_mut(channel) {
+            None => return,
+            Some(members) => { members.pop(nick); }
+        }
+        if !self.shares_channel(nick) {
+            self.forget(nick);
+        }
+    }
+
+    /// Breaks a channel MODE line's mode string and parameters into the modes it sets
+    /// and unsets. Parsing stops at a mode whose parameter is missing.
+    pub fn parse_modes(&self, channel: &[u8], args: &[~[u8]]) -> ~[ModeChange] {
+        let members = self.channels.find(channel);
+        let mut changes = ~[];
+        let mut params = args.slice_from(1).iter();
+        let mut adding = true;
+        for &mode in args[0].iter() {
+            if mode == '+' as u8 || mode == '-' as u8 {
+                adding = mode == '+' as u8;
+                continue;
+            }
+            let status = self.prefixes.iter().any(|&(m, _)| m == mode);
+            let takes_param = status || self.param_modes.contains(&mode) ||
+                              (adding && self.set_param_modes.contains(&mode));
+            let param = if !takes_param {
+                None
+            } else {
+                match params.next() {
+                    None => break,
+                    Some(p) => Some(p.clone())
+                }
+            };
+            let nick = if !status {
+                None
+            } else {
+                param.as_ref().map(|p| {
+                    match members.and_then(|m| m.find(p.as_slice())) {
+                        None => p.clone(),
+                        Some(member) => member.nick.clone()
+                    }
+                })
+            };
+            changes.push(ModeChange { adding: adding, mode: mode, param: param, nick: nick });
+        }
+        changes
+    }
+
+    /// Breaks a mode string and its parameters, as a MODE line for the channel would give
+    /// them, into MODE lines that each change no more modes with a parameter than the
+    /// server's ISUPPORT MODES allows
+    pub fn mode_lines(&self, channel: &[u8], args: &[~[u8]]) -> ~[~[u8]] {
+        let changes = self.parse_modes(channel, args);
+        let max = self.max_modes.map(|max| cmp::max(max, 1));
+        let mut lines = ~[];
+        let mut start = 0;
+        while start < changes.len() {
+            let mut end = start;
+            let mut params = 0;
+            while end < changes.len() {
+                if changes[end].param.is_some() {
+                    if max == Some(params) {
+                        break;
+                    }
+                    params += 1;
+                }
+                end += 1;
+            }
+            lines.push(mode_line(channel, changes.slice(start, end)));
+            start = end;
+        }
+        lines
+    }
+
+    /// Updates member prefixes from the modes a MODE line changed in one of the bot's
+    /// channels
+    fn apply_modes(&mut self, channel: &[u8], changes: &[ModeChange]) {
+        for change in changes.iter() {
+            let prefix = match self.prefixes.iter().find(|&&(m, _)| m == change.mode) {
+                None => continue,
+                Some(&(_, p)) => p
+            };
+            let nick = match change.nick {
+                None => continue,
+                Some(ref nick) => nick.as_slice()
+            };
+            let mut member = match self.channels.find_mut(channel).and_then(|m| m.pop(nick)) {
+                None => continue,
+                Some(member) => member
+            };
+            if change.adding {
+                self.add_prefix(&mut member, prefix);
+            } else {
+                member.prefixes.retain(|&p| p != prefix);
+            }
+            self.channels.find_mut(channel).unwrap().insert(nick, member);
+        }
+    }
+
+    fn is_prefix(&self, b: u8) -> bool {
+        self.prefixes.iter().any(|&(_, p)| p == b)
+    }
+
+    /// Gives the member the prefix, keeping the highest first
+    fn add_prefix(&self, member: &mut Member, prefix: u8) {
+        if member.prefixes.contains(&prefix) {
+            return;
+        }
+        let rank = |p: &u8| self.prefixes.iter().position(|&(_, q)| q == *p).unwrap_or(0);
+        let pos = member.prefixes.iter().position(|p| rank(p) > rank(&prefix))
+                        .unwrap_or(member.prefixes.len());
+        member.prefixes.insert(pos, prefix);
+    }
+}
+
+/// Returns a MODE line that makes the changes in the channel
+fn mode_line(channel: &[u8], changes: &[ModeChange]) -> ~[u8] {
+    let mut modes = ~[];
+    let mut params = ~[];
+    let mut adding = None;
+    for change in changes.iter() {
+        if adding != Some(change.adding) {
+            modes.push(if change.adding { '+' as u8 } else { '-' as u8 });
+            adding = Some(change.adding);
+        }
+        modes.push(change.mode);
+        match change.param {
+            None => (),
+            Some(ref param) => params.push(param.as_slice())
+        }
+    }
+    let mut words = ~[bytes!("MODE"), channel, modes.as_slice()];
+    words.push_all(params.as_slice());
+    words.connect_vec(&(' ' as u8))
+}
+
+/// Returns an empty member list for a channel the bot joined
+fn new_members(casemap: CaseMapping) -> CaseMap<Member> {
+    let mut members = CaseMap::new();
+    members.set_casemapping(casemap);
+    members
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Users, Member, new_members};
+    use casemap;
+
+    fn args(line: &str) -> ~[~[u8]] {
+        line.words().map(|w| w.as_bytes().to_owned()).collect()
+    }
+
+    #[test]
+    fn test_parse_modes_status() {
+        let mut users = Users::new();
+        let mut members = new_members(casemap::Rfc1459);
+        members.insert(bytes!("Nick1"), Member { nick: bytes!("Nick1").to_owned(), prefixes: ~[] });
+        users.channels.insert(bytes!("#chan"), members);
+
+        let changes = users.parse_modes(bytes!("#chan"), args("+o-v nick1 nick2"));
+        assert_eq!(changes.len(), 2);
+        assert!(changes[0].adding);
+        assert_eq!(changes[0].mode, 'o' as u8);
+        assert_eq!(changes[0].param, Some(bytes!("nick1").to_owned()));
+        assert_eq!(changes[0].nick, Some(bytes!("Nick1").to_owned()));
+        assert!(!changes[1].adding);
+        assert_eq!(changes[1].mode, 'v' as u8);
+        assert_eq!(changes[1].nick, Some(bytes!("nick2").to_owned()));
+    }
+
+    #[test]
+    fn test_parse_modes_key() {
+        let users = Users::new();
+        let changes = users.parse_modes(bytes!("#chan"), args("+k-k secret secret"));
+        assert_eq!(changes.len(), 2);
+        assert!(changes[0].adding && !changes[1].adding);
+        assert_eq!(changes[0].param, Some(bytes!("secret").to_owned()));
+        assert_eq!(changes[1].param, Some(bytes!("secret").to_owned()));
+        assert_eq!(changes[0].nick, None);
+
+        // unlike k, l only takes a parameter when it's set
+        let changes = users.parse_modes(bytes!("#chan"), args("-l+l 10"));
+        assert_eq!(changes.len(), 2);
+        assert_eq!(changes[0].param, None);
+        assert_eq!(changes[1].param, Some(bytes!("10").to_owned()));
+    }
+
+    #[test]
+    fn test_parse_modes_missing_param() {
+        let users = Users::new();
+        let changes = users.parse_modes(bytes!("#chan"), args("+mov nick1"));
+        assert_eq!(changes.len(), 2);
+        assert_eq!(changes[0].mode, 'm' as u8);
+        assert_eq!(changes[1].mode, 'o' as u8);
+        assert!(users.parse_modes(bytes!("#chan"), args("-k")).is_empty());
+    }
+}
@@ -0,0 +1,88 @@
+//! UTF-8 helpers, vended to Lua as irc.utf8
+//!
+//! Event arguments are raw bytes, which may not be valid UTF-8, and slicing them with
+//! string.sub can split multi-byte characters.
+//!
+//! irc.utf8.valid(s): Returns whether s is valid UTF-8
+//! irc.utf8.decode(s): Returns s with invalid sequences replaced by U+FFFD
+//! irc.utf8.len(s): Returns the number of characters in s, decoded as with decode()
+//! irc.utf8.truncate(s, n): Returns at most the first n characters of s
+//! irc.utf8.truncatebytes(s, n): Returns the longest prefix of s that fits in n bytes
+//!                               without splitting a character
+//!
+//! len, truncate and truncatebytes decode s as with decode() first.
+
+#[allow(uppercase_variables)];
+
+use lua;
+use std::str;
+
+/// Pushes the irc.utf8 table
+pub unsafe fn push_table(L: &mut lua::ExternState) {
+    L.newtable();
+    L.registerlib(None, [
+        ("valid", lua_valid),
+        ("decode", lua_decode),
+        ("len", lua_len),
+        ("truncate", lua_truncate),
+        ("truncatebytes", lua_truncatebytes)
+    ]);
+}
+
+lua_extern! {
+    unsafe fn lua_valid(L: &mut lua::ExternState) -> i32 {
+        // 1 arg: s
+
+        let s = L.checkbytes(1);
+        L.pushboolean(str::is_utf8(s));
+        1
+    }
+
+    unsafe fn lua_decode(L: &mut lua::ExternState) -> i32 {
+        // 1 arg: s
+
+        let s = str::from_utf8_lossy(L.checkbytes(1)).into_owned();
+        L.pushstring(s.as_slice());
+        1
+    }
+
+    unsafe fn lua_len(L: &mut lua::ExternState) -> i32 {
+        // 1 arg: s
+
+        let s = str::from_utf8_lossy(L.checkbytes(1)).into_owned();
+        L.pushinteger(s.char_len() as int);
+        1
+    }
+
+    unsafe fn lua_truncate(L: &mut lua::ExternState) -> i32 {
+        // 2 args: s, n
+
+        let s = str::from_utf8_lossy(L.checkbytes(1)).into_owned();
+        let n = L.checkinteger(2);
+        L.argcheck(n >= 0, 2, "count must not be negative");
+        let end = match s.char_indices().nth(n as uint) {
+            None => s.len(),
+            Some((idx, _)) => idx
+        };
+        L.pushstring(s.slice_to(end));
+        1
+    }
+
+    unsafe fn lua_truncatebytes(L: &mut lua::ExternState) -> i32 {
+        // 2 args: s, n
+
+        let s = str::from_utf8_lossy(L.checkbytes(1)).into_owned();
+        let n = L.checkinteger(2);
+        L.argcheck(n >= 0, 2, "count must not be negative");
+        let n = n as uint;
+        let mut end = 0;
+        for (idx, c) in s.char_indices() {
+            if idx + c.len_utf8_bytes() > n {
+                break;
+            }
+            end = idx + c.len_utf8_bytes();
+        }
+        L.pushstring(s.slice_to(end));
+        1
+    }
+}
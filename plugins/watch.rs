@@ -0,0 +1,306 @@
+//! Tracking whether users are online
+//!
+//! The nicks in the server's watch list, and those plugins pass to irc.watch, are
+//! followed with MONITOR if the server's ISUPPORT has it, up to its limit. The rest
+//! are polled with ISON every POLL_INTERVAL. Watching starts once the MOTD is over,
+//! since ISUPPORT comes before it, and nicks added later are monitored or polled on the
+//! next tick. A nick plugins watch stops being watched once its last callback is
+//! removed, unless it's in the server's watch list.
+//!
+//! With labeled-response each ISON is sent with a label of its own, so only the replies
+//! to the bot's polls update the nicks, and not those to a plugin's irc.query.
+//! Otherwise an RPL_ISON is taken as the reply to the oldest poll only if the nicks it
+//! lists were all in that poll.
+//!
+//! The nicks are kept for the connection, across plugin reloads.
+
+use casemap;
+use casemap::CaseMapping;
+use isupport::ISupport;
+use outbound;
+use outbound::Outbound;
+use tags;
+use irc::conn::{Conn, Line, IRCCode};
+use std::mem;
+use time;
+
+static POLL_INTERVAL: u64 = 60 * 1000000000; // ns between ISON polls
+static MAX_LIST_LEN: uint = 400; // bytes of nicks in each MONITOR or ISON line
+
+static RPL_ISON: uint = 303;
+static RPL_ENDOFMOTD: uint = 376;
+static ERR_NOMOTD: uint = 422;
+static RPL_MONONLINE: uint = 730;
+static RPL_MONOFFLINE: uint = 731;
+static ERR_MONLISTFULL: uint = 734;
+
+pub struct Watch {
+    priv nicks: ~[Entry],
+    priv monitor: Option<uint>, // MONITOR's limit on nicks, 0 for none, if the server has it
+    priv full: bool, // the server said the MONITOR list is full
+    priv started: bool, // the MOTD is over
+    priv polls: ~[Poll], // each ISON that hasn't been answered yet
+    priv unmonitor: ~[~[u8]], // nicks to take off the server's MONITOR list on the next tick
+    priv labeled: bool, // the server has labeled-response
+    priv next_label: uint,
+    priv last_poll: u64, // precise_time_ns() of the last ISON poll
+    priv poll_due: bool // a nick was added that needs polling
+}
+
+struct Entry {
+    nick: ~[u8],
+    listed: bool, // in the server's watch list, so it's never removed
+    monitored: bool, // on the server's MONITOR list
+    online: Option<bool> // None until the server tells us
+}
+
+struct Poll {
+    label: Option<~str>, // with labeled-response
+    nicks: ~[~[u8]]
+}
+
+impl Watch {
+    pub fn new(nicks: &[~str]) -> Watch {
+        let mut watch = Watch { nicks: ~[], monitor: None, full: false, started: false,
+                                polls: ~[], unmonitor: ~[], labeled: false, next_label: 0,
+                                last_poll: 0, poll_due: false };
+        for nick in nicks.iter() {
+            watch.add(&casemap::Rfc1459, nick.as_bytes());
+        }
+        for entry in watch.nicks.mut_iter() {
+            entry.listed = true;
+        }
+        watch
+    }
+
+    /// Takes the MONITOR limit from the server's ISUPPORT
+    pub fn set_isupport(&mut self, isupport: &ISupport) {
+        self.monitor = isupport.get("MONITOR").map(|limit| from_str(limit).unwrap_or(0));
+    }
+
+    /// Sets whether the server has labeled-response, which polls are labeled with
+    pub fn set_labeled_response(&mut self, enabled: bool) {
+        self.labeled = enabled;
+    }
+
+    /// Forgets who's online and what's monitored, e.g. on a new connection
+    pub fn clear(&mut self) {
+        self.started = false;
+        self.full = false;
+        self.polls.clear();
+        self.unmonitor.clear();
+        for entry in self.nicks.mut_iter() {
+            entry.monitored = false;
+            entry.online = None;
+        }
+    }
+
+    /// Starts watching the nick, if it isn't already. Returns whether it's online, if
+    /// that's known.
+    pub fn add(&mut self, casemap: &CaseMapping, nick: &[u8]) -> Option<bool> {
+        match self.find(casemap, nick) {
+            Some(i) => return self.nicks[i].online,
+            None => ()
+        }
+        self.nicks.push(Entry { nick: nick.to_owned(), listed: false, monitored: false,
+                                online: None });
+        self.poll_due = true;
+        None
+    }
+
+    /// Stops watching the nick, unless it's in the server's watch list
+    pub fn remove(&mut self, casemap: &CaseMapping, nick: &[u8]) {
+        let i = match self.find(casemap, nick) {
+            Some(i) if !self.nicks[i].listed => i,
+            _ => return
+        };
+        let entry = self.nicks.remove(i).unwrap();
+        if entry.monitored {
+            self.unmonitor.push(entry.nick);
+        }
+    }
+
+    /// Starts watching at the end of the MOTD, and updates the watched nicks from
+    /// MONITOR and ISON replies. Returns the nicks that came online or went offline.
+    pub fn line_received(&mut self, conn: &mut Conn, out: &mut Outbound, casemap: &CaseMapping,
+                         line: &Line, tags: &[(~str, ~str)]) -> ~[(~[u8], bool)] {
+        let mut changes = ~[];
+        match line.command {
+            IRCCode(code) if (code == RPL_ENDOFMOTD || code == ERR_NOMOTD) && !self.started => {
+                self.started = true;
+                self.monitor_nicks(conn, out);
+                self.poll(conn, out);
+            }
+            IRCCode(code) if (code == RPL_MONONLINE || code == RPL_MONOFFLINE) &&
+                             line.args.len() >= 2 => {
+                // the first argument is our nick. Online targets are nick!user@host.
+                for target in line.args[1].split(|&b| b == ',' as u8) {
+                    let nick = target.split(|&b| b == '!' as u8).next().unwrap_or(target);
+                    match self.set(casemap, nick, code == RPL_MONONLINE) {
+                        Some(change) => changes.push(change),
+                        None => ()
+                    }
+                }
+            }
+            IRCCode(code) if code == ERR_MONLISTFULL && line.args.len() >= 3 => {
+                // the nicks that didn't fit are polled instead, as are any added later
+                self.full = true;
+                for nick in line.args[2].split(|&b| b == ',' as u8) {
+                    match self.find(casemap, nick) {
+                        Some(i) => self.nicks[i].monitored = false,
+                        None => ()
+                    }
+                }
+                self.poll_due = true;
+            }
+            IRCCode(code) if code == RPL_ISON && line.args.len() >= 2 && !self.polls.is_empty() => {
+                let online: ~[&[u8]] = line.args[1].split(|&b| b == ' ' as u8)
+                                                   .filter(|n| !n.is_empty()).collect();
+                let poll = match self.answered(casemap, tags::find(tags, "label"), online) {
+                    Some(i) => self.polls.remove(i).unwrap(),
+                    None => return changes
+                };
+                for nick in poll.nicks.iter() {
+                    let on = online.iter().any(|n| casemap.eq(*n, nick.as_slice()));
+                    match self.set(casemap, nick.as_slice(), on) {
+                        Some(change) => changes.push(change),
+                        None => ()
+                    }
+                }
+            }
+            _ => ()
+        }
+        changes
+    }
+
+    /// Monitors or polls the nicks added since the last tick, and polls the nicks that
+    /// aren't monitored every POLL_INTERVAL
+    pub fn tick(&mut self, conn: &mut Conn, out: &mut Outbound) {
+        if !self.started {
+            return;
+        }
+        if !self.unmonitor.is_empty() {
+            let removed = mem::replace(&mut self.unmonitor, ~[]);
+            for chunk in chunks(removed).iter() {
+                let nicks = chunk.connect_vec(&(',' as u8));
+                let line = [bytes!("MONITOR - "), nicks.as_slice()].concat_vec();
+                out.send_raw(conn, outbound::Bot, line.as_slice());
+            }
+        }
+        if self.poll_due {
+            self.monitor_nicks(conn, out);
+        }
+        if self.poll_due || time::precise_time_ns() - self.last_poll >= POLL_INTERVAL {
+            self.poll(conn, out);
+        }
+    }
+
+    /// Returns which poll an RPL_ISON with the label and online nicks answers, if any
+    fn answered(&self, casemap: &CaseMapping, label: Option<&str>, online: &[&[u8]])
+                -> Option<uint> {
+        match label {
+            Some(label) => {
+                return self.polls.iter().position(|p| p.label.as_ref().map_or(false, |l| {
+                    l.as_slice() == label
+                }));
+            }
+            None => ()
+        }
+        match self.polls.head() {
+            Some(poll) if poll.label.is_none() => {
+                let ours = online.iter().all(|n| {
+                    poll.nicks.iter().any(|nick| casemap.eq(*n, nick.as_slice()))
+                });
+                if ours { Some(0) } else { None }
+            }
+            _ => None
+        }
+    }
+
+    fn find(&self, casemap: &CaseMapping, nick: &[u8]) -> Option<uint> {
+        self.nicks.iter().position(|e| casemap.eq(e.nick.as_slice(), nick))
+    }
+
+    /// Records whether the nick is online, returning the change if it's new
+    fn set(&mut self, casemap: &CaseMapping, nick: &[u8], online: bool)
+           -> Option<(~[u8], bool)> {
+        let entry = match self.find(casemap, nick) {
+            Some(i) => &mut self.nicks[i],
+            None => return None
+        };
+        if entry.online == Some(online) {
+            return None;
+        }
+        entry.online = Some(online);
+        Some((entry.nick.clone(), online))
+    }
+
+    /// Adds the nicks that aren't monitored yet to the server's MONITOR list, as many as
+    /// its limit allows
+    fn monitor_nicks(&mut self, conn: &mut Conn, out: &mut Outbound) {
+        let limit = match self.monitor {
+            Some(_) if self.full => return,
+            None => return,
+            Some(limit) => limit
+        };
+        let mut count = self.nicks.iter().filter(|e| e.monitored).count();
+        let mut added = ~[];
+        for entry in self.nicks.mut_iter().filter(|e| !e.monitored) {
+            if limit > 0 && count >= limit {
+                break;
+            }
+            entry.monitored = true;
+            added.push(entry.nick.clone());
+            count += 1;
+        }
+        for chunk in chunks(added).iter() {
+            let nicks = chunk.connect_vec(&(',' as u8));
+            let line = [bytes!("MONITOR + "), nicks.as_slice()].concat_vec();
+            out.send_raw(conn, outbound::Bot, line.as_slice());
+        }
+    }
+
+    /// Asks the server with ISON which of the nicks that aren't monitored are online
+    fn poll(&mut self, conn: &mut Conn, out: &mut Outbound) {
+        self.last_poll = time::precise_time_ns();
+        self.poll_due = false;
+        // a poll that wasn't answered by now never will be
+        self.polls.clear();
+        let nicks = self.nicks.iter().filter(|e| !e.monitored).map(|e| e.nick.clone()).collect();
+        for chunk in chunks(nicks).move_iter() {
+            let label = if self.labeled {
+                self.next_label += 1;
+                Some(format!("watch{}", self.next_label))
+            } else {
+                None
+            };
+            let mut line = match label {
+                Some(ref label) => format!("@label={} ISON ", *label).into_bytes(),
+                None => bytes!("ISON ").to_owned()
+            };
+            line.push_all(chunk.connect_vec(&(' ' as u8)));
+            out.send_raw(conn, outbound::Bot, line.as_slice());
+            self.polls.push(Poll { label: label, nicks: chunk });
+        }
+    }
+}
+
+/// Splits the nicks into lists that fit in a line
+fn chunks(nicks: ~[~[u8]]) -> ~[~[~[u8]]] {
+    let mut chunks = ~[];
+    let mut chunk = ~[];
+    let mut len = 0;
+    for nick in nicks.move_iter() {
+        if !chunk.is_empty() && len + nick.len() + 1 > MAX_LIST_LEN {
+            chunks.push(chunk);
+            chunk = ~[];
+            len = 0;
+        }
+        len += nick.len() + 1;
+        chunk.push(nick);
+    }
+    if !chunk.is_empty() {
+        chunks.push(chunk);
+    }
+    chunks
+}
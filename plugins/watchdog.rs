@@ -0,0 +1,141 @@
+//! Watchdog for Lua handlers
+//!
+//! Handlers run on the connection's task, so a handler that blocks, e.g. reading from
+//! a socket opened with io.open, stalls the whole connection until the server drops it.
+//! The watchdog runs on its own task and warns when a handler has been running for
+//! longer than general.handler_timeout seconds, naming the plugin and the event. It
+//! then marks the handler as expired, and the handler's instruction count hook aborts
+//! it with an error once it runs Lua code again, so the Lua state is left usable.
+
+use sync::MutexArc;
+use std::io::timer::Timer;
+use std::task;
+use time;
+
+enum Msg {
+    Started(uint, ~str, ~str, u64), // id, plugin description, event and precise_time_ns()
+    Finished
+}
+
+pub struct Watchdog {
+    priv tx: Option<Sender<Msg>>, // None if handlers have no timeout
+    priv expired: MutexArc<uint>, // id of the last handler that ran out of time, or 0
+    priv running: ~[uint], // ids of the running handlers, innermost last
+    priv next_id: uint
+}
+
+impl Watchdog {
+    /// Starts a watchdog for handlers that run longer than `timeout` seconds.
+    /// With no timeout the watchdog does nothing.
+    pub fn new(timeout: Option<uint>) -> Watchdog {
+        let expired = MutexArc::new(0u);
+        let mut watchdog = Watchdog { tx: None, expired: expired.clone(), running: ~[],
+                                      next_id: 1 };
+        let timeout = match timeout {
+            None => return watchdog,
+            Some(t) => t as u64
+        };
+        let (tx, rx) = channel();
+        task::task().named("handler watchdog").spawn(proc() {
+            watch(rx, timeout, expired);
+        });
+        watchdog.tx = Some(tx);
+        watchdog
+    }
+
+    /// Notes that the plugin started running a handler for the event
+    pub fn started(&mut self, plugin: &str, event: &str) {
+        let id = self.next_id;
+        self.next_id += 1;
+        self.running.push(id);
+        match self.tx {
+            None => (),
+            Some(ref tx) => {
+                let now = time::precise_time_ns();
+                tx.try_send(Started(id, plugin.to_owned(), event.to_owned(), now));
+            }
+        }
+    }
+
+    /// Notes that the most recently started handler finished
+    pub fn finished(&mut self) {
+        self.running.pop();
+        match self.tx {
+            None => (),
+            Some(ref tx) => { tx.try_send(Finished); }
+        }
+    }
+
+    /// Returns whether the innermost running handler ran out of time, so its hook
+    /// should abort it
+    pub fn expired(&self) -> bool {
+        match self.running.last() {
+            None => false,
+            Some(&id) => self.expired.access(|expired| *expired == id)
+        }
+    }
+}
+
+struct Running {
+    id: uint,
+    plugin: ~str,
+    event: ~str,
+    started: u64, // precise_time_ns() when the handler started
+    reported: bool
+}
+
+fn watch(rx: Receiver<Msg>, timeout: u64, expired: MutexArc<uint>) {
+    let mut timer = match Timer::new() {
+        Ok(t) => t,
+        Err(e) => {
+            println!("Error creating handler watchdog timer: {}", e);
+            return;
+        }
+    };
+    // handlers can dispatch events themselves, so the innermost handler is last
+    let mut running: ~[Running] = ~[];
+    loop {
+        let waiting = match running.last() {
+            Some(r) if !r.reported => Some(r.started),
+            _ => None
+        };
+        let msg = match waiting {
+            None => rx.recv_opt(),
+            Some(started) => {
+                let elapsed = (time::precise_time_ns() - started) / 1000000;
+                let limit = timeout * 1000;
+                let expired = timer.oneshot(if elapsed < limit { limit - elapsed } else { 0 });
+                select! (
+                    msg = rx.recv_opt() => msg,
+                    () = expired.recv() => {
+                        let r = running.mut_last().unwrap();
+                        println!("Warning: plugin {} has been handling event {} for more than \
+                                  {} seconds; it's aborted once it runs Lua code again, and \
+                                  the connection is stalled until then",
+                                 r.plugin, r.event, timeout);
+                        expired.access(|e| *e = r.id);
+                        r.reported = true;
+                        continue;
+                    }
+                )
+            }
+        };
+        match msg {
+            None => break, // the plugins are gone
+            Some(Started(id, plugin, event, started)) => {
+                running.push(Running { id: id, plugin: plugin, event: event, started: started,
+                                       reported: false });
+            }
+            Some(Finished) => {
+                match running.pop() {
+                    Some(ref r) if r.reported => {
+                        let secs = (time::precise_time_ns() - r.started) / 1000000000;
+                        println!("Plugin {} finished handling event {} after {} seconds",
+                                 r.plugin, r.event, secs);
+                    }
+                    _ => ()
+                }
+            }
+        }
+    }
+}
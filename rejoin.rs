@@ -0,0 +1,132 @@
+/// Rejoining channels the bot is kicked from
+///
+/// When the bot is kicked, plugins get irc.KICKED. If the channel is in the server's
+/// rejoin_on_kick list, the bot rejoins it after rejoin_delay seconds, with the
+/// channel's autojoin key if it has one. A JOIN the server refuses, e.g. because the
+/// kick came with a ban, is retried after the same delay, up to rejoin_attempts times.
+/// Any other error the server answers the JOIN with ends the rejoin.
+
+use casemap::CaseMapping;
+use config;
+use outbound;
+use timer;
+use State;
+use irc::conn::{Conn, Line, IRCCmd, IRCCode};
+use std::str;
+
+// numerics for a JOIN the server refused
+static ERR_CHANNELISFULL: uint = 471;
+static ERR_INVITEONLYCHAN: uint = 473;
+static ERR_BANNEDFROMCHAN: uint = 474;
+static ERR_BADCHANNELKEY: uint = 475;
+static ERR_NEEDREGGEDNICK: uint = 477;
+
+pub struct Rejoin {
+    priv channels: ~[~str], // channels to rejoin when kicked from, "*" for any
+    priv keys: ~[(~str, ~str)], // channel and key of the autojoin channels with keys
+    priv delay: u64, // ms to wait before each attempt
+    priv attempts: uint, // JOINs to send before giving up
+    priv pending: ~[(~[u8], uint)] // channels being rejoined, and the JOINs left for each
+}
+
+impl Rejoin {
+    pub fn new(server: &config::Server) -> Rejoin {
+        Rejoin {
+            channels: server.rejoin_on_kick.clone(),
+            keys: server.autojoin.iter().filter_map(|chan| {
+                chan.password.as_ref().map(|key| (chan.name.clone(), key.clone()))
+            }).collect(),
+            delay: server.rejoin_delay as u64 * 1000,
+            attempts: server.rejoin_attempts,
+            pending: ~[]
+        }
+    }
+
+    /// Returns the index of the pending channel, and how many JOINs it has left
+    fn find(&self, casemap: CaseMapping, chan: &[u8]) -> Option<(uint, uint)> {
+        self.pending.iter().enumerate().find(|&(_, &(ref c, _))| {
+            casemap.eq(c.as_slice(), chan)
+        }).map(|(i, &(_, left))| (i, left))
+    }
+}
+
+/// Tells plugins when the bot is kicked, and rejoins the channel if it's configured to.
+/// Also retries a rejoin the server refused, and stops once the bot is back.
+pub fn line_dispatched(conn: &mut Conn, state: &mut State, line: &Line) {
+    let casemap = state.isupport.casemapping();
+    let me = conn.me().nick().to_owned();
+    match line.command {
+        IRCCmd(ref cmd) if cmd.as_slice() == "KICK" && line.args.len() >= 2 &&
+                           line.prefix.is_some() &&
+                           casemap.eq(line.args[1].as_slice(), me.as_slice()) => {
+            let chan = line.args[0].as_slice();
+            let reason = line.args.get(2).map_or(&[], |r| r.as_slice());
+            let rejoin = state.rejoin.attempts > 0 && state.rejoin.channels.iter().any(|c| {
+                c.as_slice() == "*" || casemap.eq(c.as_bytes(), chan)
+            });
+            println!("Kicked from {}{}", str::from_utf8_lossy(chan),
+                     if rejoin { ", rejoining" } else { "" });
+            state.plugins.dispatch_kicked(conn, &mut state.out, chan, line.prefix.get_ref(),
+                                          reason, rejoin);
+            if rejoin {
+                state.rejoin.pending.retain(|&(ref c, _)| !casemap.eq(c.as_slice(), chan));
+                state.rejoin.pending.push((chan.to_owned(), state.rejoin.attempts));
+                schedule(state, chan);
+            }
+        }
+        IRCCmd(ref cmd) if cmd.as_slice() == "JOIN" && !line.args.is_empty() &&
+                           line.prefix.as_ref().map_or(false, |u| {
+                               casemap.eq(u.nick(), me.as_slice())
+                           }) => {
+            let chan = line.args[0].as_slice();
+            state.rejoin.pending.retain(|&(ref c, _)| !casemap.eq(c.as_slice(), chan));
+        }
+        IRCCode(code) if line.args.len() >= 2 && (code == ERR_CHANNELISFULL ||
+                         code == ERR_INVITEONLYCHAN || code == ERR_BANNEDFROMCHAN ||
+                         code == ERR_BADCHANNELKEY || code == ERR_NEEDREGGEDNICK) => {
+            // the first argument is our nick
+            let chan = line.args[1].as_slice();
+            match state.rejoin.find(casemap, chan) {
+                Some((_, left)) if left > 0 => schedule(state, chan),
+                Some((i, _)) => {
+                    println!("Giving up on rejoining {}", str::from_utf8_lossy(chan));
+                    state.rejoin.pending.remove(i);
+                }
+                None => ()
+            }
+        }
+        IRCCode(code) if code >= 400 && code < 500 && line.args.len() >= 2 => {
+            // e.g. ERR_NOSUCHCHANNEL or ERR_TOOMANYCHANNELS, which won't go away by retrying
+            let chan = line.args[1].as_slice();
+            match state.rejoin.find(casemap, chan) {
+                Some((i, _)) => {
+                    println!("Giving up on rejoining {}: {}", str::from_utf8_lossy(chan),
+                             str::from_utf8_lossy(line.args.last().unwrap().as_slice()));
+                    state.rejoin.pending.remove(i);
+                }
+                None => ()
+            }
+        }
+        _ => ()
+    }
+}
+
+/// Sends a JOIN for the pending channel after the delay, using up one of its attempts
+fn schedule(state: &mut State, chan: &[u8]) {
+    let casemap = state.isupport.casemapping();
+    match state.rejoin.find(casemap, chan) {
+        Some((i, left)) => state.rejoin.pending[i] = (chan.to_owned(), left - 1),
+        None => return
+    }
+    let key = state.rejoin.keys.iter().find(|&&(ref c, _)| casemap.eq(c.as_bytes(), chan))
+                                      .map_or(~"", |&(_, ref k)| k.clone());
+    let chan = chan.to_owned();
+    timer::after("rejoin", state.rejoin.delay, state.cmd_tx.clone(),
+                 proc(conn: &mut Conn, state: &mut State) {
+        // we may be back already, e.g. because a plugin joined
+        let casemap = state.isupport.casemapping();
+        if state.rejoin.find(casemap, chan.as_slice()).is_some() {
+            state.out.join(conn, outbound::Bot, chan.as_slice(), key.as_bytes());
+        }
+    });
+}
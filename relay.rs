@@ -0,0 +1,246 @@
+/// Local relay for irclib's connection
+///
+/// irclib opens its own connection from a host and port, so the bot makes the
+/// connection itself, racing the server's addresses, through a proxy or from a chosen
+/// local address, and hands it to irclib through a listener on 127.0.0.1 that copies
+/// bytes between irclib's connection and the upstream one. irclib's own connect is then
+/// to the local listener, so the server's connect_timeout covers the whole connection.
+///
+/// Any local user could connect to the listener, so irclib registers with a random
+/// username for each connection, and the relay only relays the connection whose USER
+/// line has it, replacing it with the configured username on the way upstream.
+///
+/// The bot can close the upstream connection with the relay's Closer, e.g. to reconnect
+/// from a server that stopped answering, which irclib wouldn't notice while it's blocked
+/// reading from the relay.
+
+use bound;
+use config;
+use resolver;
+use socks;
+use std::io::net::ip::{Ipv4Addr, SocketAddr};
+use std::io::net::tcp::{TcpListener, TcpStream};
+use std::io::timer::Timer;
+use std::io::net::tcp::TcpAcceptor;
+use std::io::{Acceptor, Listener};
+use std::ascii::StrAsciiExt;
+use std::{rand, str, task};
+
+static PROXY_TIMEOUT: u64 = 30000; // milliseconds to wait for the proxy during the handshake
+static REGISTER_TIMEOUT: u64 = 10000; // milliseconds a relayed connection has to send USER
+static MAX_HEAD: uint = 4096; // bytes a relayed connection may send before its USER line
+
+/// Where irclib connects to reach the relayed connection
+pub struct Relay {
+    port: u16, // on 127.0.0.1
+    user: ~str, // the username irclib registers with, which the relay checks for
+    closer: Closer
+}
+
+/// Closes the relayed connection, so irclib's read fails and it disconnects
+#[deriving(Clone)]
+pub struct Closer {
+    priv tx: Sender<()>
+}
+
+impl Closer {
+    pub fn close(&self) {
+        self.tx.try_send(());
+    }
+}
+
+/// A connection that can be relayed
+pub trait Upstream: Reader + Writer + Clone + Send {
+    /// Closes both directions, waking up a blocked read
+    fn shutdown(&mut self);
+}
+
+impl Upstream for TcpStream {
+    fn shutdown(&mut self) {
+        let _ = self.close_write();
+        let _ = self.close_read();
+    }
+}
+
+/// Connects to the server through its proxy and from its bind address, whichever are
+/// configured, or else to the first of its addresses that accepts, returning where
+/// irclib should connect to instead
+pub fn connect(server: &config::Server) -> Result<Relay, ~str> {
+    let (host, port) = match server.proxy {
+        Some(ref proxy) => (proxy.host.clone(), proxy.port),
+        None => (server.host.clone(), server.port)
+    };
+    let timeout = server.connect_timeout.map(|secs| secs as u64 * 1000);
+    let timed_out = format!("{} didn't accept the connection within {} seconds", host,
+                            server.connect_timeout.unwrap_or(0));
+    let res = match server.bind_address {
+        Some(local) => {
+            resolver::within(timeout, proc() { bound::connect(local, host.as_slice(), port) })
+                .unwrap_or(Err(timed_out)).and_then(|s| through(s, server))
+        }
+        None if server.proxy.is_some() => {
+            resolver::within(timeout, proc() { resolver::connect(host.as_slice(), port) })
+                .unwrap_or(Err(timed_out)).and_then(|s| through(s, server))
+        }
+        None => {
+            let (v6, prefer_ipv6) = (!server.ipv4_only, server.prefer_ipv6);
+            resolver::within(timeout, proc() {
+                resolver::addresses(host.as_slice(), true, v6).and_then(|addrs| {
+                    let addrs = resolver::interleave(addrs, prefer_ipv6);
+                    resolver::race(addrs.as_slice(), port)
+                })
+            }).unwrap_or(Err(timed_out)).and_then(|s| through(s, server))
+        }
+    };
+    res.map_err(|e| match server.proxy {
+        Some(ref proxy) => format!("proxy {}: {}", proxy.host, e),
+        None => e
+    })
+}
+
+/// Has the proxy, if there is one, connect to the server, and relays the connection
+fn through<S: Upstream>(mut upstream: S, server: &config::Server) -> Result<Relay, ~str> {
+    match server.proxy {
+        Some(ref proxy) => {
+            // give up on a proxy that stops answering by closing the connection under it
+            let (done_tx, done_rx) = channel::<()>();
+            let mut watched = upstream.clone();
+            task::task().named("proxy timeout").spawn(proc() {
+                let mut timer = match Timer::new() {
+                    Ok(t) => t,
+                    Err(_) => return
+                };
+                let expired = timer.oneshot(PROXY_TIMEOUT);
+                select! (
+                    _ = expired.recv() => watched.shutdown(),
+                    _ = done_rx.recv_opt() => ()
+                )
+            });
+            let res = socks::handshake(&mut upstream, proxy, server.host, server.port);
+            done_tx.try_send(());
+            try!(res);
+        }
+        None => ()
+    }
+    spawn(upstream, server.user.clone())
+}
+
+/// Relays irclib's connection to the returned port to `upstream`. irclib has to register
+/// with the returned username, which the relay replaces with `user`.
+pub fn spawn<S: Upstream>(upstream: S, user: ~str) -> Result<Relay, ~str> {
+    let addr = SocketAddr { ip: Ipv4Addr(127, 0, 0, 1), port: 0 };
+    let mut listener = match TcpListener::bind(addr) {
+        Ok(l) => l,
+        Err(e) => return Err(format!("could not start the relay: {}", e))
+    };
+    let port = match listener.socket_name() {
+        Ok(addr) => addr.port,
+        Err(e) => return Err(format!("could not start the relay: {}", e))
+    };
+    let mut acceptor = match listener.listen() {
+        Ok(a) => a,
+        Err(e) => return Err(format!("could not start the relay: {}", e))
+    };
+    // the connection is left alone if the Closer is dropped without closing it
+    let (close_tx, close_rx) = channel::<()>();
+    let mut closing = upstream.clone();
+    task::task().named("relay closer").spawn(proc() {
+        if close_rx.recv_opt().is_some() {
+            closing.shutdown();
+        }
+    });
+    let secret = format!("{:016x}", rand::random::<u64>());
+    let expected = secret.clone();
+    task::task().named("relay").spawn(proc() {
+        let mut upstream = upstream;
+        let (client, head) = match accept(&mut acceptor, expected.as_slice(), user.as_slice()) {
+            Ok(accepted) => accepted,
+            Err(e) => {
+                println!("Error accepting the relayed connection: {}", e);
+                upstream.shutdown();
+                return;
+            }
+        };
+        if upstream.write(head).is_err() {
+            return upstream.shutdown();
+        }
+        let (up_reader, client_writer) = (upstream.clone(), client.clone());
+        task::task().named("relay reader").spawn(proc() {
+            pipe(up_reader, client_writer);
+        });
+        pipe(client, upstream);
+    });
+    Ok(Relay { port: port, user: secret, closer: Closer { tx: close_tx } })
+}
+
+/// Accepts connections until one registers with the secret username, returning it and
+/// what it sent so far, with the username replaced by `user`. Only irclib's one
+/// connection is relayed.
+fn accept(acceptor: &mut TcpAcceptor, secret: &str, user: &str)
+          -> Result<(TcpStream, ~[u8]), ~str> {
+    loop {
+        let mut client = match acceptor.accept() {
+            Ok(s) => s,
+            Err(e) => return Err(e.to_str())
+        };
+        client.set_read_timeout(Some(REGISTER_TIMEOUT));
+        let head = registration(&mut client, secret, user);
+        client.set_read_timeout(None);
+        match head {
+            Some(head) => return Ok((client, head)),
+            None => println!("Refused a relayed connection that didn't register as the bot")
+        }
+    }
+}
+
+/// Reads from the connection up to the end of its USER line, returning what it sent with
+/// the username replaced by `user`, or None if the username isn't the secret
+fn registration(client: &mut TcpStream, secret: &str, user: &str) -> Option<~[u8]> {
+    let mut head = ~[];
+    let mut buf = [0u8, ..512];
+    loop {
+        let mut start = 0;
+        for end in range(0, head.len()).filter(|&i| head[i] == '\n' as u8) {
+            let line = head.slice(start, end);
+            let mut words = line.split(|&b| b == ' ' as u8);
+            let is_user = words.next().map_or(false, |cmd| {
+                str::from_utf8(cmd).map_or(false, |cmd| cmd.eq_ignore_ascii_case("USER"))
+            });
+            if is_user {
+                let name = match words.next() {
+                    Some(name) if name == secret.as_bytes() => name.len(),
+                    _ => return None
+                };
+                let rest = head.slice_from(start + "USER ".len() + name);
+                return Some([head.slice_to(start), bytes!("USER "), user.as_bytes(), rest]
+                            .concat_vec());
+            }
+            start = end + 1;
+        }
+        if head.len() >= MAX_HEAD {
+            return None;
+        }
+        match client.read(buf) {
+            Ok(n) => head.push_all(buf.slice_to(n)),
+            Err(_) => return None
+        }
+    }
+}
+
+/// Copies from one connection to the other until either is closed, and then closes both
+/// so the other direction stops too
+fn pipe<R: Upstream, W: Upstream>(mut from: R, mut to: W) {
+    let mut buf = [0u8, ..4096];
+    loop {
+        match from.read(buf) {
+            Ok(n) => {
+                if to.write(buf.slice_to(n)).is_err() {
+                    break;
+                }
+            }
+            Err(_) => break
+        }
+    }
+    to.shutdown();
+    from.shutdown();
+}
@@ -0,0 +1,277 @@
+/// DNS lookups
+///
+/// Addresses are looked up with the system resolver. TXT records aren't available
+/// through it, so they're queried directly over UDP from the first nameserver in
+/// /etc/resolv.conf.
+///
+/// IRC servers are connected to "happy eyeballs" style: connections to the server's
+/// addresses are raced, alternating between IPv6 and IPv4 and started CONNECT_STAGGER
+/// apart, so a broken address or family doesn't hold up the connection. Connecting is
+/// given up on after the server's connect_timeout, rather than waiting for the kernel.
+
+use std::io;
+use std::io::File;
+use std::io::net::addrinfo;
+use std::io::net::ip::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::io::net::tcp::TcpStream;
+use std::io::net::udp::UdpSocket;
+use std::io::timer;
+use std::{rand, task};
+
+static DNS_PORT: u16 = 53;
+static TIMEOUT: u64 = 3000; // milliseconds to wait for each attempt
+static ATTEMPTS: uint = 2;
+static TYPE_TXT: u16 = 16;
+static CLASS_IN: u16 = 1;
+static CONNECT_STAGGER: u64 = 250; // milliseconds between connection attempts in a race
+
+/// Returns the addresses for the name, restricted to IPv4 or IPv6 if requested
+pub fn addresses(name: &str, v4: bool, v6: bool) -> Result<~[IpAddr], ~str> {
+    let addrs = match addrinfo::get_host_addresses(name) {
+        Ok(addrs) => addrs,
+        Err(e) => return Err(e.to_str())
+    };
+    let mut result = ~[];
+    for addr in addrs.move_iter() {
+        let wanted = match addr {
+            Ipv4Addr(..) => v4,
+            Ipv6Addr(..) => v6
+        };
+        if wanted && !result.contains(&addr) {
+            result.push(addr);
+        }
+    }
+    Ok(result)
+}
+
+/// Connects to the first address of the host that accepts the connection
+pub fn connect(host: &str, port: u16) -> Result<TcpStream, ~str> {
+    let addrs = match addrinfo::get_host_addresses(host) {
+        Ok(addrs) => addrs,
+        Err(e) => return Err(e.to_str())
+    };
+    let mut last = format!("no addresses for {}", host);
+    for &ip in addrs.iter() {
+        match TcpStream::connect(SocketAddr { ip: ip, port: port }) {
+            Ok(s) => return Ok(s),
+            Err(e) => last = e.to_str()
+        }
+    }
+    Err(last)
+}
+
+/// Orders addresses for connecting, alternating between IPv6 and IPv4 starting with the
+/// preferred family
+pub fn interleave(addrs: ~[IpAddr], prefer_ipv6: bool) -> ~[IpAddr] {
+    let (v6, v4) = addrs.partition(|addr| match *addr { Ipv6Addr(..) => true, _ => false });
+    let (first, second) = if prefer_ipv6 { (v6, v4) } else { (v4, v6) };
+    let mut result = ~[];
+    let (mut first, mut second) = (first.move_iter(), second.move_iter());
+    loop {
+        let (a, b) = (first.next(), second.next());
+        if a.is_none() && b.is_none() {
+            break;
+        }
+        for &ip in a.iter().chain(b.iter()) {
+            result.push(ip);
+        }
+    }
+    result
+}
+
+/// Races connections to the addresses in order, each started CONNECT_STAGGER after the
+/// one before it, and returns the first connection that's accepted. Connections that
+/// are accepted after it are closed.
+pub fn race(addrs: &[IpAddr], port: u16) -> Result<TcpStream, ~str> {
+    let (tx, rx) = channel();
+    for (i, &ip) in addrs.iter().enumerate() {
+        let tx = tx.clone();
+        task::task().named("connect race").spawn(proc() {
+            if i > 0 {
+                timer::sleep(i as u64 * CONNECT_STAGGER);
+            }
+            let res = TcpStream::connect(SocketAddr { ip: ip, port: port });
+            tx.try_send((ip, res.map_err(|e| e.to_str())));
+        });
+    }
+    let mut last = ~"no addresses";
+    for _ in range(0, addrs.len()) {
+        match rx.recv() {
+            (_, Ok(stream)) => return Ok(stream),
+            (ip, Err(e)) => last = format!("{}: {}", ip, e)
+        }
+    }
+    Err(last)
+}
+
+/// Runs `f` on its own task and returns its result, or None if it takes longer than `ms`
+/// milliseconds. A task that's given up on is left to finish, and its result is dropped.
+pub fn within<T: Send>(ms: Option<u64>, f: proc() -> T) -> Option<T> {
+    let ms = match ms {
+        None => return Some(f()),
+        Some(ms) => ms
+    };
+    let (tx, rx) = channel();
+    task::task().named("connect timeout").spawn(proc() {
+        tx.try_send(f());
+    });
+    let mut timer = match timer::Timer::new() {
+        Ok(t) => t,
+        Err(_) => return rx.recv_opt()
+    };
+    let expired = timer.oneshot(ms);
+    select! (
+        res = rx.recv_opt() => res,
+        _ = expired.recv() => None
+    )
+}
+
+/// Returns the TXT records for the name, each with its strings concatenated
+pub fn txt(name: &str) -> Result<~[~[u8]], ~str> {
+    let server = SocketAddr { ip: nameserver(), port: DNS_PORT };
+    let bind = match server.ip {
+        Ipv4Addr(..) => Ipv4Addr(0, 0, 0, 0),
+        Ipv6Addr(..) => Ipv6Addr(0, 0, 0, 0, 0, 0, 0, 0)
+    };
+    let mut sock = match UdpSocket::bind(SocketAddr { ip: bind, port: 0 }) {
+        Ok(s) => s,
+        Err(e) => return Err(e.to_str())
+    };
+    sock.set_read_timeout(Some(TIMEOUT));
+
+    let id = rand::random::<u16>();
+    let query = match build_query(id, name, TYPE_TXT) {
+        None => return Err(format!("invalid name `{}'", name)),
+        Some(q) => q
+    };
+    let mut buf = [0u8, ..4096];
+    for _ in range(0, ATTEMPTS) {
+        match sock.sendto(query, server) {
+            Ok(()) => (),
+            Err(e) => return Err(e.to_str())
+        }
+        loop {
+            let n = match sock.recvfrom(buf) {
+                Ok((n, from)) if from == server => n,
+                Ok(_) => continue, // not from our nameserver
+                Err(ref e) if e.kind == io::TimedOut => break,
+                Err(e) => return Err(e.to_str())
+            };
+            let resp = buf.slice_to(n);
+            if resp.len() < 12 || read_u16(resp, 0) != id {
+                continue; // not our answer
+            }
+            return parse_txt(resp);
+        }
+    }
+    Err(~"timed out")
+}
+
+/// Returns the first nameserver in /etc/resolv.conf, or localhost
+fn nameserver() -> IpAddr {
+    let contents = File::open(&Path::new("/etc/resolv.conf")).and_then(|mut f| f.read_to_str());
+    let contents = match contents {
+        Ok(s) => s,
+        Err(_) => ~""
+    };
+    for line in contents.lines() {
+        let mut words = line.words();
+        if words.next() == Some("nameserver") {
+            match words.next().and_then(from_str::<IpAddr>) {
+                Some(ip) => return ip,
+                None => ()
+            }
+        }
+    }
+    Ipv4Addr(127, 0, 0, 1)
+}
+
+fn build_query(id: u16, name: &str, qtype: u16) -> Option<~[u8]> {
+    let mut q = ~[];
+    push_u16(&mut q, id);
+    push_u16(&mut q, 0x0100); // recursion desired
+    push_u16(&mut q, 1); // one question
+    push_u16(&mut q, 0);
+    push_u16(&mut q, 0);
+    push_u16(&mut q, 0);
+    for label in name.trim_right_chars(&'.').split('.') {
+        if label.is_empty() || label.len() > 63 {
+            return None;
+        }
+        q.push(label.len() as u8);
+        q.push_all(label.as_bytes());
+    }
+    q.push(0);
+    push_u16(&mut q, qtype);
+    push_u16(&mut q, CLASS_IN);
+    Some(q)
+}
+
+fn parse_txt(resp: &[u8]) -> Result<~[~[u8]], ~str> {
+    let flags = read_u16(resp, 2);
+    match flags & 0xF {
+        0 => (),
+        3 => return Ok(~[]), // no such name
+        rcode => return Err(format!("server returned error {}", rcode))
+    }
+    let (qdcount, ancount) = (read_u16(resp, 4), read_u16(resp, 6));
+    let mut i = 12;
+    for _ in range(0, qdcount) {
+        i = try!(skip_name(resp, i)) + 4;
+    }
+    let mut records = ~[];
+    for _ in range(0, ancount) {
+        i = try!(skip_name(resp, i));
+        if i + 10 > resp.len() {
+            return Err(~"truncated response");
+        }
+        let (rtype, rdlen) = (read_u16(resp, i), read_u16(resp, i + 8) as uint);
+        i += 10;
+        if i + rdlen > resp.len() {
+            return Err(~"truncated response");
+        }
+        if rtype == TYPE_TXT {
+            let rdata = resp.slice(i, i + rdlen);
+            let mut text = ~[];
+            let mut j = 0;
+            while j < rdata.len() {
+                let len = rdata[j] as uint;
+                let end = if j + 1 + len > rdata.len() { rdata.len() } else { j + 1 + len };
+                text.push_all(rdata.slice(j + 1, end));
+                j = end;
+            }
+            records.push(text);
+        }
+        i += rdlen;
+    }
+    Ok(records)
+}
+
+/// Returns the index just past the (possibly compressed) name at i
+fn skip_name(resp: &[u8], i: uint) -> Result<uint, ~str> {
+    let mut i = i;
+    loop {
+        if i >= resp.len() {
+            return Err(~"truncated response");
+        }
+        let len = resp[i] as uint;
+        if len == 0 {
+            return Ok(i + 1);
+        } else if len & 0xC0 == 0xC0 {
+            return Ok(i + 2);
+        }
+        i += 1 + len;
+    }
+}
+
+fn read_u16(b: &[u8], i: uint) -> u16 {
+    if i + 1 >= b.len() {
+        return 0;
+    }
+    (b[i] as u16 << 8) | b[i + 1] as u16
+}
+
+fn push_u16(v: &mut ~[u8], n: u16) {
+    v.push((n >> 8) as u8);
+    v.push(n as u8);
+}
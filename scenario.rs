@@ -0,0 +1,266 @@
+/// Scripted end-to-end scenarios
+///
+/// `rustirc scenario <file>` starts a mock server on localhost, connects the bot to it
+/// with the normal configuration (except for the server address), and plays the
+/// script against it. Each line of the script is one step:
+///
+///     send <line>         Sends a raw line to the bot, e.g. `send :irc.test 001 rustbot :Hi`
+///     expect <pattern>    Waits for the bot to send a line matching the pattern, where
+///                         `*` and `?` are wildcards and case is ignored. Lines that don't
+///                         match are skipped.
+///     timeout <secs>      Sets how long later expect steps wait, 5 seconds by default
+///     assert <expr>       Evaluates a Lua expression in the plugins' state and fails
+///                         unless it's true
+///     wait <secs>         Pauses, e.g. to let plugin timers run
+///
+/// Blank lines and lines starting with # are ignored. The first step that fails ends the
+/// scenario, and the exit status says whether it passed.
+///
+/// `rustirc test <plugin.lua> <file>` tests a single plugin the same way. Only that
+/// plugin is loaded, no channels are joined, and the mock server completes registration
+/// before the first step, so the script can go straight to sending events and
+/// expecting the plugin's replies.
+
+use {Cmd, State};
+use config;
+use plugins::mask;
+use irc::conn::Conn;
+use sync::MutexArc;
+use std::{str, task};
+use std::io::{BufferedReader, File, Listener, Acceptor};
+use std::io::net::ip::{Ipv4Addr, SocketAddr};
+use std::io::net::tcp::{TcpListener, TcpStream};
+use std::io::timer::Timer;
+
+static DEFAULT_TIMEOUT: u64 = 5;
+
+enum Step {
+    Send(~str),
+    Expect(~str),
+    Timeout(u64),
+    Assert(~str),
+    Wait(u64)
+}
+
+/// Runs the scenario in the file, returning whether it passed
+pub fn run(conf: &config::Config, path: &Path) -> bool {
+    let mut conf = conf.clone();
+    let steps = match parse(path) {
+        Ok(steps) => {
+            match conf.test_plugin.clone() {
+                None => steps,
+                Some(plugin) => {
+                    conf.plugin_paths = ~[plugin];
+                    conf.servers[0].autojoin = ~[];
+                    let welcome = format!(":test.server 001 {} :Welcome", conf.servers[0].nick);
+                    // steps on line 0 come before the script
+                    let mut all = ~[(0, Expect(~"USER *")), (0, Send(welcome))];
+                    all.push_all_move(steps);
+                    all
+                }
+            }
+        }
+        Err(e) => {
+            println!("Error reading scenario {}: {}", path.display(), e);
+            return false;
+        }
+    };
+
+    let addr = SocketAddr { ip: Ipv4Addr(127, 0, 0, 1), port: 0 };
+    let listener = match TcpListener::bind(addr) {
+        Ok(l) => l,
+        Err(e) => {
+            println!("Error starting the mock server: {}", e);
+            return false;
+        }
+    };
+    let port = match listener.socket_name() {
+        Ok(addr) => addr.port,
+        Err(e) => {
+            println!("Error starting the mock server: {}", e);
+            return false;
+        }
+    };
+    let mut acceptor = match listener.listen() {
+        Ok(a) => a,
+        Err(e) => {
+            println!("Error starting the mock server: {}", e);
+            return false;
+        }
+    };
+
+    // connect the bot once, to the mock server
+    conf.servers[0].host = ~"127.0.0.1";
+    conf.servers[0].port = port;
+    conf.servers[0].addresses = ~[(~"127.0.0.1", port)];
+    let arc = MutexArc::new(None);
+    let arc2 = arc.clone();
+    task::task().named("scenario bot").spawn(proc() {
+        match ::connect(&conf, 0, &arc2, &::joined::Joined::new()) {
+            Ok(()) => println!("The bot quit"),
+            Err(e) => println!("Connection error: {}", e)
+        }
+        arc2.access(|c| *c = None);
+    });
+
+    let stream = match acceptor.accept() {
+        Ok(s) => s,
+        Err(e) => {
+            println!("Error accepting the bot's connection: {}", e);
+            return false;
+        }
+    };
+    let lines = spawn_reader(stream.clone());
+    let mut player = Player { stream: stream, lines: lines, arc: arc, timeout: DEFAULT_TIMEOUT };
+    for &(lineno, ref step) in steps.iter() {
+        match player.play(step) {
+            Ok(()) => (),
+            Err(e) => {
+                if lineno == 0 {
+                    println!("FAIL during registration: {}", e);
+                } else {
+                    println!("FAIL at line {}: {}", lineno, e);
+                }
+                return false;
+            }
+        }
+    }
+    println!("PASS");
+    true
+}
+
+struct Player {
+    stream: TcpStream,
+    lines: Receiver<~str>, // lines the bot sent
+    arc: MutexArc<Option<Sender<Cmd>>>,
+    timeout: u64 // seconds
+}
+
+impl Player {
+    fn play(&mut self, step: &Step) -> Result<(), ~str> {
+        match *step {
+            Send(ref line) => {
+                println!("<< {}", line);
+                let line = format!("{}\r\n", line);
+                self.stream.write(line.as_bytes()).map_err(|e| e.to_str())
+            }
+            Expect(ref pat) => self.expect(pat.as_slice()),
+            Timeout(secs) => {
+                self.timeout = secs;
+                Ok(())
+            }
+            Assert(ref expr) => self.assert(expr.as_slice()),
+            Wait(secs) => {
+                match Timer::new() {
+                    Ok(mut timer) => timer.sleep(secs * 1000),
+                    Err(e) => return Err(e.to_str())
+                }
+                Ok(())
+            }
+        }
+    }
+
+    fn expect(&mut self, pat: &str) -> Result<(), ~str> {
+        let mut timer = match Timer::new() {
+            Ok(t) => t,
+            Err(e) => return Err(e.to_str())
+        };
+        let expired = timer.oneshot(self.timeout * 1000);
+        loop {
+            let lines = &self.lines;
+            select! (
+                line = lines.recv_opt() => {
+                    match line {
+                        None => return Err(format!("the bot disconnected, expecting `{}'", pat)),
+                        Some(line) => {
+                            println!(">> {}", line);
+                            if mask::glob(pat.as_bytes(), line.as_bytes()) {
+                                return Ok(());
+                            }
+                        }
+                    }
+                },
+                () = expired.recv() => {
+                    return Err(format!("timed out after {} seconds expecting `{}'",
+                                       self.timeout, pat));
+                }
+            )
+        }
+    }
+
+    fn assert(&mut self, expr: &str) -> Result<(), ~str> {
+        let (tx, rx) = channel();
+        let code = expr.to_owned();
+        let mut cmd = Some(proc(conn: &mut Conn, state: &mut State) {
+            tx.try_send(state.plugins.eval(conn, &mut state.out, code.as_slice()));
+        });
+        let sent = self.arc.access(|chan| {
+            match *chan {
+                None => false,
+                Some(ref c) => c.try_send(cmd.take_unwrap())
+            }
+        });
+        if !sent {
+            return Err(format!("the bot isn't connected, asserting `{}'", expr));
+        }
+        match rx.recv_opt() {
+            None => Err(format!("the bot disconnected, asserting `{}'", expr)),
+            Some(Ok(true)) => Ok(()),
+            Some(Ok(false)) => Err(format!("assertion `{}' is false", expr)),
+            Some(Err(e)) => Err(format!("assertion `{}' raised an error: {}", expr, e))
+        }
+    }
+}
+
+/// Spawns a task that sends each line the bot sends on the returned channel
+fn spawn_reader(stream: TcpStream) -> Receiver<~str> {
+    let (tx, rx) = channel();
+    task::task().named("scenario reader").spawn(proc() {
+        let mut reader = BufferedReader::new(stream);
+        loop {
+            match reader.read_until('\n' as u8) {
+                Ok(line) => {
+                    let line = str::from_utf8_lossy(line).into_owned();
+                    if !tx.try_send(line.trim_right_chars(& &['\r', '\n']).to_owned()) {
+                        break;
+                    }
+                }
+                Err(_) => break
+            }
+        }
+    });
+    rx
+}
+
+fn parse(path: &Path) -> Result<~[(uint, Step)], ~str> {
+    let contents = match File::open(path).and_then(|mut f| f.read_to_str()) {
+        Ok(s) => s,
+        Err(e) => return Err(e.to_str())
+    };
+    let mut steps = ~[];
+    for (i, line) in contents.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with("#") {
+            continue;
+        }
+        let (cmd, arg) = match line.find(' ') {
+            None => (line, ""),
+            Some(idx) => (line.slice_to(idx), line.slice_from(idx + 1).trim_left())
+        };
+        let step = match cmd {
+            "send" => Send(arg.to_owned()),
+            "expect" => Expect(arg.to_owned()),
+            "assert" => Assert(arg.to_owned()),
+            "timeout" | "wait" => {
+                let secs = match from_str::<u64>(arg) {
+                    None => return Err(format!("line {}: expected a number of seconds", i + 1)),
+                    Some(secs) => secs
+                };
+                if cmd == "timeout" { Timeout(secs) } else { Wait(secs) }
+            }
+            _ => return Err(format!("line {}: unknown step `{}'", i + 1, cmd))
+        };
+        steps.push((i + 1, step));
+    }
+    Ok(steps)
+}
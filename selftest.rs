@@ -0,0 +1,136 @@
+/// End-to-end self-test of the connection
+///
+/// The test sends a CTCP PING to the server's selftest_target, waits for it to come
+/// back and be dispatched to plugins, then does the same with a plain PRIVMSG. Both go
+/// through Outbound like any other message, so this exercises the whole
+/// send/receive/dispatch path. The target is our own nick unless one is configured,
+/// because servers only send channel messages back to the sender with echo-message.
+
+use State;
+use outbound;
+use timer;
+use irc::conn::{Conn, Line, IRCCmd, IRCCTCP};
+use std::{rand, str};
+use time;
+
+static TIMEOUT: u64 = 10000; // milliseconds allowed for each stage
+
+pub struct SelfTest {
+    priv token: ~str,
+    priv target: ~[u8],
+    priv stage: Stage,
+    priv started: u64 // precise_time_ns() when the current stage began
+}
+
+#[deriving(Eq)]
+enum Stage {
+    Ping,
+    Echo
+}
+
+/// Starts a self-test, reporting progress on stdout
+pub fn start(conn: &mut Conn, state: &mut State) {
+    if !state.logged_in {
+        println!("Self-test: not logged in");
+        return;
+    }
+    if state.selftest.is_some() {
+        println!("Self-test: a test is already running");
+        return;
+    }
+
+    let target = match state.selftest_target {
+        Some(ref target) => target.as_bytes().to_owned(),
+        None => conn.me().nick().to_owned()
+    };
+    let test = SelfTest {
+        token: format!("{:08x}", rand::random::<u32>()),
+        target: target,
+        stage: Ping,
+        started: time::precise_time_ns()
+    };
+    println!("Self-test: sending CTCP PING to {}", str::from_utf8_lossy(test.target));
+    let msg = format!("\x01PING {}\x01", test.token);
+    state.out.privmsg(conn, outbound::Console, test.target, msg.as_bytes());
+    schedule_timeout(state, &test);
+    state.selftest = Some(test);
+}
+
+/// Checks whether a line that was just dispatched completes a stage of the running test
+pub fn line_dispatched(conn: &mut Conn, state: &mut State, line: &Line) {
+    let mut test = match state.selftest.take() {
+        None => return,
+        Some(test) => test
+    };
+    if !test.matches(conn, line) {
+        state.selftest = Some(test);
+        return;
+    }
+
+    let now = time::precise_time_ns();
+    let ms = (now - test.started) / 1000000;
+    match test.stage {
+        Ping => {
+            println!("Self-test: CTCP PING received and dispatched in {}ms", ms);
+            println!("Self-test: sending PRIVMSG to {}", str::from_utf8_lossy(test.target));
+            let msg = format!("selftest {}", test.token);
+            state.out.privmsg(conn, outbound::Console, test.target, msg.as_bytes());
+            test.stage = Echo;
+            test.started = now;
+            schedule_timeout(state, &test);
+            state.selftest = Some(test);
+        }
+        Echo => {
+            println!("Self-test: PRIVMSG received and dispatched in {}ms", ms);
+            println!("Self-test: passed");
+        }
+    }
+}
+
+/// Aborts any running test, e.g. because the connection went away
+pub fn abort(state: &mut State) {
+    if state.selftest.take().is_some() {
+        println!("Self-test: failed, connection lost");
+    }
+}
+
+fn schedule_timeout(state: &State, test: &SelfTest) {
+    let token = test.token.clone();
+    let stage = test.stage;
+    timer::after("selftest timeout", TIMEOUT, state.cmd_tx.clone(),
+                 proc(_conn: &mut Conn, state: &mut State) {
+        let timed_out = match state.selftest {
+            Some(ref test) => test.token == token && test.stage == stage,
+            None => false
+        };
+        if timed_out {
+            state.selftest = None;
+            let what = match stage { Ping => "CTCP PING", Echo => "PRIVMSG" };
+            println!("Self-test: failed, no {} received after {}ms", what, TIMEOUT);
+        }
+    });
+}
+
+impl SelfTest {
+    fn matches(&self, conn: &Conn, line: &Line) -> bool {
+        let from_me = match line.prefix {
+            None => false,
+            Some(ref user) => user.nick() == conn.me().nick()
+        };
+        if !from_me {
+            return false;
+        }
+        match (self.stage, &line.command) {
+            (Ping, &IRCCTCP(ref cmd, _)) => {
+                cmd.as_slice() == bytes!("PING") && line.args.len() > 0 &&
+                    line.args[0].as_slice() == self.token.as_bytes()
+            }
+            (Echo, &IRCCmd(ref cmd)) => {
+                let msg = format!("selftest {}", self.token);
+                cmd.as_slice() == "PRIVMSG" && line.args.len() > 1 &&
+                    line.args[1].as_slice() == msg.as_bytes()
+            }
+            _ => false
+        }
+    }
+}
@@ -0,0 +1,9 @@
+-- Repeats what it's told, e.g. "!echo hello". Handy for checking the bot is listening.
+
+local irc = require "irc"
+
+irc.plugin{name = "echo", version = "1.0", description = "Repeats what it's told"}
+
+irc.addcommand("echo", function(ctx)
+    ctx.reply(ctx.text)
+end, {help = "<text>: Repeats the text", minargs = 1})
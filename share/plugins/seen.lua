@@ -0,0 +1,57 @@
+-- Remembers when each nick was last active, e.g. "!seen alice". Activity is only
+-- remembered for the current connection.
+
+local irc = require "irc"
+
+irc.plugin{name = "seen", version = "1.0", description = "Reports when a nick was last active"}
+
+local seen = {} -- lowercased nick -> {nick=, time=, what=}
+
+local function record(user, what)
+    if user then
+        seen[irc.lower(user.nick)] = {nick = user.nick, time = os.time(), what = what}
+    end
+end
+
+irc.addhandler("PRIVMSG", function(_, user, dst)
+    record(user, "talking in " .. dst)
+end)
+irc.addhandler(irc.ACTION, function(_, user, dst)
+    record(user, "acting in " .. dst)
+end)
+irc.addhandler("JOIN", function(_, user, chan)
+    record(user, "joining " .. chan)
+end)
+irc.addhandler("PART", function(_, user, chan)
+    record(user, "leaving " .. chan)
+end)
+irc.addhandler("QUIT", function(_, user)
+    record(user, "quitting")
+end)
+irc.addhandler("NICK", function(_, user, nick)
+    if user then
+        record(user, "changing nick to " .. nick)
+        record({nick = nick}, "changing nick from " .. user.nick)
+    end
+end)
+
+local function ago(secs)
+    if secs < 60 then return secs .. "s" end
+    if secs < 3600 then return math.floor(secs / 60) .. "m" end
+    if secs < 86400 then return math.floor(secs / 3600) .. "h" end
+    return math.floor(secs / 86400) .. "d"
+end
+
+irc.addcommand("seen", function(ctx, nick)
+    if irc.eq(nick, ctx.sender.nick) then
+        ctx.reply("You're right here, " .. ctx.sender.nick)
+        return
+    end
+    local entry = seen[irc.lower(nick)]
+    if not entry then
+        ctx.reply("I haven't seen " .. nick)
+    else
+        ctx.reply(string.format("%s was last seen %s, %s ago", entry.nick, entry.what,
+                                ago(os.time() - entry.time)))
+    end
+end, {help = "<nick>: Tells when the nick was last active", minargs = 1})
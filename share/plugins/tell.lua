@@ -0,0 +1,46 @@
+-- Leaves messages for people who aren't around, e.g. "!tell bob the build is fixed".
+-- The message is delivered the next time bob talks or joins a channel the bot is in.
+-- Messages are only kept for the current connection.
+
+local irc = require "irc"
+
+irc.plugin{name = "tell", version = "1.0", description = "Leaves messages for other nicks"}
+
+local name, config = ...
+local max = tonumber(config.max_messages) or 10 -- messages held for each nick
+
+local pending = {} -- lowercased nick -> array of {from=, time=, text=}
+
+local function deliver(user, chan)
+    local key = irc.lower(user.nick)
+    local msgs = pending[key]
+    if not msgs then return end
+    pending[key] = nil
+    for _, msg in ipairs(msgs) do
+        irc.privmsg(chan, string.format("%s: %s said %s: %s", user.nick, msg.from,
+                                        os.date("%Y-%m-%d %H:%M", msg.time), msg.text))
+    end
+end
+
+irc.addhandler("PRIVMSG", function(_, user, dst)
+    if user then
+        deliver(user, irc.eq(dst, irc.me().nick) and user.nick or dst)
+    end
+end)
+irc.addhandler("JOIN", function(_, user, chan)
+    if user then deliver(user, chan) end
+end)
+
+irc.addcommand("tell", function(ctx, nick)
+    local text = ctx.text:match("^%S+%s+(.+)$")
+    local key = irc.lower(nick)
+    local msgs = pending[key] or {}
+    if #msgs >= max then
+        ctx.reply(nick .. " already has too many messages waiting")
+        return
+    end
+    table.insert(msgs, {from = ctx.sender.nick, time = os.time(), text = text})
+    pending[key] = msgs
+    ctx.reply("I'll pass that on to " .. nick)
+end, {help = "<nick> <message>: Delivers the message when the nick is next around",
+      minargs = 2})
@@ -0,0 +1,28 @@
+-- Reports how long the bot has been connected to the server, e.g. "!uptime".
+
+local irc = require "irc"
+
+irc.plugin{name = "uptime", version = "1.0",
+           description = "Reports how long the bot has been connected"}
+
+-- plugins are loaded when the connection starts
+local connected = os.time()
+
+local function duration(secs)
+    local days, hours = math.floor(secs / 86400), math.floor(secs % 86400 / 3600)
+    local mins = math.floor(secs % 3600 / 60)
+    if days > 0 then
+        return string.format("%dd %dh %dm", days, hours, mins)
+    elseif hours > 0 then
+        return string.format("%dh %dm", hours, mins)
+    end
+    return string.format("%dm %ds", mins, secs % 60)
+end
+
+irc.addhandler(irc.CONNECTED, function()
+    connected = os.time()
+end)
+
+irc.addcommand("uptime", function(ctx)
+    ctx.reply("Connected for " .. duration(os.time() - connected))
+end, {help = "Shows how long the bot has been connected"})
@@ -0,0 +1,107 @@
+-- Announces the title of the first http:// URL in each channel message. https URLs are
+-- skipped, since the tcp package doesn't do TLS. URLs whose host resolves to a
+-- loopback, private or link-local address are skipped too, so that anyone in the
+-- channel can't use the bot to reach services on its own network.
+--
+-- Options in [plugins.urltitle]:
+--   max_length: longest title announced, longer ones are cut short; default 200
+
+local irc = require "irc"
+local tcp = require "tcp"
+local dns = require "dns"
+local log = require "log"
+
+irc.plugin{name = "urltitle", version = "1.0", description = "Announces the titles of URLs"}
+
+local name, config = ...
+local max_length = tonumber(config.max_length) or 200
+local max_body = 64 * 1024 -- bytes read looking for the title
+
+local entities = {amp = "&", lt = "<", gt = ">", quot = '"', apos = "'", nbsp = " "}
+
+local function decode(s)
+    s = s:gsub("&#(%d+);", function(n)
+        n = tonumber(n)
+        return n < 128 and string.char(n) or nil
+    end)
+    return (s:gsub("&(%a+);", entities))
+end
+
+local function announce(chan, body)
+    local title = body:match("<[Tt][Ii][Tt][Ll][Ee][^>]*>(.-)</[Tt][Ii][Tt][Ll][Ee]>")
+    if not title then return end
+    title = decode(title):gsub("%s+", " "):match("^%s*(.-)%s*$")
+    if title == "" then return end
+    if #title > max_length then
+        title = title:sub(1, max_length) .. "..."
+    end
+    irc.privmsg(chan, "Title: " .. irc.stripformat(title))
+end
+
+-- Returns whether the address is one URLs from IRC mustn't reach: unspecified,
+-- loopback, private (including carrier-grade NAT and unique local) or link-local
+local function internal(addr)
+    local a, b = addr:match("^(%d+)%.(%d+)%.%d+%.%d+$")
+    if a then
+        a, b = tonumber(a), tonumber(b)
+        return a == 0 or a == 10 or a == 127 or (a == 100 and b >= 64 and b < 128) or
+               (a == 169 and b == 254) or (a == 172 and b >= 16 and b < 32) or
+               (a == 192 and b == 168)
+    end
+    addr = addr:lower()
+    local mapped = addr:match("^::ffff:(%d+%.%d+%.%d+%.%d+)$")
+    if mapped then return internal(mapped) end
+    local group = addr:match("^(%x%x%x%x):") or ""
+    return addr == "::" or addr == "::1" or group:match("^f[cd]") ~= nil or
+           group:match("^fe[89ab]") ~= nil
+end
+
+local function get(chan, url, addr, host, port, path)
+    local buf = {}
+    local size = 0
+    local sock = tcp.connect(addr, port, function(sock, event, data)
+        if event == "data" then
+            table.insert(buf, data)
+            size = size + #data
+            if size >= max_body then sock:close() end
+        elseif event == "closed" then
+            local resp = table.concat(buf)
+            local status = tonumber(resp:match("^HTTP/%d%.%d (%d+)"))
+            if status == 200 then
+                announce(chan, resp)
+            elseif data then
+                log.debug("fetching %s: %s", url, data)
+            end
+        end
+    end)
+    sock:write(string.format("GET %s HTTP/1.0\r\nHost: %s\r\nUser-Agent: rustirc\r\n" ..
+                             "Accept: text/html\r\nConnection: close\r\n\r\n", path, host))
+end
+
+local function fetch(chan, url)
+    local host, port, path = url:match("^http://([^/:?#]+):?(%d*)([^#]*)")
+    if not host then return end
+    port = tonumber(port) or 80
+    if path == "" then path = "/" elseif path:sub(1, 1) == "?" then path = "/" .. path end
+    -- connect to the address that was checked, rather than letting tcp.connect resolve
+    -- the host again and maybe get a different answer
+    dns.resolve(host, function(addrs, err)
+        if not addrs or #addrs == 0 then
+            log.debug("resolving %s: %s", host, err or "no addresses")
+            return
+        end
+        for _, addr in ipairs(addrs) do
+            if internal(addr) then
+                log.debug("not fetching %s: %s is an internal address", url, addr)
+                return
+            end
+        end
+        get(chan, url, addrs[1], host, port, path)
+    end)
+end
+
+irc.addhandler("PRIVMSG", function(_, user, dst, text)
+    if not user or irc.eq(dst, irc.me().nick) then return end
+    local url = text:match("http://[^%s<>\"]+")
+    if url then fetch(dst, url) end
+end)
@@ -0,0 +1,87 @@
+/// SOCKS5 proxy support
+///
+/// The bot connects to the proxy and asks it to connect to the server (RFC 1928), with
+/// RFC 1929 username and password authentication if configured, before relaying the
+/// connection to irclib. The proxy resolves the server's name, so e.g. .onion servers
+/// work through Tor.
+
+use config;
+use std::io;
+
+static VERSION: u8 = 5;
+static AUTH_NONE: u8 = 0;
+static AUTH_PASSWORD: u8 = 2;
+static CMD_CONNECT: u8 = 1;
+static ATYP_IPV4: u8 = 1;
+static ATYP_DOMAIN: u8 = 3;
+static ATYP_IPV6: u8 = 4;
+
+/// Asks the proxy on the other end of `s` to connect to the server, after which `s`
+/// carries the connection to the server
+pub fn handshake<S: Reader + Writer>(s: &mut S, proxy: &config::Proxy, host: &str, port: u16)
+                                     -> Result<(), ~str> {
+    let methods = if proxy.username.is_some() { ~[AUTH_NONE, AUTH_PASSWORD] } else { ~[AUTH_NONE] };
+    try!(send(s, [~[VERSION, methods.len() as u8], methods].concat_vec()));
+    let reply = try!(recv(s, 2));
+    match reply[1] {
+        m if m == AUTH_NONE => (),
+        m if m == AUTH_PASSWORD => {
+            let user = proxy.username.as_ref().map_or(~"", |u| u.clone());
+            let pass = proxy.password.as_ref().map_or(~"", |p| p.clone());
+            if user.len() > 255 || pass.len() > 255 {
+                return Err(~"the username and password must be at most 255 bytes");
+            }
+            try!(send(s, [~[1, user.len() as u8], user.into_bytes(), ~[pass.len() as u8],
+                          pass.into_bytes()].concat_vec()));
+            if try!(recv(s, 2))[1] != 0 {
+                return Err(~"authentication failed");
+            }
+        }
+        _ => return Err(~"no acceptable authentication method")
+    }
+
+    if host.len() > 255 {
+        return Err(format!("host name {} is too long", host));
+    }
+    try!(send(s, [~[VERSION, CMD_CONNECT, 0, ATYP_DOMAIN, host.len() as u8],
+                  host.as_bytes().to_owned(), ~[(port >> 8) as u8, port as u8]].concat_vec()));
+    let reply = try!(recv(s, 4));
+    if reply[1] != 0 {
+        return Err(format!("connection to {}:{} refused: {}", host, port, reply_error(reply[1])));
+    }
+    // skip the bound address and port
+    let len = match reply[3] {
+        a if a == ATYP_IPV4 => 4,
+        a if a == ATYP_IPV6 => 16,
+        a if a == ATYP_DOMAIN => try!(recv(s, 1))[0] as uint,
+        _ => return Err(~"invalid reply")
+    };
+    try!(recv(s, len + 2));
+    Ok(())
+}
+
+fn send<S: Writer>(s: &mut S, data: ~[u8]) -> Result<(), ~str> {
+    s.write(data).map_err(|e| e.to_str())
+}
+
+fn recv<S: Reader>(s: &mut S, len: uint) -> Result<~[u8], ~str> {
+    match s.read_bytes(len) {
+        Ok(data) => Ok(data),
+        Err(ref e) if e.kind == io::EndOfFile => Err(~"the proxy closed the connection"),
+        Err(e) => Err(e.to_str())
+    }
+}
+
+fn reply_error(code: u8) -> &'static str {
+    match code {
+        1 => "general failure",
+        2 => "not allowed by ruleset",
+        3 => "network unreachable",
+        4 => "host unreachable",
+        5 => "connection refused",
+        6 => "TTL expired",
+        7 => "command not supported",
+        8 => "address type not supported",
+        _ => "unknown error"
+    }
+}
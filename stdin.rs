@@ -1,38 +1,80 @@
 /// Handle stdin commands
 
-use {Cmd, State};
+use {Cmd, Conns, State};
+use selftest;
+use invite;
+use timeline;
+use outbound;
 use std::{io,task};
-use sync::MutexArc;
 use irc::conn::Conn;
 
 /// Spawns a new (unwatched) task to handle stdin
-pub fn spawn_stdin_listener(arc: MutexArc<Option<Sender<Cmd>>>) {
+pub fn spawn_stdin_listener(conns: Conns) {
     task::task().named("stdin listener").spawn(proc() {
-        handle_stdin(arc);
+        handle_stdin(conns);
     });
 }
 
-fn handle_stdin(arc: MutexArc<Option<Sender<Cmd>>>) {
+fn handle_stdin(conns: Conns) {
     let mut stdin = io::BufferedReader::new(io::stdin());
+    let mut lua_mode = false; // lines that aren't commands are run as Lua
+    let mut current = 0; // index of the server commands are sent to
     for line in stdin.lines() {
         let line = line.unwrap(); // ignore error handling
-        match parse_line(line) {
+        let cmd = if line.trim() == "/server" || line.starts_with("/server ") {
+            cmd_server(&conns, &mut current, line.slice_from(7).trim());
+            None
+        } else if line.trim() == "/lua" {
+            lua_mode = !lua_mode;
+            if lua_mode {
+                println!("Lua mode: lines are run in the plugins' state, /lua to leave");
+                prompt();
+            } else {
+                println!("Left Lua mode");
+            }
+            None
+        } else if lua_mode && !line.starts_with("/") {
+            run_lua(line.trim_right_chars(& &['\r', '\n']), true)
+        } else {
+            parse_line(line)
+        };
+        match cmd {
             None => (),
             Some(cmd) => {
+                let (ref name, ref arc) = conns[current];
                 let mut cmd = Some(cmd);
                 if !arc.access(|chan| {
                     match *chan {
-                        None => true,
+                        None => false,
                         Some(ref c) => c.try_send(cmd.take_unwrap())
                     }
                 }) {
-                    println!("Error: no active connection");
+                    println!("Error: no active connection to {}", name);
                 }
             }
         }
     }
 }
 
+/// Switches the server that commands are sent to, or lists the servers
+fn cmd_server(conns: &Conns, current: &mut uint, name: &str) {
+    if name == "" {
+        for (i, &(ref name, ref arc)) in conns.iter().enumerate() {
+            let connected = arc.access(|chan| chan.is_some());
+            println!("{} {}{}", if i == *current { "*" } else { " " }, name,
+                     if connected { "" } else { " (not connected)" });
+        }
+        return;
+    }
+    match conns.iter().position(|&(ref n, _)| n.as_slice() == name) {
+        None => println!("Error: no server named {}", name),
+        Some(i) => {
+            *current = i;
+            println!("Sending commands to {}", name);
+        }
+    }
+}
+
 fn parse_line(line: &str) -> Option<Cmd> {
     if !line.starts_with("/") {
         return None;
@@ -48,6 +90,17 @@ fn parse_line(line: &str) -> Option<Cmd> {
         "quit" => cmd_quit(line),
         "raw" => cmd_raw(line),
         "reload" => cmd_reload(line),
+        "load" => cmd_load(line),
+        "unload" => cmd_unload(line),
+        "plugins" => cmd_plugins(line),
+        "lua" => run_lua(line, false),
+        "selftest" => cmd_selftest(line),
+        "audit" => cmd_audit(line),
+        "invites" => cmd_invites(line),
+        "status" => cmd_status(line),
+        "handlers" => cmd_handlers(line),
+        "accept" => cmd_resolve(line, true),
+        "deny" => cmd_resolve(line, false),
         _ => None
     }
 }
@@ -70,8 +123,8 @@ fn cmd_msg(line: &str) -> Option<Cmd> {
 
     let dst = dst.to_owned();
     let msg = msg.to_owned();
-    Some(proc(conn: &mut Conn, _state: &mut State) {
-        conn.privmsg(dst.as_bytes(), msg.as_bytes());
+    Some(proc(conn: &mut Conn, state: &mut State) {
+        state.out.privmsg(conn, outbound::Console, dst.as_bytes(), msg.as_bytes());
     })
 }
 
@@ -84,8 +137,9 @@ fn cmd_join(line: &str) -> Option<Cmd> {
 
     let chans = chans.to_owned();
     let keys = if line == "" { None } else { Some(line.to_owned()) };
-    Some(proc(conn: &mut Conn, _state: &mut State) {
-        conn.join(chans.as_bytes(), keys.as_ref().map_or(&[], |s| s.as_bytes()));
+    Some(proc(conn: &mut Conn, state: &mut State) {
+        let keys = keys.as_ref().map_or(&[], |s| s.as_bytes());
+        state.out.join(conn, outbound::Console, chans.as_bytes(), keys);
     })
 }
 
@@ -97,29 +151,140 @@ fn cmd_part(line: &str) -> Option<Cmd> {
 
     let chans = chans.to_owned();
     let msg = if msg == "" { None } else { Some(msg.to_owned()) };
-    Some(proc(conn: &mut Conn, _state: &mut State) {
-        conn.part(chans.as_bytes(), msg.as_ref().map_or(&[], |s| s.as_bytes()));
+    Some(proc(conn: &mut Conn, state: &mut State) {
+        let msg = msg.as_ref().map_or(&[], |s| s.as_bytes());
+        state.out.part(conn, outbound::Console, chans.as_bytes(), msg);
     })
 }
 
 fn cmd_quit(line: &str) -> Option<Cmd> {
     let line = line.trim_left();
     let line = if line == "" { None } else { Some(line.to_owned()) };
-    Some(proc(conn: &mut Conn, _state: &mut State) {
-        conn.quit(line.as_ref().map_or(&[], |s| s.as_bytes()));
+    Some(proc(conn: &mut Conn, state: &mut State) {
+        ::quit(conn, state, outbound::Console, line.as_ref().map_or("", |s| s.as_slice()));
     })
 }
 
 fn cmd_raw(line: &str) -> Option<Cmd> {
     let line = line.to_owned();
-    Some(proc(conn: &mut Conn, _state: &mut State) {
-        conn.send_raw(line.as_bytes());
+    Some(proc(conn: &mut Conn, state: &mut State) {
+        state.out.send_raw(conn, outbound::Console, line.as_bytes());
     })
 }
 
 fn cmd_reload(_line: &str) -> Option<Cmd> {
     Some(proc(conn: &mut Conn, state: &mut State) {
         println!("Reloading plugins...");
-        state.plugins.reload_plugins(conn);
+        state.plugins.reload_plugins(conn, &mut state.out);
+    })
+}
+
+fn cmd_load(line: &str) -> Option<Cmd> {
+    let name = line.trim().to_owned();
+    if name.is_empty() {
+        return None;
+    }
+    Some(proc(conn: &mut Conn, state: &mut State) {
+        match state.plugins.load_plugin(conn, &mut state.out, name.as_slice()) {
+            Ok(()) => println!("Loaded plugin {}", name),
+            Err(e) => println!("Error: {}", e)
+        }
+    })
+}
+
+fn cmd_unload(line: &str) -> Option<Cmd> {
+    let name = line.trim().to_owned();
+    if name.is_empty() {
+        return None;
+    }
+    Some(proc(_conn: &mut Conn, state: &mut State) {
+        let count = state.plugins.unload_plugin(name.as_slice());
+        println!("Unloaded plugin {} ({} handlers removed)", name, count);
+    })
+}
+
+fn cmd_plugins(_line: &str) -> Option<Cmd> {
+    Some(proc(_conn: &mut Conn, state: &mut State) {
+        state.plugins.print_plugins();
+    })
+}
+
+/// Runs the code in the plugins' state and prints the results, followed by the prompt
+/// in Lua mode
+fn run_lua(code: &str, lua_mode: bool) -> Option<Cmd> {
+    if code.trim().is_empty() {
+        if lua_mode {
+            prompt();
+        }
+        return None;
+    }
+    let code = code.to_owned();
+    Some(proc(conn: &mut Conn, state: &mut State) {
+        match state.plugins.run_lua(conn, &mut state.out, code.as_slice()) {
+            Ok(ref results) if results.is_empty() => (),
+            Ok(results) => println!("{}", results),
+            Err(e) => println!("Error: {}", e)
+        }
+        if lua_mode {
+            prompt();
+        }
+    })
+}
+
+fn prompt() {
+    print!("lua> ");
+    io::stdio::flush();
+}
+
+fn cmd_selftest(_line: &str) -> Option<Cmd> {
+    Some(proc(conn: &mut Conn, state: &mut State) {
+        selftest::start(conn, state);
+    })
+}
+
+fn cmd_audit(line: &str) -> Option<Cmd> {
+    let filter = line.trim().to_owned();
+    Some(proc(_conn: &mut Conn, state: &mut State) {
+        state.out.print_audit(filter.as_slice(), 20);
+    })
+}
+
+fn cmd_invites(_line: &str) -> Option<Cmd> {
+    Some(proc(_conn: &mut Conn, state: &mut State) {
+        invite::list(state);
+    })
+}
+
+fn cmd_resolve(line: &str, accept: bool) -> Option<Cmd> {
+    let id = match from_str::<uint>(line.trim()) {
+        None => return None,
+        Some(id) => id
+    };
+    Some(proc(conn: &mut Conn, state: &mut State) {
+        println!("{}", invite::resolve(conn, state, id, accept));
+    })
+}
+
+fn cmd_status(_line: &str) -> Option<Cmd> {
+    Some(proc(conn: &mut Conn, state: &mut State) {
+        timeline::print_status(conn, state);
+    })
+}
+
+fn cmd_handlers(line: &str) -> Option<Cmd> {
+    let (word, rest) = parse_word(line);
+    if word == "clear" {
+        let plugin = rest.trim().to_owned();
+        if plugin.is_empty() {
+            return None;
+        }
+        return Some(proc(_conn: &mut Conn, state: &mut State) {
+            let count = state.plugins.clear_handlers(plugin.as_slice());
+            println!("Removed {} handlers of plugin {}", count, plugin);
+        });
+    }
+    let event = if word == "" { None } else { Some(word.to_owned()) };
+    Some(proc(_conn: &mut Conn, state: &mut State) {
+        state.plugins.print_handlers(event.as_ref().map(|e| e.as_slice()));
     })
 }
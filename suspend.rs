@@ -0,0 +1,81 @@
+/// Recovery from suspend and VM pauses
+///
+/// After the machine sleeps, the TCP connection is usually dead, but nothing notices
+/// until the kernel gives up on it much later. A periodic check compares the wall and
+/// monotonic clocks against the check interval; when either has jumped, the connection
+/// is probed with a PING, and if no PONG arrives promptly we close it and reconnect.
+
+use State;
+use timer;
+use irc::conn::{Conn, Line, IRCCmd};
+use std::rand;
+use time;
+
+pub static CHECK_INTERVAL: u64 = 5000; // milliseconds between clock checks
+static JUMP_THRESHOLD: u64 = 30000; // milliseconds of unexplained time that trigger a probe
+static PROBE_TIMEOUT: u64 = 10000; // milliseconds to wait for the PONG
+
+pub struct Clock {
+    priv mono: u64, // precise_time_ns() at the last check
+    priv wall: i64, // wall clock ms at the last check
+    priv probe: Option<~str> // the token of the PING we're waiting on
+}
+
+impl Clock {
+    pub fn new() -> Clock {
+        Clock { mono: time::precise_time_ns(), wall: wall_ms(), probe: None }
+    }
+}
+
+/// Checks for a clock jump since the last check, probing the connection if there was one
+pub fn check(conn: &mut Conn, state: &mut State) {
+    let (mono, wall) = (time::precise_time_ns(), wall_ms());
+    let mono_elapsed = (mono - state.clock.mono) / 1000000;
+    let wall_elapsed = if wall > state.clock.wall { (wall - state.clock.wall) as u64 } else { 0 };
+    state.clock.mono = mono;
+    state.clock.wall = wall;
+
+    let elapsed = if mono_elapsed > wall_elapsed { mono_elapsed } else { wall_elapsed };
+    if elapsed < CHECK_INTERVAL + JUMP_THRESHOLD || !state.logged_in {
+        return;
+    }
+    if state.clock.probe.is_some() {
+        return;
+    }
+    println!("Clock jumped by {}s, probably from a suspend; checking the connection",
+             (elapsed - CHECK_INTERVAL) / 1000);
+    let token = format!("resume-{:08x}", rand::random::<u32>());
+    let line = format!("PING :{}", token);
+    state.out.send_protocol(conn, line.as_bytes());
+    state.clock.probe = Some(token.clone());
+    timer::after("suspend probe", PROBE_TIMEOUT, state.cmd_tx.clone(),
+                 proc(_conn: &mut Conn, state: &mut State) {
+        if state.clock.probe.as_ref() == Some(&token) {
+            // the connection is dead, so there's no point in quitting
+            state.clock.probe = None;
+            ::drop_connection(state, "no PONG after resume");
+        }
+    });
+}
+
+/// Clears the probe when its PONG arrives
+pub fn line_received(state: &mut State, line: &Line) {
+    let token = match state.clock.probe {
+        None => return,
+        Some(ref token) => token.clone()
+    };
+    match line.command {
+        IRCCmd(ref cmd) if cmd.as_slice() == "PONG" => {
+            if line.args.iter().any(|arg| arg.as_slice() == token.as_bytes()) {
+                println!("Connection is still alive after resume");
+                state.clock.probe = None;
+            }
+        }
+        _ => ()
+    }
+}
+
+fn wall_ms() -> i64 {
+    let now = time::get_time();
+    now.sec * 1000 + now.nsec as i64 / 1000000
+}
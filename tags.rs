@@ -0,0 +1,139 @@
+/// IRCv3 message tags
+///
+/// irclib doesn't know about tags. A tagged line reaches us with the tags as its
+/// command, and the rest of the line folded into its arguments. We put the rest of
+/// the line back together and parse it again, so the rest of the bot only ever sees
+/// untagged lines.
+
+use irc::conn;
+use irc::conn::{Event, Line, IRCCmd};
+use std::str;
+use time;
+
+/// A line's tags, in order. Tags without a value have an empty value.
+pub type Tags = ~[(~str, ~str)];
+
+/// Strips the tags from a received line, returning the untagged event and its tags
+pub fn split_event(event: Event) -> (Event, Tags) {
+    match event {
+        conn::LineReceived(line) => {
+            match split(&line) {
+                None => (conn::LineReceived(line), ~[]),
+                Some((tags, line)) => (conn::LineReceived(line), tags)
+            }
+        }
+        event => (event, ~[])
+    }
+}
+
+/// Returns the value of the tag, if present
+pub fn find<'a>(tags: &'a [(~str, ~str)], name: &str) -> Option<&'a str> {
+    tags.iter().find(|&&(ref n, _)| n.as_slice() == name).map(|&(_, ref v)| v.as_slice())
+}
+
+/// Returns the time the server-time tag says the line was sent, in seconds since
+/// the epoch, if it has a valid one, e.g. time=2011-10-19T16:40:51.620Z
+pub fn server_time(tags: &[(~str, ~str)]) -> Option<f64> {
+    let value = match find(tags, "time") {
+        None => return None,
+        Some(v) if v.ends_with("Z") => v.slice_to(v.len() - 1),
+        Some(_) => return None
+    };
+    let (secs, frac) = match value.find('.') {
+        None => (value, "0"),
+        Some(i) => (value.slice_to(i), value.slice_from(i + 1))
+    };
+    // a Tm without a UTC offset converts as UTC
+    let tm = match time::strptime(secs, "%Y-%m-%dT%H:%M:%S") {
+        Ok(tm) => tm,
+        Err(_) => return None
+    };
+    let frac: f64 = match from_str(format!("0.{}", frac).as_slice()) {
+        None => return None,
+        Some(f) => f
+    };
+    Some(tm.to_timespec().sec as f64 + frac)
+}
+
+/// Formats tags for the start of an outgoing line, including the leading @
+pub fn format(tags: &[(~str, ~str)]) -> ~str {
+    let mut s = ~"@";
+    for (i, &(ref name, ref value)) in tags.iter().enumerate() {
+        if i > 0 {
+            s.push_char(';');
+        }
+        s.push_str(*name);
+        if !value.is_empty() {
+            s.push_char('=');
+            s.push_str(escape(*value));
+        }
+    }
+    s
+}
+
+fn split(line: &Line) -> Option<(Tags, Line)> {
+    let tags = match line.command {
+        IRCCmd(ref cmd) if cmd.starts_with("@") => parse(cmd.slice_from(1)),
+        _ => return None
+    };
+    // The source, if any, started with a colon, so irclib took it and everything after
+    // it as the trailing argument. Otherwise the command and middle arguments are intact,
+    // and only the last argument may have been trailing. Either way, marking the last
+    // argument as trailing reproduces the line.
+    let mut rest = ~[];
+    let n = line.args.len();
+    for (i, arg) in line.args.iter().enumerate() {
+        if i > 0 {
+            rest.push(' ' as u8);
+        }
+        if i == n - 1 {
+            rest.push(':' as u8);
+        }
+        rest.push_all(arg.as_slice());
+    }
+    Line::parse(rest).map(|line| (tags, line))
+}
+
+fn parse(s: &str) -> Tags {
+    s.split(';').filter(|t| !t.is_empty()).map(|tag| {
+        match tag.find('=') {
+            None => (tag.to_owned(), ~""),
+            Some(i) => (tag.slice_to(i).to_owned(), unescape(tag.slice_from(i+1)))
+        }
+    }).collect()
+}
+
+fn unescape(s: &str) -> ~str {
+    let mut out = str::with_capacity(s.len());
+    let mut chars = s.chars();
+    loop {
+        match chars.next() {
+            None => break,
+            Some('\\') => match chars.next() {
+                Some(':') => out.push_char(';'),
+                Some('s') => out.push_char(' '),
+                Some('r') => out.push_char('\r'),
+                Some('n') => out.push_char('\n'),
+                Some(c) => out.push_char(c), // includes \\
+                None => break // a trailing backslash is dropped
+            },
+            Some(c) => out.push_char(c)
+        }
+    }
+    out
+}
+
+fn escape(s: &str) -> ~str {
+    let mut out = str::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            ';' => out.push_str("\\:"),
+            ' ' => out.push_str("\\s"),
+            '\\' => out.push_str("\\\\"),
+            '\r' => out.push_str("\\r"),
+            '\n' => out.push_str("\\n"),
+            c => out.push_char(c)
+        }
+    }
+    out
+}
@@ -0,0 +1,76 @@
+/// Timeline of the current connection
+///
+/// Records when each stage of connecting and registering happened, so the console
+/// /status command can show where a slow connect spent its time. Name resolution
+/// happens inside the connect call, so it's included in the time to connect.
+
+use State;
+use irc::conn::{Conn, Line, IRCCmd, IRCCode};
+use std::str;
+use time;
+
+static MAX_EVENTS: uint = 50; // later joins are not recorded beyond this
+
+pub struct Timeline {
+    priv started: time::Tm, // wall clock time the connection attempt began
+    priv start_ns: u64,
+    priv events: ~[(u64, ~str)] // precise_time_ns() and description of each stage
+}
+
+impl Timeline {
+    /// Starts a timeline for a connection attempt to host:port
+    pub fn new(host: &str, port: u16) -> Timeline {
+        let mut timeline = Timeline {
+            started: time::now(),
+            start_ns: time::precise_time_ns(),
+            events: ~[]
+        };
+        timeline.mark(format!("connecting to {}:{}", host, port));
+        timeline
+    }
+
+    /// Records that a stage happened now
+    pub fn mark(&mut self, what: ~str) {
+        if self.events.len() < MAX_EVENTS {
+            self.events.push((time::precise_time_ns(), what));
+        }
+    }
+}
+
+/// Records the registration stages and our own joins
+pub fn line_received(conn: &Conn, state: &mut State, line: &Line) {
+    let what = match line.command {
+        IRCCode(1) => ~"registered (001)",
+        IRCCode(376) | IRCCode(422) => ~"end of MOTD",
+        IRCCode(903) => ~"SASL authentication succeeded",
+        IRCCode(904) => ~"SASL authentication failed",
+        IRCCmd(ref cmd) if cmd.as_slice() == "JOIN" && !line.args.is_empty() => {
+            let from_me = match line.prefix {
+                None => false,
+                Some(ref user) => user.nick() == conn.me().nick()
+            };
+            if !from_me {
+                return;
+            }
+            format!("joined {}", str::from_utf8_lossy(line.args[0]))
+        }
+        _ => return
+    };
+    state.timeline.mark(what);
+}
+
+/// Prints the status of the connection, including its timeline
+pub fn print_status(conn: &Conn, state: &State) {
+    println!("Nick: {} (configured: {})", str::from_utf8_lossy(conn.me().nick()), state.nick);
+    println!("Logged in: {}", state.logged_in);
+    println!("Session: {}", state.session);
+    match state.keepalive.lag() {
+        None => println!("Lag: unknown"),
+        Some(ms) => println!("Lag: {}ms", ms)
+    }
+    let timeline = &state.timeline;
+    println!("Connection started at {}", timeline.started.rfc3339());
+    for &(ns, ref what) in timeline.events.iter() {
+        println!("  +{:>8}ms {}", (ns - timeline.start_ns) / 1000000, *what);
+    }
+}
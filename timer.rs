@@ -0,0 +1,43 @@
+/// Timers that run code on the connection's task
+
+use {Cmd, State};
+use std::{io, task};
+use irc::conn::Conn;
+
+/// Spawns a new (unwatched) task that runs `f` on the connection every `ms` milliseconds.
+/// The task exits once the connection goes away.
+pub fn every(name: &'static str, ms: u64, chan: Sender<Cmd>, f: fn(&mut Conn, &mut State)) {
+    task::task().named(name).spawn(proc() {
+        let mut timer = match io::timer::Timer::new() {
+            Ok(t) => t,
+            Err(e) => {
+                println!("Error creating {} timer: {}", name, e);
+                return;
+            }
+        };
+        let periodic = timer.periodic(ms);
+        loop {
+            periodic.recv();
+            if !chan.try_send(proc(conn: &mut Conn, state: &mut State) { f(conn, state) }) {
+                // the connection is gone
+                break;
+            }
+        }
+    });
+}
+
+/// Spawns a new (unwatched) task that runs `cmd` on the connection after `ms` milliseconds.
+/// If the connection is gone by then, `cmd` is dropped.
+pub fn after(name: &'static str, ms: u64, chan: Sender<Cmd>, cmd: Cmd) {
+    task::task().named(name).spawn(proc() {
+        let mut timer = match io::timer::Timer::new() {
+            Ok(t) => t,
+            Err(e) => {
+                println!("Error creating {} timer: {}", name, e);
+                return;
+            }
+        };
+        timer.sleep(ms);
+        chan.try_send(cmd);
+    });
+}